@@ -1,6 +0,0 @@
-pub struct APU {}
-impl APU {
-    pub fn new() -> APU {
-        APU {}
-    }
-}
@@ -0,0 +1,227 @@
+// https://wiki.nesdev.com/w/index.php/APU_DMC
+
+use region::Region;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+// timer periods in CPU cycles
+static RATE_TABLE_NTSC : [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+static RATE_TABLE_PAL : [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+pub struct DMC {
+    rate_table : &'static [u16; 16],
+
+    irq_enabled : bool,
+    looping : bool,
+    pub irq : bool,
+
+    timer_period : u16,
+    timer : u16,
+
+    // memory reader
+    sample_addr : u16,
+    sample_length : u16,
+    current_addr : u16,
+    bytes_remaining : u16,
+    sample_buffer : Option<u8>,
+
+    // output unit
+    shift : u8,
+    bits_remaining : u8,
+    silence : bool,
+    output_level : u8,
+}
+
+impl DMC {
+    pub fn new() -> DMC {
+        DMC {
+            rate_table : &RATE_TABLE_NTSC,
+            irq_enabled : false,
+            looping : false,
+            irq : false,
+            timer_period : RATE_TABLE_NTSC[0],
+            timer : 0,
+            sample_addr : 0xC000,
+            sample_length : 1,
+            current_addr : 0xC000,
+            bytes_remaining : 0,
+            sample_buffer : None,
+            shift : 0,
+            bits_remaining : 8,
+            silence : true,
+            output_level : 0,
+        }
+    }
+
+    pub fn set_region(&mut self, region : Region) {
+        self.rate_table = match region {
+            Region::Pal => &RATE_TABLE_PAL,
+            Region::Ntsc | Region::Dendy => &RATE_TABLE_NTSC,
+        };
+    }
+
+    // reg is the register offset, 0 - 3
+    pub fn write_reg(&mut self, reg : u16, val : u8) {
+        match reg {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = (val & 0x80) != 0;
+                self.looping = (val & 0x40) != 0;
+                self.timer_period = self.rate_table[(val & 0x0F) as usize];
+
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            },
+            // -DDD DDDD, direct load of the output level
+            1 => self.output_level = val & 0x7F,
+            // sample address is %11AAAAAA.AA000000
+            2 => self.sample_addr = 0xC000 | ((val as u16) << 6),
+            // sample length is %LLLL.LLLL0001
+            3 => self.sample_length = ((val as u16) << 4) | 1,
+            _ => panic!("invalid dmc register {}", reg),
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // bit 4 of $4015
+    pub fn set_enabled(&mut self, enabled : bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        }
+        else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // the address the memory reader wants to fetch from, when its
+    // sample buffer is empty
+    pub fn fetch_addr(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        }
+        else {
+            None
+        }
+    }
+
+    // called with the byte read from fetch_addr
+    pub fn fill(&mut self, val : u8) {
+        self.sample_buffer = Some(val);
+
+        // wraps around to $8000, not $0000
+        self.current_addr = if self.current_addr == 0xFFFF {
+            0x8000
+        } else {
+            self.current_addr + 1
+        };
+
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            }
+            else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if (self.shift & 1) == 1 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            }
+            else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+
+        // start a new output cycle
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift = val;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // clocked every CPU cycle, since the rate table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            self.clock_output();
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+}
+
+// the rate table comes from the region
+impl SaveState for DMC {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bool(self.irq_enabled);
+        w.bool(self.looping);
+        w.bool(self.irq);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+
+        w.u16(self.sample_addr);
+        w.u16(self.sample_length);
+        w.u16(self.current_addr);
+        w.u16(self.bytes_remaining);
+        w.bool(self.sample_buffer.is_some());
+        w.u8(self.sample_buffer.unwrap_or(0));
+
+        w.u8(self.shift);
+        w.u8(self.bits_remaining);
+        w.bool(self.silence);
+        w.u8(self.output_level);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.irq_enabled = r.bool();
+        self.looping = r.bool();
+        self.irq = r.bool();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+
+        self.sample_addr = r.u16();
+        self.sample_length = r.u16();
+        self.current_addr = r.u16();
+        self.bytes_remaining = r.u16();
+        let has_sample = r.bool();
+        let sample = r.u8();
+        self.sample_buffer = if has_sample { Some(sample) } else { None };
+
+        self.shift = r.u8();
+        self.bits_remaining = r.u8();
+        self.silence = r.bool();
+        self.output_level = r.u8();
+    }
+}
@@ -0,0 +1,349 @@
+// https://wiki.nesdev.com/w/index.php/FDS_audio
+// The Famicom Disk System's sound: a single channel playing a 64 step
+// wavetable, with a volume envelope and a modulation unit that bends the
+// pitch with another table.
+
+use super::{ ExpansionAudio, apu_pulse_level };
+use savestate::{ SaveState, StateWriter, StateReader };
+
+const WAVE_FIRST    : u16 = 0x4040;
+const WAVE_LAST     : u16 = 0x407F;
+const VOL_ENVELOPE  : u16 = 0x4080;
+const FREQ_LOW      : u16 = 0x4082;
+const FREQ_HIGH     : u16 = 0x4083;
+const MOD_ENVELOPE  : u16 = 0x4084;
+const MOD_COUNTER   : u16 = 0x4085;
+const MOD_FREQ_LOW  : u16 = 0x4086;
+const MOD_FREQ_HIGH : u16 = 0x4087;
+const MOD_TABLE     : u16 = 0x4088;
+const MASTER        : u16 = 0x4089;
+const ENVELOPE_SPEED : u16 = 0x408A;
+const VOL_GAIN      : u16 = 0x4090;
+const MOD_GAIN      : u16 = 0x4092;
+
+// how much each modulation table entry changes the counter, 4 resets it
+static MOD_STEPS : [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// 2/2, 2/3, 2/4 and 2/5
+static MASTER_VOLUMES : [f32; 4] = [1.0, 2.0 / 3.0, 0.5, 0.4];
+
+// at full volume, the FDS is about 2.4 times as loud as an APU pulse
+const MAX_LEVEL : f32 = 2.4;
+
+struct FdsEnvelope {
+    // the gain is set directly instead of changing over time
+    disabled : bool,
+    increase : bool,
+    speed : u8,
+    gain : u8,
+    counter : u32,
+}
+
+impl FdsEnvelope {
+    fn new() -> FdsEnvelope {
+        FdsEnvelope {
+            disabled : true,
+            increase : false,
+            speed : 0,
+            gain : 0,
+            counter : 0,
+        }
+    }
+
+    // MDVV VVVV
+    fn write(&mut self, val : u8, master_speed : u8) {
+        self.disabled = val & 0x80 != 0;
+        self.increase = val & 0x40 != 0;
+        self.speed = val & 0x3F;
+        if self.disabled {
+            self.gain = val & 0x3F;
+        }
+        self.reset_counter(master_speed);
+    }
+
+    fn reset_counter(&mut self, master_speed : u8) {
+        self.counter = 8 * (self.speed as u32 + 1) * master_speed as u32;
+    }
+
+    fn clock(&mut self, master_speed : u8) {
+        if self.disabled {
+            return;
+        }
+
+        if self.counter > 0 {
+            self.counter -= 1;
+            return;
+        }
+        self.reset_counter(master_speed);
+
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        }
+        else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+impl SaveState for FdsEnvelope {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bool(self.disabled);
+        w.bool(self.increase);
+        w.u8(self.speed);
+        w.u8(self.gain);
+        w.u32(self.counter);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.disabled = r.bool();
+        self.increase = r.bool();
+        self.speed = r.u8();
+        self.gain = r.u8();
+        self.counter = r.u32();
+    }
+}
+
+pub struct Fds {
+    wave : [u8; 64],
+    // the wavetable can only be written while this is set, and the
+    // channel holds its output meanwhile
+    wave_write : bool,
+    wave_halt : bool,
+    wave_acc : u32,
+    wave_pos : u8,
+    freq : u16,
+
+    envelopes_halted : bool,
+    master_speed : u8,
+    vol_envelope : FdsEnvelope,
+    mod_envelope : FdsEnvelope,
+
+    mod_table : [u8; 32],
+    mod_write_pos : u8,
+    // 0 - 63, each table entry is used twice
+    mod_pos : u8,
+    mod_halt : bool,
+    mod_freq : u16,
+    mod_acc : u32,
+    // 7 bit signed
+    mod_counter : i8,
+
+    master_volume : u8,
+    output : f32,
+}
+
+impl Fds {
+    pub fn new() -> Fds {
+        Fds {
+            wave : [0; 64],
+            wave_write : false,
+            wave_halt : true,
+            wave_acc : 0,
+            wave_pos : 0,
+            freq : 0,
+            envelopes_halted : false,
+            master_speed : 0xE8,
+            vol_envelope : FdsEnvelope::new(),
+            mod_envelope : FdsEnvelope::new(),
+            mod_table : [0; 32],
+            mod_write_pos : 0,
+            mod_pos : 0,
+            mod_halt : true,
+            mod_freq : 0,
+            mod_acc : 0,
+            mod_counter : 0,
+            master_volume : 0,
+            output : 0.0,
+        }
+    }
+
+    fn step_mod(&mut self) {
+        let entry = self.mod_table[(self.mod_pos / 2) as usize];
+        self.mod_counter = if entry == 4 {
+            0
+        } else {
+            sign_extend_7(self.mod_counter.wrapping_add(MOD_STEPS[entry as usize]))
+        };
+        self.mod_pos = (self.mod_pos + 1) & 0x3F;
+    }
+
+    // the wave frequency bent by the modulator, the calculation is from
+    // the wiki and matches the hardware's rounding
+    fn modulated_freq(&self) -> u32 {
+        let pitch = self.freq as i32;
+        let mut temp = self.mod_counter as i32 * self.mod_envelope.gain as i32;
+
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            if self.mod_counter < 0 { temp -= 1 } else { temp += 2 }
+        }
+
+        if temp >= 192 {
+            temp -= 256;
+        }
+        else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+
+        (pitch + temp).max(0) as u32
+    }
+}
+
+// keeps a counter in the 7 bit signed range
+fn sign_extend_7(val : i8) -> i8 {
+    ((val as u8) << 1) as i8 >> 1
+}
+
+impl ExpansionAudio for Fds {
+    fn maps_addr(&self, addr : u16) -> bool {
+        matches!(addr, WAVE_FIRST...ENVELOPE_SPEED | VOL_GAIN | MOD_GAIN)
+    }
+
+    fn write(&mut self, addr : u16, val : u8) {
+        match addr {
+            WAVE_FIRST...WAVE_LAST if self.wave_write =>
+                self.wave[(addr - WAVE_FIRST) as usize] = val & 0x3F,
+            VOL_ENVELOPE => self.vol_envelope.write(val, self.master_speed),
+            FREQ_LOW => self.freq = (self.freq & 0x0F00) | val as u16,
+            // HE-- FFFF
+            FREQ_HIGH => {
+                self.freq = (self.freq & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.wave_halt = val & 0x80 != 0;
+                self.envelopes_halted = val & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_acc = 0;
+                    self.wave_pos = 0;
+                }
+            },
+            MOD_ENVELOPE => self.mod_envelope.write(val, self.master_speed),
+            MOD_COUNTER => self.mod_counter = sign_extend_7(val as i8),
+            MOD_FREQ_LOW => self.mod_freq = (self.mod_freq & 0x0F00) | val as u16,
+            MOD_FREQ_HIGH => {
+                self.mod_freq = (self.mod_freq & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.mod_halt = val & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_acc = 0;
+                }
+            },
+            MOD_TABLE if self.mod_halt => {
+                self.mod_table[self.mod_write_pos as usize] = val & 0x07;
+                self.mod_write_pos = (self.mod_write_pos + 1) & 0x1F;
+            },
+            // W--- --VV
+            MASTER => {
+                self.wave_write = val & 0x80 != 0;
+                self.master_volume = val & 0x03;
+            },
+            ENVELOPE_SPEED => self.master_speed = val,
+            _ => (),
+        }
+    }
+
+    fn read(&mut self, addr : u16) -> Option<u8> {
+        // the top bits are open bus, which is usually $40 from the
+        // address's high byte
+        match addr {
+            WAVE_FIRST...WAVE_LAST =>
+                Some(self.wave[(addr - WAVE_FIRST) as usize] | 0x40),
+            VOL_GAIN => Some(self.vol_envelope.gain | 0x40),
+            MOD_GAIN => Some(self.mod_envelope.gain | 0x40),
+            _ => None,
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halt && self.master_speed != 0 {
+            let speed = self.master_speed;
+            self.vol_envelope.clock(speed);
+            self.mod_envelope.clock(speed);
+        }
+
+        if !self.mod_halt && self.mod_freq != 0 {
+            self.mod_acc += self.mod_freq as u32;
+            if self.mod_acc >= 0x10000 {
+                self.mod_acc -= 0x10000;
+                self.step_mod();
+            }
+        }
+
+        if !self.wave_halt && !self.wave_write {
+            self.wave_acc = (self.wave_acc + self.modulated_freq()) & 0x3F_FFFF;
+            self.wave_pos = (self.wave_acc >> 16) as u8;
+        }
+
+        // the output holds while the wavetable is being written
+        if !self.wave_write {
+            let gain = self.vol_envelope.gain.min(32) as f32;
+            let level = self.wave[self.wave_pos as usize] as f32 * gain / (63.0 * 32.0);
+            self.output = level * MASTER_VOLUMES[self.master_volume as usize] *
+                          MAX_LEVEL * apu_pulse_level();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+}
+
+impl SaveState for Fds {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bytes(&self.wave);
+        w.bool(self.wave_write);
+        w.bool(self.wave_halt);
+        w.u32(self.wave_acc);
+        w.u8(self.wave_pos);
+        w.u16(self.freq);
+
+        w.bool(self.envelopes_halted);
+        w.u8(self.master_speed);
+        self.vol_envelope.save_state(w);
+        self.mod_envelope.save_state(w);
+
+        w.bytes(&self.mod_table);
+        w.u8(self.mod_write_pos);
+        w.u8(self.mod_pos);
+        w.bool(self.mod_halt);
+        w.u16(self.mod_freq);
+        w.u32(self.mod_acc);
+        w.u8(self.mod_counter as u8);
+
+        w.u8(self.master_volume);
+        w.f64(self.output as f64);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        r.bytes(&mut self.wave);
+        self.wave_write = r.bool();
+        self.wave_halt = r.bool();
+        self.wave_acc = r.u32();
+        self.wave_pos = r.u8() & 0x3F;
+        self.freq = r.u16();
+
+        self.envelopes_halted = r.bool();
+        self.master_speed = r.u8();
+        self.vol_envelope.load_state(r);
+        self.mod_envelope.load_state(r);
+
+        r.bytes(&mut self.mod_table);
+        for entry in self.mod_table.iter_mut() {
+            *entry &= 0x07;
+        }
+        self.mod_write_pos = r.u8() & 0x1F;
+        self.mod_pos = r.u8() & 0x3F;
+        self.mod_halt = r.bool();
+        self.mod_freq = r.u16();
+        self.mod_acc = r.u32();
+        self.mod_counter = sign_extend_7(r.u8() as i8);
+
+        self.master_volume = r.u8() & 0x03;
+        self.output = r.f64() as f32;
+    }
+}
@@ -0,0 +1,121 @@
+// https://wiki.nesdev.com/w/index.php/MMC5_audio
+// Two pulse channels that work like the APU's without the sweep unit, and
+// a raw 8 bit PCM channel. The length counters and envelopes are clocked
+// at a fixed 240Hz instead of by a frame counter.
+
+use super::{ ExpansionAudio, mixer };
+use super::super::pulse::Pulse;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+const PULSE_1_FIRST : u16 = 0x5000;
+const PULSE_1_LAST  : u16 = 0x5003;
+const PULSE_2_FIRST : u16 = 0x5004;
+const PULSE_2_LAST  : u16 = 0x5007;
+const PCM_MODE      : u16 = 0x5010;
+const PCM_DATA      : u16 = 0x5011;
+const STATUS        : u16 = 0x5015;
+
+// about 240Hz
+const FRAME_CYCLES : u16 = 7457;
+
+pub struct Mmc5 {
+    pulse_1 : Pulse,
+    pulse_2 : Pulse,
+    // in read mode the pcm channel takes its samples from cpu reads, which
+    // isn't emulated
+    pcm_read_mode : bool,
+    pcm : u8,
+    frame_cycle : u16,
+    odd_cycle : bool,
+}
+
+impl Mmc5 {
+    pub fn new() -> Mmc5 {
+        Mmc5 {
+            pulse_1 : Pulse::without_sweep(),
+            pulse_2 : Pulse::without_sweep(),
+            pcm_read_mode : false,
+            pcm : 0,
+            frame_cycle : 0,
+            odd_cycle : false,
+        }
+    }
+}
+
+impl ExpansionAudio for Mmc5 {
+    fn maps_addr(&self, addr : u16) -> bool {
+        matches!(addr, PULSE_1_FIRST...PULSE_2_LAST | PCM_MODE | PCM_DATA | STATUS)
+    }
+
+    fn write(&mut self, addr : u16, val : u8) {
+        match addr {
+            PULSE_1_FIRST...PULSE_1_LAST =>
+                self.pulse_1.write_reg(addr - PULSE_1_FIRST, val),
+            PULSE_2_FIRST...PULSE_2_LAST =>
+                self.pulse_2.write_reg(addr - PULSE_2_FIRST, val),
+            PCM_MODE => self.pcm_read_mode = val & 0x01 != 0,
+            // writing 0 does nothing, it's used for the irq
+            PCM_DATA if !self.pcm_read_mode && val != 0 => self.pcm = val,
+            STATUS => {
+                self.pulse_1.length.set_enabled(val & 0x01 != 0);
+                self.pulse_2.length.set_enabled(val & 0x02 != 0);
+            },
+            _ => (),
+        }
+    }
+
+    fn read(&mut self, addr : u16) -> Option<u8> {
+        if addr == STATUS {
+            Some((self.pulse_1.length.active() as u8) |
+                 ((self.pulse_2.length.active() as u8) << 1))
+        }
+        else {
+            None
+        }
+    }
+
+    fn clock(&mut self) {
+        self.frame_cycle += 1;
+        if self.frame_cycle == FRAME_CYCLES {
+            self.frame_cycle = 0;
+
+            self.pulse_1.envelope.clock();
+            self.pulse_2.envelope.clock();
+            self.pulse_1.length.clock();
+            self.pulse_2.length.clock();
+        }
+
+        if self.odd_cycle {
+            self.pulse_1.clock_timer();
+            self.pulse_2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+    }
+
+    // the pulses go through the same kind of mixer as the APU's, and the
+    // pcm channel is about as loud as the dmc
+    fn output(&self) -> f32 {
+        mixer::pulse_out(self.pulse_1.output() as f32, self.pulse_2.output() as f32) +
+        mixer::tnd_out(0.0, 0.0, self.pcm as f32 / 2.0)
+    }
+}
+
+impl SaveState for Mmc5 {
+    fn save_state(&self, w : &mut StateWriter) {
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        w.bool(self.pcm_read_mode);
+        w.u8(self.pcm);
+        w.u16(self.frame_cycle);
+        w.bool(self.odd_cycle);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.pulse_1.load_state(r);
+        self.pulse_2.load_state(r);
+        self.pcm_read_mode = r.bool();
+        self.pcm = r.u8();
+        self.frame_cycle = r.u16();
+        self.odd_cycle = r.bool();
+    }
+}
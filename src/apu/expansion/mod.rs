@@ -0,0 +1,82 @@
+// https://wiki.nesdev.com/w/index.php/Expansion_audio
+//
+// Some cartridges have their own sound chips, which the Famicom mixes in
+// with the APU through the cartridge connector. Each chip watches the cpu
+// bus for writes to its registers, and adds its output to the APU's.
+
+mod vrc6;
+mod vrc7;
+mod sunsoft5b;
+mod n163;
+mod mmc5;
+mod fds;
+
+pub use self::vrc6::Vrc6;
+pub use self::vrc7::Vrc7;
+pub use self::sunsoft5b::Sunsoft5B;
+pub use self::n163::N163;
+pub use self::mmc5::Mmc5;
+pub use self::fds::Fds;
+
+use super::mixer;
+use savestate::SaveState;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpansionChip {
+    Vrc6,
+    Vrc7,
+    Sunsoft5B,
+    N163,
+    Mmc5,
+    Fds,
+}
+
+impl ExpansionChip {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ExpansionChip::Vrc6      => "VRC6",
+            ExpansionChip::Vrc7      => "VRC7",
+            ExpansionChip::Sunsoft5B => "Sunsoft 5B",
+            ExpansionChip::N163      => "Namco 163",
+            ExpansionChip::Mmc5      => "MMC5",
+            ExpansionChip::Fds       => "FDS",
+        }
+    }
+
+    pub fn create(&self) -> Box<dyn ExpansionAudio> {
+        match *self {
+            ExpansionChip::Vrc6      => Box::new(Vrc6::new()),
+            ExpansionChip::Vrc7      => Box::new(Vrc7::new()),
+            ExpansionChip::Sunsoft5B => Box::new(Sunsoft5B::new()),
+            ExpansionChip::N163      => Box::new(N163::new()),
+            ExpansionChip::Mmc5      => Box::new(Mmc5::new()),
+            ExpansionChip::Fds       => Box::new(Fds::new()),
+        }
+    }
+}
+
+// saved along with the APU, see APU::save_state
+pub trait ExpansionAudio : SaveState {
+    // whether the chip has a register at this cpu address
+    fn maps_addr(&self, addr : u16) -> bool;
+
+    fn write(&mut self, addr : u16, val : u8);
+
+    // most registers are write only
+    fn read(&mut self, _addr : u16) -> Option<u8> {
+        None
+    }
+
+    // called once per cpu cycle
+    fn clock(&mut self);
+
+    // on the same scale as the APU mixer's output, see apu_pulse_level
+    fn output(&self) -> f32;
+}
+
+// The relative levels of the chips are set against one APU pulse channel
+// at full volume, which is what most measurements compare them to.
+// https://wiki.nesdev.com/w/index.php/Namco_163_audio#Mixing
+fn apu_pulse_level() -> f32 {
+    mixer::pulse_out(15.0, 0.0)
+}
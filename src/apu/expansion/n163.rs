@@ -0,0 +1,163 @@
+// https://wiki.nesdev.com/w/index.php/Namco_163_audio
+// Up to 8 wavetable channels, with the waveforms and the channel registers
+// sharing 128 bytes of internal ram. Only one channel is updated and
+// output at a time, so more channels means a lower rate for each.
+
+use super::{ ExpansionAudio, apu_pulse_level };
+use savestate::{ SaveState, StateWriter, StateReader };
+
+const DATA_PORT : u16 = 0x4800;
+const ADDR_PORT : u16 = 0xF800;
+
+const RAM_SIZE : usize = 0x80;
+// the number of active channels is in the last channel's registers
+const CHANNELS_REG : usize = 0x7F;
+
+// cpu cycles to update one channel
+const CYCLES_PER_CHANNEL : u8 = 15;
+
+// a channel at full volume and full swing is several times louder than
+// an APU pulse, depending on the board. this is about the middle
+const MAX_LEVEL : f32 = 3.0;
+
+pub struct N163 {
+    ram : [u8; RAM_SIZE],
+    addr : u8,
+    auto_increment : bool,
+
+    cycle : u8,
+    // counts down from 7 to the last active channel
+    channel : usize,
+    // the last output of each channel
+    outputs : [i8; 8],
+}
+
+impl N163 {
+    pub fn new() -> N163 {
+        N163 {
+            ram : [0; RAM_SIZE],
+            addr : 0,
+            auto_increment : false,
+            cycle : 0,
+            channel : 7,
+            outputs : [0; 8],
+        }
+    }
+
+    fn num_channels(&self) -> usize {
+        ((self.ram[CHANNELS_REG] >> 4) & 0x07) as usize + 1
+    }
+
+    fn increment_addr(&mut self) {
+        if self.auto_increment {
+            self.addr = (self.addr + 1) & 0x7F;
+        }
+    }
+
+    fn update_channel(&mut self, channel : usize) {
+        let base = 0x40 + channel * 8;
+        let regs = &self.ram[base..base + 8];
+
+        let freq = regs[0] as u32 | ((regs[2] as u32) << 8) |
+                   ((regs[4] as u32 & 0x03) << 16);
+        let mut phase = regs[1] as u32 | ((regs[3] as u32) << 8) |
+                        ((regs[5] as u32) << 16);
+        // in 4 bit samples
+        let length = 256 - (regs[4] as u32 & 0xFC);
+        let wave_addr = regs[6] as u32;
+        let volume = (regs[7] & 0x0F) as i8;
+
+        phase = (phase + freq) % (length << 16);
+
+        let sample_addr = ((phase >> 16) + wave_addr) & 0xFF;
+        let byte = self.ram[(sample_addr / 2) as usize];
+        let sample = if sample_addr & 1 == 0 { byte & 0x0F } else { byte >> 4 };
+
+        self.outputs[channel] = (sample as i8 - 8) * volume;
+
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+    }
+}
+
+impl ExpansionAudio for N163 {
+    fn maps_addr(&self, addr : u16) -> bool {
+        addr & 0xF800 == DATA_PORT || addr & 0xF800 == ADDR_PORT
+    }
+
+    fn write(&mut self, addr : u16, val : u8) {
+        match addr & 0xF800 {
+            DATA_PORT => {
+                self.ram[self.addr as usize] = val;
+                self.increment_addr();
+            },
+            ADDR_PORT => {
+                self.addr = val & 0x7F;
+                self.auto_increment = val & 0x80 != 0;
+            },
+            _ => (),
+        }
+    }
+
+    fn read(&mut self, addr : u16) -> Option<u8> {
+        if addr & 0xF800 == DATA_PORT {
+            let val = self.ram[self.addr as usize];
+            self.increment_addr();
+            Some(val)
+        }
+        else {
+            None
+        }
+    }
+
+    fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.cycle = 0;
+
+        let channel = self.channel;
+        self.update_channel(channel);
+
+        let last = 8 - self.num_channels();
+        self.channel = if channel <= last { 7 } else { channel - 1 };
+    }
+
+    // the real chip switches between the channels fast enough that it's
+    // heard as their average
+    fn output(&self) -> f32 {
+        let num_channels = self.num_channels();
+        let sum : i32 = self.outputs[8 - num_channels..].iter()
+            .map(|&out| out as i32)
+            .sum();
+
+        sum as f32 / num_channels as f32 / 120.0 * MAX_LEVEL * apu_pulse_level()
+    }
+}
+
+// the channels' registers and waveforms are all in the ram
+impl SaveState for N163 {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bytes(&self.ram);
+        w.u8(self.addr);
+        w.bool(self.auto_increment);
+        w.u8(self.cycle);
+        w.u8(self.channel as u8);
+        for &out in self.outputs.iter() {
+            w.u8(out as u8);
+        }
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        r.bytes(&mut self.ram);
+        self.addr = r.u8() & 0x7F;
+        self.auto_increment = r.bool();
+        self.cycle = r.u8();
+        self.channel = (r.u8() & 0x07) as usize;
+        for out in self.outputs.iter_mut() {
+            *out = r.u8() as i8;
+        }
+    }
+}
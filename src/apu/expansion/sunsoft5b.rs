@@ -0,0 +1,290 @@
+// https://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+// A YM2149F (a version of the AY-3-8910) with 3 square wave channels,
+// a noise generator and an envelope generator, all on a logarithmic
+// volume scale.
+
+use super::{ ExpansionAudio, apu_pulse_level };
+use savestate::{ SaveState, StateWriter, StateReader };
+
+const ADDR_SELECT : u16 = 0xC000;
+const ADDR_DATA : u16 = 0xE000;
+
+// the chip's internal counters run every 16 cpu cycles
+const PRESCALER : u8 = 16;
+
+// how loud a channel at its loudest is compared to an APU pulse. The 5B is
+// a lot louder than the APU, and the volume curve makes it worse
+const MAX_LEVEL : f32 = 2.0;
+
+struct Tone {
+    period : u16,
+    counter : u16,
+    high : bool,
+}
+
+pub struct Sunsoft5B {
+    selected : u8,
+
+    tones : [Tone; 3],
+    // 0 enables a channel's tone or noise, unlike what you'd expect
+    mixer : u8,
+    // 4 bit volume, or bit 4 for using the envelope
+    volumes : [u8; 3],
+
+    noise_period : u8,
+    noise_counter : u8,
+    // 17 bit shift register
+    noise_shift : u32,
+
+    envelope_period : u16,
+    envelope_counter : u16,
+    envelope_shape : u8,
+    // 0 - 31 through the current cycle of the envelope
+    envelope_step : u8,
+    envelope_holding : bool,
+    envelope_flipped : bool,
+
+    prescaler : u8,
+    // amplitude of each of the 32 levels, 1.5dB apart
+    levels : [f32; 32],
+}
+
+impl Sunsoft5B {
+    pub fn new() -> Sunsoft5B {
+        let mut levels = [0.0; 32];
+        for (i, level) in levels.iter_mut().enumerate().skip(1) {
+            *level = 10f32.powf((i as f32 - 31.0) * 1.5 / 20.0);
+        }
+
+        Sunsoft5B {
+            selected : 0,
+            tones : [
+                Tone { period : 0, counter : 0, high : false },
+                Tone { period : 0, counter : 0, high : false },
+                Tone { period : 0, counter : 0, high : false },
+            ],
+            mixer : 0xFF,
+            volumes : [0; 3],
+            noise_period : 0,
+            noise_counter : 0,
+            noise_shift : 1,
+            envelope_period : 0,
+            envelope_counter : 0,
+            envelope_shape : 0,
+            envelope_step : 0,
+            envelope_holding : false,
+            envelope_flipped : false,
+            prescaler : 0,
+            levels : levels,
+        }
+    }
+
+    fn write_reg(&mut self, reg : u8, val : u8) {
+        match reg {
+            0x00...0x05 => {
+                let tone = &mut self.tones[reg as usize / 2];
+                tone.period = if reg & 1 == 0 {
+                    (tone.period & 0x0F00) | val as u16
+                } else {
+                    (tone.period & 0x00FF) | ((val as u16 & 0x0F) << 8)
+                };
+            },
+            0x06 => self.noise_period = val & 0x1F,
+            0x07 => self.mixer = val,
+            0x08...0x0A => self.volumes[reg as usize - 0x08] = val & 0x1F,
+            0x0B => self.envelope_period = (self.envelope_period & 0xFF00) | val as u16,
+            0x0C => self.envelope_period = (self.envelope_period & 0x00FF) | ((val as u16) << 8),
+            // CAaH, writing restarts the envelope
+            0x0D => {
+                self.envelope_shape = val & 0x0F;
+                self.envelope_step = 0;
+                self.envelope_counter = 0;
+                self.envelope_holding = false;
+                self.envelope_flipped = false;
+            },
+            // the rest are io ports
+            _ => (),
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        self.envelope_counter += 1;
+        if self.envelope_counter < self.envelope_period.max(1) {
+            return;
+        }
+        self.envelope_counter = 0;
+
+        if self.envelope_holding {
+            return;
+        }
+
+        if self.envelope_step < 31 {
+            self.envelope_step += 1;
+            return;
+        }
+
+        let shape = self.envelope_shape;
+        let cont      = shape & 0x08 != 0;
+        let attack    = shape & 0x04 != 0;
+        let alternate = shape & 0x02 != 0;
+        let hold      = shape & 0x01 != 0;
+
+        if !cont {
+            // one cycle and then silence
+            self.envelope_holding = true;
+            self.envelope_flipped = attack;
+        }
+        else if hold {
+            self.envelope_holding = true;
+            if alternate {
+                self.envelope_flipped = !self.envelope_flipped;
+            }
+        }
+        else {
+            self.envelope_step = 0;
+            if alternate {
+                self.envelope_flipped = !self.envelope_flipped;
+            }
+        }
+    }
+
+    // 0 - 31
+    fn envelope_level(&self) -> u8 {
+        let attack = self.envelope_shape & 0x04 != 0;
+        let rising = attack != self.envelope_flipped;
+
+        if self.envelope_holding && self.envelope_shape & 0x08 == 0 {
+            // shapes without continue end at 0
+            return 0;
+        }
+        if rising { self.envelope_step } else { 31 - self.envelope_step }
+    }
+
+    fn clock_noise(&mut self) {
+        self.noise_counter += 1;
+        // noise runs at half the rate of the tones
+        if self.noise_counter < self.noise_period.max(1) * 2 {
+            return;
+        }
+        self.noise_counter = 0;
+
+        let bit = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+        self.noise_shift = (self.noise_shift >> 1) | (bit << 16);
+    }
+}
+
+impl ExpansionAudio for Sunsoft5B {
+    fn maps_addr(&self, addr : u16) -> bool {
+        addr & 0xE000 == ADDR_SELECT || addr & 0xE000 == ADDR_DATA
+    }
+
+    fn write(&mut self, addr : u16, val : u8) {
+        match addr & 0xE000 {
+            ADDR_SELECT => self.selected = val & 0x0F,
+            ADDR_DATA => {
+                let reg = self.selected;
+                self.write_reg(reg, val);
+            },
+            _ => (),
+        }
+    }
+
+    fn clock(&mut self) {
+        self.prescaler += 1;
+        if self.prescaler < PRESCALER {
+            return;
+        }
+        self.prescaler = 0;
+
+        for tone in self.tones.iter_mut() {
+            tone.counter += 1;
+            if tone.counter >= tone.period.max(1) {
+                tone.counter = 0;
+                tone.high = !tone.high;
+            }
+        }
+
+        self.clock_noise();
+        self.clock_envelope();
+    }
+
+    fn output(&self) -> f32 {
+        let noise_high = self.noise_shift & 1 != 0;
+        let mut sum = 0.0;
+
+        for (i, tone) in self.tones.iter().enumerate() {
+            let tone_on = tone.high || self.mixer & (0x01 << i) != 0;
+            let noise_on = noise_high || self.mixer & (0x08 << i) != 0;
+            if !(tone_on && noise_on) {
+                continue;
+            }
+
+            let volume = self.volumes[i];
+            let level = if volume & 0x10 != 0 {
+                self.envelope_level()
+            }
+            else if volume == 0 {
+                0
+            }
+            else {
+                // the 4 bit volumes line up with every other envelope level
+                volume * 2 + 1
+            };
+
+            sum += self.levels[level as usize];
+        }
+
+        sum * MAX_LEVEL * apu_pulse_level()
+    }
+}
+
+// the volume levels are fixed, so they aren't saved
+impl SaveState for Sunsoft5B {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u8(self.selected);
+        for tone in self.tones.iter() {
+            w.u16(tone.period);
+            w.u16(tone.counter);
+            w.bool(tone.high);
+        }
+        w.u8(self.mixer);
+        w.bytes(&self.volumes);
+
+        w.u8(self.noise_period);
+        w.u8(self.noise_counter);
+        w.u32(self.noise_shift);
+
+        w.u16(self.envelope_period);
+        w.u16(self.envelope_counter);
+        w.u8(self.envelope_shape);
+        w.u8(self.envelope_step);
+        w.bool(self.envelope_holding);
+        w.bool(self.envelope_flipped);
+
+        w.u8(self.prescaler);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.selected = r.u8();
+        for tone in self.tones.iter_mut() {
+            tone.period = r.u16();
+            tone.counter = r.u16();
+            tone.high = r.bool();
+        }
+        self.mixer = r.u8();
+        r.bytes(&mut self.volumes);
+
+        self.noise_period = r.u8();
+        self.noise_counter = r.u8();
+        self.noise_shift = r.u32();
+
+        self.envelope_period = r.u16();
+        self.envelope_counter = r.u16();
+        self.envelope_shape = r.u8();
+        self.envelope_step = r.u8().min(31);
+        self.envelope_holding = r.bool();
+        self.envelope_flipped = r.bool();
+
+        self.prescaler = r.u8();
+    }
+}
@@ -0,0 +1,260 @@
+// https://wiki.nesdev.com/w/index.php/VRC6_audio
+// Two pulse channels with 8 duty cycles, and a sawtooth channel.
+
+use super::{ ExpansionAudio, apu_pulse_level };
+use savestate::{ SaveState, StateWriter, StateReader };
+
+const FREQ_CONTROL : u16 = 0x9003;
+
+struct Vrc6Pulse {
+    // ignore duty and always output the volume
+    mode : bool,
+    duty : u8,
+    volume : u8,
+    period : u16,
+    enabled : bool,
+    timer : u16,
+    step : u8,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            mode : false,
+            duty : 0,
+            volume : 0,
+            period : 0,
+            enabled : false,
+            timer : 0,
+            step : 0,
+        }
+    }
+
+    fn write(&mut self, reg : u16, val : u8) {
+        match reg {
+            // MDDD VVVV
+            0 => {
+                self.mode = val & 0x80 != 0;
+                self.duty = (val >> 4) & 0x07;
+                self.volume = val & 0x0F;
+            },
+            1 => self.period = (self.period & 0x0F00) | val as u16,
+            // E--- PPPP
+            _ => {
+                self.period = (self.period & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.enabled = val & 0x80 != 0;
+                // disabling resets the duty cycle
+                if !self.enabled {
+                    self.step = 15;
+                }
+            },
+        }
+    }
+
+    fn clock(&mut self, shift : u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = if self.step == 0 { 15 } else { self.step - 1 };
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bool(self.mode);
+        w.u8(self.duty);
+        w.u8(self.volume);
+        w.u16(self.period);
+        w.bool(self.enabled);
+        w.u16(self.timer);
+        w.u8(self.step);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.mode = r.bool();
+        self.duty = r.u8();
+        self.volume = r.u8();
+        self.period = r.u16();
+        self.enabled = r.bool();
+        self.timer = r.u16();
+        self.step = r.u8();
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.mode || self.step <= self.duty) {
+            self.volume
+        }
+        else {
+            0
+        }
+    }
+}
+
+struct Vrc6Saw {
+    rate : u8,
+    period : u16,
+    enabled : bool,
+    timer : u16,
+    // the accumulator gets added to on every other step, and reset
+    // after 14 steps
+    step : u8,
+    accumulator : u8,
+}
+
+impl Vrc6Saw {
+    fn new() -> Vrc6Saw {
+        Vrc6Saw {
+            rate : 0,
+            period : 0,
+            enabled : false,
+            timer : 0,
+            step : 0,
+            accumulator : 0,
+        }
+    }
+
+    fn write(&mut self, reg : u16, val : u8) {
+        match reg {
+            0 => self.rate = val & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | val as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            },
+        }
+    }
+
+    fn clock(&mut self, shift : u8) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            }
+            else if self.step & 1 == 0 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u8(self.rate);
+        w.u16(self.period);
+        w.bool(self.enabled);
+        w.u16(self.timer);
+        w.u8(self.step);
+        w.u8(self.accumulator);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.rate = r.u8();
+        self.period = r.u16();
+        self.enabled = r.bool();
+        self.timer = r.u16();
+        self.step = r.u8();
+        self.accumulator = r.u8();
+    }
+
+    // the top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+pub struct Vrc6 {
+    pulse_1 : Vrc6Pulse,
+    pulse_2 : Vrc6Pulse,
+    saw : Vrc6Saw,
+    halt : bool,
+    // shifts every channel's period right, making them faster
+    shift : u8,
+}
+
+impl Vrc6 {
+    pub fn new() -> Vrc6 {
+        Vrc6 {
+            pulse_1 : Vrc6Pulse::new(),
+            pulse_2 : Vrc6Pulse::new(),
+            saw : Vrc6Saw::new(),
+            halt : false,
+            shift : 0,
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc6 {
+    fn maps_addr(&self, addr : u16) -> bool {
+        matches!(addr, 0x9000...0x9003 | 0xA000...0xA002 | 0xB000...0xB002)
+    }
+
+    fn write(&mut self, addr : u16, val : u8) {
+        match addr {
+            FREQ_CONTROL => {
+                self.halt = val & 0x01 != 0;
+                self.shift = if val & 0x04 != 0 {
+                    8
+                } else if val & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            },
+            0x9000...0x9002 => self.pulse_1.write(addr - 0x9000, val),
+            0xA000...0xA002 => self.pulse_2.write(addr - 0xA000, val),
+            0xB000...0xB002 => self.saw.write(addr - 0xB000, val),
+            _ => (),
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse_1.clock(self.shift);
+        self.pulse_2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    // the channels are mixed linearly, and a pulse at full volume is about
+    // as loud as one of the APU's
+    fn output(&self) -> f32 {
+        let sum = self.pulse_1.output() + self.pulse_2.output() + self.saw.output();
+        sum as f32 * apu_pulse_level() / 15.0
+    }
+}
+
+impl SaveState for Vrc6 {
+    fn save_state(&self, w : &mut StateWriter) {
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        self.saw.save_state(w);
+        w.bool(self.halt);
+        w.u8(self.shift);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.pulse_1.load_state(r);
+        self.pulse_2.load_state(r);
+        self.saw.load_state(r);
+        self.halt = r.bool();
+        self.shift = r.u8();
+    }
+}
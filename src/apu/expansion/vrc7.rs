@@ -0,0 +1,475 @@
+// https://wiki.nesdev.com/w/index.php/VRC7_audio
+// A cut down YM2413 (OPLL): 6 channels of 2 operator FM synthesis, with
+// 15 built in instruments and one custom one.
+//
+// This isn't a bit exact model of the chip's log-sin and exponent tables,
+// but works the same way: each channel is a modulator sine wave bending the
+// phase of a carrier sine wave, each with an ADSR envelope measured in dB.
+
+use super::{ ExpansionAudio, apu_pulse_level };
+use savestate::{ SaveState, StateWriter, StateReader };
+use std::f64::consts::PI;
+
+const ADDR_SELECT : u16 = 0x9010;
+const ADDR_DATA : u16 = 0x9030;
+
+// the chip makes a sample every 36 cpu cycles
+const CYCLES_PER_SAMPLE : u8 = 36;
+const SAMPLE_RATE : f64 = 1_789_773.0 / 36.0;
+
+const NUM_CHANNELS : usize = 6;
+
+// the envelope's range, anything quieter is silent
+const MAX_ATTENUATION : f64 = 48.0;
+
+// https://wiki.nesdev.com/w/index.php/VRC7_audio#Internal_patch_set
+static PATCHES : [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+// frequency multiplier of each operator, times 2
+static MULTIPLIERS : [u8; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+// key scale level attenuation in dB at the top octave, by the top 4 bits
+// of the frequency number
+static KSL_TABLE : [f64; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25,
+    36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+
+// how long the envelope takes to go through its whole range at rate 4,
+// every 4 rates after that is twice as fast
+const ATTACK_TIME : f64 = 2.8;
+const DECAY_TIME : f64 = 10.0;
+
+// tremolo and vibrato
+const AM_RATE : f64 = 3.7;
+const AM_DEPTH : f64 = 4.8;
+const VIB_RATE : f64 = 6.4;
+// in cents
+const VIB_DEPTH : f64 = 7.0;
+
+// how far the modulator at full volume moves the carrier's phase, in cycles
+const MOD_DEPTH : f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+// the settings from one half of a patch
+struct OperatorPatch {
+    am : bool,
+    vib : bool,
+    // holds at the sustain level instead of fading out
+    sustained : bool,
+    ksr : bool,
+    mult : u8,
+    ksl : u8,
+    rectified : bool,
+    attack : u8,
+    decay : u8,
+    sustain_level : u8,
+    release : u8,
+}
+
+impl OperatorPatch {
+    // op is 0 for the modulator and 1 for the carrier
+    fn from_patch(patch : &[u8; 8], op : usize) -> OperatorPatch {
+        let flags = patch[op];
+        OperatorPatch {
+            am : flags & 0x80 != 0,
+            vib : flags & 0x40 != 0,
+            sustained : flags & 0x20 != 0,
+            ksr : flags & 0x10 != 0,
+            mult : flags & 0x0F,
+            ksl : patch[2 + op] >> 6,
+            rectified : patch[3] & (0x08 << op) != 0,
+            attack : patch[4 + op] >> 4,
+            decay : patch[4 + op] & 0x0F,
+            sustain_level : patch[6 + op] >> 4,
+            release : patch[6 + op] & 0x0F,
+        }
+    }
+}
+
+struct Operator {
+    // in cycles of the waveform
+    phase : f64,
+    state : EnvelopeState,
+    // in dB
+    envelope : f64,
+}
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase : 0.0,
+            state : EnvelopeState::Release,
+            envelope : MAX_ATTENUATION,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        self.state = EnvelopeState::Release;
+    }
+
+    // dB per sample for a 4 bit rate
+    fn rate_step(rate : u8, rks : u8, full_time : f64) -> f64 {
+        if rate == 0 {
+            return 0.0;
+        }
+        let rate = (rate * 4 + rks).min(63) as f64;
+        let time = full_time * 2f64.powf(-(rate - 4.0) / 4.0);
+        MAX_ATTENUATION / (time * SAMPLE_RATE)
+    }
+
+    fn clock_envelope(&mut self, patch : &OperatorPatch, rks : u8,
+                      channel_sustain : bool) {
+        let rks = if patch.ksr { rks } else { rks >> 2 };
+
+        match self.state {
+            EnvelopeState::Attack => {
+                if patch.attack == 15 {
+                    self.envelope = 0.0;
+                } else {
+                    self.envelope -= Operator::rate_step(patch.attack, rks, ATTACK_TIME);
+                }
+                if self.envelope <= 0.0 {
+                    self.envelope = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            },
+            EnvelopeState::Decay => {
+                self.envelope += Operator::rate_step(patch.decay, rks, DECAY_TIME);
+                let sustain_level = patch.sustain_level as f64 * 3.0;
+                if self.envelope >= sustain_level {
+                    self.envelope = sustain_level;
+                    self.state = EnvelopeState::Sustain;
+                }
+            },
+            // percussive sounds keep fading out at the release rate
+            EnvelopeState::Sustain => if !patch.sustained {
+                self.envelope += Operator::rate_step(patch.release, rks, DECAY_TIME);
+            },
+            EnvelopeState::Release => {
+                let rate = if channel_sustain {
+                    5
+                } else if patch.sustained {
+                    patch.release
+                } else {
+                    7
+                };
+                self.envelope += Operator::rate_step(rate, rks, DECAY_TIME);
+            },
+        }
+
+        self.envelope = self.envelope.min(MAX_ATTENUATION);
+    }
+
+    fn save_state(&self, w : &mut StateWriter) {
+        w.f64(self.phase);
+        w.u8(self.state as u8);
+        w.f64(self.envelope);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.phase = r.f64();
+        self.state = match r.u8() {
+            0 => EnvelopeState::Attack,
+            1 => EnvelopeState::Decay,
+            2 => EnvelopeState::Sustain,
+            _ => EnvelopeState::Release,
+        };
+        self.envelope = r.f64();
+    }
+
+    // phase offset is in cycles
+    fn output(&self, patch : &OperatorPatch, phase_offset : f64, attenuation : f64) -> f64 {
+        let total = self.envelope + attenuation;
+        if total >= MAX_ATTENUATION {
+            return 0.0;
+        }
+
+        let mut wave = (2.0 * PI * (self.phase + phase_offset)).sin();
+        if patch.rectified && wave < 0.0 {
+            wave = 0.0;
+        }
+        wave * 10f64.powf(-total / 20.0)
+    }
+}
+
+struct Channel {
+    fnum : u16,
+    block : u8,
+    key : bool,
+    sustain : bool,
+    instrument : u8,
+    volume : u8,
+
+    modulator : Operator,
+    carrier : Operator,
+    // the modulator's last two outputs, for feedback
+    feedback : [f64; 2],
+    output : f64,
+}
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            fnum : 0,
+            block : 0,
+            key : false,
+            sustain : false,
+            instrument : 0,
+            volume : 0,
+            modulator : Operator::new(),
+            carrier : Operator::new(),
+            feedback : [0.0; 2],
+            output : 0.0,
+        }
+    }
+
+    fn set_key(&mut self, key : bool) {
+        if key && !self.key {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        }
+        else if !key && self.key {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key = key;
+    }
+
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u16(self.fnum);
+        w.u8(self.block);
+        w.bool(self.key);
+        w.bool(self.sustain);
+        w.u8(self.instrument);
+        w.u8(self.volume);
+        self.modulator.save_state(w);
+        self.carrier.save_state(w);
+        w.f64(self.feedback[0]);
+        w.f64(self.feedback[1]);
+        w.f64(self.output);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.fnum = r.u16() & 0x1FF;
+        self.block = r.u8() & 0x07;
+        self.key = r.bool();
+        self.sustain = r.bool();
+        self.instrument = r.u8() & 0x0F;
+        self.volume = r.u8() & 0x0F;
+        self.modulator.load_state(r);
+        self.carrier.load_state(r);
+        self.feedback = [r.f64(), r.f64()];
+        self.output = r.f64();
+    }
+
+    fn ksl_attenuation(&self, ksl : u8) -> f64 {
+        if ksl == 0 {
+            return 0.0;
+        }
+        let base = KSL_TABLE[(self.fnum >> 5) as usize] - 6.0 * (7 - self.block) as f64;
+        // 1.5, 3 or 6 dB per octave
+        base.max(0.0) * [0.0, 0.5, 1.0, 2.0][ksl as usize]
+    }
+
+    fn clock(&mut self, patch : &[u8; 8], am : f64, vib : f64) {
+        let mod_patch = OperatorPatch::from_patch(patch, 0);
+        let car_patch = OperatorPatch::from_patch(patch, 1);
+
+        // key scale rate, from the octave and the top bit of the frequency
+        let rks = self.block * 2 + (self.fnum >> 8) as u8;
+
+        let base_freq = self.fnum as f64 * 2f64.powi(self.block as i32 - 1) *
+                        SAMPLE_RATE / (1 << 18) as f64;
+
+        let advance = |op : &mut Operator, patch : &OperatorPatch| {
+            let mut freq = base_freq * MULTIPLIERS[patch.mult as usize] as f64 / 2.0;
+            if patch.vib {
+                freq *= vib;
+            }
+            op.phase = (op.phase + freq / SAMPLE_RATE).fract();
+        };
+        advance(&mut self.modulator, &mod_patch);
+        advance(&mut self.carrier, &car_patch);
+
+        let sustain = self.sustain;
+        self.modulator.clock_envelope(&mod_patch, rks, sustain);
+        self.carrier.clock_envelope(&car_patch, rks, sustain);
+
+        let am_for = |patch : &OperatorPatch| if patch.am { am } else { 0.0 };
+
+        // feedback is a modulation index of pi/16 up to 4pi
+        let fb = patch[3] & 0x07;
+        let fb_offset = if fb == 0 {
+            0.0
+        } else {
+            (self.feedback[0] + self.feedback[1]) / 2.0 * 2f64.powi(fb as i32 - 5) / 2.0
+        };
+
+        let mod_attenuation = (patch[2] & 0x3F) as f64 * 0.75 +
+                              self.ksl_attenuation(mod_patch.ksl) + am_for(&mod_patch);
+        let mod_out = self.modulator.output(&mod_patch, fb_offset, mod_attenuation);
+        self.feedback = [self.feedback[1], mod_out];
+
+        let car_attenuation = self.volume as f64 * 3.0 +
+                              self.ksl_attenuation(car_patch.ksl) + am_for(&car_patch);
+        self.output = self.carrier.output(&car_patch, mod_out * MOD_DEPTH, car_attenuation);
+    }
+}
+
+pub struct Vrc7 {
+    selected : u8,
+    custom_patch : [u8; 8],
+    channels : Vec<Channel>,
+    cycle : u8,
+    // in cycles
+    am_phase : f64,
+    vib_phase : f64,
+    output : f32,
+}
+
+impl Vrc7 {
+    pub fn new() -> Vrc7 {
+        Vrc7 {
+            selected : 0,
+            custom_patch : [0; 8],
+            channels : (0..NUM_CHANNELS).map(|_| Channel::new()).collect(),
+            cycle : 0,
+            am_phase : 0.0,
+            vib_phase : 0.0,
+            output : 0.0,
+        }
+    }
+
+    fn write_reg(&mut self, reg : u8, val : u8) {
+        let channel = (reg & 0x0F) as usize;
+        if reg >= 0x10 && channel >= NUM_CHANNELS {
+            return;
+        }
+
+        match reg & 0xF0 {
+            0x00 if reg < 0x08 => self.custom_patch[reg as usize] = val,
+            0x10 => {
+                let c = &mut self.channels[channel];
+                c.fnum = (c.fnum & 0x100) | val as u16;
+            },
+            // --ST OOOF
+            0x20 => {
+                let c = &mut self.channels[channel];
+                c.fnum = (c.fnum & 0xFF) | ((val as u16 & 0x01) << 8);
+                c.block = (val >> 1) & 0x07;
+                c.sustain = val & 0x20 != 0;
+                c.set_key(val & 0x10 != 0);
+            },
+            // IIII VVVV
+            0x30 => {
+                let c = &mut self.channels[channel];
+                c.instrument = val >> 4;
+                c.volume = val & 0x0F;
+            },
+            _ => (),
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc7 {
+    fn maps_addr(&self, addr : u16) -> bool {
+        addr == ADDR_SELECT || addr == ADDR_DATA
+    }
+
+    fn write(&mut self, addr : u16, val : u8) {
+        match addr {
+            ADDR_SELECT => self.selected = val,
+            ADDR_DATA => {
+                let reg = self.selected;
+                self.write_reg(reg, val);
+            },
+            _ => (),
+        }
+    }
+
+    fn clock(&mut self) {
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_SAMPLE {
+            return;
+        }
+        self.cycle = 0;
+
+        self.am_phase = (self.am_phase + AM_RATE / SAMPLE_RATE).fract();
+        self.vib_phase = (self.vib_phase + VIB_RATE / SAMPLE_RATE).fract();
+
+        let am = (1.0 - (2.0 * PI * self.am_phase).cos()) / 2.0 * AM_DEPTH;
+        let vib = 2f64.powf((2.0 * PI * self.vib_phase).sin() * VIB_DEPTH / 1200.0);
+
+        let mut sum = 0.0;
+        for channel in self.channels.iter_mut() {
+            let patch = match channel.instrument {
+                0 => self.custom_patch,
+                n => PATCHES[n as usize - 1],
+            };
+            channel.clock(&patch, am, vib);
+            sum += channel.output;
+        }
+
+        // a channel at full volume is about as loud as an APU pulse
+        self.output = sum as f32 * apu_pulse_level();
+    }
+
+    fn output(&self) -> f32 {
+        self.output
+    }
+}
+
+impl SaveState for Vrc7 {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u8(self.selected);
+        w.bytes(&self.custom_patch);
+        for channel in self.channels.iter() {
+            channel.save_state(w);
+        }
+        w.u8(self.cycle);
+        w.f64(self.am_phase);
+        w.f64(self.vib_phase);
+        w.f64(self.output as f64);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.selected = r.u8();
+        r.bytes(&mut self.custom_patch);
+        for channel in self.channels.iter_mut() {
+            channel.load_state(r);
+        }
+        self.cycle = r.u8();
+        self.am_phase = r.f64();
+        self.vib_phase = r.f64();
+        self.output = r.f64() as f32;
+    }
+}
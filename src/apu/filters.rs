@@ -0,0 +1,73 @@
+// https://wiki.nesdev.com/w/index.php/APU_Mixer#Emulation
+// The console's output goes through two high-pass filters and a low-pass
+// filter before it gets to the TV. They're all first order.
+
+use std::f32::consts::PI;
+
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+struct Filter {
+    kind : FilterKind,
+    alpha : f32,
+    prev_in : f32,
+    prev_out : f32,
+}
+
+impl Filter {
+    fn new(kind : FilterKind, cutoff : f32, sample_rate : f32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate;
+
+        let alpha = match kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+
+        Filter {
+            kind : kind,
+            alpha : alpha,
+            prev_in : 0.0,
+            prev_out : 0.0,
+        }
+    }
+
+    fn process(&mut self, x : f32) -> f32 {
+        let y = match self.kind {
+            FilterKind::HighPass =>
+                self.alpha * (self.prev_out + x - self.prev_in),
+            FilterKind::LowPass =>
+                self.prev_out + self.alpha * (x - self.prev_out),
+        };
+
+        self.prev_in = x;
+        self.prev_out = y;
+        y
+    }
+}
+
+pub struct OutputFilters {
+    filters : [Filter; 3],
+}
+
+impl OutputFilters {
+    pub fn new(sample_rate : f32) -> OutputFilters {
+        OutputFilters {
+            filters : [
+                Filter::new(FilterKind::HighPass, 90.0, sample_rate),
+                Filter::new(FilterKind::HighPass, 440.0, sample_rate),
+                Filter::new(FilterKind::LowPass, 14000.0, sample_rate),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, samples : &mut [f32]) {
+        for sample in samples.iter_mut() {
+            for filter in self.filters.iter_mut() {
+                *sample = filter.process(*sample);
+            }
+        }
+    }
+}
@@ -0,0 +1,28 @@
+// https://wiki.nesdev.com/w/index.php/APU_Mixer
+// The channels are combined by a resistor network, which isn't linear.
+
+use super::{ ChannelOutputs, NUM_CHANNELS };
+
+// the inputs are channel outputs, but can be scaled by a volume setting
+pub fn pulse_out(pulse_1 : f32, pulse_2 : f32) -> f32 {
+    let sum = pulse_1 + pulse_2;
+    if sum == 0.0 { 0.0 } else { 95.88 / (8128.0 / sum + 100.0) }
+}
+
+pub fn tnd_out(triangle : f32, noise : f32, dmc : f32) -> f32 {
+    let sum = triangle / 8227.0 +
+              noise / 12241.0 +
+              dmc / 22638.0;
+    if sum == 0.0 { 0.0 } else { 159.79 / (1.0 / sum + 100.0) }
+}
+
+// returns a level between 0 and 1 when the gains are at most 1 and there
+// aren't any expansion chips. gains are in the order of Channel::ALL
+pub fn mix(outputs : &ChannelOutputs, gains : &[f32; NUM_CHANNELS]) -> f32 {
+    pulse_out(outputs.pulse_1 as f32 * gains[0], outputs.pulse_2 as f32 * gains[1]) +
+    tnd_out(outputs.triangle as f32 * gains[2],
+            outputs.noise as f32 * gains[3],
+            outputs.dmc as f32 * gains[4]) +
+    // expansion audio is mixed in linearly
+    outputs.expansion * gains[5]
+}
@@ -0,0 +1,603 @@
+// https://wiki.nesdev.com/w/index.php/APU
+
+#[cfg(test)]
+mod tests;
+mod units;
+mod pulse;
+mod triangle;
+mod noise;
+mod dmc;
+mod mixer;
+mod filters;
+mod resampler;
+mod stream;
+mod expansion;
+
+use self::pulse::Pulse;
+use self::triangle::Triangle;
+use self::noise::Noise;
+use self::dmc::DMC;
+use self::stream::SampleStream;
+use self::expansion::ExpansionAudio;
+pub use self::expansion::ExpansionChip;
+use region::Region;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+const PULSE_1_FIRST  : u16 = 0x4000;
+const PULSE_1_LAST   : u16 = 0x4003;
+const PULSE_2_FIRST  : u16 = 0x4004;
+const PULSE_2_LAST   : u16 = 0x4007;
+const TRIANGLE_FIRST : u16 = 0x4008;
+const TRIANGLE_LAST  : u16 = 0x400B;
+const NOISE_FIRST    : u16 = 0x400C;
+const NOISE_LAST     : u16 = 0x400F;
+const DMC_FIRST      : u16 = 0x4010;
+const DMC_LAST       : u16 = 0x4013;
+const STATUS         : u16 = 0x4015;
+const FRAME_COUNTER  : u16 = 0x4017;
+
+// https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+// CPU cycles at which each step of the sequence happens. the last entry is
+// when the sequence starts over
+static FRAME_STEPS_NTSC : [[usize; 5]; 2] = [
+    [7457, 14913, 22371, 29829, 29830],
+    [7457, 14913, 22371, 37281, 37282],
+];
+static FRAME_STEPS_PAL : [[usize; 5]; 2] = [
+    [8313, 16627, 24939, 33253, 33254],
+    [8313, 16627, 24939, 41565, 41566],
+];
+
+pub const DEFAULT_SAMPLE_RATE : u32 = 44100;
+
+pub const NUM_CHANNELS : usize = 6;
+
+// the resampler is flushed this often so samples are ready in between frames
+const SAMPLE_CHUNK_CYCLES : usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameMode {
+    FourStep,
+    FiveStep,
+}
+
+// the current output level of each channel, before mixing
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ChannelOutputs {
+    pub pulse_1  : u8,
+    pub pulse_2  : u8,
+    pub triangle : u8,
+    pub noise    : u8,
+    pub dmc      : u8,
+    // all of the cartridge's sound chips together, already mixed
+    pub expansion : f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    DMC,
+    // cartridge sound chips
+    Expansion,
+}
+
+impl Channel {
+    pub const ALL : [Channel; NUM_CHANNELS] = [
+        Channel::Pulse1, Channel::Pulse2, Channel::Triangle,
+        Channel::Noise, Channel::DMC, Channel::Expansion,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Channel::Pulse1   => "pulse1",
+            Channel::Pulse2   => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise    => "noise",
+            Channel::DMC      => "dmc",
+            Channel::Expansion => "expansion",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Channel> {
+        Channel::ALL.iter().cloned().find(|channel| channel.name() == name)
+    }
+
+    // the level this channel would have if it were the only one playing
+    fn level(&self, outputs : &ChannelOutputs) -> f32 {
+        match *self {
+            Channel::Pulse1   => mixer::pulse_out(outputs.pulse_1 as f32, 0.0),
+            Channel::Pulse2   => mixer::pulse_out(0.0, outputs.pulse_2 as f32),
+            Channel::Triangle => mixer::tnd_out(outputs.triangle as f32, 0.0, 0.0),
+            Channel::Noise    => mixer::tnd_out(0.0, outputs.noise as f32, 0.0),
+            Channel::DMC      => mixer::tnd_out(0.0, 0.0, outputs.dmc as f32),
+            Channel::Expansion => outputs.expansion,
+        }
+    }
+}
+
+pub struct APU {
+    pulse_1  : Pulse,
+    pulse_2  : Pulse,
+    triangle : Triangle,
+    noise    : Noise,
+    dmc      : DMC,
+    // sound chips on the cartridge
+    expansion : Vec<Box<dyn ExpansionAudio>>,
+
+    frame_steps : &'static [[usize; 5]; 2],
+    frame_mode : FrameMode,
+    frame_cycle : usize,
+    irq_inhibit : bool,
+    frame_irq : bool,
+
+    // pulse timers only run on every other CPU cycle
+    odd_cycle : bool,
+
+    // audio output
+    clock_rate : f64,
+    sample_rate : u32,
+    rate_adjustment : f64,
+    output : SampleStream,
+    // one stream per channel when recording them separately
+    stems : Vec<SampleStream>,
+    last_outputs : ChannelOutputs,
+
+    // mix settings, these only change the output and never what the
+    // emulated hardware sees. indexed by Channel
+    volumes : [f32; NUM_CHANNELS],
+    muted : [bool; NUM_CHANNELS],
+    solo : Option<Channel>,
+
+    // cycles since the streams were last flushed
+    sample_cycle : usize,
+}
+
+impl APU {
+    pub fn new() -> APU {
+        let outputs = ChannelOutputs::default();
+        let level = mixer::mix(&outputs, &[1.0; NUM_CHANNELS]);
+
+        let mut apu = APU {
+            pulse_1  : Pulse::new(true),
+            pulse_2  : Pulse::new(false),
+            triangle : Triangle::new(),
+            noise    : Noise::new(),
+            dmc      : DMC::new(),
+            expansion : Vec::new(),
+            frame_steps : &FRAME_STEPS_NTSC,
+            frame_mode : FrameMode::FourStep,
+            frame_cycle : 0,
+            irq_inhibit : false,
+            frame_irq : false,
+            odd_cycle : false,
+            clock_rate : Region::Ntsc.cpu_clock_rate(),
+            sample_rate : DEFAULT_SAMPLE_RATE,
+            rate_adjustment : 1.0,
+            output : SampleStream::new(Region::Ntsc.cpu_clock_rate(),
+                                       DEFAULT_SAMPLE_RATE, level),
+            stems : Vec::new(),
+            last_outputs : outputs,
+            volumes : [1.0; NUM_CHANNELS],
+            muted : [false; NUM_CHANNELS],
+            solo : None,
+            sample_cycle : 0,
+        };
+
+        // the triangle starts out at a nonzero level
+        apu.last_outputs = apu.channel_outputs();
+        apu.reset_streams();
+        apu
+    }
+
+    pub fn set_region(&mut self, region : Region) {
+        self.frame_steps = match region {
+            Region::Pal => &FRAME_STEPS_PAL,
+            Region::Ntsc | Region::Dendy => &FRAME_STEPS_NTSC,
+        };
+        self.noise.set_region(region);
+        self.dmc.set_region(region);
+
+        self.clock_rate = region.cpu_clock_rate();
+        self.reset_streams();
+    }
+
+    // the rate of the samples returned by take_samples
+    pub fn set_sample_rate(&mut self, sample_rate : u32) {
+        self.sample_rate = sample_rate;
+        self.reset_streams();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // scales how many samples are made per emulated second, for keeping
+    // the audio device fed when the emulation runs a little fast or slow.
+    // should stay very close to 1 so the change in pitch isn't noticeable
+    pub fn set_rate_adjustment(&mut self, ratio : f64) {
+        self.rate_adjustment = ratio;
+        let rate = self.sample_rate as f64 * ratio;
+
+        self.output.set_rates(self.clock_rate, rate);
+        for stem in self.stems.iter_mut() {
+            stem.set_rates(self.clock_rate, rate);
+        }
+    }
+
+    // also makes a separate stream for each channel, see take_stem_samples
+    pub fn set_stems_enabled(&mut self, enabled : bool) {
+        self.stems = if enabled {
+            Channel::ALL.iter().filter(|&&channel| self.has_channel(channel))
+                .map(|&channel| self.new_stream(channel.level(&self.last_outputs)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+    }
+
+    pub fn stems_enabled(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    // the expansion channel is only there when the cartridge has sound chips
+    fn has_channel(&self, channel : Channel) -> bool {
+        channel != Channel::Expansion || !self.expansion.is_empty()
+    }
+
+    // the channels take_stem_samples returns, in order
+    pub fn stem_channels(&self) -> Vec<Channel> {
+        if self.stems_enabled() {
+            Channel::ALL.iter().cloned().filter(|&channel| self.has_channel(channel)).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    pub fn add_expansion(&mut self, chip : ExpansionChip) {
+        self.expansion.push(chip.create());
+        let stems = self.stems_enabled();
+        self.set_stems_enabled(stems);
+    }
+
+    pub fn expansion_maps_addr(&self, addr : u16) -> bool {
+        self.expansion.iter().any(|chip| chip.maps_addr(addr))
+    }
+
+    // every chip that maps the address sees the write
+    pub fn expansion_write(&mut self, addr : u16, val : u8) {
+        for chip in self.expansion.iter_mut().filter(|chip| chip.maps_addr(addr)) {
+            chip.write(addr, val);
+        }
+    }
+
+    pub fn expansion_read(&mut self, addr : u16) -> Option<u8> {
+        self.expansion.iter_mut()
+            .filter(|chip| chip.maps_addr(addr))
+            .filter_map(|chip| chip.read(addr))
+            .next()
+    }
+
+    fn new_stream(&self, level : f32) -> SampleStream {
+        let mut stream = SampleStream::new(self.clock_rate, self.sample_rate, level);
+        stream.set_rates(self.clock_rate, self.sample_rate as f64 * self.rate_adjustment);
+        stream
+    }
+
+    // 1.0 is the normal level
+    pub fn set_channel_volume(&mut self, channel : Channel, volume : f32) {
+        self.volumes[channel as usize] = volume.max(0.0);
+        self.update_mix();
+    }
+
+    pub fn channel_volume(&self, channel : Channel) -> f32 {
+        self.volumes[channel as usize]
+    }
+
+    pub fn set_channel_muted(&mut self, channel : Channel, muted : bool) {
+        self.muted[channel as usize] = muted;
+        self.update_mix();
+    }
+
+    pub fn channel_muted(&self, channel : Channel) -> bool {
+        self.muted[channel as usize]
+    }
+
+    // only the solo channel is heard, if there is one
+    pub fn set_solo(&mut self, solo : Option<Channel>) {
+        self.solo = solo;
+        self.update_mix();
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.solo
+    }
+
+    fn channel_gains(&self) -> [f32; NUM_CHANNELS] {
+        let mut gains = [0.0; NUM_CHANNELS];
+        for (i, &channel) in Channel::ALL.iter().enumerate() {
+            let audible = match self.solo {
+                Some(solo) => solo == channel,
+                None => !self.muted[i],
+            };
+            if audible {
+                gains[i] = self.volumes[i];
+            }
+        }
+        gains
+    }
+
+    fn mixed_level(&self, outputs : &ChannelOutputs) -> f32 {
+        mixer::mix(outputs, &self.channel_gains())
+    }
+
+    // the mix settings changed, so the output level might have too
+    fn update_mix(&mut self) {
+        let level = self.mixed_level(&self.last_outputs);
+        self.output.set_level(self.sample_cycle, level);
+    }
+
+    // drops any buffered audio
+    fn reset_streams(&mut self) {
+        self.output = self.new_stream(self.mixed_level(&self.last_outputs));
+        let stems = self.stems_enabled();
+        self.set_stems_enabled(stems);
+        self.sample_cycle = 0;
+    }
+
+    pub fn reg_write(&mut self, addr : u16, val : u8) {
+        match addr {
+            PULSE_1_FIRST...PULSE_1_LAST =>
+                self.pulse_1.write_reg(addr - PULSE_1_FIRST, val),
+            PULSE_2_FIRST...PULSE_2_LAST =>
+                self.pulse_2.write_reg(addr - PULSE_2_FIRST, val),
+            TRIANGLE_FIRST...TRIANGLE_LAST =>
+                self.triangle.write_reg(addr - TRIANGLE_FIRST, val),
+            NOISE_FIRST...NOISE_LAST =>
+                self.noise.write_reg(addr - NOISE_FIRST, val),
+            DMC_FIRST...DMC_LAST =>
+                self.dmc.write_reg(addr - DMC_FIRST, val),
+
+            // ---D NT21, enables each channel
+            STATUS => {
+                self.pulse_1.length.set_enabled((val & 0x01) != 0);
+                self.pulse_2.length.set_enabled((val & 0x02) != 0);
+                self.triangle.length.set_enabled((val & 0x04) != 0);
+                self.noise.length.set_enabled((val & 0x08) != 0);
+                self.dmc.set_enabled((val & 0x10) != 0);
+
+                // writing clears the dmc interrupt flag
+                self.dmc.irq = false;
+            },
+
+            // MI-- ----
+            FRAME_COUNTER => {
+                self.frame_mode = if (val & 0x80) != 0 {
+                    FrameMode::FiveStep
+                } else {
+                    FrameMode::FourStep
+                };
+
+                self.irq_inhibit = (val & 0x40) != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+
+                // TODO the sequence really restarts 3 or 4 cycles later
+                self.frame_cycle = 0;
+
+                // 5 step mode clocks everything right away
+                if self.frame_mode == FrameMode::FiveStep {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            },
+
+            _ => (),
+        }
+    }
+
+    // IF-D NT21, bit 5 is open bus and isn't driven
+    pub fn read_status(&mut self) -> u8 {
+        let ret = self.peek_status();
+
+        // reading clears the frame interrupt flag
+        self.frame_irq = false;
+        ret
+    }
+
+    // the status register without the side effects of reading it
+    pub fn peek_status(&self) -> u8 {
+        (self.pulse_1.length.active() as u8) |
+        ((self.pulse_2.length.active() as u8) << 1) |
+        ((self.triangle.length.active() as u8) << 2) |
+        ((self.noise.length.active() as u8) << 3) |
+        ((self.dmc.active() as u8) << 4) |
+        ((self.frame_irq as u8) << 6) |
+        ((self.dmc.irq as u8) << 7)
+    }
+
+    // a reset silences every channel, like writing $00 to $4015
+    pub fn reset(&mut self) {
+        self.reg_write(STATUS, 0);
+        self.frame_irq = false;
+    }
+
+    pub fn irq_asserted(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // the dmc reads its samples through the cpu's memory, the cpu should
+    // call dmc_fill with the byte at this address when there is one
+    pub fn dmc_fetch_addr(&self) -> Option<u16> {
+        self.dmc.fetch_addr()
+    }
+
+    pub fn dmc_fill(&mut self, val : u8) {
+        self.dmc.fill(val);
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse_1.length.clock();
+        self.pulse_2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+
+        self.pulse_1.clock_sweep();
+        self.pulse_2.clock_sweep();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+
+        let steps = self.frame_steps[self.frame_mode as usize];
+        let cycle = self.frame_cycle;
+
+        if cycle == steps[0] || cycle == steps[2] {
+            self.clock_quarter_frame();
+        }
+        else if cycle == steps[1] || cycle == steps[3] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+
+            if cycle == steps[3] && self.frame_mode == FrameMode::FourStep &&
+               !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+        }
+        else if cycle == steps[4] {
+            self.frame_cycle = 0;
+        }
+    }
+
+    // runs the APU for the given number of CPU cycles
+    pub fn step(&mut self, cycles : usize) {
+        for _ in 0..cycles {
+            self.clock_frame_counter();
+
+            self.triangle.clock_timer();
+            self.noise.clock_timer();
+            self.dmc.clock_timer();
+
+            if self.odd_cycle {
+                self.pulse_1.clock_timer();
+                self.pulse_2.clock_timer();
+            }
+            self.odd_cycle = !self.odd_cycle;
+
+            for chip in self.expansion.iter_mut() {
+                chip.clock();
+            }
+
+            self.update_output();
+        }
+    }
+
+    // feeds any change in the channel levels to the streams
+    fn update_output(&mut self) {
+        let outputs = self.channel_outputs();
+        if outputs != self.last_outputs {
+            let cycle = self.sample_cycle;
+            let level = self.mixed_level(&outputs);
+            self.output.set_level(cycle, level);
+            for (stem, channel) in self.stems.iter_mut().zip(Channel::ALL.iter()) {
+                stem.set_level(cycle, channel.level(&outputs));
+            }
+            self.last_outputs = outputs;
+        }
+
+        self.sample_cycle += 1;
+        if self.sample_cycle == SAMPLE_CHUNK_CYCLES {
+            self.flush_samples();
+        }
+    }
+
+    fn flush_samples(&mut self) {
+        self.output.flush(self.sample_cycle);
+        for stem in self.stems.iter_mut() {
+            stem.flush(self.sample_cycle);
+        }
+        self.sample_cycle = 0;
+    }
+
+    // returns all of the audio generated since the last call, as mono
+    // samples between -1 and 1 at the sample rate
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.flush_samples();
+        self.output.take()
+    }
+
+    // like take_samples, but for each channel on its own, in the order of
+    // stem_channels. empty unless stems are enabled
+    pub fn take_stem_samples(&mut self) -> Vec<Vec<f32>> {
+        self.flush_samples();
+        self.stems.iter_mut().map(|stem| stem.take()).collect()
+    }
+
+    pub fn channel_outputs(&self) -> ChannelOutputs {
+        ChannelOutputs {
+            pulse_1  : self.pulse_1.output(),
+            pulse_2  : self.pulse_2.output(),
+            triangle : self.triangle.output(),
+            noise    : self.noise.output(),
+            dmc      : self.dmc.output(),
+            expansion : self.expansion.iter().map(|chip| chip.output()).sum(),
+        }
+    }
+}
+
+// the channels, frame counter and sound chips. audio that hasn't been
+// taken yet is dropped on load. The chips come from the cartridge, so the
+// state is loaded into the ones it already has
+impl SaveState for APU {
+    fn save_state(&self, w : &mut StateWriter) {
+        self.pulse_1.save_state(w);
+        self.pulse_2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+
+        w.bool(self.frame_mode == FrameMode::FiveStep);
+        w.usize(self.frame_cycle);
+        w.bool(self.irq_inhibit);
+        w.bool(self.frame_irq);
+        w.bool(self.odd_cycle);
+
+        for chip in self.expansion.iter() {
+            chip.save_state(w);
+        }
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.pulse_1.load_state(r);
+        self.pulse_2.load_state(r);
+        self.triangle.load_state(r);
+        self.noise.load_state(r);
+        self.dmc.load_state(r);
+
+        self.frame_mode = if r.bool() { FrameMode::FiveStep } else { FrameMode::FourStep };
+        self.frame_cycle = r.usize();
+        self.irq_inhibit = r.bool();
+        self.frame_irq = r.bool();
+        self.odd_cycle = r.bool();
+
+        // older states don't have the chips, which are left as they are
+        if r.version() >= 3 {
+            for chip in self.expansion.iter_mut() {
+                chip.load_state(r);
+            }
+        }
+
+        self.last_outputs = self.channel_outputs();
+        self.reset_streams();
+    }
+}
@@ -0,0 +1,119 @@
+// https://wiki.nesdev.com/w/index.php/APU_Noise
+
+use super::units::{ Envelope, LengthCounter };
+use region::Region;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+// timer periods in CPU cycles
+static PERIOD_TABLE_NTSC : [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+static PERIOD_TABLE_PAL : [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+pub struct Noise {
+    pub envelope : Envelope,
+    pub length : LengthCounter,
+
+    period_table : &'static [u16; 16],
+    // uses bit 6 for feedback instead of bit 1, for a shorter sequence
+    short_mode : bool,
+    shift : u16,
+    timer_period : u16,
+    timer : u16,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            envelope : Envelope::new(),
+            length : LengthCounter::new(),
+            period_table : &PERIOD_TABLE_NTSC,
+            short_mode : false,
+            // loaded with 1 on power up
+            shift : 1,
+            timer_period : PERIOD_TABLE_NTSC[0],
+            timer : 0,
+        }
+    }
+
+    pub fn set_region(&mut self, region : Region) {
+        self.period_table = match region {
+            Region::Pal => &PERIOD_TABLE_PAL,
+            Region::Ntsc | Region::Dendy => &PERIOD_TABLE_NTSC,
+        };
+    }
+
+    // reg is the register offset, 0 - 3
+    pub fn write_reg(&mut self, reg : u16, val : u8) {
+        match reg {
+            // --LC VVVV
+            0 => {
+                self.length.halt = (val & 0x20) != 0;
+                self.envelope.write_control(val);
+            },
+            1 => (),
+            // M--- PPPP
+            2 => {
+                self.short_mode = (val & 0x80) != 0;
+                self.timer_period = self.period_table[(val & 0x0F) as usize];
+            },
+            // LLLL L---
+            3 => {
+                self.length.load(val >> 3);
+                self.envelope.start = true;
+            },
+            _ => panic!("invalid noise register {}", reg),
+        }
+    }
+
+    // clocked every CPU cycle, since the period table is in CPU cycles
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+
+            let other_bit = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> other_bit)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn shift_register(&self) -> u16 {
+        self.shift
+    }
+
+    pub fn output(&self) -> u8 {
+        if (self.shift & 1) == 1 || !self.length.active() {
+            0
+        }
+        else {
+            self.envelope.output()
+        }
+    }
+}
+
+// the period table comes from the region
+impl SaveState for Noise {
+    fn save_state(&self, w : &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+        w.bool(self.short_mode);
+        w.u16(self.shift);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.envelope.load_state(r);
+        self.length.load_state(r);
+        self.short_mode = r.bool();
+        self.shift = r.u16();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+    }
+}
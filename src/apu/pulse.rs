@@ -0,0 +1,186 @@
+// https://wiki.nesdev.com/w/index.php/APU_Pulse
+// https://wiki.nesdev.com/w/index.php/APU_Sweep
+
+use super::units::{ Envelope, LengthCounter };
+use savestate::{ SaveState, StateWriter, StateReader };
+
+static DUTY_TABLE : [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+struct Sweep {
+    enabled : bool,
+    period : u8,
+    negate : bool,
+    shift : u8,
+    reload : bool,
+    divider : u8,
+}
+
+pub struct Pulse {
+    // pulse 1 negates with ones' complement, pulse 2 with two's complement
+    ones_complement : bool,
+    // the MMC5's pulses don't have a sweep unit, so they never get muted
+    has_sweep : bool,
+
+    pub envelope : Envelope,
+    pub length : LengthCounter,
+    sweep : Sweep,
+
+    duty : u8,
+    sequence_pos : u8,
+    timer_period : u16,
+    timer : u16,
+}
+
+impl Pulse {
+    pub fn new(ones_complement : bool) -> Pulse {
+        Pulse {
+            ones_complement : ones_complement,
+            has_sweep : true,
+            envelope : Envelope::new(),
+            length : LengthCounter::new(),
+            sweep : Sweep {
+                enabled : false,
+                period : 0,
+                negate : false,
+                shift : 0,
+                reload : false,
+                divider : 0,
+            },
+            duty : 0,
+            sequence_pos : 0,
+            timer_period : 0,
+            timer : 0,
+        }
+    }
+
+    pub fn without_sweep() -> Pulse {
+        Pulse { has_sweep : false, ..Pulse::new(false) }
+    }
+
+    // reg is the register offset, 0 - 3
+    pub fn write_reg(&mut self, reg : u16, val : u8) {
+        match reg {
+            // DDLC VVVV
+            0 => {
+                self.duty = val >> 6;
+                self.length.halt = (val & 0x20) != 0;
+                self.envelope.write_control(val);
+            },
+            // EPPP NSSS
+            1 => {
+                self.sweep.enabled = (val & 0x80) != 0;
+                self.sweep.period = (val >> 4) & 0b111;
+                self.sweep.negate = (val & 0x08) != 0;
+                self.sweep.shift = val & 0b111;
+                self.sweep.reload = true;
+            },
+            // timer low
+            2 => self.timer_period = (self.timer_period & 0x0700) | val as u16,
+            // LLLL LTTT
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) |
+                                    (((val & 0b111) as u16) << 8);
+                self.length.load(val >> 3);
+                self.sequence_pos = 0;
+                self.envelope.start = true;
+            },
+            _ => panic!("invalid pulse register {}", reg),
+        }
+    }
+
+    // clocked every APU cycle (every other CPU cycle)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_pos = (self.sequence_pos + 1) & 0b111;
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep.shift;
+        if self.sweep.negate {
+            let change = if self.ones_complement { change + 1 } else { change };
+            self.timer_period.saturating_sub(change)
+        }
+        else {
+            self.timer_period + change
+        }
+    }
+
+    // the sweep unit mutes the channel even when it's disabled
+    fn sweep_muting(&self) -> bool {
+        self.has_sweep && (self.timer_period < 8 || self.sweep_target() > 0x7FF)
+    }
+
+    // clocked by half frames
+    pub fn clock_sweep(&mut self) {
+        if self.sweep.divider == 0 && self.sweep.enabled &&
+           self.sweep.shift > 0 && !self.sweep_muting() {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep.divider == 0 || self.sweep.reload {
+            self.sweep.divider = self.sweep.period;
+            self.sweep.reload = false;
+        }
+        else {
+            self.sweep.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        let duty_high =
+            DUTY_TABLE[self.duty as usize][self.sequence_pos as usize] == 1;
+
+        if !duty_high || !self.length.active() || self.sweep_muting() {
+            0
+        }
+        else {
+            self.envelope.output()
+        }
+    }
+}
+
+impl SaveState for Pulse {
+    fn save_state(&self, w : &mut StateWriter) {
+        self.envelope.save_state(w);
+        self.length.save_state(w);
+
+        w.bool(self.sweep.enabled);
+        w.u8(self.sweep.period);
+        w.bool(self.sweep.negate);
+        w.u8(self.sweep.shift);
+        w.bool(self.sweep.reload);
+        w.u8(self.sweep.divider);
+
+        w.u8(self.duty);
+        w.u8(self.sequence_pos);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.envelope.load_state(r);
+        self.length.load_state(r);
+
+        self.sweep.enabled = r.bool();
+        self.sweep.period = r.u8();
+        self.sweep.negate = r.bool();
+        self.sweep.shift = r.u8();
+        self.sweep.reload = r.bool();
+        self.sweep.divider = r.u8();
+
+        self.duty = r.u8();
+        self.sequence_pos = r.u8();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+    }
+}
@@ -0,0 +1,146 @@
+// Band-limited resampling of the APU output down to the audio device rate.
+//
+// The mixed APU output only changes at discrete clock times, so instead of
+// filtering every cycle, each change is added to the output as a
+// band-limited step: the size of the change times a windowed sinc impulse,
+// placed at the fractional output sample position the change happened at.
+// Summing up the buffer then gives the band-limited signal. This is the same
+// idea as blargg's blip_buf.
+
+use std::f64::consts::PI;
+
+// the impulse is split up into PHASES fractional sample offsets
+const PHASES : usize = 32;
+const TAPS : usize = 16;
+
+pub struct Resampler {
+    // output samples per input clock
+    factor : f64,
+    // fractional output sample position of the first clock of this frame
+    offset : f64,
+
+    kernel : Vec<[f32; TAPS]>,
+    // each entry is the change in level at that output sample
+    buffer : Vec<f32>,
+    integrator : f32,
+}
+
+impl Resampler {
+    pub fn new(clock_rate : f64, sample_rate : f64) -> Resampler {
+        let mut kernel = Vec::with_capacity(PHASES);
+
+        for phase in 0..PHASES {
+            let frac = phase as f64 / PHASES as f64;
+            let mut taps = [0f32; TAPS];
+            let mut sum = 0.0;
+
+            for (i, tap) in taps.iter_mut().enumerate() {
+                // distance from the center of the impulse, in output samples
+                let x = i as f64 - (TAPS / 2) as f64 + 1.0 - frac;
+
+                // cut off a little under nyquist
+                let cutoff = 0.9;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x * cutoff).sin() / (PI * x * cutoff)
+                };
+
+                // blackman window
+                let n = (x + (TAPS / 2) as f64) / TAPS as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * n).cos() +
+                             0.08 * (4.0 * PI * n).cos();
+
+                let val = sinc * window;
+                *tap = val as f32;
+                sum += val;
+            }
+
+            // normalize so each step ends up the right height
+            for tap in taps.iter_mut() {
+                *tap /= sum as f32;
+            }
+
+            kernel.push(taps);
+        }
+
+        Resampler {
+            factor : sample_rate / clock_rate,
+            offset : 0.0,
+            kernel : kernel,
+            buffer : vec![0.0; TAPS],
+            integrator : 0.0,
+        }
+    }
+
+    // how many output samples a number of input clocks make
+    pub fn samples_for_clocks(&self, clocks : usize) -> f64 {
+        clocks as f64 * self.factor
+    }
+
+    // adjusts the output rate without losing any buffered audio,
+    // used for small corrections to keep the audio device fed
+    pub fn set_rates(&mut self, clock_rate : f64, sample_rate : f64) {
+        self.factor = sample_rate / clock_rate;
+    }
+
+    // adds a change in level at the given clock of the current frame
+    pub fn add_delta(&mut self, clock : usize, delta : f32) {
+        let pos = self.offset + clock as f64 * self.factor;
+        let index = pos as usize;
+        let phase = ((pos - index as f64) * PHASES as f64) as usize;
+
+        if self.buffer.len() < index + TAPS {
+            self.buffer.resize(index + TAPS, 0.0);
+        }
+
+        for (i, tap) in self.kernel[phase].iter().enumerate() {
+            self.buffer[index + i] += delta * tap;
+        }
+    }
+
+    // ends the frame after the given number of clocks, and appends all of
+    // the finished samples to out
+    pub fn end_frame(&mut self, clocks : usize, out : &mut Vec<f32>) {
+        let end = self.offset + clocks as f64 * self.factor;
+        let num_samples = end as usize;
+
+        if self.buffer.len() < num_samples + TAPS {
+            self.buffer.resize(num_samples + TAPS, 0.0);
+        }
+
+        for delta in self.buffer.drain(..num_samples) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+
+        self.offset = end - num_samples as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_response() {
+        let mut r = Resampler::new(1_789_773.0, 44_100.0);
+        let mut out = Vec::new();
+
+        r.add_delta(1000, 0.5);
+        r.end_frame(29781, &mut out);
+
+        // about 1/60 of a second of samples
+        assert!(out.len() == 733 || out.len() == 734);
+
+        // settles at the new level, with silence before the step
+        assert!(out[0].abs() < 0.001);
+        assert!((out[100] - 0.5).abs() < 0.001);
+        assert!((out[out.len() - 1] - 0.5).abs() < 0.001);
+
+        // carries over into the next frame
+        out.clear();
+        r.end_frame(29781, &mut out);
+        assert!((out[0] - 0.5).abs() < 0.001);
+    }
+}
@@ -0,0 +1,55 @@
+// One mono stream of output samples: the resampler plus the filters, fed
+// with the level of whatever it's recording whenever that level changes.
+
+use super::filters::OutputFilters;
+use super::resampler::Resampler;
+
+pub struct SampleStream {
+    resampler : Resampler,
+    filters : OutputFilters,
+    level : f32,
+    samples : Vec<f32>,
+    // if nobody takes the samples, only this many are kept
+    max_samples : usize,
+}
+
+impl SampleStream {
+    // only changes in level go to the resampler, so the stream starts at
+    // the current level to avoid a pop
+    pub fn new(clock_rate : f64, sample_rate : u32, level : f32) -> SampleStream {
+        SampleStream {
+            resampler : Resampler::new(clock_rate, sample_rate as f64),
+            filters : OutputFilters::new(sample_rate as f32),
+            level : level,
+            samples : Vec::new(),
+            max_samples : sample_rate as usize,
+        }
+    }
+
+    pub fn set_rates(&mut self, clock_rate : f64, sample_rate : f64) {
+        self.resampler.set_rates(clock_rate, sample_rate);
+    }
+
+    // cycle is counted from the last flush
+    pub fn set_level(&mut self, cycle : usize, level : f32) {
+        if level != self.level {
+            self.resampler.add_delta(cycle, level - self.level);
+            self.level = level;
+        }
+    }
+
+    pub fn flush(&mut self, cycles : usize) {
+        let start = self.samples.len();
+        self.resampler.end_frame(cycles, &mut self.samples);
+        self.filters.process(&mut self.samples[start..]);
+
+        if self.samples.len() > self.max_samples {
+            let excess = self.samples.len() - self.max_samples;
+            self.samples.drain(..excess);
+        }
+    }
+
+    pub fn take(&mut self) -> Vec<f32> {
+        ::std::mem::take(&mut self.samples)
+    }
+}
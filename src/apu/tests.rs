@@ -0,0 +1,486 @@
+use super::{ APU, FrameMode };
+
+// runs until the next quarter frame
+fn step_quarter_frame(a : &mut APU) {
+    a.step(7457);
+}
+
+#[test]
+fn length_counter() {
+    let mut a = APU::new();
+
+    // disabled channels don't load their length counter
+    a.reg_write(0x4003, 0x08);
+    assert_eq!(a.read_status() & 0x01, 0x00);
+
+    a.reg_write(0x4015, 0x0F);
+    a.reg_write(0x4003, 0x08);
+    a.reg_write(0x4007, 0x08);
+    a.reg_write(0x400B, 0x08);
+    a.reg_write(0x400F, 0x08);
+    assert_eq!(a.read_status() & 0x0F, 0x0F);
+
+    // index 1 is a length of 254, other index 0 is 10
+    a.reg_write(0x4003, 0x00);
+    assert_eq!(a.pulse_1.length.counter, 10);
+    assert_eq!(a.pulse_2.length.counter, 254);
+
+    // disabling clears the counter
+    a.reg_write(0x4015, 0x0E);
+    assert_eq!(a.read_status() & 0x0F, 0x0E);
+
+    // counts down on half frames, 2 per frame
+    a.reg_write(0x4015, 0x0F);
+    a.reg_write(0x4003, 0x00);
+    for _ in 0..5 {
+        a.step(29830);
+    }
+    assert_eq!(a.pulse_1.length.counter, 0);
+    assert_eq!(a.pulse_2.length.counter, 244);
+
+    // unless halted
+    a.reg_write(0x4004, 0x20);
+    a.step(29830);
+    assert_eq!(a.pulse_2.length.counter, 244);
+}
+
+#[test]
+fn frame_irq() {
+    let mut a = APU::new();
+
+    a.step(29828);
+    assert_eq!(a.irq_asserted(), false);
+    a.step(1);
+    assert_eq!(a.irq_asserted(), true);
+
+    // reading status clears it
+    assert_eq!(a.read_status() & 0x40, 0x40);
+    assert_eq!(a.irq_asserted(), false);
+    assert_eq!(a.read_status() & 0x40, 0x00);
+
+    // inhibit flag clears it and keeps it from being set
+    a.step(29830);
+    assert_eq!(a.irq_asserted(), true);
+    a.reg_write(0x4017, 0x40);
+    assert_eq!(a.irq_asserted(), false);
+    a.step(29830);
+    assert_eq!(a.irq_asserted(), false);
+
+    // 5 step mode never sets it
+    a.reg_write(0x4017, 0x80);
+    assert_eq!(a.frame_mode, FrameMode::FiveStep);
+    a.step(37282 * 2);
+    assert_eq!(a.irq_asserted(), false);
+}
+
+#[test]
+fn envelope() {
+    let mut a = APU::new();
+    a.reg_write(0x4015, 0x01);
+
+    // constant volume
+    a.reg_write(0x4000, 0b1011_0111);
+    a.reg_write(0x4002, 0x80);
+    a.reg_write(0x4003, 0x00);
+    assert_eq!(a.pulse_1.envelope.output(), 7);
+
+    // decaying, period 0 means it drops by 1 every quarter frame
+    a.reg_write(0x4000, 0b1000_0000);
+    step_quarter_frame(&mut a);
+    assert_eq!(a.pulse_1.envelope.output(), 15);
+    a.step(14913 - 7457);
+    assert_eq!(a.pulse_1.envelope.output(), 14);
+
+    // restarted by writing the last register
+    a.reg_write(0x4003, 0x00);
+    a.step(22371 - 14913);
+    assert_eq!(a.pulse_1.envelope.output(), 15);
+}
+
+#[test]
+fn pulse_output() {
+    let mut a = APU::new();
+    a.reg_write(0x4015, 0x01);
+
+    // 50% duty, constant volume 10, period 8
+    a.reg_write(0x4000, 0b1011_1010);
+    a.reg_write(0x4002, 0x08);
+    a.reg_write(0x4003, 0x00);
+
+    // 8 steps of (period + 1) APU cycles, 2 CPU cycles each
+    let mut outputs = Vec::new();
+    for _ in 0..8 {
+        outputs.push(a.channel_outputs().pulse_1);
+        a.step(18);
+    }
+    assert_eq!(outputs, vec![0, 10, 10, 10, 10, 0, 0, 0]);
+
+    // periods under 8 are muted
+    a.reg_write(0x4002, 0x07);
+    for _ in 0..8 {
+        assert_eq!(a.channel_outputs().pulse_1, 0);
+        a.step(16);
+    }
+}
+
+#[test]
+fn sweep() {
+    let mut a = APU::new();
+    a.reg_write(0x4015, 0x03);
+
+    // enabled, period 0, negate, shift 1
+    a.reg_write(0x4001, 0b1000_1001);
+    a.reg_write(0x4002, 0x00);
+    a.reg_write(0x4003, 0x01);
+    a.reg_write(0x4005, 0b1000_1001);
+    a.reg_write(0x4006, 0x00);
+    a.reg_write(0x4007, 0x01);
+
+    // first half frame reloads the divider and sweeps
+    a.step(14913);
+
+    // pulse 1 subtracts one more than pulse 2
+    assert_eq!(a.pulse_1.sweep_target(), 0x100 - 0x80 - 1 - 0x40);
+    assert_eq!(a.pulse_2.sweep_target(), 0x100 - 0x80 - 0x40);
+}
+
+#[test]
+fn triangle_linear_counter() {
+    let mut a = APU::new();
+    a.reg_write(0x4015, 0x04);
+
+    // linear counter of 2
+    a.reg_write(0x4008, 0x02);
+    a.reg_write(0x400A, 0x00);
+    a.reg_write(0x400B, 0x00);
+
+    // reloaded on the first quarter frame, then counts down
+    step_quarter_frame(&mut a);
+    let start = a.channel_outputs().triangle;
+    a.step(10);
+    assert!(a.channel_outputs().triangle != start);
+
+    a.step(14913 - 7457 - 10);
+    a.step(22371 - 14913);
+    let silenced = a.channel_outputs().triangle;
+    a.step(100);
+    assert_eq!(a.channel_outputs().triangle, silenced);
+}
+
+#[test]
+fn noise_shift_register() {
+    let mut a = APU::new();
+    a.reg_write(0x4015, 0x08);
+    a.reg_write(0x400C, 0x1F);
+    a.reg_write(0x400E, 0x00);
+    a.reg_write(0x400F, 0x00);
+
+    // long mode has a period of 32767
+    let start = a.noise.shift_register();
+    a.step(4);
+    assert!(a.noise.shift_register() != start);
+    a.step(4 * 32766);
+    assert_eq!(a.noise.shift_register(), start);
+}
+
+#[test]
+fn dmc_memory_reader() {
+    let mut a = APU::new();
+
+    // address $C040, length 17
+    a.reg_write(0x4012, 0x01);
+    a.reg_write(0x4013, 0x01);
+    assert_eq!(a.dmc_fetch_addr(), None);
+
+    a.reg_write(0x4015, 0x10);
+    assert_eq!(a.read_status() & 0x10, 0x10);
+
+    for i in 0..17 {
+        assert_eq!(a.dmc_fetch_addr(), Some(0xC040 + i));
+        a.dmc_fill(0x00);
+        // nothing else is fetched until the buffer is emptied
+        assert_eq!(a.dmc_fetch_addr(), None);
+        a.step(428 * 8);
+    }
+
+    assert_eq!(a.read_status() & 0x10, 0x00);
+    assert_eq!(a.dmc_fetch_addr(), None);
+}
+
+#[test]
+fn dmc_loop_and_irq() {
+    let mut a = APU::new();
+
+    // irq enabled, length 1
+    a.reg_write(0x4010, 0x80);
+    a.reg_write(0x4013, 0x00);
+    a.reg_write(0x4015, 0x10);
+    a.dmc_fill(0x00);
+    assert_eq!(a.irq_asserted(), true);
+    assert_eq!(a.read_status() & 0x80, 0x80);
+
+    // reading status doesn't clear it, writing does
+    assert_eq!(a.read_status() & 0x80, 0x80);
+    a.reg_write(0x4015, 0x00);
+    assert_eq!(a.irq_asserted(), false);
+
+    // looping samples restart instead
+    a.reg_write(0x4010, 0xC0);
+    a.reg_write(0x4015, 0x10);
+    a.dmc_fill(0x00);
+    assert_eq!(a.irq_asserted(), false);
+    assert_eq!(a.read_status() & 0x10, 0x10);
+
+    // disabling irqs clears the flag
+    a.reg_write(0x4010, 0x80);
+    a.reg_write(0x4015, 0x10);
+    a.step(428 * 8);
+    a.dmc_fill(0x00);
+    assert_eq!(a.irq_asserted(), true);
+    a.reg_write(0x4010, 0x00);
+    assert_eq!(a.irq_asserted(), false);
+}
+
+#[test]
+fn dmc_output() {
+    let mut a = APU::new();
+
+    a.reg_write(0x4011, 0x40);
+    assert_eq!(a.channel_outputs().dmc, 0x40);
+
+    // fastest rate, length 1
+    a.reg_write(0x4010, 0x0F);
+    a.reg_write(0x4013, 0x00);
+    a.reg_write(0x4015, 0x10);
+    a.dmc_fill(0b0000_1111);
+
+    // the first output cycle is silent, then the sample is played
+    a.step(54 * 8);
+    assert_eq!(a.channel_outputs().dmc, 0x40);
+    a.step(54 * 4);
+    assert_eq!(a.channel_outputs().dmc, 0x48);
+    a.step(54 * 4);
+    assert_eq!(a.channel_outputs().dmc, 0x40);
+}
+
+#[test]
+fn mixer() {
+    use super::mixer::{ pulse_out, tnd_out };
+
+    assert_eq!(pulse_out(0.0, 0.0), 0.0);
+    assert_eq!(tnd_out(0.0, 0.0, 0.0), 0.0);
+
+    // full volume on everything comes out just under 1
+    let full = pulse_out(15.0, 15.0) + tnd_out(15.0, 15.0, 127.0);
+    assert!(full > 0.95 && full < 1.0);
+
+    // two pulses are quieter than twice one
+    assert!(pulse_out(15.0, 15.0) < 2.0 * pulse_out(15.0, 0.0));
+}
+
+#[test]
+fn sample_output() {
+    let mut a = APU::new();
+    a.set_sample_rate(48000);
+
+    // silence stays silent
+    a.step(29781);
+    let samples = a.take_samples();
+    assert!(samples.len() >= 798 && samples.len() <= 799);
+    assert!(samples.iter().all(|s| *s == 0.0));
+
+    // a 440Hz square wave, 50% duty at full volume
+    a.reg_write(0x4015, 0x01);
+    a.reg_write(0x4000, 0b1011_1111);
+    a.reg_write(0x4002, 0xFD);
+    a.reg_write(0x4003, 0x00);
+
+    a.step(29781 * 6);
+    let samples = a.take_samples();
+    assert!(samples.len() >= 4792 && samples.len() <= 4793);
+
+    // the high pass filters take out the dc offset, so it swings
+    // both ways, and the low pass filter keeps it from overshooting much
+    let max = samples.iter().cloned().fold(0.0, f32::max);
+    let min = samples.iter().cloned().fold(0.0, f32::min);
+    assert!(max > 0.05 && max < 0.2);
+    assert!(min < -0.05 && min > -0.2);
+
+    // nothing left after taking
+    assert!(a.take_samples().is_empty());
+}
+
+#[test]
+fn stems() {
+    use super::Channel;
+
+    let mut a = APU::new();
+    assert!(a.take_stem_samples().is_empty());
+
+    a.set_stems_enabled(true);
+    a.reg_write(0x4015, 0x01);
+    a.reg_write(0x4000, 0b1011_1111);
+    a.reg_write(0x4002, 0xFD);
+    a.reg_write(0x4003, 0x00);
+    a.step(29781);
+
+    let samples = a.take_samples();
+    let stems = a.take_stem_samples();
+    // there aren't any expansion chips to make a stem for
+    let channels = a.stem_channels();
+    assert_eq!(stems.len(), channels.len());
+    assert!(!channels.contains(&Channel::Expansion));
+
+    // only pulse 1 is playing, so its stem is the whole output
+    for (channel, stem) in channels.iter().zip(stems.iter()) {
+        assert_eq!(stem.len(), samples.len());
+        if *channel == Channel::Pulse1 {
+            for (a, b) in stem.iter().zip(samples.iter()) {
+                assert!((a - b).abs() < 0.0001);
+            }
+        } else {
+            assert!(stem.iter().all(|s| *s == 0.0));
+        }
+    }
+}
+
+#[test]
+fn mute_and_solo() {
+    use super::Channel;
+
+    let mut a = APU::new();
+    a.reg_write(0x4015, 0x01);
+    a.reg_write(0x4000, 0b1011_1111);
+    a.reg_write(0x4002, 0xFD);
+    a.reg_write(0x4003, 0x00);
+
+    // the loudest sample in a frame, after giving the filters a frame
+    // to settle
+    let peak = |a : &mut APU| {
+        a.step(29781);
+        a.take_samples();
+        a.step(29781);
+        a.take_samples().iter().cloned().fold(0.0, f32::max)
+    };
+    let full = peak(&mut a);
+    assert!(full > 0.05);
+
+    a.set_channel_volume(Channel::Pulse1, 0.5);
+    let half = peak(&mut a);
+    assert!(half > full * 0.4 && half < full * 0.6);
+
+    // muting doesn't change what the hardware sees
+    a.set_channel_muted(Channel::Pulse1, true);
+    assert!(peak(&mut a) < 0.01);
+    assert_eq!(a.read_status() & 0x01, 0x01);
+
+    // solo overrides muting
+    a.set_solo(Some(Channel::Pulse1));
+    assert!(peak(&mut a) > 0.02);
+    a.set_solo(Some(Channel::Noise));
+    assert!(peak(&mut a) < 0.01);
+}
+
+#[test]
+fn vrc6_pulse() {
+    use super::{ Channel, ExpansionChip };
+
+    let mut a = APU::new();
+    a.add_expansion(ExpansionChip::Vrc6);
+    assert!(a.expansion_maps_addr(0x9000));
+    assert!(!a.expansion_maps_addr(0x4000));
+    assert_eq!(a.channel_outputs().expansion, 0.0);
+
+    // constant volume 15 with the duty cycle ignored, so it's always high
+    a.expansion_write(0x9000, 0x8F);
+    a.expansion_write(0x9001, 0xFF);
+    a.expansion_write(0x9002, 0x80);
+    a.step(100);
+    let level = a.channel_outputs().expansion;
+    assert!(level > 0.0);
+
+    // the expansion channel has its own stem and mute setting
+    a.set_stems_enabled(true);
+    assert_eq!(a.stem_channels(), Channel::ALL.to_vec());
+    a.set_solo(Some(Channel::Expansion));
+    assert_eq!(a.mixed_level(&a.channel_outputs()), level);
+    a.set_solo(None);
+    a.set_channel_muted(Channel::Expansion, true);
+    let outputs = a.channel_outputs();
+    let silent = super::ChannelOutputs { expansion : 0.0, ..outputs };
+    assert_eq!(a.mixed_level(&outputs), a.mixed_level(&silent));
+}
+
+#[test]
+fn n163_ram_port() {
+    use super::ExpansionChip;
+
+    let mut a = APU::new();
+    a.add_expansion(ExpansionChip::N163);
+
+    // address 0x10 with auto increment
+    a.expansion_write(0xF800, 0x90);
+    a.expansion_write(0x4800, 0x12);
+    a.expansion_write(0x4800, 0x34);
+
+    a.expansion_write(0xF800, 0x90);
+    assert_eq!(a.expansion_read(0x4800), Some(0x12));
+    assert_eq!(a.expansion_read(0x4800), Some(0x34));
+
+    // without auto increment
+    a.expansion_write(0xF800, 0x10);
+    assert_eq!(a.expansion_read(0x4800), Some(0x12));
+    assert_eq!(a.expansion_read(0x4800), Some(0x12));
+
+    // the address port is write only
+    assert_eq!(a.expansion_read(0xF800), None);
+}
+
+#[test]
+fn expansion_save_state() {
+    use super::ExpansionChip;
+    use savestate::{ self, SaveState, StateWriter, StateReader };
+
+    let chips = [ExpansionChip::Vrc6, ExpansionChip::Vrc7, ExpansionChip::Sunsoft5B,
+                 ExpansionChip::N163, ExpansionChip::Mmc5, ExpansionChip::Fds];
+    let with_chips = || {
+        let mut a = APU::new();
+        for &chip in chips.iter() {
+            a.add_expansion(chip);
+        }
+        a
+    };
+
+    let mut a = with_chips();
+    // a note on each chip
+    for &(addr, val) in [(0x9000, 0x3F), (0x9001, 0x80), (0x9002, 0x81),
+                         (0x9010, 0x30), (0x9030, 0x10), (0x9010, 0x10), (0x9030, 0x80),
+                         (0x9010, 0x20), (0x9030, 0x15),
+                         (0xC000, 0x00), (0xE000, 0x40), (0xC000, 0x07), (0xE000, 0x3E),
+                         (0xC000, 0x08), (0xE000, 0x0F),
+                         (0xF800, 0x80), (0x4800, 0xF0), (0x4800, 0x0F),
+                         (0xF800, 0xF8), (0x4800, 0x00), (0x4800, 0x00), (0x4800, 0x10),
+                         (0x4800, 0x00), (0x4800, 0xF8), (0x4800, 0x00), (0x4800, 0x00),
+                         (0x4800, 0x0F),
+                         (0x5015, 0x01), (0x5000, 0xBF), (0x5002, 0x40), (0x5003, 0x08),
+                         (0x4089, 0x80), (0x4040, 0x3F), (0x4041, 0x20), (0x4089, 0x00),
+                         (0x4080, 0xA0), (0x4082, 0x00), (0x4083, 0x04)].iter() {
+        a.expansion_write(addr, val);
+    }
+    a.step(5000);
+
+    let mut w = StateWriter::new();
+    a.save_state(&mut w);
+    let state = w.into_bytes();
+
+    let mut b = with_chips();
+    b.load_state(&mut StateReader::new(&state, savestate::STATE_VERSION));
+
+    // the chips carry on the same from the loaded state
+    for _ in 0..200 {
+        a.step(37);
+        b.step(37);
+        assert_eq!(a.channel_outputs().expansion, b.channel_outputs().expansion);
+    }
+    assert!(a.channel_outputs().expansion != 0.0);
+}
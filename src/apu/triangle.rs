@@ -0,0 +1,117 @@
+// https://wiki.nesdev.com/w/index.php/APU_Triangle
+
+use super::units::LengthCounter;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+static SEQUENCE : [u8; 32] = [
+    15, 14, 13, 12, 11, 10,  9,  8,  7,  6,  5,  4,  3,  2,  1,  0,
+     0,  1,  2,  3,  4,  5,  6,  7,  8,  9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct Triangle {
+    pub length : LengthCounter,
+
+    // also the length counter halt flag
+    control : bool,
+    linear_reload_value : u8,
+    linear_counter : u8,
+    linear_reload : bool,
+
+    sequence_pos : u8,
+    timer_period : u16,
+    timer : u16,
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            length : LengthCounter::new(),
+            control : false,
+            linear_reload_value : 0,
+            linear_counter : 0,
+            linear_reload : false,
+            sequence_pos : 0,
+            timer_period : 0,
+            timer : 0,
+        }
+    }
+
+    // reg is the register offset, 0 - 3
+    pub fn write_reg(&mut self, reg : u16, val : u8) {
+        match reg {
+            // CRRR RRRR
+            0 => {
+                self.control = (val & 0x80) != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = val & 0x7F;
+            },
+            1 => (),
+            2 => self.timer_period = (self.timer_period & 0x0700) | val as u16,
+            // LLLL LTTT
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) |
+                                    (((val & 0b111) as u16) << 8);
+                self.length.load(val >> 3);
+                self.linear_reload = true;
+            },
+            _ => panic!("invalid triangle register {}", reg),
+        }
+    }
+
+    // clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length.active() {
+                self.sequence_pos = (self.sequence_pos + 1) & 0x1F;
+            }
+        }
+        else {
+            self.timer -= 1;
+        }
+    }
+
+    // clocked by quarter frames
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        }
+        else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // the triangle keeps outputting its current step when it's silenced,
+    // instead of dropping to 0
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.sequence_pos as usize]
+    }
+}
+
+impl SaveState for Triangle {
+    fn save_state(&self, w : &mut StateWriter) {
+        self.length.save_state(w);
+        w.bool(self.control);
+        w.u8(self.linear_reload_value);
+        w.u8(self.linear_counter);
+        w.bool(self.linear_reload);
+        w.u8(self.sequence_pos);
+        w.u16(self.timer_period);
+        w.u16(self.timer);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.length.load_state(r);
+        self.control = r.bool();
+        self.linear_reload_value = r.u8();
+        self.linear_counter = r.u8();
+        self.linear_reload = r.bool();
+        self.sequence_pos = r.u8();
+        self.timer_period = r.u16();
+        self.timer = r.u16();
+    }
+}
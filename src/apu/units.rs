@@ -0,0 +1,140 @@
+// Building blocks shared between the APU channels.
+// https://wiki.nesdev.com/w/index.php/APU_Envelope
+// https://wiki.nesdev.com/w/index.php/APU_Length_Counter
+
+use savestate::{ SaveState, StateWriter, StateReader };
+
+static LENGTH_TABLE : [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub struct LengthCounter {
+    pub enabled : bool,
+    pub halt : bool,
+    pub counter : u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            enabled : false,
+            halt : false,
+            counter : 0,
+        }
+    }
+
+    // index is the top 5 bits of the channel's last register
+    pub fn load(&mut self, index : u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(index & 0x1F) as usize];
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled : bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // clocked by half frames
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+pub struct Envelope {
+    pub start : bool,
+    pub looping : bool,
+    pub constant_volume : bool,
+    // either the constant volume or the divider period
+    pub volume : u8,
+    divider : u8,
+    decay_level : u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start : false,
+            looping : false,
+            constant_volume : false,
+            volume : 0,
+            divider : 0,
+            decay_level : 0,
+        }
+    }
+
+    // --LC VVVV, where L also halts the length counter
+    pub fn write_control(&mut self, val : u8) {
+        self.looping = (val & 0x20) != 0;
+        self.constant_volume = (val & 0x10) != 0;
+        self.volume = val & 0x0F;
+    }
+
+    // clocked by quarter frames
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay_level = 15;
+            self.divider = self.volume;
+        }
+        else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay_level > 0 {
+                self.decay_level -= 1;
+            }
+            else if self.looping {
+                self.decay_level = 15;
+            }
+        }
+        else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume { self.volume } else { self.decay_level }
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.halt);
+        w.u8(self.counter);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.enabled = r.bool();
+        self.halt = r.bool();
+        self.counter = r.u8();
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bool(self.start);
+        w.bool(self.looping);
+        w.bool(self.constant_volume);
+        w.u8(self.volume);
+        w.u8(self.divider);
+        w.u8(self.decay_level);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.start = r.bool();
+        self.looping = r.bool();
+        self.constant_volume = r.bool();
+        self.volume = r.u8();
+        self.divider = r.u8();
+        self.decay_level = r.u8();
+    }
+}
@@ -1,18 +1,126 @@
 use ::sdl2::audio::{ AudioSpecDesired, AudioQueue };
 
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+const SAMPLE_RATE : i32 = 44100;
+// in sample frames, also roughly how much the device takes at once
+const DEVICE_BUFFER : u16 = 1024;
+
+// how much audio to keep queued, in seconds. Less than the minimum and
+// the device may run dry, more than the maximum adds noticeable latency
+const MIN_LATENCY : f32 = 0.03;
+const TARGET_LATENCY : f32 = 0.05;
+const MAX_LATENCY : f32 = 0.15;
+
+// the most the sample rate is adjusted by to keep the queue on target,
+// half a percent isn't audible
+const MAX_RATE_ADJUSTMENT : f64 = 0.005;
+
 pub struct EmulatorAudio {
     queue : AudioQueue<f32>,
+    // in samples
+    min_queued : usize,
+    target_queued : usize,
+    max_queued : usize,
+
+    underruns : usize,
+    overruns : usize,
 }
 
 impl EmulatorAudio {
-    pub fn new(sdl_context : &::Sdl) -> EmulatorAudio {
+    pub fn new(sdl_context : &::sdl2::Sdl) -> EmulatorAudio {
         let spec = AudioSpecDesired {
-            freq : None,
-            channels : None,
-            samples : None,
+            freq : Some(SAMPLE_RATE),
+            channels : Some(1),
+            samples : Some(DEVICE_BUFFER),
+        };
+        let queue : AudioQueue<f32> =
+            sdl_context.audio().unwrap().open_queue(None, &spec).unwrap();
+
+        let freq = queue.spec().freq as f32;
+        let audio = EmulatorAudio {
+            queue : queue,
+            min_queued : (freq * MIN_LATENCY) as usize,
+            target_queued : (freq * TARGET_LATENCY) as usize,
+            max_queued : (freq * MAX_LATENCY) as usize,
+            underruns : 0,
+            overruns : 0,
         };
-        EmulatorAudio {
-            queue : sdl_context.audio().unwrap().open_queue(None, &spec).unwrap(),
+
+        audio.prime();
+        audio.queue.resume();
+        audio
+    }
+
+    // the device may not support the rate we asked for
+    pub fn sample_rate(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    // samples waiting to be played
+    pub fn queued(&self) -> usize {
+        self.queue.size() as usize / mem::size_of::<f32>()
+    }
+
+    // how full the queue is compared to where we want it, 1.0 is on target
+    pub fn fill_level(&self) -> f32 {
+        self.queued() as f32 / self.target_queued as f32
+    }
+
+    // dynamic rate control: make a few less samples when the queue is
+    // over its target and a few more when it's under, so it settles at the
+    // target instead of drifting into an underrun or overrun
+    pub fn rate_adjustment(&self) -> f64 {
+        let fill = (self.fill_level() as f64).min(2.0);
+        1.0 + MAX_RATE_ADJUSTMENT * (1.0 - fill)
+    }
+
+    // blocks until the queue drains down to the target, used to pace
+    // emulation off of the audio device's clock
+    pub fn wait_for_space(&self) {
+        while self.queued() > self.target_queued {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    pub fn underruns(&self) -> usize {
+        self.underruns
+    }
+
+    pub fn overruns(&self) -> usize {
+        self.overruns
+    }
+
+    // fills the queue with silence up to the target, so there is
+    // something to play while the next frames are generated
+    fn prime(&self) {
+        let queued = self.queued();
+        if queued < self.target_queued {
+            self.queue.queue(&vec![0.0; self.target_queued - queued]);
+        }
+    }
+
+    pub fn queue_samples(&mut self, samples : &[f32]) {
+        let queued = self.queued();
+
+        if queued == 0 {
+            // the device ran dry, which is a click. start over with some
+            // silence so it doesn't keep happening
+            self.underruns += 1;
+            self.prime();
+        }
+        else if queued > self.max_queued {
+            // we're getting ahead of the device, drop this batch rather
+            // than let the latency keep growing
+            self.overruns += 1;
+            return;
         }
+        else if queued < self.min_queued {
+            self.prime();
+        }
+
+        self.queue.queue(samples);
     }
 }
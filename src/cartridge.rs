@@ -1,10 +1,26 @@
 use super::{ Memory, ComponentRc };
+use region::Region;
+use nsf::Nsf;
+use apu::ExpansionChip;
+use savestate::{ self, SaveState, StateWriter, StateReader };
 
 pub struct Cartridge {
     prgrom_size : u16,
     prgrom : Vec<u8>,
     chrrom : Vec<u8>,
     vram : [u8; VRAM_SIZE as usize],
+    region : Option<Region>,
+
+    // mapped at $6000 - $7FFF when there is any
+    prg_ram : Vec<u8>,
+    // for nsf files, the 4k bank of prgrom mapped into each 4k of $8000 - $FFFF
+    nsf_banks : Option<[u8; 8]>,
+    // sound chips, which the apu mixes in
+    expansion_chips : Vec<ExpansionChip>,
+
+    // of the rom as it was loaded, so save states can tell which game
+    // they're for
+    rom_hash : u32,
 }
 
 impl Cartridge {
@@ -22,24 +38,63 @@ impl Cartridge {
                 prgrom : new_prgrom,
                 chrrom : new_chrrom,
                 vram : [0; VRAM_SIZE as usize],
+                region : None,
+                prg_ram : Vec::new(),
+                nsf_banks : None,
+                expansion_chips : Vec::new(),
+                rom_hash : 0,
             }
         )
     }
 
-    // https://wiki.nesdev.com/w/index.php/INES
+    // https://wiki.nesdev.com/w/index.php/NSF#Bank_switching
+    // an nsf file's data, mapped like the nsf hardware would. Files that
+    // don't use bank switching are treated as 8 banks in order
+    pub fn from_nsf(nsf : &Nsf) -> Cartridge {
+        // the data starts partway into its first bank
+        let padding = match nsf.bank_init {
+            Some(_) => (nsf.load_addr & 0x0FFF) as usize,
+            None => (nsf.load_addr - PRG_FIRST) as usize,
+        };
+
+        let mut prgrom = vec![0; padding];
+        prgrom.extend_from_slice(&nsf.data);
+
+        let num_banks = prgrom.len().div_ceil(NSF_BANK_SIZE);
+        prgrom.resize(num_banks.max(8) * NSF_BANK_SIZE, 0);
+
+        Cartridge {
+            prgrom_size : 0,
+            prgrom : prgrom,
+            chrrom : vec![0; 0x2000],
+            vram : [0; VRAM_SIZE as usize],
+            region : nsf.region(),
+            prg_ram : vec![0; PRG_RAM_SIZE],
+            nsf_banks : Some(nsf.bank_init.unwrap_or([0, 1, 2, 3, 4, 5, 6, 7])),
+            expansion_chips : nsf.expansion_chips(),
+            rom_hash : savestate::hash(&nsf.data),
+        }
+    }
+
     pub fn from_ines_file(filename : &str) -> Cartridge {
         use std::fs::File;
         use std::io::prelude::*;
-        const HEADER_SIZE : usize = 16;
-        const PRGROM_BANK_SIZE : usize = 16384;
-        const CHRROM_BANK_SIZE : usize = 8192;
 
         let file = File::open(filename).expect("error opening file");
         let data : Vec<u8> = file.bytes()
                         .map(|r| r.expect("error reading data"))
                         .collect();
 
-        let data = &data[..];
+        let cart = Cartridge::from_ines_bytes(&data);
+        println!("loaded cartridge {}", filename);
+        cart
+    }
+
+    // https://wiki.nesdev.com/w/index.php/INES
+    pub fn from_ines_bytes(data : &[u8]) -> Cartridge {
+        const HEADER_SIZE : usize = 16;
+        const PRGROM_BANK_SIZE : usize = 16384;
+        const CHRROM_BANK_SIZE : usize = 8192;
 
         let header = &data[..HEADER_SIZE];
 
@@ -49,6 +104,25 @@ impl Cartridge {
         let flags7          = header[7];
         let mapper = ((flags6 & 0xF0) >> 4) | (flags7 & 0xF0);
 
+        // https://wiki.nesdev.com/w/index.php/NES_2.0#CPU.2FPPU_Timing
+        let is_nes2 = (flags7 & 0x0C) == 0x08;
+        let region = if is_nes2 {
+            match header[12] & 0b11 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                // multiple region
+                _ => None,
+            }
+        }
+        // plain iNES only has a rarely used PAL bit
+        else if header[9] & 1 == 1 {
+            Some(Region::Pal)
+        }
+        else {
+            None
+        };
+
         let prgrom_size = PRGROM_BANK_SIZE * num_prgrom_banks as usize;
         let chrrom_size = CHRROM_BANK_SIZE * num_chrrom_banks as usize;
 
@@ -78,7 +152,7 @@ impl Cartridge {
         println!("flags 6: {:08b}", flags6);
         println!("flags 7: {:08b}", flags7);
         println!("mapper: {}", mapper);
-        println!("loaded cartridge {}", filename);
+        println!("region: {:?}", region);
         println!("num prgrom banks: {}; total prgrom size: {}k",
             num_prgrom_banks, prgrom_size / 1024);
         println!("num chrrom banks: {}; total chrrom size: {}k",
@@ -90,13 +164,75 @@ impl Cartridge {
             _ => unimplemented!("mapper {}", mapper),
         }
 
+        let rom_hash = savestate::hash(&data[prgrom_start..chrrom_end]);
+
+        // https://wiki.nesdev.com/w/index.php/PRG_RAM_circuit
+        // plain iNES doesn't say whether there's PRG RAM, and test roms
+        // report their results in it, so there is unless NES 2.0 says not
+        let has_prg_ram = !is_nes2 || header[10] != 0;
+
         Cartridge {
             prgrom_size : new_prgrom.len() as u16,
             prgrom : new_prgrom,
             chrrom : new_chrrom,
             vram : [0; VRAM_SIZE as usize],
+            region : region,
+            prg_ram : if has_prg_ram { vec![0; PRG_RAM_SIZE] } else { Vec::new() },
+            nsf_banks : None,
+            expansion_chips : mapper_expansion_audio(mapper),
+            rom_hash : rom_hash,
         }
     }
+
+    // the region from the rom header, if it specifies one
+    pub fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    pub fn rom_hash(&self) -> u32 {
+        self.rom_hash
+    }
+
+    // the sound chips on the cartridge, for the apu to add
+    pub fn expansion_audio(&self) -> &[ExpansionChip] {
+        &self.expansion_chips
+    }
+
+    // the FDS loads nsf data into ram, so the program can write over it
+    // https://wiki.nesdev.com/w/index.php/NSF#FDS_RAM
+    fn prg_writable(&self) -> bool {
+        self.nsf_banks.is_none() || self.expansion_chips.contains(&ExpansionChip::Fds)
+    }
+}
+
+// https://wiki.nesdev.com/w/index.php/NROM
+// an iNES file for tests, with code at $8000 and no CHR. IRQs go to the
+// same place as resets
+#[cfg(test)]
+pub fn test_nrom(code : &[u8], reset : u16, nmi : u16) -> Vec<u8> {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0; 0x4000];
+    prg[..code.len()].copy_from_slice(code);
+    prg[0x3FFA..0x3FFC].copy_from_slice(&nmi.to_le_bytes());
+    prg[0x3FFC..0x3FFE].copy_from_slice(&reset.to_le_bytes());
+    prg[0x3FFE..0x4000].copy_from_slice(&reset.to_le_bytes());
+    rom.extend_from_slice(&prg);
+    rom.extend_from_slice(&[0; 0x2000]);
+    rom
+}
+
+// https://wiki.nesdev.com/w/index.php/Expansion_audio
+// the sound chips on boards with each mapper. Some boards for these mappers
+// leave the chip out, but games for them don't write to its registers
+fn mapper_expansion_audio(mapper : u8) -> Vec<ExpansionChip> {
+    match mapper {
+        5       => vec![ExpansionChip::Mmc5],
+        19      => vec![ExpansionChip::N163],
+        24 | 26 => vec![ExpansionChip::Vrc6],
+        69      => vec![ExpansionChip::Sunsoft5B],
+        85      => vec![ExpansionChip::Vrc7],
+        _       => Vec::new(),
+    }
 }
 
 const CHR_FIRST : u16 = 0x0000;
@@ -104,6 +240,14 @@ const CHR_LAST : u16 = 0x1FFF;
 const PRG_FIRST : u16 = 0x8000;
 const PRG_LAST : u16 = 0xFFFF;
 
+const PRG_RAM_FIRST : u16 = 0x6000;
+const PRG_RAM_LAST : u16 = 0x7FFF;
+const PRG_RAM_SIZE : usize = 0x2000;
+
+const NSF_BANKS_FIRST : u16 = 0x5FF8;
+const NSF_BANKS_LAST : u16 = 0x5FFF;
+const NSF_BANK_SIZE : usize = 0x1000;
+
 const VRAM_SIZE : u16 = 0x0800;
 const VRAM_FIRST : u16 = 0x2000;
 const VRAM_LAST : u16 = 0x3EFF;
@@ -114,6 +258,31 @@ const VRAM_LAST : u16 = 0x3EFF;
  but it's easier to code.
 */
 
+impl Cartridge {
+    // whether anything on the cartridge responds to a cpu address,
+    // reads from anywhere else are open bus
+    pub fn maps_cpu_addr(&self, addr : u16) -> bool {
+        match addr {
+            PRG_FIRST...PRG_LAST => true,
+            PRG_RAM_FIRST...PRG_RAM_LAST => !self.prg_ram.is_empty(),
+            NSF_BANKS_FIRST...NSF_BANKS_LAST => self.nsf_banks.is_some(),
+            _ => false,
+        }
+    }
+
+    fn prg_index(&self, addr : u16) -> usize {
+        let offset = (addr - PRG_FIRST) as usize;
+        match self.nsf_banks {
+            Some(banks) => {
+                let bank = banks[offset / NSF_BANK_SIZE] as usize;
+                (bank * NSF_BANK_SIZE + offset % NSF_BANK_SIZE) % self.prgrom.len()
+            },
+            // vertical mirroring
+            None => offset % self.prgrom_size as usize,
+        }
+    }
+}
+
 // TODO horizontal mirroring
 impl Memory for Cartridge {
     fn loadb(&self, addr : u16) -> u8 {
@@ -121,9 +290,11 @@ impl Memory for Cartridge {
             CHR_FIRST...CHR_LAST => self.chrrom[addr as usize],
             VRAM_FIRST...VRAM_LAST => self.vram[(addr % VRAM_SIZE) as usize],
 
-            // vertical mirroring
-            PRG_FIRST...PRG_LAST => self.prgrom[((addr - PRG_FIRST) %
-                                                 self.prgrom_size) as usize],
+            PRG_FIRST...PRG_LAST => self.prgrom[self.prg_index(addr)],
+            PRG_RAM_FIRST...PRG_RAM_LAST =>
+                self.prg_ram[(addr - PRG_RAM_FIRST) as usize],
+            // the bank registers are write only
+            NSF_BANKS_FIRST...NSF_BANKS_LAST => 0,
             _ => panic!("invalid cartridge address"),
         }
     }
@@ -131,9 +302,57 @@ impl Memory for Cartridge {
         match addr {
             CHR_FIRST...CHR_LAST => self.chrrom[addr as usize] = val,
             VRAM_FIRST...VRAM_LAST => self.vram[(addr % VRAM_SIZE) as usize] = val,
-            PRG_FIRST...PRG_LAST => self.prgrom[((addr - PRG_FIRST) %
-                                                self.prgrom_size) as usize] = val,
+            // nsf data is read only
+            PRG_FIRST...PRG_LAST if !self.prg_writable() => (),
+            PRG_FIRST...PRG_LAST => {
+                let index = self.prg_index(addr);
+                self.prgrom[index] = val;
+            },
+            PRG_RAM_FIRST...PRG_RAM_LAST =>
+                self.prg_ram[(addr - PRG_RAM_FIRST) as usize] = val,
+            NSF_BANKS_FIRST...NSF_BANKS_LAST => {
+                if let Some(ref mut banks) = self.nsf_banks {
+                    banks[(addr - NSF_BANKS_FIRST) as usize] = val;
+                }
+            },
             _ => panic!("invalid cartridge address"),
         }
     }
 }
+
+// the parts of the cartridge that can change. chr is saved whole since
+// carts without chr rom use it as ram
+impl SaveState for Cartridge {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bytes(&self.vram);
+        w.bytes(&self.chrrom);
+        w.bytes(&self.prg_ram);
+        w.bool(self.nsf_banks.is_some());
+        w.bytes(&self.nsf_banks.unwrap_or([0; 8]));
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        r.bytes(&mut self.vram);
+        r.bytes(&mut self.chrrom);
+        r.bytes(&mut self.prg_ram);
+        let has_banks = r.bool();
+        let mut banks = [0; 8];
+        r.bytes(&mut banks);
+        if has_banks {
+            self.nsf_banks = Some(banks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapper_sound_chips() {
+        assert_eq!(mapper_expansion_audio(0), vec![]);
+        assert_eq!(mapper_expansion_audio(24), vec![ExpansionChip::Vrc6]);
+        assert_eq!(mapper_expansion_audio(26), vec![ExpansionChip::Vrc6]);
+        assert_eq!(mapper_expansion_audio(85), vec![ExpansionChip::Vrc7]);
+    }
+}
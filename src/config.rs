@@ -0,0 +1,405 @@
+// Emulator settings, read from a simple text file of `key = value` lines.
+// Lines starting with '#' are comments. Command line options override
+// whatever is in the file.
+
+use palette::{ Palette, NtscParams };
+use region::Region;
+use apu::{ Channel, NUM_CHANNELS };
+use ports::{ DeviceKind, NUM_PORTS };
+use gamepad::ButtonMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PaletteSource {
+    Default,
+    File(String),
+    Ntsc(NtscParams),
+}
+
+// what decides how fast frames are run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    // sleep until the next frame is due
+    Timer,
+    // keep the audio queue at its target level
+    Audio,
+    // wait for the display's vertical sync
+    Vsync,
+}
+
+impl Pacing {
+    pub fn from_name(name : &str) -> Option<Pacing> {
+        match name {
+            "timer" => Some(Pacing::Timer),
+            "audio" => Some(Pacing::Audio),
+            "vsync" => Some(Pacing::Vsync),
+            _       => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub palette : PaletteSource,
+    // None means use the region from the rom header
+    pub region : Option<Region>,
+    pub pacing : Pacing,
+    // .wav file to record audio to from the start
+    pub record : Option<String>,
+    // also record each channel to its own file
+    pub record_stems : bool,
+    // save state slots go in <states>.state0 to <states>.state9
+    pub states : Option<String>,
+    // .fm2 movie to play from the start
+    pub movie : Option<String>,
+    // .fm2 file to record a movie to from power on, and the file F10
+    // records to
+    pub record_movie : Option<String>,
+    // frames between rewind snapshots
+    pub rewind_interval : usize,
+    // bytes the rewind history can use, 0 turns rewinding off
+    pub rewind_memory : usize,
+    // how many times faster than normal play rewinding goes
+    pub rewind_speed : f64,
+    // mix settings, indexed by Channel
+    pub channel_volumes : [f32; NUM_CHANNELS],
+    pub muted : [bool; NUM_CHANNELS],
+    pub solo : Option<Channel>,
+    // what's plugged into each controller port
+    pub ports : [DeviceKind; NUM_PORTS],
+    // what's plugged into the famicom's expansion port
+    pub expansion : DeviceKind,
+    // button maps for gamepads by name, and None's for the rest
+    pub gamepads : Vec<(Option<String>, ButtonMap)>,
+    // how far a stick has to be pushed to press the d-pad, out of 1
+    pub gamepad_deadzone : f32,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            palette : PaletteSource::Default,
+            region : None,
+            pacing : Pacing::Audio,
+            record : None,
+            record_stems : false,
+            states : None,
+            movie : None,
+            record_movie : None,
+            rewind_interval : 1,
+            rewind_memory : 64 << 20,
+            rewind_speed : 1.0,
+            channel_volumes : [1.0; NUM_CHANNELS],
+            muted : [false; NUM_CHANNELS],
+            solo : None,
+            ports : [DeviceKind::Controller; NUM_PORTS],
+            expansion : DeviceKind::None,
+            gamepads : Vec::new(),
+            gamepad_deadzone : 0.4,
+        }
+    }
+}
+
+fn parse_float(key : &str, val : &str) -> f64 {
+    val.parse().unwrap_or_else(|_| panic!("invalid value for {}: {}", key, val))
+}
+
+fn parse_channel(key : &str, val : &str) -> Channel {
+    Channel::from_name(val)
+        .unwrap_or_else(|| panic!("invalid channel for {}: {}", key, val))
+}
+
+fn parse_bool(key : &str, val : &str) -> bool {
+    match val {
+        "true" | "on" | "yes" => true,
+        "false" | "off" | "no" => false,
+        _ => panic!("invalid value for {}: {}", key, val),
+    }
+}
+
+impl Config {
+    pub fn from_file(filename : &str) -> Config {
+        use std::fs::File;
+        use std::io::prelude::*;
+
+        let mut text = String::new();
+        File::open(filename).expect("error opening config file")
+            .read_to_string(&mut text).expect("error reading config file");
+
+        let mut config = Config::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let val = match parts.next() {
+                Some(val) => val.trim(),
+                None => panic!("invalid config line: {}", line),
+            };
+
+            config.set(key, val);
+        }
+        config
+    }
+
+    // used for both config file entries and command line options
+    pub fn set(&mut self, key : &str, val : &str) {
+        match key {
+            "palette" => self.palette = match val {
+                "default" => PaletteSource::Default,
+                "ntsc" => PaletteSource::Ntsc(self.ntsc_params()),
+                filename => PaletteSource::File(String::from(filename)),
+            },
+            "hue" | "saturation" | "contrast" | "brightness" | "gamma" => {
+                let mut params = self.ntsc_params();
+                let val = parse_float(key, val);
+                match key {
+                    "hue"        => params.hue = val,
+                    "saturation" => params.saturation = val,
+                    "contrast"   => params.contrast = val,
+                    "brightness" => params.brightness = val,
+                    _            => params.gamma = val,
+                }
+                self.palette = PaletteSource::Ntsc(params);
+            },
+            "region" => self.region = match val {
+                "auto" => None,
+                name => match Region::from_name(name) {
+                    Some(region) => Some(region),
+                    None => panic!("invalid region: {}", name),
+                },
+            },
+            "pacing" => self.pacing = match Pacing::from_name(val) {
+                Some(pacing) => pacing,
+                None => panic!("invalid pacing: {}", val),
+            },
+            "record" => self.record = Some(String::from(val)),
+            "record_stems" => self.record_stems = parse_bool(key, val),
+            "states" => self.states = Some(String::from(val)),
+            "movie" => self.movie = Some(String::from(val)),
+            "record_movie" => self.record_movie = Some(String::from(val)),
+            "rewind_interval" => self.rewind_interval = match val.parse() {
+                Ok(n) if n > 0 => n,
+                _ => panic!("invalid value for {}: {}", key, val),
+            },
+            // in megabytes
+            "rewind_memory" => self.rewind_memory = (parse_float(key, val) * (1 << 20) as f64) as usize,
+            "rewind_speed" => self.rewind_speed = parse_float(key, val),
+            // a comma separated list of channels, or none
+            "mute" => {
+                self.muted = [false; NUM_CHANNELS];
+                for name in val.split(',').map(str::trim).filter(|name| *name != "none") {
+                    self.muted[parse_channel(key, name) as usize] = true;
+                }
+            },
+            // a Four Score takes both ports
+            "four_score" => self.ports = if parse_bool(key, val) {
+                [DeviceKind::FourScore; NUM_PORTS]
+            } else {
+                [DeviceKind::Controller; NUM_PORTS]
+            },
+            "port1" | "port2" => {
+                let port = if key == "port1" { 0 } else { 1 };
+                self.ports[port] = match DeviceKind::from_name(val) {
+                    Some(kind) if !kind.is_expansion() => kind,
+                    _ => panic!("invalid device for {}: {}", key, val),
+                };
+            },
+            "expansion" => self.expansion = match DeviceKind::from_name(val) {
+                Some(kind) if kind.is_expansion() || kind == DeviceKind::None => kind,
+                _ => panic!("invalid device for {}: {}", key, val),
+            },
+            // "name: a=b, b=a", or just the bindings for every other pad.
+            // Each line changes the bindings it has
+            "gamepad" => {
+                let mut parts = val.rsplitn(2, ':');
+                let bindings = parts.next().unwrap_or("");
+                let name = parts.next().map(|name| String::from(name.trim()));
+
+                let i = match self.gamepads.iter().position(|&(ref n, _)| *n == name) {
+                    Some(i) => i,
+                    None => {
+                        self.gamepads.push((name, ButtonMap::default()));
+                        self.gamepads.len() - 1
+                    },
+                };
+                self.gamepads[i].1.remap(bindings).unwrap_or_else(|e| panic!("{}", e));
+            },
+            "gamepad_deadzone" => self.gamepad_deadzone = match val.parse() {
+                Ok(d) if (0.0..1.0).contains(&d) => d,
+                _ => panic!("invalid value for {}: {}", key, val),
+            },
+            "solo" => self.solo = match val {
+                "none" => None,
+                name => Some(parse_channel(key, name)),
+            },
+            // pulse1_volume, triangle_volume, ...
+            _ => match key.strip_suffix("_volume") {
+                Some(name) => {
+                    let channel = parse_channel(key, name);
+                    self.channel_volumes[channel as usize] = parse_float(key, val) as f32;
+                },
+                None => panic!("unknown config key: {}", key),
+            },
+        }
+    }
+
+    fn ntsc_params(&self) -> NtscParams {
+        match self.palette {
+            PaletteSource::Ntsc(params) => params,
+            _ => NtscParams::default(),
+        }
+    }
+
+    pub fn load_palette(&self) -> Palette {
+        match self.palette {
+            PaletteSource::Default => Palette::default(),
+            PaletteSource::File(ref filename) => Palette::from_pal_file(filename),
+            PaletteSource::Ntsc(ref params) => Palette::generate(params),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_options() {
+        let mut c = Config::default();
+        assert_eq!(c.palette, PaletteSource::Default);
+
+        c.set("palette", "smooth.pal");
+        assert_eq!(c.palette, PaletteSource::File(String::from("smooth.pal")));
+
+        c.set("gamma", "2.2");
+        c.set("hue", "-15");
+        match c.palette {
+            PaletteSource::Ntsc(params) => {
+                assert_eq!(params.gamma, 2.2);
+                assert_eq!(params.hue, -15.0);
+                assert_eq!(params.saturation, 1.0);
+            },
+            _ => panic!("expected ntsc palette"),
+        }
+    }
+
+    #[test]
+    fn region_option() {
+        let mut c = Config::default();
+        assert_eq!(c.region, None);
+
+        c.set("region", "pal");
+        assert_eq!(c.region, Some(Region::Pal));
+
+        c.set("region", "auto");
+        assert_eq!(c.region, None);
+    }
+
+    #[test]
+    fn pacing_option() {
+        let mut c = Config::default();
+        assert_eq!(c.pacing, Pacing::Audio);
+
+        c.set("pacing", "vsync");
+        assert_eq!(c.pacing, Pacing::Vsync);
+
+        c.set("pacing", "timer");
+        assert_eq!(c.pacing, Pacing::Timer);
+    }
+
+    #[test]
+    fn record_options() {
+        let mut c = Config::default();
+        assert_eq!(c.record, None);
+        assert!(!c.record_stems);
+
+        c.set("record", "song.wav");
+        c.set("record_stems", "on");
+        assert_eq!(c.record, Some(String::from("song.wav")));
+        assert!(c.record_stems);
+    }
+
+    #[test]
+    fn states_option() {
+        let mut c = Config::default();
+        assert_eq!(c.states, None);
+        c.set("states", "saves/game");
+        assert_eq!(c.states, Some(String::from("saves/game")));
+    }
+
+    #[test]
+    fn port_options() {
+        let mut c = Config::default();
+        assert_eq!(c.ports, [DeviceKind::Controller; NUM_PORTS]);
+        c.set("port2", "none");
+        assert_eq!(c.ports, [DeviceKind::Controller, DeviceKind::None]);
+        c.set("port2", "zapper");
+        assert_eq!(c.ports, [DeviceKind::Controller, DeviceKind::Zapper]);
+        c.set("four_score", "on");
+        assert_eq!(c.ports, [DeviceKind::FourScore; NUM_PORTS]);
+
+        assert_eq!(c.expansion, DeviceKind::None);
+        c.set("expansion", "keyboard");
+        assert_eq!(c.expansion, DeviceKind::FamilyKeyboard);
+        c.set("port2", "vaus");
+        assert_eq!(c.ports[1], DeviceKind::Vaus);
+    }
+
+    #[test]
+    fn movie_options() {
+        let mut c = Config::default();
+        c.set("movie", "run.fm2");
+        c.set("record_movie", "new.fm2");
+        assert_eq!(c.movie, Some(String::from("run.fm2")));
+        assert_eq!(c.record_movie, Some(String::from("new.fm2")));
+    }
+
+    #[test]
+    fn rewind_options() {
+        let mut c = Config::default();
+        c.set("rewind_interval", "4");
+        c.set("rewind_memory", "0.5");
+        c.set("rewind_speed", "2");
+        assert_eq!(c.rewind_interval, 4);
+        assert_eq!(c.rewind_memory, 512 * 1024);
+        assert_eq!(c.rewind_speed, 2.0);
+    }
+
+    #[test]
+    fn gamepad_options() {
+        let mut c = Config::default();
+        c.set("gamepad", "select = back");
+        c.set("gamepad", "8BitDo SN30 Pro: a=a, b=b");
+        c.set("gamepad", "8BitDo SN30 Pro: start=start+guide");
+        c.set("gamepad_deadzone", "0.25");
+
+        let mut default = ButtonMap::default();
+        default.remap("select = back").unwrap();
+        let mut sn30 = ButtonMap::default();
+        sn30.remap("a=a, b=b, start=start+guide").unwrap();
+        assert_eq!(c.gamepads, vec![
+            (None, default),
+            (Some(String::from("8BitDo SN30 Pro")), sn30),
+        ]);
+        assert_eq!(c.gamepad_deadzone, 0.25);
+    }
+
+    #[test]
+    fn mix_options() {
+        let mut c = Config::default();
+
+        c.set("mute", "pulse2, dmc");
+        assert_eq!(c.muted, [false, true, false, false, true, false]);
+        c.set("mute", "none");
+        assert_eq!(c.muted, [false; NUM_CHANNELS]);
+
+        c.set("solo", "triangle");
+        assert_eq!(c.solo, Some(Channel::Triangle));
+
+        c.set("noise_volume", "0.5");
+        assert_eq!(c.channel_volumes[Channel::Noise as usize], 0.5);
+    }
+}
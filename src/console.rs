@@ -0,0 +1,671 @@
+// The console on its own, without a window, audio device or keyboard. A
+// Frontend shows its frames, plays its audio and gives it input, or it can
+// be run headless. This is also the library's interface for tools that
+// want to drive the emulator and look inside it.
+
+use cartridge::Cartridge;
+use config::Config;
+use region::Region;
+use cpu::{ CPU, InterruptStatus, Registers };
+use ppu::PPU;
+use apu::{ self, APU, Channel };
+use controller::{ ButtonAction, Button };
+use ports::{ Ports, DeviceKind, EXPANSION_PORT };
+use family_keyboard::KeyboardKey;
+use savestate::{ self, SaveState, StateWriter, StateReader, StateHeader, Chunk };
+use ComponentRc;
+
+use std::cell::Ref;
+
+// things that happen inside the console, see Nes::subscribe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleEvent {
+    // the ppu finished drawing a frame and started vblank
+    FrameComplete,
+    // the cpu took an interrupt
+    Nmi,
+    Irq,
+}
+
+type Listener = Box<dyn FnMut(ConsoleEvent)>;
+
+pub struct Nes {
+    cpu : CPU,
+    ppu : ComponentRc<PPU>,
+    apu : ComponentRc<APU>,
+    ports : ComponentRc<Ports>,
+    region : Region,
+
+    // kept to power the console back on with
+    cart : ComponentRc<Cartridge>,
+    config : Config,
+    sample_rate : u32,
+
+    listeners : Vec<Listener>,
+}
+
+impl Nes {
+    // audio is made at sample_rate, see audio_samples
+    pub fn new(cart : Cartridge, config : &Config, sample_rate : u32) -> Nes {
+        let cart = ComponentRc::new(cart);
+        let region = config.region.or(cart.borrow().region()).unwrap_or(Region::Ntsc);
+        let ppu = ComponentRc::new(PPU::new(cart.new_ref()));
+        let apu = ComponentRc::new(APU::new());
+        let ports = ComponentRc::new(Ports::new(config.ports));
+        ports.borrow_mut().plug(EXPANSION_PORT, config.expansion);
+        let cpu = CPU::new(
+            cart.new_ref(), ppu.new_ref(), apu.new_ref(), ports.new_ref());
+
+        let mut nes = Nes {
+            cpu : cpu,
+            ppu : ppu,
+            apu : apu,
+            ports : ports,
+            region : region,
+            cart : cart,
+            config : config.clone(),
+            sample_rate : sample_rate,
+            listeners : Vec::new(),
+        };
+        nes.power_on();
+        nes
+    }
+
+    // an iNES rom with the default settings
+    pub fn from_rom(data : &[u8]) -> Nes {
+        Nes::new(Cartridge::from_ines_bytes(data), &Config::default(),
+                 apu::DEFAULT_SAMPLE_RATE)
+    }
+
+    pub fn from_rom_file(filename : &str) -> Nes {
+        Nes::new(Cartridge::from_ines_file(filename), &Config::default(),
+                 apu::DEFAULT_SAMPLE_RATE)
+    }
+
+    // sets up freshly made components from the config
+    fn power_on(&mut self) {
+        let config = &self.config;
+
+        self.ppu.borrow_mut().set_palette(config.load_palette());
+        {
+            let mut apu = self.apu.borrow_mut();
+            apu.set_sample_rate(self.sample_rate);
+            apu.set_stems_enabled(config.record_stems);
+            for &channel in Channel::ALL.iter() {
+                apu.set_channel_volume(channel, config.channel_volumes[channel as usize]);
+                apu.set_channel_muted(channel, config.muted[channel as usize]);
+            }
+            apu.set_solo(config.solo);
+        }
+
+        self.cpu.set_region(self.region);
+        self.cpu.send_reset();
+    }
+
+    // turns the console off and on again. everything but the cartridge
+    // starts over, including the mix settings from the config
+    pub fn power_cycle(&mut self) {
+        self.ppu = ComponentRc::new(PPU::new(self.cart.new_ref()));
+        self.apu = ComponentRc::new(APU::new());
+        // whatever is plugged in stays plugged in
+        let kinds = self.ports.borrow().kinds();
+        let expansion = self.ports.borrow().kind(EXPANSION_PORT);
+        self.ports = ComponentRc::new(Ports::new(kinds));
+        self.ports.borrow_mut().plug(EXPANSION_PORT, expansion);
+        self.cpu = CPU::new(self.cart.new_ref(), self.ppu.new_ref(),
+                            self.apu.new_ref(), self.ports.new_ref());
+        self.power_on();
+    }
+
+    // the reset button. ram is kept, and the program starts over from the
+    // reset vector
+    pub fn reset(&mut self) {
+        self.ppu.borrow_mut().reset();
+        self.apu.borrow_mut().reset();
+        self.cpu.send_reset();
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // called with every ConsoleEvent from now on
+    pub fn subscribe<F>(&mut self, listener : F) where F : FnMut(ConsoleEvent) + 'static {
+        self.listeners.push(Box::new(listener));
+    }
+
+    fn emit(&mut self, event : ConsoleEvent) {
+        for listener in self.listeners.iter_mut() {
+            listener(event);
+        }
+    }
+
+    // runs one cpu instruction, along with any interrupt taken before it,
+    // and returns how many cpu cycles it took
+    pub fn step_instruction(&mut self) -> usize {
+        self.step().0
+    }
+
+    // also returns whether a frame was finished
+    fn step(&mut self) -> (usize, bool) {
+        let cycles = self.cpu.step();
+
+        match self.cpu.last_interrupt() {
+            InterruptStatus::NMI => self.emit(ConsoleEvent::Nmi),
+            InterruptStatus::IRQ => self.emit(ConsoleEvent::Irq),
+            InterruptStatus::Reset | InterruptStatus::None => (),
+        }
+
+        let frame_complete = self.ppu.borrow_mut().take_frame_complete();
+        if frame_complete {
+            self.emit(ConsoleEvent::FrameComplete);
+        }
+        (cycles, frame_complete)
+    }
+
+    // runs until the ppu moves on to the next scanline. instructions aren't
+    // split up, so this can go a few dots past the start of the line
+    pub fn step_scanline(&mut self) {
+        let scanline = self.ppu.borrow().scanline();
+        while self.ppu.borrow().scanline() == scanline {
+            self.step();
+        }
+    }
+
+    // runs until the ppu finishes a frame
+    pub fn step_frame(&mut self) {
+        while !self.step().1 {}
+    }
+
+    // the scanline the ppu is on, 0 is the top of the picture
+    pub fn scanline(&self) -> u16 {
+        self.ppu.borrow().scanline()
+    }
+
+    // cpu cycles since power on
+    pub fn cycles(&self) -> usize {
+        self.cpu.cycles()
+    }
+
+    pub fn cpu_registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    pub fn set_cpu_registers(&mut self, regs : Registers) {
+        self.cpu.set_registers(regs);
+    }
+
+    // reads the cpu's address space like a debugger would: registers read
+    // this way don't get cleared or advanced
+    pub fn peek_cpu(&self, addr : u16) -> u8 {
+        self.cpu.peek_mem(addr)
+    }
+
+    // writes to ram or the cartridge. writes to registers are ignored, use
+    // write_cpu for those
+    pub fn poke_cpu(&mut self, addr : u16, val : u8) {
+        self.cpu.poke_mem(addr, val);
+    }
+
+    // reads and writes like the cpu would, side effects and all
+    pub fn read_cpu(&mut self, addr : u16) -> u8 {
+        self.cpu.read_mem(addr)
+    }
+
+    pub fn write_cpu(&mut self, addr : u16, val : u8) {
+        self.cpu.write_mem(addr, val);
+    }
+
+    // the ppu's address space: pattern tables, nametables and palettes
+    pub fn peek_ppu(&self, addr : u16) -> u8 {
+        self.ppu.borrow().peek_mem(addr)
+    }
+
+    pub fn poke_ppu(&mut self, addr : u16, val : u8) {
+        self.ppu.borrow_mut().poke_mem(addr, val);
+    }
+
+    // sprite memory, 64 sprites of 4 bytes each
+    pub fn oam(&self) -> [u8; 256] {
+        self.ppu.borrow().oam
+    }
+
+    // a snapshot of the whole machine, which load_state can go back to
+    pub fn save_state(&self) -> Vec<u8> {
+        fn save(component : &dyn SaveState) -> Vec<u8> {
+            let mut w = StateWriter::new();
+            component.save_state(&mut w);
+            w.into_bytes()
+        }
+
+        let header = StateHeader {
+            version : savestate::STATE_VERSION,
+            rom_hash : self.cart.borrow().rom_hash(),
+            region : self.region,
+        };
+        savestate::write_state(&header, &[
+            (b"CPU ", save(&self.cpu)),
+            (b"PPU ", save(&*self.ppu.borrow())),
+            (b"APU ", save(&*self.apu.borrow())),
+            (b"CART", save(&*self.cart.borrow())),
+            (b"CTRL", save(&*self.ports.borrow())),
+        ])
+    }
+
+    // states from older versions load too, but nothing changes if the
+    // state is for another rom or region, or is broken
+    pub fn load_state(&mut self, data : &[u8]) -> Result<(), String> {
+        let (header, chunks) = savestate::read_state(data)?;
+        if header.rom_hash != self.cart.borrow().rom_hash() {
+            return Err(String::from("save state is for a different rom"));
+        }
+        if header.region != self.region {
+            return Err(format!("save state is for {:?}, not {:?}", header.region, self.region));
+        }
+
+        // a chunk can only be checked by reading it, so if any of them are
+        // broken, go back to how things were before the first was read
+        let backup = self.save_state();
+        if let Err(e) = self.load_chunks(header.version, &chunks) {
+            let (_, chunks) = savestate::read_state(&backup)?;
+            self.load_chunks(savestate::STATE_VERSION, &chunks)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn load_chunks(&mut self, version : u32, chunks : &[Chunk]) -> Result<(), String> {
+        for &(tag, chunk) in chunks {
+            let mut r = StateReader::new(chunk, version);
+            match &tag {
+                b"CPU " => self.cpu.load_state(&mut r),
+                b"PPU " => self.ppu.borrow_mut().load_state(&mut r),
+                b"APU " => self.apu.borrow_mut().load_state(&mut r),
+                b"CART" => self.cart.borrow_mut().load_state(&mut r),
+                b"CTRL" => self.ports.borrow_mut().load_state(&mut r),
+                _ => continue,
+            }
+            r.finish().map_err(|e| format!("{} in {}", e, String::from_utf8_lossy(&tag).trim()))?;
+        }
+        Ok(())
+    }
+
+    pub fn save_state_file(&self, filename : &str) -> Result<(), String> {
+        ::std::fs::write(filename, self.save_state())
+            .map_err(|e| format!("error writing {}: {}", filename, e))
+    }
+
+    pub fn load_state_file(&mut self, filename : &str) -> Result<(), String> {
+        let data = ::std::fs::read(filename)
+            .map_err(|e| format!("error reading {}: {}", filename, e))?;
+        self.load_state(&data)
+    }
+
+    // the last finished frame, SCREEN_WIDTH x SCREEN_HEIGHT pixels in BGR24
+    pub fn framebuffer(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.ppu.borrow(), |ppu| &ppu.get_pixeldata()[..])
+    }
+
+    // the audio made since the last call, as mono samples between -1 and 1
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.apu.borrow_mut().take_samples()
+    }
+
+    // each channel's audio on its own, in the order of stem_channels. empty
+    // unless config.record_stems was set
+    pub fn stem_samples(&mut self) -> Vec<Vec<f32>> {
+        self.apu.borrow_mut().take_stem_samples()
+    }
+
+    pub fn stem_channels(&self) -> Vec<Channel> {
+        self.apu.borrow().stem_channels()
+    }
+
+    // every button on controller 1, as Button masks ored together
+    pub fn set_buttons(&mut self, buttons : u8) {
+        self.set_player_buttons(0, buttons);
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.player_buttons(0)
+    }
+
+    pub fn update_button(&mut self, action : ButtonAction, button : Button) {
+        self.update_player_button(0, action, button);
+    }
+
+    // the same for any player's controller, counted from 0. Players 3 and
+    // 4 need a Four Score
+    pub fn set_player_buttons(&mut self, player : usize, buttons : u8) {
+        self.ports.borrow_mut().set_buttons(player, buttons);
+    }
+
+    pub fn player_buttons(&self, player : usize) -> u8 {
+        self.ports.borrow().buttons(player)
+    }
+
+    pub fn update_player_button(&mut self, player : usize, action : ButtonAction, button : Button) {
+        self.ports.borrow_mut().update(player, action, button);
+    }
+
+    // where the mouse or whatever is aiming a light gun is pointing, in
+    // screen pixels, None when it's off the screen
+    pub fn set_pointer(&mut self, pos : Option<(i32, i32)>) {
+        self.ports.borrow_mut().set_pointer(pos);
+    }
+
+    pub fn set_trigger(&mut self, pulled : bool) {
+        self.ports.borrow_mut().set_trigger(pulled);
+    }
+
+    // how far the mouse moved, for paddles and trackballs
+    pub fn move_pointer(&mut self, dx : i32, dy : i32) {
+        self.ports.borrow_mut().move_pointer(dx, dy);
+    }
+
+    // a key on the Family BASIC keyboard
+    pub fn update_key(&mut self, action : ButtonAction, key : KeyboardKey) {
+        self.ports.borrow_mut().update_key(action, key);
+    }
+
+    // swaps what's plugged into a port, EXPANSION_PORT for the famicom's
+    // expansion port
+    pub fn plug(&mut self, port : usize, kind : DeviceKind) {
+        self.ports.borrow_mut().plug(port, kind);
+    }
+
+    pub fn port_device(&self, port : usize) -> DeviceKind {
+        self.ports.borrow().kind(port)
+    }
+
+    // see APU::set_rate_adjustment
+    pub fn set_rate_adjustment(&mut self, ratio : f64) {
+        self.apu.borrow_mut().set_rate_adjustment(ratio);
+    }
+
+    pub fn set_channel_muted(&mut self, channel : Channel, muted : bool) {
+        self.apu.borrow_mut().set_channel_muted(channel, muted);
+    }
+
+    pub fn channel_muted(&self, channel : Channel) -> bool {
+        self.apu.borrow().channel_muted(channel)
+    }
+
+    pub fn set_solo(&mut self, solo : Option<Channel>) {
+        self.apu.borrow_mut().set_solo(solo);
+    }
+
+    pub fn solo(&self) -> Option<Channel> {
+        self.apu.borrow().solo()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ppu::SCREEN_SIZE;
+    use cartridge;
+    use frontend::{ Frontend, EmulatorEvent };
+
+    // an NROM image that turns on NMIs and then strobes the controller
+    // forever, with an NMI handler that returns right away
+    fn test_cart_rom() -> Vec<u8> {
+        // LDA #$80; STA $2000; LDA #1; STA $4016; JMP $8005
+        let mut code = vec![0xA9, 0x80, 0x8D, 0x00, 0x20,
+                            0xA9, 0x01, 0x8D, 0x16, 0x40, 0x4C, 0x05, 0x80];
+        // RTI, for nmis
+        code.resize(0x100, 0);
+        code.push(0x40);
+        cartridge::test_nrom(&code, 0x8000, 0x8100)
+    }
+
+    fn test_cart() -> Cartridge {
+        Cartridge::from_ines_bytes(&test_cart_rom())
+    }
+
+    #[test]
+    fn headless() {
+        let mut nes = Nes::new(test_cart(), &Config::default(), 48000);
+        assert_eq!(nes.region(), Region::Ntsc);
+
+        nes.step_frame();
+        assert_eq!(nes.framebuffer().len(), SCREEN_SIZE);
+        nes.audio_samples();
+
+        nes.step_frame();
+        let samples = nes.audio_samples();
+        assert!(samples.len() >= 790 && samples.len() <= 810);
+
+        nes.set_buttons(Button::A.mask() | Button::Start.mask());
+        nes.update_button(ButtonAction::Released, Button::A);
+        assert_eq!(nes.buttons(), Button::Start.mask());
+    }
+
+    // runs a set number of frames as fast as it can
+    struct TestFrontend {
+        frames : usize,
+        samples : usize,
+    }
+
+    impl Frontend for TestFrontend {
+        fn sample_rate(&self) -> u32 { 44100 }
+        fn present_frame(&mut self, pixels : &[u8]) {
+            assert_eq!(pixels.len(), SCREEN_SIZE);
+            self.frames += 1;
+        }
+        fn queue_audio(&mut self, samples : &[f32]) {
+            self.samples += samples.len();
+        }
+        fn events(&mut self) -> Vec<EmulatorEvent> {
+            if self.frames == 60 {
+                vec![EmulatorEvent::Exit]
+            } else {
+                vec![EmulatorEvent::ControllerEvent {
+                    player : 0, action : ButtonAction::Pressed, button : Button::Start
+                }]
+            }
+        }
+        fn wait_for_frame(&mut self, frame_rate : f64) {
+            assert!((frame_rate - 60.1).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn frontend() {
+        let mut frontend = TestFrontend { frames : 0, samples : 0 };
+        ::run_with_frontend(test_cart(), Config::default(), &mut frontend);
+        assert_eq!(frontend.frames, 60);
+        // about a second of audio, less what's still in the resampler
+        assert!(frontend.samples > 43000 && frontend.samples <= 44100);
+    }
+
+    #[test]
+    fn stepping() {
+        let mut nes = Nes::from_rom(&test_cart_rom());
+
+        // the reset is taken before the first instruction
+        let cycles = nes.step_instruction();
+        assert_eq!(cycles, 7 + 2);
+        assert_eq!(nes.cpu_registers().pc, 0x8002);
+        assert_eq!(nes.cycles(), cycles);
+
+        let scanline = nes.scanline();
+        nes.step_scanline();
+        assert_eq!(nes.scanline(), scanline + 1);
+
+        nes.reset();
+        nes.step_instruction();
+        assert_eq!(nes.cpu_registers().pc, 0x8002);
+
+        let mut regs = nes.cpu_registers();
+        regs.a = 0x12;
+        nes.set_cpu_registers(regs);
+        assert_eq!(nes.cpu_registers().a, 0x12);
+    }
+
+    #[test]
+    fn peek_and_poke() {
+        let mut nes = Nes::from_rom(&test_cart_rom());
+        nes.step_frame();
+
+        // peeking doesn't clear vblank, reading does
+        assert!(nes.peek_cpu(0x2002) & 0x80 != 0);
+        assert!(nes.peek_cpu(0x2002) & 0x80 != 0);
+        assert!(nes.read_cpu(0x2002) & 0x80 != 0);
+        assert!(nes.peek_cpu(0x2002) & 0x80 == 0);
+
+        // ram, including mirrors
+        nes.poke_cpu(0x0010, 0xAB);
+        assert_eq!(nes.peek_cpu(0x0810), 0xAB);
+        // registers aren't written to
+        nes.poke_cpu(0x2000, 0x00);
+        nes.step_frame();
+        assert!(nes.peek_cpu(0x2002) & 0x80 != 0);
+        assert_eq!(nes.peek_cpu(0x8000), 0xA9);
+
+        nes.poke_ppu(0x3F01, 0x21);
+        assert_eq!(nes.peek_ppu(0x3F01), 0x21);
+        nes.poke_ppu(0x2000, 0x05);
+        assert_eq!(nes.peek_ppu(0x2000), 0x05);
+
+        nes.power_cycle();
+        assert_eq!(nes.peek_cpu(0x0010), 0);
+    }
+
+    #[test]
+    fn save_states() {
+        let mut nes = Nes::from_rom(&test_cart_rom());
+        nes.step_frame();
+        nes.poke_cpu(0x0010, 0x12);
+        nes.poke_ppu(0x3F00, 0x16);
+        let state = nes.save_state();
+
+        let run = |nes : &mut Nes| {
+            for _ in 0..10 {
+                nes.step_frame();
+            }
+            let samples = nes.audio_samples();
+            (nes.framebuffer().to_vec(), nes.cpu_registers(), nes.cycles(), samples)
+        };
+        nes.audio_samples();
+        let first = run(&mut nes);
+
+        nes.poke_cpu(0x0010, 0x34);
+        nes.poke_ppu(0x3F00, 0x0F);
+        nes.load_state(&state).unwrap();
+        assert_eq!(nes.peek_cpu(0x0010), 0x12);
+        assert_eq!(nes.peek_ppu(0x3F00), 0x16);
+        assert!(run(&mut nes) == first);
+
+        // a chunk from this version that's cut off is broken, and nothing
+        // loads, even from the chunks before it
+        nes.poke_cpu(0x0010, 0x34);
+        let before = nes.save_state();
+        let (header, chunks) = savestate::read_state(&state).unwrap();
+        let mut broken : Vec<_> = chunks.iter().map(|&(ref tag, data)| (tag, data.to_vec())).collect();
+        let last = broken.len() - 1;
+        broken[last].1.pop();
+        assert!(nes.load_state(&savestate::write_state(&header, &broken)).is_err());
+        assert!(nes.load_state(&savestate::write_state(&header, &[(b"CPU ", chunks[0].1[..100].to_vec())])).is_err());
+        assert_eq!(nes.save_state(), before);
+
+        let mut other_rom = test_cart_rom();
+        other_rom[0x10 + 0x200] = 0xEA;
+        assert!(Nes::from_rom(&other_rom).load_state(&state).is_err());
+
+        let config = Config { region : Some(Region::Pal), ..Config::default() };
+        let mut pal = Nes::new(test_cart(), &config, 44100);
+        assert!(pal.load_state(&state).is_err());
+
+        assert!(nes.load_state(&state[..state.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn older_save_states() {
+        let mut nes = Nes::from_rom(&test_cart_rom());
+        nes.step_frame();
+        nes.poke_cpu(0x0010, 0x12);
+        let state = nes.save_state();
+        nes.poke_cpu(0x0010, 0x34);
+
+        // version 1 states had less in them, and what's missing reads as
+        // zeros
+        let (header, chunks) = savestate::read_state(&state).unwrap();
+        let header = StateHeader { version : 1, ..header };
+        let old : Vec<_> = chunks.iter().map(|&(ref tag, data)| (tag, data[..data.len() / 2].to_vec())).collect();
+        nes.load_state(&savestate::write_state(&header, &old)).unwrap();
+        assert_eq!(nes.peek_cpu(0x0010), 0x12);
+    }
+
+    #[test]
+    fn movie_playback() {
+        use movie::{ Movie, MovieState };
+
+        let mut nes = Nes::from_rom(&test_cart_rom());
+        for _ in 0..5 {
+            nes.step_frame();
+        }
+        let mut recording = MovieState::record(&mut nes, "test", false).unwrap();
+        for frame in 0..20 {
+            nes.set_buttons(frame as u8 * 7);
+            recording.before_frame(&mut nes);
+            nes.step_frame();
+        }
+        let end = (nes.framebuffer().to_vec(), nes.cpu_registers(), nes.cycles());
+
+        // through the file format and back
+        let text = recording.movie().to_fm2();
+        let movie = Movie::from_fm2(&text).unwrap();
+        assert_eq!(movie.frames.len(), 20);
+
+        let mut other = Nes::from_rom(&test_cart_rom());
+        let mut playing = MovieState::play(&mut other, movie).unwrap();
+        while playing.before_frame(&mut other) {
+            other.step_frame();
+        }
+        assert_eq!(playing.frame(), 20);
+        assert_eq!(other.buttons(), 19 * 7);
+        assert!((other.framebuffer().to_vec(), other.cpu_registers(), other.cycles()) == end);
+
+        // movies get the controllers they were recorded with, and only play
+        // on their region
+        let movie = Movie { savestate : None, ..playing.movie().clone() };
+        other.plug(1, DeviceKind::Zapper);
+        other.plug(EXPANSION_PORT, DeviceKind::FamilyKeyboard);
+        MovieState::play(&mut other, movie.clone()).unwrap();
+        assert_eq!(other.port_device(1), DeviceKind::Controller);
+        assert_eq!(other.port_device(EXPANSION_PORT), DeviceKind::None);
+        assert!(MovieState::play(&mut other, Movie { pal : true, ..movie.clone() }).is_err());
+
+        // fm2 can't say a movie is for a Dendy, so they can't have movies
+        let config = Config { region : Some(Region::Dendy), ..Config::default() };
+        let mut dendy = Nes::new(test_cart(), &config, 44100);
+        assert!(MovieState::record(&mut dendy, "test", false).is_err());
+        assert!(MovieState::play(&mut dendy, Movie { pal : true, ..movie.clone() }).is_err());
+        assert!(MovieState::play(&mut dendy, movie).is_err());
+    }
+
+    #[test]
+    fn events() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let mut nes = Nes::from_rom(&test_cart_rom());
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&events);
+        nes.subscribe(move |event| log.borrow_mut().push(event));
+
+        nes.step_frame();
+        nes.step_frame();
+        nes.step_instruction();
+
+        let events = events.borrow();
+        let count = |kind| events.iter().filter(|&&event| event == kind).count();
+        assert_eq!(count(ConsoleEvent::FrameComplete), 2);
+        assert_eq!(count(ConsoleEvent::Nmi), 2);
+        assert_eq!(count(ConsoleEvent::Irq), 0);
+        // each nmi comes right after the frame that caused it
+        assert_eq!(events[0], ConsoleEvent::FrameComplete);
+        assert_eq!(events[1], ConsoleEvent::Nmi);
+    }
+}
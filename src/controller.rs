@@ -1,11 +1,14 @@
+use savestate::{ SaveState, StateWriter, StateReader };
+use ports::InputDevice;
+use ppu::PPU;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ButtonAction {
     Pressed,
     Released,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Button {
     A,
     B,
@@ -17,6 +20,23 @@ pub enum Button {
     Right,
 }
 
+impl Button {
+    // the button's bit in the controller's report, A is read first
+    pub fn mask(&self) -> u8 {
+        let bit_pos = match *self {
+            Button::A      => 0,
+            Button::B      => 1,
+            Button::Select => 2,
+            Button::Start  => 3,
+            Button::Up     => 4,
+            Button::Down   => 5,
+            Button::Left   => 6,
+            Button::Right  => 7,
+        };
+        1 << bit_pos
+    }
+}
+
 #[derive(Debug)]
 pub struct Controller {
     current_buttons : u8,
@@ -34,28 +54,25 @@ impl Controller {
     }
 
     pub fn update(&mut self, action : ButtonAction, button : Button) {
-        let bit_pos = match button {
-            Button::A      => 0,
-            Button::B      => 1,
-            Button::Select => 2,
-            Button::Start  => 3,
-            Button::Up     => 4,
-            Button::Down   => 5,
-            Button::Left   => 6,
-            Button::Right  => 7,
+        let buttons = match action {
+            ButtonAction::Pressed  => self.current_buttons | button.mask(),
+            ButtonAction::Released => self.current_buttons & !button.mask(),
         };
+        self.set_buttons(buttons);
+    }
 
-        match action {
-            ButtonAction::Pressed  => self.current_buttons |= 1 << bit_pos,
-            ButtonAction::Released => self.current_buttons &= !(1 << bit_pos),
-        };
+    // every button at once, as Button masks ored together
+    pub fn set_buttons(&mut self, buttons : u8) {
+        self.current_buttons = buttons;
 
         // update the internal shift register whenever the strobe is active
         if self.strobe {
             self.stored_buttons = self.current_buttons;
         }
+    }
 
-        // println!("{:?}", self);
+    pub fn buttons(&self) -> u8 {
+        self.current_buttons
     }
 
     pub fn read_next(&mut self) -> u8 {
@@ -66,6 +83,11 @@ impl Controller {
         ret
     }
 
+    // what read_next would return, without shifting
+    pub fn peek_next(&self) -> u8 {
+        self.stored_buttons & 1
+    }
+
     // buttons are continually loaded into shift register when strobe is set
     pub fn set_strobe(&mut self, val : u8) {
         self.strobe = (val & 1) == 1;
@@ -73,6 +95,44 @@ impl Controller {
     }
 }
 
+impl InputDevice for Controller {
+    fn write(&mut self, val : u8) {
+        self.set_strobe(val);
+    }
+
+    fn read(&mut self, _port : usize, _ppu : &PPU) -> u8 {
+        self.read_next()
+    }
+
+    fn peek(&self, _port : usize, _ppu : &PPU) -> u8 {
+        self.peek_next()
+    }
+
+    fn set_buttons(&mut self, controller : usize, buttons : u8) {
+        if controller == 0 {
+            Controller::set_buttons(self, buttons);
+        }
+    }
+
+    fn buttons(&self, controller : usize) -> u8 {
+        if controller == 0 { Controller::buttons(self) } else { 0 }
+    }
+}
+
+impl SaveState for Controller {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u8(self.current_buttons);
+        w.u8(self.stored_buttons);
+        w.bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.current_buttons = r.u8();
+        self.stored_buttons = r.u8();
+        self.strobe = r.bool();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
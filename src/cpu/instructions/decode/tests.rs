@@ -1,4 +1,5 @@
-    use ::{ Memory, CPU };
+    use ::Memory;
+    use cpu::CPU;
 
     #[test]
     fn cycles() {
@@ -4,8 +4,14 @@ mod instructions;
 
 use self::instructions::InstrArg;
 use cartridge::Cartridge;
-use super::{ ComponentRc, PPU, APU, Controller };
+use super::ComponentRc;
+use ppu::PPU;
+use apu::APU;
+use ports::{ Ports, DeviceKind, NUM_PORTS };
+use region::Region;
 use Memory;
+use savestate::{ SaveState, StateWriter, StateReader };
+use std::cell::Cell;
 
 const RAM_FIRST     : u16 = 0x0000;
 const RAM_LAST      : u16 = 0x1FFF;
@@ -20,7 +26,12 @@ const IO_FIRST : u16 = 0x4000;
 const IO_LAST  : u16 = 0x4017;
 
 const OAMDMA : u16 = 0x4014;
+const APU_STATUS : u16 = 0x4015;
 const CONTROLLER_1 : u16 = 0x4016;
+const CONTROLLER_2 : u16 = 0x4017;
+
+// https://wiki.nesdev.com/w/index.php/APU_DMC#Memory_reader
+const DMC_STALL_CYCLES : usize = 4;
 
 
 pub struct CPUMem {
@@ -35,8 +46,22 @@ pub struct CPUMem {
     cart : ComponentRc<Cartridge>,
     ppu  : ComponentRc<PPU>,
     apu  : ComponentRc<APU>,
-    controller : ComponentRc<Controller>,
+    ports : ComponentRc<Ports>,
     stalled_cycles : usize,
+
+    region : Region,
+    // leftover fraction of a ppu dot, for regions that don't run a
+    // whole number of dots per cpu cycle
+    dot_remainder : usize,
+
+    // https://wiki.nesdev.com/w/index.php/Open_bus_behavior
+    // the last value on the data bus, which is what reads from unmapped
+    // addresses return
+    open_bus : Cell<u8>,
+
+    // set when the current instruction reads a controller register,
+    // for emulating dmc dma corrupting controller reads
+    controller_read : Cell<Option<u16>>,
 }
 
 fn split_bytes(val : u16) -> (u8, u8) {
@@ -49,32 +74,96 @@ fn concat_bytes(high : u8, low : u8) -> u16 {
 
 impl Memory for CPUMem {
     fn loadb(&self, addr : u16) -> u8 {
-        match addr {
+        let open_bus = self.open_bus.get();
+
+        // cartridge sound chips have a few readable registers, all in
+        // cartridge space
+        if addr >= CART_FIRST && self.apu.borrow().expansion_maps_addr(addr) {
+            if let Some(val) = self.apu.borrow_mut().expansion_read(addr) {
+                self.open_bus.set(val);
+                return val;
+            }
+        }
+
+        let val = match addr {
             RAM_FIRST...RAM_LAST => self.ram[(addr % RAM_SIZE) as usize],
-            CART_FIRST...CART_LAST => self.cart.borrow().loadb(addr),
+            CART_FIRST...CART_LAST if self.cart.borrow().maps_cpu_addr(addr) =>
+                self.cart.borrow().loadb(addr),
             PPUREGS_FIRST...PPUREGS_LAST =>
                 self.ppu.borrow_mut().reg_read((addr % PPUREGS_SIZE) as u8),
-            OAMDMA => 0,
-            CONTROLLER_1 => self.controller.borrow_mut().read_next(),
-            IO_FIRST...IO_LAST => 0, //TODO
-            _ => panic!("couldn't map addr 0x{:04x} to CPU memory", addr),
-        }
+
+            // bit 5 isn't driven
+            APU_STATUS =>
+                self.apu.borrow_mut().read_status() | (open_bus & 0x20),
+
+            // only the low bits are driven by the controllers
+            CONTROLLER_1 | CONTROLLER_2 => {
+                self.controller_read.set(Some(addr));
+                let port = (addr - CONTROLLER_1) as usize;
+                self.ports.borrow_mut().read(port, &self.ppu.borrow()) | (open_bus & 0xE0)
+            },
+
+            // unmapped, including write only registers
+            _ => open_bus,
+        };
+
+        self.open_bus.set(val);
+        val
     }
     fn storeb(&mut self, addr : u16, val : u8) {
+        self.open_bus.set(val);
+
+        // sound chips share their addresses with the rest of the cartridge
+        if self.apu.borrow().expansion_maps_addr(addr) {
+            self.apu.borrow_mut().expansion_write(addr, val);
+        }
+
         match addr {
             RAM_FIRST...RAM_LAST => self.ram[(addr % RAM_SIZE) as usize] = val,
-            CART_FIRST...CART_LAST => self.cart.borrow_mut().storeb(addr, val),
+            CART_FIRST...CART_LAST if self.cart.borrow().maps_cpu_addr(addr) =>
+                self.cart.borrow_mut().storeb(addr, val),
             PPUREGS_FIRST...PPUREGS_LAST =>
                 self.ppu.borrow_mut().reg_write((addr % PPUREGS_SIZE) as u8, val),
             OAMDMA => self.oamdma(val),
-            CONTROLLER_1 => self.controller.borrow_mut().set_strobe(val),
-            IO_FIRST...IO_LAST => (), //TODO
-            _ => panic!("couldn't map addr 0x{:04x} to CPU memory", addr),
+            // $4017 writes go to the apu's frame counter
+            CONTROLLER_1 => self.ports.borrow_mut().write(val),
+            IO_FIRST...IO_LAST => self.apu.borrow_mut().reg_write(addr, val),
+            _ => (),
         }
     }
 }
 
 impl CPUMem {
+    // what loadb would return, without side effects like clearing flags
+    // or advancing the controller. reads from anything that isn't there
+    // return the open bus value
+    fn peekb(&self, addr : u16) -> u8 {
+        let open_bus = self.open_bus.get();
+
+        match addr {
+            RAM_FIRST...RAM_LAST => self.ram[(addr % RAM_SIZE) as usize],
+            CART_FIRST...CART_LAST if self.cart.borrow().maps_cpu_addr(addr) =>
+                self.cart.borrow().loadb(addr),
+            PPUREGS_FIRST...PPUREGS_LAST =>
+                self.ppu.borrow().peek_reg((addr % PPUREGS_SIZE) as u8),
+            APU_STATUS => self.apu.borrow().peek_status() | (open_bus & 0x20),
+            CONTROLLER_1 | CONTROLLER_2 =>
+                self.ports.borrow().peek((addr - CONTROLLER_1) as usize, &self.ppu.borrow())
+                    | (open_bus & 0xE0),
+            _ => open_bus,
+        }
+    }
+
+    // writes to ram and the cartridge, without touching any registers
+    fn pokeb(&mut self, addr : u16, val : u8) {
+        match addr {
+            RAM_FIRST...RAM_LAST => self.ram[(addr % RAM_SIZE) as usize] = val,
+            CART_FIRST...CART_LAST if self.cart.borrow().maps_cpu_addr(addr) =>
+                self.cart.borrow_mut().storeb(addr, val),
+            _ => (),
+        }
+    }
+
     fn oamdma(&mut self, page_num : u8) {
         let src_addr = (page_num as u16) << 8;
 
@@ -86,7 +175,45 @@ impl CPUMem {
         // 514 cycles for each oamdma
         // TODO odd/even timing?
         // TODO figure out a better solution for stalling oamdma cycles
-        self.stalled_cycles = 514;
+        self.stalled_cycles += 514;
+    }
+
+    fn tick_ppu(&mut self, cycles : usize) {
+        let (num, den) = self.region.dots_per_cpu_cycle();
+        let total = cycles * num + self.dot_remainder;
+
+        self.dot_remainder = total % den;
+        self.ppu.borrow_mut().step(total / den);
+    }
+
+    // runs the apu, doing sample fetches for the dmc as it asks for them.
+    // the last cycle is assumed to be when the current instruction
+    // accessed memory
+    fn tick_apu(&mut self, cycles : usize) {
+        for cycle in 0..cycles {
+            self.apu.borrow_mut().step(1);
+
+            let fetch_addr = self.apu.borrow().dmc_fetch_addr();
+            if let Some(addr) = fetch_addr {
+                // on the NTSC cpu, a fetch that lands on a controller read
+                // makes the cpu read the register again, so a bit gets lost
+                if cycle == cycles - 1 && self.region == Region::Ntsc {
+                    if let Some(controller_addr) = self.controller_read.get() {
+                        self.loadb(controller_addr);
+                    }
+                }
+
+                let val = self.loadb(addr);
+                self.apu.borrow_mut().dmc_fill(val);
+                self.stalled_cycles += DMC_STALL_CYCLES;
+            }
+        }
+
+        self.controller_read.set(None);
+    }
+
+    fn irq_asserted(&self) -> bool {
+        self.apu.borrow().irq_asserted()
     }
 
     fn fetch_stalled_cycles(&mut self) -> usize {
@@ -137,9 +264,23 @@ pub struct CPU {
     flags : CPUFlags,
     mem : CPUMem,
     interrupt_status : InterruptStatus,
+    // the interrupt taken at the start of the last step, if any
+    last_interrupt : InterruptStatus,
     cycles : usize,
 }
 
+// a copy of the cpu's registers, for looking at it from outside
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub a : u8,
+    pub x : u8,
+    pub y : u8,
+    pub sp : u8,
+    pub pc : u16,
+    // nv_bdizc, with b always 0
+    pub p : u8,
+}
+
 use std::fmt;
 impl fmt::Debug for CPU {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -156,8 +297,8 @@ impl fmt::Debug for CPU {
 
 const STACK_BEGIN : u16 = 0x100;
 
-#[derive(Debug, PartialEq)]
-enum InterruptStatus {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterruptStatus {
     None,
     NMI,
     IRQ,
@@ -166,12 +307,15 @@ enum InterruptStatus {
 
 impl CPU {
 
-    pub fn step_for_scanlines(&mut self, num_scanlines : usize) {
-        const CYCLES_PER_SCANLINE : usize = 114;
-        while self.cycles < CYCLES_PER_SCANLINE*num_scanlines {
-            self.cycles += self.step();
+    // runs until the ppu starts vblank, at which point a full frame
+    // has been rendered
+    pub fn step_frame(&mut self) {
+        loop {
+            self.step();
+            if self.mem.ppu.borrow_mut().take_frame_complete() {
+                break;
+            }
         }
-        self.cycles -= CYCLES_PER_SCANLINE*num_scanlines;
     }
 
     // does interrupt if requested
@@ -190,10 +334,14 @@ impl CPU {
                 self.reset();
                 cycles += INTERRUPT_CYCLES;
             },
-            InterruptStatus::IRQ => unimplemented!(),
+            InterruptStatus::IRQ => {
+                self.irq();
+                cycles += INTERRUPT_CYCLES;
+            },
             InterruptStatus::None => (),
         };
 
+        self.last_interrupt = self.interrupt_status;
         self.interrupt_status = InterruptStatus::None;
 
         let decode_result = instructions::decode::fetch_and_decode(self);
@@ -201,16 +349,123 @@ impl CPU {
 
         cycles += decode_result.num_cycles;
 
+        // register reads and writes mostly happen on the last cycle of an
+        // instruction, so catch the ppu up to that point first
+        self.mem.tick_ppu(cycles - 1);
+
         (op.instr)(self, op.arg);
 
+        self.mem.tick_ppu(1);
+        self.mem.tick_apu(cycles);
+
+        // the rest of the system keeps running while dma has the cpu stalled.
         // TODO test
-        cycles += self.mem.fetch_stalled_cycles();
+        let stalled_cycles = self.mem.fetch_stalled_cycles();
+        self.mem.tick_ppu(stalled_cycles);
+        self.mem.tick_apu(stalled_cycles);
+        cycles += stalled_cycles;
+
+        if self.interrupt_status == InterruptStatus::None {
+            if self.mem.ppu.borrow_mut().poll_nmi() {
+                self.interrupt_status = InterruptStatus::NMI;
+            }
+            // IRQ is level triggered, so it keeps getting taken for as
+            // long as it's asserted and not masked
+            else if self.mem.irq_asserted() && !self.flags.i {
+                self.interrupt_status = InterruptStatus::IRQ;
+            }
+        }
 
+        self.cycles += cycles;
         cycles
     }
 
     pub fn get_pc(&self) -> u16 { self.pc }
 
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a : self.a,
+            x : self.x,
+            y : self.y,
+            sp : self.sp,
+            pc : self.pc,
+            p : self.flags.to_byte(),
+        }
+    }
+
+    pub fn set_registers(&mut self, regs : Registers) {
+        self.a = regs.a;
+        self.x = regs.x;
+        self.y = regs.y;
+        self.sp = regs.sp;
+        self.pc = regs.pc;
+        self.flags = CPUFlags::from_byte(regs.p);
+    }
+
+    // cycles run since power on
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    // the interrupt the last step started by taking
+    pub fn last_interrupt(&self) -> InterruptStatus {
+        self.last_interrupt
+    }
+
+    // like read_mem and write_mem, but without side effects on the rest of
+    // the system, see CPUMem::peekb and CPUMem::pokeb
+    pub fn peek_mem(&self, addr : u16) -> u8 {
+        self.mem.peekb(addr)
+    }
+
+    pub fn poke_mem(&mut self, addr : u16, val : u8) {
+        self.mem.pokeb(addr, val);
+    }
+
+    // direct access to the cpu's address space, for setting up the
+    // system from outside of a program
+    pub fn read_mem(&self, addr : u16) -> u8 {
+        self.mem.loadb(addr)
+    }
+
+    pub fn write_mem(&mut self, addr : u16, val : u8) {
+        self.mem.storeb(addr, val);
+    }
+
+    // calls the subroutine at addr like JSR would, for running code that
+    // doesn't have a program around it (like the routines in nsf files).
+    // the call is done once the pc gets to return_addr
+    pub fn call(&mut self, addr : u16, a : u8, x : u8, return_addr : u16) {
+        // RTS adds 1 to the address it pops
+        let (ret_high, ret_low) = split_bytes(return_addr.wrapping_sub(1));
+        self.push(ret_high);
+        self.push(ret_low);
+
+        self.a = a;
+        self.x = x;
+        self.y = 0;
+        self.pc = addr;
+        self.flags.i = true;
+    }
+
+    // lets the rest of the system run while the cpu does nothing
+    pub fn idle(&mut self, cycles : usize) {
+        self.mem.tick_ppu(cycles);
+        self.mem.tick_apu(cycles);
+
+        // dma stalls don't matter when there's nothing to stall
+        self.mem.fetch_stalled_cycles();
+        self.cycles += cycles;
+    }
+
+    // also sets the region of the ppu this cpu is connected to
+    pub fn set_region(&mut self, region : Region) {
+        self.mem.region = region;
+        self.mem.dot_remainder = 0;
+        self.mem.ppu.borrow_mut().set_region(region);
+        self.mem.apu.borrow_mut().set_region(region);
+    }
+
     fn push(&mut self, val : u8) {
         self.mem.storeb(STACK_BEGIN + self.sp as u16, val);
         self.sp = self.sp.wrapping_sub(1);
@@ -221,8 +476,8 @@ impl CPU {
         self.mem.loadb(STACK_BEGIN + self.sp as u16)
     }
 
+    // reset takes priority over any other pending interrupt
     pub fn send_reset(&mut self) {
-        debug_assert_eq!(self.interrupt_status, InterruptStatus::None);
         self.interrupt_status = InterruptStatus::Reset;
     }
 
@@ -231,6 +486,7 @@ impl CPU {
         let dest_high = self.mem.loadb(0xFFFD);
         let dest_low = self.mem.loadb(0xFFFC);
         self.pc = concat_bytes(dest_high, dest_low);
+        self.flags.i = true;
     }
 
     pub fn send_nmi(&mut self) {
@@ -254,6 +510,22 @@ impl CPU {
         self.flags.i = true;
     }
 
+    fn irq(&mut self) {
+        let (ret_high, ret_low) = split_bytes(self.pc);
+        self.push(ret_high);
+        self.push(ret_low);
+
+        // b flag is clear, like nmi
+        let status = self.flags.to_byte();
+        self.push(status);
+
+        let dest_high = self.mem.loadb(0xFFFF);
+        let dest_low = self.mem.loadb(0xFFFE);
+        self.pc = concat_bytes(dest_high, dest_low);
+
+        self.flags.i = true;
+    }
+
     fn pc_getdb(&mut self) -> u16  {
         let ret = self.mem.loadb(self.pc) as u16 +
             ((self.mem.loadb(self.pc + 1) as u16) << 8);
@@ -283,15 +555,19 @@ impl CPU {
         let cart = Cartridge::test_ref();
         let ppu  = ComponentRc::new(PPU::new(cart.new_ref()));
         let apu  = ComponentRc::new(APU::new());
-        let controller = ComponentRc::new(Controller::new());
+        let ports = ComponentRc::new(Ports::new([DeviceKind::Controller; NUM_PORTS]));
 
-        CPU::new(cart, ppu, apu, controller)
+        CPU::new(cart, ppu, apu, ports)
     }
 
     pub fn new(cart : ComponentRc<Cartridge>,
                ppu  : ComponentRc<PPU>,
                apu  : ComponentRc<APU>,
-               controller : ComponentRc<Controller>) -> CPU {
+               ports : ComponentRc<Ports>) -> CPU {
+
+        for &chip in cart.borrow().expansion_audio() {
+            apu.borrow_mut().add_expansion(chip);
+        }
 
         CPU {
             a : 0,
@@ -313,10 +589,77 @@ impl CPU {
                 cart : cart,
                 ppu : ppu,
                 apu : apu,
-                controller : controller,
+                ports : ports,
                 stalled_cycles : 0,
+                region : Region::Ntsc,
+                dot_remainder : 0,
+                open_bus : Cell::new(0),
+                controller_read : Cell::new(None),
             },
             interrupt_status : InterruptStatus::None,
+            last_interrupt : InterruptStatus::None,
         }
     }
 }
+
+impl InterruptStatus {
+    fn id(&self) -> u8 {
+        match *self {
+            InterruptStatus::None  => 0,
+            InterruptStatus::NMI   => 1,
+            InterruptStatus::IRQ   => 2,
+            InterruptStatus::Reset => 3,
+        }
+    }
+
+    fn from_id(id : u8) -> InterruptStatus {
+        match id {
+            1 => InterruptStatus::NMI,
+            2 => InterruptStatus::IRQ,
+            3 => InterruptStatus::Reset,
+            _ => InterruptStatus::None,
+        }
+    }
+}
+
+// the cpu and its memory, but not the components connected to it
+impl SaveState for CPU {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u8(self.a);
+        w.u8(self.x);
+        w.u8(self.y);
+        w.u8(self.sp);
+        w.u16(self.pc);
+        w.u8(self.flags.to_byte());
+        w.u8(self.interrupt_status.id());
+        w.u8(self.last_interrupt.id());
+        w.usize(self.cycles);
+
+        w.bytes(&self.mem.ram);
+        w.usize(self.mem.stalled_cycles);
+        w.usize(self.mem.dot_remainder);
+        w.u8(self.mem.open_bus.get());
+        w.bool(self.mem.controller_read.get().is_some());
+        w.u16(self.mem.controller_read.get().unwrap_or(0));
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.a = r.u8();
+        self.x = r.u8();
+        self.y = r.u8();
+        self.sp = r.u8();
+        self.pc = r.u16();
+        self.flags = CPUFlags::from_byte(r.u8());
+        self.interrupt_status = InterruptStatus::from_id(r.u8());
+        self.last_interrupt = InterruptStatus::from_id(r.u8());
+        self.cycles = r.usize();
+
+        r.bytes(&mut self.mem.ram);
+        self.mem.stalled_cycles = r.usize();
+        self.mem.dot_remainder = r.usize();
+        self.mem.open_bus.set(r.u8());
+        let controller_read = r.bool();
+        let addr = r.u16();
+        self.mem.controller_read.set(if controller_read { Some(addr) } else { None });
+    }
+}
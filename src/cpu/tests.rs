@@ -95,3 +95,140 @@ fn mapping() {
     c.mem.storeb(0x1FFF, 5);
     assert_eq!(c.mem.loadb(0x07FF), 0x5);
 }
+
+#[test]
+fn nmi_from_ppu() {
+    let mut c = CPU::test();
+
+    c.mem.storeb(0xFFFA, 0x03);
+    c.mem.storeb(0xFFFB, 0x80);
+
+    // JMP $8000, then a NOP for the NMI handler
+    c.mem.storeb(0x8000, 0x4C);
+    c.mem.storeb(0x8001, 0x00);
+    c.mem.storeb(0x8002, 0x80);
+    c.mem.storeb(0x8003, 0xEA);
+
+    // enable NMI, then run until vblank
+    c.mem.storeb(0x2000, 0x80);
+    c.step_frame();
+
+    c.step();
+    assert_eq!(c.pc, 0x8004);
+    assert_eq!(c.sp, 0xFC);
+    assert_eq!(c.flags.i, true);
+}
+
+#[test]
+fn open_bus() {
+    let mut c = CPU::test();
+
+    // unmapped reads return the last value on the bus
+    c.mem.storeb(0x0010, 0x5A);
+    assert_eq!(c.mem.loadb(0x0010), 0x5A);
+    assert_eq!(c.mem.loadb(0x4018), 0x5A);
+    assert_eq!(c.mem.loadb(0x5000), 0x5A);
+
+    c.mem.storeb(0x4000, 0x7E);
+    assert_eq!(c.mem.loadb(0x4014), 0x7E);
+
+    // the controllers only drive the low bits
+    c.mem.storeb(0x0010, 0x40);
+    c.mem.loadb(0x0010);
+    assert_eq!(c.mem.loadb(0x4016), 0x40);
+    c.mem.loadb(0x0010);
+    assert_eq!(c.mem.loadb(0x4017), 0x40);
+}
+
+#[test]
+fn pal_dot_ratio() {
+    use region::Region;
+    let mut c = CPU::test();
+    c.set_region(Region::Pal);
+
+    // 3.2 dots per cycle, so 16 dots every 5 cycles
+    for i in 0..5 {
+        assert_eq!(c.mem.dot_remainder, (i * 16) % 5);
+        c.mem.tick_ppu(1);
+    }
+    assert_eq!(c.mem.dot_remainder, 0);
+
+    c.mem.tick_ppu(2);
+    assert_eq!(c.mem.dot_remainder, 2);
+}
+
+#[test]
+fn irq_from_apu() {
+    let mut c = CPU::test();
+
+    c.mem.storeb(0xFFFE, 0x04);
+    c.mem.storeb(0xFFFF, 0x80);
+
+    // CLI, then JMP $8001, with a NOP for the IRQ handler
+    c.mem.storeb(0x8000, 0x58);
+    c.mem.storeb(0x8001, 0x4C);
+    c.mem.storeb(0x8002, 0x01);
+    c.mem.storeb(0x8003, 0x80);
+    c.mem.storeb(0x8004, 0xEA);
+
+    // run until the frame counter interrupt is taken
+    let mut cycles = 0;
+    while c.sp == 0xFF {
+        cycles += c.step();
+        assert!(cycles < 30000);
+    }
+
+    assert_eq!(c.pc, 0x8005);
+    assert_eq!(c.flags.i, true);
+    assert_eq!(c.mem.loadb(0x4015) & 0x40, 0x40);
+}
+
+#[test]
+fn dmc_dma() {
+    let mut c = CPU::test();
+
+    // fill the sample area and point the dmc at it
+    c.mem.storeb(0xC000, 0xAB);
+    c.mem.storeb(0x4013, 0x00);
+    c.mem.storeb(0x4015, 0x10);
+
+    // the fetch takes 4 extra cycles
+    c.mem.tick_apu(1);
+    assert_eq!(c.mem.fetch_stalled_cycles(), 4);
+    assert_eq!(c.mem.apu.borrow().dmc_fetch_addr(), None);
+}
+
+#[test]
+fn dmc_dma_controller_conflict() {
+    use controller::ButtonAction::Pressed;
+    use controller::Button::*;
+
+    let mut c = CPU::test();
+    c.mem.ports.borrow_mut().update(0, Pressed, A);
+    c.mem.ports.borrow_mut().update(0, Pressed, Select);
+
+    // strobe, then read A while a dmc fetch happens
+    c.mem.storeb(0x4016, 1);
+    c.mem.storeb(0x4016, 0);
+    c.mem.storeb(0x4013, 0x00);
+    c.mem.storeb(0x4015, 0x10);
+    assert_eq!(c.mem.loadb(0x4016) & 1, 1);
+    c.mem.tick_apu(1);
+
+    // B got read twice, so the next read is select
+    assert_eq!(c.mem.loadb(0x4016) & 1, 1);
+
+    // PAL doesn't have the bug
+    use region::Region;
+    let mut c = CPU::test();
+    c.set_region(Region::Pal);
+    c.mem.ports.borrow_mut().update(0, Pressed, A);
+    c.mem.ports.borrow_mut().update(0, Pressed, Select);
+    c.mem.storeb(0x4016, 1);
+    c.mem.storeb(0x4016, 0);
+    c.mem.storeb(0x4013, 0x00);
+    c.mem.storeb(0x4015, 0x10);
+    assert_eq!(c.mem.loadb(0x4016) & 1, 1);
+    c.mem.tick_apu(1);
+    assert_eq!(c.mem.loadb(0x4016) & 1, 0);
+}
@@ -0,0 +1,171 @@
+// https://wiki.nesdev.com/w/index.php/Family_BASIC_Keyboard
+// The Family BASIC keyboard, in the expansion port. Its 72 keys are in a
+// matrix of 9 rows, each split into two columns of 4 keys, and games scan
+// it through $4016 writes:
+//
+//   D0 : back to row 0
+//   D1 : the column, going from 1 to 0 moves on to the next row
+//   D2 : 1 to enable the keyboard
+//
+// Reads of $4017 give the column's keys in D1-D4, 0 when pressed.
+
+use controller::ButtonAction;
+use ports::InputDevice;
+use ppu::PPU;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyboardKey {
+    F1, F2, F3, F4, F5, F6, F7, F8,
+    Num0, Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9,
+    A, B, C, D, E, F, G, H, I, J, K, L, M,
+    N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Minus, Caret, Yen, Stop, At, LeftBracket, RightBracket, Return,
+    Semicolon, Colon, Underscore, Comma, Period, Slash,
+    Esc, Ctr, LeftShift, RightShift, Grph, Kana, Space,
+    ClrHome, Ins, Del, Up, Down, Left, Right,
+}
+
+const NUM_ROWS : usize = 9;
+
+// each row's keys, column 0 and then column 1, from D1 up
+const MATRIX : [[KeyboardKey; 8]; NUM_ROWS] = {
+    use self::KeyboardKey::*;
+    [
+        [RightBracket, LeftBracket, Return, F8, Stop, Yen, RightShift, Kana],
+        [Semicolon, Colon, At, F7, Caret, Minus, Slash, Underscore],
+        [K, L, O, F6, Num0, P, Comma, Period],
+        [J, U, I, F5, Num8, Num9, N, M],
+        [H, G, Y, F4, Num6, Num7, V, B],
+        [D, R, T, F3, Num4, Num5, C, F],
+        [A, S, W, F2, Num3, E, Z, X],
+        [Ctr, Q, Esc, F1, Num2, Num1, Grph, LeftShift],
+        [Left, Right, Up, ClrHome, Ins, Del, Space, Down],
+    ]
+};
+
+#[derive(Debug)]
+pub struct FamilyKeyboard {
+    // the keys held in each row, a bit per key in MATRIX order
+    keys : [u8; NUM_ROWS],
+    row : usize,
+    column : u8,
+    enabled : bool,
+}
+
+impl FamilyKeyboard {
+    pub fn new() -> FamilyKeyboard {
+        FamilyKeyboard {
+            keys : [0; NUM_ROWS],
+            row : 0,
+            column : 0,
+            enabled : false,
+        }
+    }
+
+    fn bits(&self, port : usize) -> u8 {
+        if port != 1 || !self.enabled {
+            return 0;
+        }
+        // past the last row nothing is pressed
+        let held = self.keys.get(self.row).map_or(0, |keys| keys >> (self.column * 4));
+        (!held & 0x0F) << 1
+    }
+}
+
+impl InputDevice for FamilyKeyboard {
+    fn write(&mut self, val : u8) {
+        self.enabled = val & 0x04 != 0;
+        if !self.enabled {
+            return;
+        }
+
+        let column = (val >> 1) & 1;
+        if self.column == 1 && column == 0 {
+            self.row += 1;
+        }
+        self.column = column;
+        if val & 0x01 != 0 {
+            self.row = 0;
+        }
+    }
+
+    fn read(&mut self, port : usize, _ppu : &PPU) -> u8 {
+        self.bits(port)
+    }
+
+    fn peek(&self, port : usize, _ppu : &PPU) -> u8 {
+        self.bits(port)
+    }
+
+    fn update_key(&mut self, action : ButtonAction, key : KeyboardKey) {
+        for (row, keys) in MATRIX.iter().enumerate() {
+            if let Some(i) = keys.iter().position(|&k| k == key) {
+                match action {
+                    ButtonAction::Pressed  => self.keys[row] |= 1 << i,
+                    ButtonAction::Released => self.keys[row] &= !(1 << i),
+                }
+            }
+        }
+    }
+}
+
+impl SaveState for FamilyKeyboard {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bytes(&self.keys);
+        w.u8(self.row as u8);
+        w.u8(self.column);
+        w.bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        r.bytes(&mut self.keys);
+        self.row = r.u8() as usize;
+        self.column = r.u8();
+        self.enabled = r.bool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::KeyboardKey::*;
+
+    // the keys held in each half row, the way Family BASIC scans them
+    fn scan(keyboard : &mut FamilyKeyboard) -> Vec<u8> {
+        let ppu = PPU::test();
+        let mut halves = Vec::new();
+        keyboard.write(0x05);
+        for _ in 0..NUM_ROWS {
+            keyboard.write(0x04);
+            halves.push(!(keyboard.read(1, &ppu) >> 1) & 0x0F);
+            keyboard.write(0x06);
+            halves.push(!(keyboard.read(1, &ppu) >> 1) & 0x0F);
+        }
+        halves
+    }
+
+    #[test]
+    fn matrix() {
+        let mut keyboard = FamilyKeyboard::new();
+        keyboard.update_key(ButtonAction::Pressed, Return);
+        keyboard.update_key(ButtonAction::Pressed, Space);
+        keyboard.update_key(ButtonAction::Pressed, A);
+        keyboard.update_key(ButtonAction::Pressed, X);
+        keyboard.update_key(ButtonAction::Released, A);
+
+        let mut expected = vec![0; NUM_ROWS * 2];
+        expected[0] = 0x04;
+        expected[13] = 0x08;
+        expected[17] = 0x04;
+        assert_eq!(scan(&mut keyboard), expected);
+
+        // past the last row, and with the keyboard off
+        let ppu = PPU::test();
+        keyboard.write(0x04);
+        assert_eq!(keyboard.read(1, &ppu), 0x1E);
+        keyboard.write(0x00);
+        assert_eq!(keyboard.read(1, &ppu), 0x00);
+        assert_eq!(keyboard.read(0, &ppu), 0x00);
+    }
+}
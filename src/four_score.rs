@@ -0,0 +1,134 @@
+// https://wiki.nesdev.com/w/index.php/Four_Score
+// The Four Score and NES Satellite adapters, which plug into both ports and
+// take four controllers. Each port reads 24 bits after a strobe: the 8 of
+// the controller for player 1 or 2, the 8 for player 3 or 4, and then a
+// signature that tells games the adapter is there. Later reads are 1.
+
+use controller::Controller;
+use ports::InputDevice;
+use ppu::PPU;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+// the signature of each port, read lowest bit first
+const SIGNATURES : [u8; 2] = [0x08, 0x04];
+
+const REPORT_BITS : u8 = 24;
+
+// the half of the adapter that's plugged into one port
+#[derive(Debug)]
+pub struct FourScore {
+    // the controller for player 1 or 2, then the one for player 3 or 4
+    controllers : [Controller; 2],
+    signature : u8,
+    // bits read since the strobe
+    reads : u8,
+    strobe : bool,
+}
+
+impl FourScore {
+    pub fn new(port : usize) -> FourScore {
+        FourScore {
+            controllers : [Controller::new(), Controller::new()],
+            signature : SIGNATURES[port],
+            reads : 0,
+            strobe : false,
+        }
+    }
+
+    fn bit(&self) -> u8 {
+        match self.reads {
+            0..=7   => self.controllers[0].peek_next(),
+            8..=15  => self.controllers[1].peek_next(),
+            16..=23 => (self.signature >> (self.reads - 16)) & 1,
+            _       => 1,
+        }
+    }
+}
+
+impl InputDevice for FourScore {
+    fn write(&mut self, val : u8) {
+        for controller in &mut self.controllers {
+            controller.set_strobe(val);
+        }
+        self.strobe = (val & 1) == 1;
+        self.reads = 0;
+    }
+
+    fn read(&mut self, _port : usize, _ppu : &PPU) -> u8 {
+        let bit = self.bit();
+        // while strobed, the first controller keeps reporting A
+        if !self.strobe && self.reads < REPORT_BITS {
+            if self.reads < 16 {
+                self.controllers[self.reads as usize / 8].read_next();
+            }
+            self.reads += 1;
+        }
+        bit
+    }
+
+    fn peek(&self, _port : usize, _ppu : &PPU) -> u8 {
+        self.bit()
+    }
+
+    fn set_buttons(&mut self, controller : usize, buttons : u8) {
+        self.controllers[controller].set_buttons(buttons);
+    }
+
+    fn buttons(&self, controller : usize) -> u8 {
+        self.controllers[controller].buttons()
+    }
+}
+
+impl SaveState for FourScore {
+    fn save_state(&self, w : &mut StateWriter) {
+        for controller in &self.controllers {
+            controller.save_state(w);
+        }
+        w.u8(self.reads);
+        w.bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        for controller in &mut self.controllers {
+            controller.load_state(r);
+        }
+        self.reads = r.u8();
+        self.strobe = r.bool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_bits(four_score : &mut FourScore, n : usize) -> Vec<u8> {
+        let ppu = PPU::test();
+        (0..n).map(|_| four_score.read(0, &ppu)).collect()
+    }
+
+    #[test]
+    fn report() {
+        let mut port1 = FourScore::new(0);
+        port1.set_buttons(0, 0x81);
+        port1.set_buttons(1, 0x02);
+        port1.write(1);
+        port1.write(0);
+        assert_eq!(read_bits(&mut port1, 26), vec![
+            1, 0, 0, 0, 0, 0, 0, 1,
+            0, 1, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 1, 0, 0, 0, 0,
+            1, 1,
+        ]);
+
+        let mut port2 = FourScore::new(1);
+        port2.write(1);
+        port2.write(0);
+        assert_eq!(&read_bits(&mut port2, 24)[16..], &[0, 0, 1, 0, 0, 0, 0, 0]);
+
+        // strobing starts over
+        port1.write(1);
+        assert_eq!(read_bits(&mut port1, 3), vec![1, 1, 1]);
+        port1.write(0);
+        assert_eq!(read_bits(&mut port1, 2), vec![1, 0]);
+    }
+}
@@ -0,0 +1,58 @@
+// Whatever shows the console to the user: it puts the frames and audio
+// somewhere, turns its input into events and keeps the emulation running
+// at the right speed. The SDL window is one, see sdl_frontend.rs
+
+use controller::{ ButtonAction, Button };
+use apu::Channel;
+use family_keyboard::KeyboardKey;
+
+#[derive(Debug)]
+pub enum EmulatorEvent {
+    // player is counted from 0, players 3 and 4 need a Four Score
+    ControllerEvent { player : usize, action : ButtonAction, button : Button },
+    // the mouse, for light guns. The pointer is in screen pixels, and None
+    // when it's outside the picture
+    Pointer(Option<(i32, i32)>),
+    Trigger(bool),
+    // how far the mouse moved, for paddles and trackballs
+    MouseMotion(i32, i32),
+    // every key, for the Family BASIC keyboard. Keys also send the other
+    // events they're mapped to
+    KeyboardEvent { action : ButtonAction, key : KeyboardKey },
+    ToggleRecording,
+    ToggleMute(Channel),
+    ToggleSolo(Channel),
+    SaveState,
+    LoadState,
+    SelectSlot(u8),
+    // play backwards for as long as rewinding is held
+    StartRewind,
+    StopRewind,
+    ToggleMovieRecording,
+    Continue,
+    Exit,
+}
+
+pub trait Frontend {
+    // the rate the console should make audio at
+    fn sample_rate(&self) -> u32;
+
+    // pixels are SCREEN_WIDTH x SCREEN_HEIGHT in BGR24, see Nes::framebuffer
+    fn present_frame(&mut self, pixels : &[u8]);
+
+    fn queue_audio(&mut self, samples : &[f32]);
+
+    // everything that happened since the last call
+    fn events(&mut self) -> Vec<EmulatorEvent>;
+
+    fn set_title(&mut self, _title : &str) {}
+
+    // returns when the next frame is due, given the console's frame rate
+    fn wait_for_frame(&mut self, frame_rate : f64);
+
+    // how much to scale the console's sample rate by to keep the audio
+    // queue on target, see APU::set_rate_adjustment
+    fn rate_adjustment(&self) -> f64 {
+        1.0
+    }
+}
@@ -0,0 +1,361 @@
+// https://wiki.libsdl.org/CategoryGameController
+// Gamepads, as SDL's game controller API sees them: the buttons of an Xbox
+// style pad, whatever the pad really is. Each pad is for the first player
+// who doesn't have one yet when it's connected, and its buttons and left
+// stick are turned into that player's controller events.
+
+use controller::{ ButtonAction, Button };
+use frontend::EmulatorEvent;
+use ports::NUM_PLAYERS;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PadButton {
+    A,
+    B,
+    X,
+    Y,
+    Back,
+    Guide,
+    Start,
+    LeftStick,
+    RightStick,
+    LeftShoulder,
+    RightShoulder,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+impl PadButton {
+    // the names SDL's mapping strings use
+    pub fn from_name(name : &str) -> Option<PadButton> {
+        match name {
+            "a"             => Some(PadButton::A),
+            "b"             => Some(PadButton::B),
+            "x"             => Some(PadButton::X),
+            "y"             => Some(PadButton::Y),
+            "back"          => Some(PadButton::Back),
+            "guide"         => Some(PadButton::Guide),
+            "start"         => Some(PadButton::Start),
+            "leftstick"     => Some(PadButton::LeftStick),
+            "rightstick"    => Some(PadButton::RightStick),
+            "leftshoulder"  => Some(PadButton::LeftShoulder),
+            "rightshoulder" => Some(PadButton::RightShoulder),
+            "dpup"          => Some(PadButton::DPadUp),
+            "dpdown"        => Some(PadButton::DPadDown),
+            "dpleft"        => Some(PadButton::DPadLeft),
+            "dpright"       => Some(PadButton::DPadRight),
+            _               => None,
+        }
+    }
+}
+
+fn nes_button(name : &str) -> Option<Button> {
+    match name {
+        "a"      => Some(Button::A),
+        "b"      => Some(Button::B),
+        "select" => Some(Button::Select),
+        "start"  => Some(Button::Start),
+        "up"     => Some(Button::Up),
+        "down"   => Some(Button::Down),
+        "left"   => Some(Button::Left),
+        "right"  => Some(Button::Right),
+        _        => None,
+    }
+}
+
+// which pad buttons press which NES buttons
+#[derive(Debug, Clone, PartialEq)]
+pub struct ButtonMap {
+    bindings : Vec<(PadButton, Button)>,
+}
+
+impl Default for ButtonMap {
+    // the pad's bottom and right buttons are B and A, like on the NES
+    fn default() -> ButtonMap {
+        ButtonMap {
+            bindings : vec![
+                (PadButton::A,         Button::B),
+                (PadButton::X,         Button::B),
+                (PadButton::B,         Button::A),
+                (PadButton::Y,         Button::A),
+                (PadButton::Back,      Button::Select),
+                (PadButton::Start,     Button::Start),
+                (PadButton::DPadUp,    Button::Up),
+                (PadButton::DPadDown,  Button::Down),
+                (PadButton::DPadLeft,  Button::Left),
+                (PadButton::DPadRight, Button::Right),
+            ],
+        }
+    }
+}
+
+impl ButtonMap {
+    // rebinds the NES buttons in a list like "a=b, select=back+guide". The
+    // pad buttons named are only for those, the rest stay as they were
+    pub fn remap(&mut self, text : &str) -> Result<(), String> {
+        for binding in text.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+            let mut parts = binding.splitn(2, '=');
+            let button = parts.next().and_then(|name| nes_button(name.trim()))
+                .ok_or_else(|| format!("invalid gamepad binding: {}", binding))?;
+            let pad_buttons = parts.next()
+                .ok_or_else(|| format!("invalid gamepad binding: {}", binding))?;
+
+            let mut new_bindings = Vec::new();
+            for name in pad_buttons.split('+').map(str::trim).filter(|n| !n.is_empty()) {
+                let pad_button = PadButton::from_name(name)
+                    .ok_or_else(|| format!("invalid gamepad button: {}", name))?;
+                new_bindings.push((pad_button, button));
+            }
+
+            self.bindings.retain(|&(p, b)| b != button && new_bindings.iter().all(|&(n, _)| n != p));
+            self.bindings.extend(new_bindings);
+        }
+        Ok(())
+    }
+
+    fn buttons(&self, pad_button : PadButton) -> Vec<Button> {
+        self.bindings.iter()
+            .filter(|&&(p, _)| p == pad_button)
+            .map(|&(_, b)| b)
+            .collect()
+    }
+}
+
+// what's holding a NES button down
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Button(PadButton),
+    // the left stick's x (0) or y (1) axis
+    Stick(usize),
+}
+
+struct Pad {
+    id : i32,
+    player : usize,
+    map : ButtonMap,
+    // the direction the left stick is held in on each axis
+    stick : [Option<Button>; 2],
+    // every NES button held, with what's holding it. Several buttons can
+    // hold the same one, like the d-pad and the stick
+    held : Vec<(Source, Button)>,
+}
+
+impl Pad {
+    // a NES button is pressed by the first source to hold it, and only
+    // released when the last one lets go
+    fn update(&mut self, source : Source, action : ButtonAction, button : Button,
+              events : &mut Vec<EmulatorEvent>) {
+        let was_held = self.held.iter().any(|&(_, b)| b == button);
+        match action {
+            ButtonAction::Pressed => if !self.held.contains(&(source, button)) {
+                self.held.push((source, button));
+            },
+            ButtonAction::Released => self.held.retain(|&h| h != (source, button)),
+        }
+        let is_held = self.held.iter().any(|&(_, b)| b == button);
+
+        if was_held != is_held {
+            events.push(event(self.player, action, button));
+        }
+    }
+}
+
+pub struct Gamepads {
+    // button maps for pads with a name, and the one for the rest
+    maps : Vec<(Option<String>, ButtonMap)>,
+    // how far the stick has to be pushed to count, out of 1
+    deadzone : f32,
+    pads : Vec<Pad>,
+}
+
+fn event(player : usize, action : ButtonAction, button : Button) -> EmulatorEvent {
+    EmulatorEvent::ControllerEvent { player : player, action : action, button : button }
+}
+
+impl Gamepads {
+    pub fn new(maps : &[(Option<String>, ButtonMap)], deadzone : f32) -> Gamepads {
+        Gamepads {
+            maps : maps.to_vec(),
+            deadzone : deadzone,
+            pads : Vec::new(),
+        }
+    }
+
+    // a newly connected pad. Returns the player it's for, or None when
+    // every player has one
+    pub fn connect(&mut self, id : i32, name : &str) -> Option<usize> {
+        let player = (0..NUM_PLAYERS).find(|&p| self.pads.iter().all(|pad| pad.player != p))?;
+        let map = self.maps.iter().find(|(n, _)| n.as_deref() == Some(name))
+            .or_else(|| self.maps.iter().find(|(n, _)| n.is_none()))
+            .map_or_else(ButtonMap::default, |(_, map)| map.clone());
+
+        self.pads.push(Pad {
+            id : id,
+            player : player,
+            map : map,
+            stick : [None; 2],
+            held : Vec::new(),
+        });
+        Some(player)
+    }
+
+    // lets go of everything the pad was holding
+    pub fn disconnect(&mut self, id : i32) -> Vec<EmulatorEvent> {
+        match self.pads.iter().position(|pad| pad.id == id) {
+            Some(i) => {
+                let mut pad = self.pads.remove(i);
+                let mut events = Vec::new();
+                while let Some(&(source, button)) = pad.held.last() {
+                    pad.update(source, ButtonAction::Released, button, &mut events);
+                }
+                events
+            },
+            None => Vec::new(),
+        }
+    }
+
+    pub fn button(&mut self, id : i32, action : ButtonAction, pad_button : PadButton) -> Vec<EmulatorEvent> {
+        let mut events = Vec::new();
+        if let Some(pad) = self.pads.iter_mut().find(|pad| pad.id == id) {
+            for button in pad.map.buttons(pad_button) {
+                pad.update(Source::Button(pad_button), action, button, &mut events);
+            }
+        }
+        events
+    }
+
+    // the left stick moving on the x (0) or y (1) axis, where it works as
+    // the d-pad once it's out of the deadzone
+    pub fn stick(&mut self, id : i32, axis : usize, value : i16) -> Vec<EmulatorEvent> {
+        let deadzone = (self.deadzone * i16::MAX as f32) as i32;
+        let pad = match self.pads.iter_mut().find(|pad| pad.id == id) {
+            Some(pad) => pad,
+            None => return Vec::new(),
+        };
+
+        let (negative, positive) = if axis == 0 {
+            (Button::Left, Button::Right)
+        } else {
+            (Button::Up, Button::Down)
+        };
+        let held = match value as i32 {
+            v if v < -deadzone => Some(negative),
+            v if v > deadzone  => Some(positive),
+            _                  => None,
+        };
+
+        let mut events = Vec::new();
+        if held != pad.stick[axis] {
+            if let Some(b) = pad.stick[axis] {
+                pad.update(Source::Stick(axis), ButtonAction::Released, b, &mut events);
+            }
+            if let Some(b) = held {
+                pad.update(Source::Stick(axis), ButtonAction::Pressed, b, &mut events);
+            }
+            pad.stick[axis] = held;
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the (player, action, button) of each event
+    fn inputs(events : Vec<EmulatorEvent>) -> Vec<(usize, ButtonAction, Button)> {
+        events.into_iter().map(|e| match e {
+            EmulatorEvent::ControllerEvent { player, action, button } => (player, action, button),
+            e => panic!("unexpected event {:?}", e),
+        }).collect()
+    }
+
+    #[test]
+    fn remapping() {
+        let mut map = ButtonMap::default();
+        assert_eq!(map.buttons(PadButton::A), vec![Button::B]);
+        map.remap("a = a+leftshoulder, select=guide").unwrap();
+        assert_eq!(map.buttons(PadButton::A), vec![Button::A]);
+        assert_eq!(map.buttons(PadButton::B), vec![]);
+        assert_eq!(map.buttons(PadButton::LeftShoulder), vec![Button::A]);
+        assert_eq!(map.buttons(PadButton::Back), vec![]);
+        assert_eq!(map.buttons(PadButton::Guide), vec![Button::Select]);
+        assert_eq!(map.buttons(PadButton::X), vec![Button::B]);
+
+        assert!(map.remap("turbo=a").is_err());
+        assert!(map.remap("a=z").is_err());
+        assert!(map.remap("a").is_err());
+    }
+
+    #[test]
+    fn hot_plugging() {
+        let mut custom = ButtonMap::default();
+        custom.remap("a=a").unwrap();
+        let mut pads = Gamepads::new(&[(Some(String::from("Custom Pad")), custom)], 0.5);
+
+        assert_eq!(pads.connect(10, "Some Pad"), Some(0));
+        assert_eq!(pads.connect(11, "Custom Pad"), Some(1));
+        assert_eq!(inputs(pads.button(10, ButtonAction::Pressed, PadButton::A)),
+                   vec![(0, ButtonAction::Pressed, Button::B)]);
+        assert_eq!(inputs(pads.button(11, ButtonAction::Pressed, PadButton::A)),
+                   vec![(1, ButtonAction::Pressed, Button::A)]);
+
+        // the first player's pad goes, and the next one takes its place
+        assert_eq!(inputs(pads.disconnect(10)), vec![(0, ButtonAction::Released, Button::B)]);
+        assert_eq!(pads.connect(12, "Some Pad"), Some(0));
+        assert_eq!(pads.connect(13, "Some Pad"), Some(2));
+        assert_eq!(pads.connect(14, "Some Pad"), Some(3));
+        assert_eq!(pads.connect(15, "Some Pad"), None);
+        assert!(pads.button(15, ButtonAction::Pressed, PadButton::A).is_empty());
+        assert!(pads.disconnect(10).is_empty());
+    }
+
+    #[test]
+    fn stick() {
+        let mut pads = Gamepads::new(&[], 0.5);
+        pads.connect(1, "Pad");
+
+        // inside the deadzone, then pushed left, then all the way right
+        assert!(pads.stick(1, 0, -10000).is_empty());
+        assert_eq!(inputs(pads.stick(1, 0, -20000)),
+                   vec![(0, ButtonAction::Pressed, Button::Left)]);
+        assert!(pads.stick(1, 0, -30000).is_empty());
+        assert_eq!(inputs(pads.stick(1, 0, 32767)), vec![
+            (0, ButtonAction::Released, Button::Left),
+            (0, ButtonAction::Pressed, Button::Right),
+        ]);
+        assert_eq!(inputs(pads.stick(1, 1, -32768)),
+                   vec![(0, ButtonAction::Pressed, Button::Up)]);
+        assert_eq!(inputs(pads.stick(1, 0, 0)),
+                   vec![(0, ButtonAction::Released, Button::Right)]);
+    }
+
+    #[test]
+    fn shared_buttons() {
+        let mut pads = Gamepads::new(&[], 0.5);
+        pads.connect(1, "Pad");
+
+        // X and A are both B, which stays held until both let go
+        assert_eq!(inputs(pads.button(1, ButtonAction::Pressed, PadButton::X)),
+                   vec![(0, ButtonAction::Pressed, Button::B)]);
+        assert!(pads.button(1, ButtonAction::Pressed, PadButton::A).is_empty());
+        assert!(pads.button(1, ButtonAction::Released, PadButton::A).is_empty());
+        assert_eq!(inputs(pads.button(1, ButtonAction::Released, PadButton::X)),
+                   vec![(0, ButtonAction::Released, Button::B)]);
+
+        // the same for the stick and the d-pad
+        assert_eq!(inputs(pads.button(1, ButtonAction::Pressed, PadButton::DPadLeft)),
+                   vec![(0, ButtonAction::Pressed, Button::Left)]);
+        assert!(pads.stick(1, 0, -32768).is_empty());
+        assert!(pads.stick(1, 0, 0).is_empty());
+        assert_eq!(inputs(pads.button(1, ButtonAction::Released, PadButton::DPadLeft)),
+                   vec![(0, ButtonAction::Released, Button::Left)]);
+
+        // a repeated release doesn't let go of anything else
+        pads.button(1, ButtonAction::Pressed, PadButton::Y);
+        assert!(pads.button(1, ButtonAction::Released, PadButton::B).is_empty());
+        assert_eq!(inputs(pads.disconnect(1)), vec![(0, ButtonAction::Released, Button::A)]);
+    }
+}
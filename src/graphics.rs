@@ -1,13 +1,11 @@
 use ::sdl2::{ render, pixels, video };
 use self::pixels::PixelFormatEnum;
+use ppu::{ SCREEN_WIDTH as WIDTH, SCREEN_HEIGHT as HEIGHT, BYTES_PER_PIXEL };
 
-const WINDOW_WIDTH : u32 = 800;
-const WINDOW_HEIGHT : u32 = 800;
+// the picture is stretched to fill the window
+pub const WINDOW_WIDTH : u32 = 800;
+pub const WINDOW_HEIGHT : u32 = 800;
 const FORMAT : PixelFormatEnum = PixelFormatEnum::BGR24;
-const WIDTH : usize = 256;
-const HEIGHT : usize = 240;
-const BYTES_PER_PIXEL : usize = 3;
-pub const SCREEN_SIZE : usize = WIDTH * HEIGHT * BYTES_PER_PIXEL;
 
 
 pub struct EmulatorGraphics {
@@ -27,7 +25,12 @@ impl EmulatorGraphics {
         self.canvas.present();
     }
 
-    pub fn new(sdl_context : &::Sdl) -> EmulatorGraphics {
+    pub fn set_title(&mut self, title : &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+
+    // with vsync, update blocks until the display's next refresh
+    pub fn new(sdl_context : &::sdl2::Sdl, vsync : bool) -> EmulatorGraphics {
         let video_subsystem = sdl_context.video().unwrap();
 
         let window = video_subsystem.window("NES", WINDOW_WIDTH, WINDOW_HEIGHT)
@@ -35,7 +38,11 @@ impl EmulatorGraphics {
             .build()
             .unwrap();
 
-        let canvas = window.into_canvas().build().unwrap();
+        let mut builder = window.into_canvas();
+        if vsync {
+            builder = builder.present_vsync();
+        }
+        let canvas = builder.build().unwrap();
         let texture_creator = canvas.texture_creator();
 
         EmulatorGraphics {
@@ -0,0 +1,153 @@
+// https://wiki.nesdev.com/w/index.php/Hori_Track
+// Hori's trackball, in the expansion port. It has the buttons of a
+// controller and reads like one on D1 of $4016, with a longer report:
+//
+//   reads 1-8   : A, B, Select, Start, Up, Down, Left, Right
+//   reads 9-12  : how far the ball rolled down since the last strobe
+//   reads 13-16 : and across
+//   reads 17-24 : the speed switch and the device's ID
+//
+// The movements are 4 bit signed numbers, sent high bit first and
+// inverted. Later reads are 1.
+
+use ports::InputDevice;
+use ppu::PPU;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+// the speed switch on low
+const ID_BITS : u32 = 0x09;
+
+const REPORT_BITS : u32 = 24;
+
+#[derive(Debug)]
+pub struct HoriTrack {
+    buttons : u8,
+    // movement since the last strobe, in mouse pixels
+    dx : i32,
+    dy : i32,
+    // the latched report, the next to be read in bit 0
+    report : u32,
+    reads : u32,
+    strobe : bool,
+}
+
+// as sent, high bit first in the low bit
+fn movement_bits(delta : i32) -> u32 {
+    let val = !(delta.clamp(-8, 7) as u32) & 0x0F;
+    ((val & 1) << 3) | ((val & 2) << 1) | ((val & 4) >> 1) | ((val & 8) >> 3)
+}
+
+impl HoriTrack {
+    pub fn new() -> HoriTrack {
+        HoriTrack {
+            buttons : 0,
+            dx : 0,
+            dy : 0,
+            report : 0,
+            reads : 0,
+            strobe : false,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.report = self.buttons as u32 |
+                      movement_bits(self.dy) << 8 |
+                      movement_bits(self.dx) << 12 |
+                      ID_BITS << 16;
+        self.reads = 0;
+        self.dx = 0;
+        self.dy = 0;
+    }
+
+    fn bits(&self, port : usize) -> u8 {
+        if port != 0 {
+            return 0;
+        }
+        let bit = if self.reads < REPORT_BITS { (self.report >> self.reads) & 1 } else { 1 };
+        (bit as u8) << 1
+    }
+}
+
+impl InputDevice for HoriTrack {
+    fn write(&mut self, val : u8) {
+        let strobe = (val & 1) == 1;
+        // the report is taken when the strobe ends
+        if self.strobe && !strobe {
+            self.latch();
+        }
+        self.strobe = strobe;
+    }
+
+    fn read(&mut self, port : usize, _ppu : &PPU) -> u8 {
+        let bits = self.bits(port);
+        if port == 0 && !self.strobe && self.reads < REPORT_BITS {
+            self.reads += 1;
+        }
+        bits
+    }
+
+    fn peek(&self, port : usize, _ppu : &PPU) -> u8 {
+        self.bits(port)
+    }
+
+    fn set_buttons(&mut self, controller : usize, buttons : u8) {
+        if controller == 0 {
+            self.buttons = buttons;
+        }
+    }
+
+    fn buttons(&self, controller : usize) -> u8 {
+        if controller == 0 { self.buttons } else { 0 }
+    }
+
+    fn move_pointer(&mut self, dx : i32, dy : i32) {
+        self.dx += dx;
+        self.dy += dy;
+    }
+}
+
+impl SaveState for HoriTrack {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u8(self.buttons);
+        w.u32(self.report);
+        w.u8(self.reads as u8);
+        w.bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.buttons = r.u8();
+        self.report = r.u32();
+        self.reads = r.u8() as u32;
+        self.strobe = r.bool();
+        self.dx = 0;
+        self.dy = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let ppu = PPU::test();
+        let mut track = HoriTrack::new();
+        track.set_buttons(0, 0x81);
+        track.move_pointer(3, -20);
+        track.write(1);
+        track.write(0);
+
+        let bits : Vec<u8> = (0..26).map(|_| track.read(0, &ppu) >> 1).collect();
+        assert_eq!(&bits[..8], &[1, 0, 0, 0, 0, 0, 0, 1]);
+        // -8 and 3, inverted
+        assert_eq!(&bits[8..16], &[0, 1, 1, 1, 1, 1, 0, 0]);
+        assert_eq!(&bits[24..], &[1, 1]);
+        assert_eq!(track.read(1, &ppu), 0);
+
+        // the movement starts over after each strobe
+        track.write(1);
+        track.write(0);
+        let bits : Vec<u8> = (0..16).map(|_| track.read(0, &ppu) >> 1).collect();
+        assert_eq!(&bits[8..], &[1, 1, 1, 1, 1, 1, 1, 1]);
+    }
+}
@@ -1,8 +1,20 @@
 
 use sdl2::event::Event;
 use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{ Keycode, LSHIFTMOD, RSHIFTMOD };
+use sdl2::mouse::MouseButton;
+use sdl2::event::WindowEvent;
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{ GameController, Axis };
+use sdl2::controller::Button as ControllerButton;
 use super::controller::{ ButtonAction, Button };
+use frontend::EmulatorEvent;
+use apu::Channel;
+use family_keyboard::KeyboardKey;
+use gamepad::{ Gamepads, PadButton };
+use config::Config;
+use graphics::{ WINDOW_WIDTH, WINDOW_HEIGHT };
+use ppu::{ SCREEN_WIDTH, SCREEN_HEIGHT };
 
 const BUTTON_A      : Keycode = Keycode::A;
 const BUTTON_B      : Keycode = Keycode::S;
@@ -13,30 +25,218 @@ const BUTTON_DOWN   : Keycode = Keycode::Down;
 const BUTTON_LEFT   : Keycode = Keycode::Left;
 const BUTTON_RIGHT  : Keycode = Keycode::Right;
 
-fn get_key_mapping(key : Keycode) -> Option<Button> {
+const TOGGLE_RECORDING : Keycode = Keycode::F9;
+const SAVE_STATE       : Keycode = Keycode::F7;
+const LOAD_STATE       : Keycode = Keycode::F8;
+const REWIND           : Keycode = Keycode::Backspace;
+const TOGGLE_MOVIE     : Keycode = Keycode::F10;
+
+fn get_slot_mapping(key : Keycode) -> Option<u8> {
+    match key {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _             => None,
+    }
+}
+
+// toggles muting a channel, or soloing it with shift held
+fn get_channel_mapping(key : Keycode) -> Option<Channel> {
+    match key {
+        Keycode::F1 => Some(Channel::Pulse1),
+        Keycode::F2 => Some(Channel::Pulse2),
+        Keycode::F3 => Some(Channel::Triangle),
+        Keycode::F4 => Some(Channel::Noise),
+        Keycode::F5 => Some(Channel::DMC),
+        _           => None,
+    }
+}
+
+// the screen pixel under a point in the window
+fn window_to_screen(x : i32, y : i32) -> Option<(i32, i32)> {
+    let x = x * SCREEN_WIDTH as i32 / WINDOW_WIDTH as i32;
+    let y = y * SCREEN_HEIGHT as i32 / WINDOW_HEIGHT as i32;
+    if x >= 0 && x < SCREEN_WIDTH as i32 && y >= 0 && y < SCREEN_HEIGHT as i32 {
+        Some((x, y))
+    } else {
+        None
+    }
+}
+
+// the player and button a key is for
+fn get_key_mapping(key : Keycode) -> Option<(usize, Button)> {
+    match key {
+        Keycode::A     => Some((0, Button::A)),
+        Keycode::S     => Some((0, Button::B)),
+        Keycode::Z     => Some((0, Button::Select)),
+        Keycode::X     => Some((0, Button::Start)),
+        Keycode::Up    => Some((0, Button::Up)),
+        Keycode::Down  => Some((0, Button::Down)),
+        Keycode::Left  => Some((0, Button::Left)),
+        Keycode::Right => Some((0, Button::Right)),
+
+        Keycode::P            => Some((1, Button::A)),
+        Keycode::O            => Some((1, Button::B)),
+        Keycode::LeftBracket  => Some((1, Button::Select)),
+        Keycode::RightBracket => Some((1, Button::Start)),
+        Keycode::I            => Some((1, Button::Up)),
+        Keycode::K            => Some((1, Button::Down)),
+        Keycode::J            => Some((1, Button::Left)),
+        Keycode::L            => Some((1, Button::Right)),
+
+        // players 3 and 4, with a Four Score
+        Keycode::W => Some((2, Button::A)),
+        Keycode::Q => Some((2, Button::B)),
+        Keycode::C => Some((2, Button::Select)),
+        Keycode::V => Some((2, Button::Start)),
+        Keycode::T => Some((2, Button::Up)),
+        Keycode::G => Some((2, Button::Down)),
+        Keycode::F => Some((2, Button::Left)),
+        Keycode::H => Some((2, Button::Right)),
+
+        Keycode::Kp2 => Some((3, Button::A)),
+        Keycode::Kp1 => Some((3, Button::B)),
+        Keycode::Kp7 => Some((3, Button::Select)),
+        Keycode::Kp9 => Some((3, Button::Start)),
+        Keycode::Kp8 => Some((3, Button::Up)),
+        Keycode::Kp5 => Some((3, Button::Down)),
+        Keycode::Kp4 => Some((3, Button::Left)),
+        Keycode::Kp6 => Some((3, Button::Right)),
+        _                     => None,
+    }
+}
+
+// the Family BASIC key a key is for. Symbols are where they are on a
+// Japanese layout
+fn get_keyboard_mapping(key : Keycode) -> Option<KeyboardKey> {
     match key {
-        Keycode::A     => Some(Button::A),
-        Keycode::S     => Some(Button::B),
-        Keycode::Z     => Some(Button::Select),
-        Keycode::X     => Some(Button::Start),
-        Keycode::Up    => Some(Button::Up),
-        Keycode::Down  => Some(Button::Down),
-        Keycode::Left  => Some(Button::Left),
-        Keycode::Right => Some(Button::Right),
-        _              => None,
+        Keycode::A            => Some(KeyboardKey::A),
+        Keycode::B            => Some(KeyboardKey::B),
+        Keycode::C            => Some(KeyboardKey::C),
+        Keycode::D            => Some(KeyboardKey::D),
+        Keycode::E            => Some(KeyboardKey::E),
+        Keycode::F            => Some(KeyboardKey::F),
+        Keycode::G            => Some(KeyboardKey::G),
+        Keycode::H            => Some(KeyboardKey::H),
+        Keycode::I            => Some(KeyboardKey::I),
+        Keycode::J            => Some(KeyboardKey::J),
+        Keycode::K            => Some(KeyboardKey::K),
+        Keycode::L            => Some(KeyboardKey::L),
+        Keycode::M            => Some(KeyboardKey::M),
+        Keycode::N            => Some(KeyboardKey::N),
+        Keycode::O            => Some(KeyboardKey::O),
+        Keycode::P            => Some(KeyboardKey::P),
+        Keycode::Q            => Some(KeyboardKey::Q),
+        Keycode::R            => Some(KeyboardKey::R),
+        Keycode::S            => Some(KeyboardKey::S),
+        Keycode::T            => Some(KeyboardKey::T),
+        Keycode::U            => Some(KeyboardKey::U),
+        Keycode::V            => Some(KeyboardKey::V),
+        Keycode::W            => Some(KeyboardKey::W),
+        Keycode::X            => Some(KeyboardKey::X),
+        Keycode::Y            => Some(KeyboardKey::Y),
+        Keycode::Z            => Some(KeyboardKey::Z),
+        Keycode::Num0         => Some(KeyboardKey::Num0),
+        Keycode::Num1         => Some(KeyboardKey::Num1),
+        Keycode::Num2         => Some(KeyboardKey::Num2),
+        Keycode::Num3         => Some(KeyboardKey::Num3),
+        Keycode::Num4         => Some(KeyboardKey::Num4),
+        Keycode::Num5         => Some(KeyboardKey::Num5),
+        Keycode::Num6         => Some(KeyboardKey::Num6),
+        Keycode::Num7         => Some(KeyboardKey::Num7),
+        Keycode::Num8         => Some(KeyboardKey::Num8),
+        Keycode::Num9         => Some(KeyboardKey::Num9),
+        Keycode::F1           => Some(KeyboardKey::F1),
+        Keycode::F2           => Some(KeyboardKey::F2),
+        Keycode::F3           => Some(KeyboardKey::F3),
+        Keycode::F4           => Some(KeyboardKey::F4),
+        Keycode::F5           => Some(KeyboardKey::F5),
+        Keycode::F6           => Some(KeyboardKey::F6),
+        Keycode::F7           => Some(KeyboardKey::F7),
+        Keycode::F8           => Some(KeyboardKey::F8),
+        Keycode::Minus        => Some(KeyboardKey::Minus),
+        Keycode::Equals       => Some(KeyboardKey::Caret),
+        Keycode::Backquote    => Some(KeyboardKey::Yen),
+        Keycode::Pause        => Some(KeyboardKey::Stop),
+        Keycode::LeftBracket  => Some(KeyboardKey::At),
+        Keycode::RightBracket => Some(KeyboardKey::LeftBracket),
+        Keycode::Backslash    => Some(KeyboardKey::RightBracket),
+        Keycode::Return       => Some(KeyboardKey::Return),
+        Keycode::Semicolon    => Some(KeyboardKey::Semicolon),
+        Keycode::Quote        => Some(KeyboardKey::Colon),
+        Keycode::End          => Some(KeyboardKey::Underscore),
+        Keycode::Comma        => Some(KeyboardKey::Comma),
+        Keycode::Period       => Some(KeyboardKey::Period),
+        Keycode::Slash        => Some(KeyboardKey::Slash),
+        Keycode::Escape       => Some(KeyboardKey::Esc),
+        Keycode::LCtrl        => Some(KeyboardKey::Ctr),
+        Keycode::LShift       => Some(KeyboardKey::LeftShift),
+        Keycode::RShift       => Some(KeyboardKey::RightShift),
+        Keycode::LAlt         => Some(KeyboardKey::Grph),
+        Keycode::RAlt         => Some(KeyboardKey::Kana),
+        Keycode::Space        => Some(KeyboardKey::Space),
+        Keycode::Home         => Some(KeyboardKey::ClrHome),
+        Keycode::Insert       => Some(KeyboardKey::Ins),
+        Keycode::Delete       => Some(KeyboardKey::Del),
+        Keycode::Up           => Some(KeyboardKey::Up),
+        Keycode::Down         => Some(KeyboardKey::Down),
+        Keycode::Left         => Some(KeyboardKey::Left),
+        Keycode::Right        => Some(KeyboardKey::Right),
+        _                     => None,
     }
 }
 
+// events that go along with whatever else an event is mapped to
+fn raw_events(event : &Event) -> Option<EmulatorEvent> {
+    match *event {
+        Event::KeyDown { keycode : Some(key), repeat : false, .. } =>
+            get_keyboard_mapping(key).map(|key|
+                EmulatorEvent::KeyboardEvent { action : ButtonAction::Pressed, key : key }),
+
+        Event::KeyUp { keycode : Some(key), .. } =>
+            get_keyboard_mapping(key).map(|key|
+                EmulatorEvent::KeyboardEvent { action : ButtonAction::Released, key : key }),
 
-#[derive(Debug)]
-pub enum EmulatorEvent {
-    ControllerEvent { action : ButtonAction, button : Button },
-    Continue,
-    Exit,
+        Event::MouseMotion { xrel, yrel, .. } =>
+            Some(EmulatorEvent::MouseMotion(xrel, yrel)),
+
+        _ => None,
+    }
+}
+
+fn pad_button(button : ControllerButton) -> PadButton {
+    match button {
+        ControllerButton::A             => PadButton::A,
+        ControllerButton::B             => PadButton::B,
+        ControllerButton::X             => PadButton::X,
+        ControllerButton::Y             => PadButton::Y,
+        ControllerButton::Back          => PadButton::Back,
+        ControllerButton::Guide         => PadButton::Guide,
+        ControllerButton::Start         => PadButton::Start,
+        ControllerButton::LeftStick     => PadButton::LeftStick,
+        ControllerButton::RightStick    => PadButton::RightStick,
+        ControllerButton::LeftShoulder  => PadButton::LeftShoulder,
+        ControllerButton::RightShoulder => PadButton::RightShoulder,
+        ControllerButton::DPadUp        => PadButton::DPadUp,
+        ControllerButton::DPadDown      => PadButton::DPadDown,
+        ControllerButton::DPadLeft      => PadButton::DPadLeft,
+        ControllerButton::DPadRight     => PadButton::DPadRight,
+    }
 }
 
 pub struct EmulatorInput {
     pump : EventPump,
+    controller_subsystem : GameControllerSubsystem,
+    // the connected pads, which close when they're dropped
+    controllers : Vec<GameController>,
+    gamepads : Gamepads,
 }
 
 impl EmulatorInput {
@@ -44,10 +244,43 @@ impl EmulatorInput {
         let event_receiver = |event| match event {
                 Event::Quit {..} => EmulatorEvent::Exit,
 
+                Event::KeyDown { keycode : Some(TOGGLE_RECORDING), repeat : false, .. } =>
+                    EmulatorEvent::ToggleRecording,
+
+                Event::KeyDown { keycode : Some(SAVE_STATE), repeat : false, .. } =>
+                    EmulatorEvent::SaveState,
+
+                Event::KeyDown { keycode : Some(LOAD_STATE), repeat : false, .. } =>
+                    EmulatorEvent::LoadState,
+
+                Event::KeyDown { keycode : Some(TOGGLE_MOVIE), repeat : false, .. } =>
+                    EmulatorEvent::ToggleMovieRecording,
+
+                Event::KeyDown { keycode : Some(REWIND), repeat : false, .. } =>
+                    EmulatorEvent::StartRewind,
+
+                Event::KeyUp { keycode : Some(REWIND), .. } =>
+                    EmulatorEvent::StopRewind,
+
+                Event::KeyDown { keycode : Some(key), repeat : false, .. }
+                    if get_slot_mapping(key).is_some() =>
+                        EmulatorEvent::SelectSlot(get_slot_mapping(key).unwrap()),
+
+                Event::KeyDown { keycode : Some(key), keymod, repeat : false, .. }
+                    if get_channel_mapping(key).is_some() => {
+                        let channel = get_channel_mapping(key).unwrap();
+                        if keymod.intersects(LSHIFTMOD | RSHIFTMOD) {
+                            EmulatorEvent::ToggleSolo(channel)
+                        } else {
+                            EmulatorEvent::ToggleMute(channel)
+                        }
+                    },
+
                 Event::KeyDown { keycode : Some(key), .. } =>
                     match get_key_mapping(key) {
-                        Some(button) =>
+                        Some((player, button)) =>
                             EmulatorEvent::ControllerEvent {
+                                player : player,
                                 action : ButtonAction::Pressed,
                                 button : button,
                             },
@@ -56,22 +289,96 @@ impl EmulatorInput {
 
                 Event::KeyUp { keycode : Some(key), .. } =>
                     match get_key_mapping(key) {
-                        Some(button) =>
+                        Some((player, button)) =>
                             EmulatorEvent::ControllerEvent {
+                                player : player,
                                 action : ButtonAction::Released,
                                 button : button,
                             },
                         None => EmulatorEvent::Continue,
                     },
 
+                // the mouse aims light guns, and the left button fires
+                Event::MouseMotion { x, y, .. } =>
+                    EmulatorEvent::Pointer(window_to_screen(x, y)),
+
+                Event::Window { win_event : WindowEvent::Leave, .. } =>
+                    EmulatorEvent::Pointer(None),
+
+                Event::MouseButtonDown { mouse_btn : MouseButton::Left, .. } =>
+                    EmulatorEvent::Trigger(true),
+
+                Event::MouseButtonUp { mouse_btn : MouseButton::Left, .. } =>
+                    EmulatorEvent::Trigger(false),
+
                 _ => EmulatorEvent::Continue,
         };
 
-        self.pump.poll_iter().map(event_receiver).collect()
+        let sdl_events : Vec<Event> = self.pump.poll_iter().collect();
+        let mut events = Vec::new();
+        for event in sdl_events {
+            if let Some(pad_events) = self.gamepad_events(&event) {
+                events.extend(pad_events);
+                continue;
+            }
+            let raw = raw_events(&event);
+            events.push(event_receiver(event));
+            events.extend(raw);
+        }
+        events
     }
-    pub fn new(sdl_context : &::Sdl) -> EmulatorInput {
+
+    // the events for gamepads being plugged in and used, None for other
+    // events. Pads already connected at startup are added too
+    fn gamepad_events(&mut self, event : &Event) -> Option<Vec<EmulatorEvent>> {
+        let events = match *event {
+            Event::ControllerDeviceAdded { which, .. } => {
+                match self.controller_subsystem.open(which) {
+                    Ok(controller) => {
+                        let name = controller.name();
+                        match self.gamepads.connect(controller.instance_id(), &name) {
+                            Some(player) => println!("{} connected for player {}", name, player + 1),
+                            None => println!("{} connected, but every player has a gamepad", name),
+                        }
+                        self.controllers.push(controller);
+                    },
+                    Err(e) => println!("error opening gamepad: {}", e),
+                }
+                Vec::new()
+            },
+
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(i) = self.controllers.iter().position(|c| c.instance_id() == which) {
+                    println!("{} disconnected", self.controllers.remove(i).name());
+                }
+                self.gamepads.disconnect(which)
+            },
+
+            Event::ControllerButtonDown { which, button, .. } =>
+                self.gamepads.button(which, ButtonAction::Pressed, pad_button(button)),
+
+            Event::ControllerButtonUp { which, button, .. } =>
+                self.gamepads.button(which, ButtonAction::Released, pad_button(button)),
+
+            Event::ControllerAxisMotion { which, axis : Axis::LeftX, value, .. } =>
+                self.gamepads.stick(which, 0, value),
+
+            Event::ControllerAxisMotion { which, axis : Axis::LeftY, value, .. } =>
+                self.gamepads.stick(which, 1, value),
+
+            Event::ControllerAxisMotion { .. } => Vec::new(),
+
+            _ => return None,
+        };
+        Some(events)
+    }
+
+    pub fn new(sdl_context : &::sdl2::Sdl, config : &Config) -> EmulatorInput {
         EmulatorInput {
             pump : sdl_context.event_pump().unwrap(),
+            controller_subsystem : sdl_context.game_controller().unwrap(),
+            controllers : Vec::new(),
+            gamepads : Gamepads::new(&config.gamepads, config.gamepad_deadzone),
         }
     }
 }
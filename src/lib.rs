@@ -1,123 +1,473 @@
 #![allow(dead_code)]
 
+#[cfg(feature = "sdl")]
 extern crate sdl2;
-use sdl2::Sdl;
 
 pub mod cartridge;
+pub mod config;
 mod cpu;
 
 mod ppu;
+pub mod palette;
+#[cfg(feature = "sdl")]
 mod graphics;
 
 mod apu;
+#[cfg(feature = "sdl")]
 mod audio;
+mod wav;
+mod recording;
 
 mod controller;
+mod ports;
+mod zapper;
+mod four_score;
+mod vaus;
+mod family_keyboard;
+mod hori_track;
+mod gamepad;
+#[cfg(feature = "sdl")]
 mod input;
 
-use cartridge::Cartridge;
-use cpu::CPU;
-
-use ppu::PPU;
-use graphics::EmulatorGraphics;
+pub mod region;
+pub mod nsf;
+
+mod console;
+mod savestate;
+mod rewind;
+pub mod movie;
+mod overlay;
+pub mod regression;
+pub mod test_rom;
+pub mod frontend;
+#[cfg(feature = "sdl")]
+mod sdl_frontend;
+
+pub use apu::{ Channel, ExpansionChip };
+pub use console::{ Nes, ConsoleEvent };
+pub use cpu::Registers as CpuRegisters;
+pub use controller::{ ButtonAction, Button };
+pub use ports::{ DeviceKind, EXPANSION_PORT };
+pub use family_keyboard::KeyboardKey;
+pub use frontend::{ Frontend, EmulatorEvent };
+pub use ppu::{ SCREEN_WIDTH, SCREEN_HEIGHT, SCREEN_SIZE };
+#[cfg(feature = "sdl")]
+pub use sdl_frontend::SdlFrontend;
 
-use apu::APU;
-use audio::EmulatorAudio;
+use cartridge::Cartridge;
+use config::Config;
+use region::Region;
 
-use controller::Controller;
-use input::{ EmulatorEvent, EmulatorInput };
+use recording::Recording;
+use rewind::Rewind;
+use movie::{ Movie, MovieState };
+use nsf::{ Nsf, NsfPlayer };
 
 use std::cell::RefCell;
 use std::rc::Rc;
 
-struct EmulatorContext {
-    graphics : EmulatorGraphics,
-    input : EmulatorInput,
-    audio : EmulatorAudio,
-    sdl_context : Sdl,
+// runs the rom without any video, audio or input, recording the given
+// number of seconds of its audio to config.record
+pub fn render_audio(cart : Cartridge, config : Config, seconds : f64) -> Result<(), String> {
+    let filename = match config.record {
+        Some(ref filename) => filename.clone(),
+        None => return Err(String::from("rendering audio needs a file to record to")),
+    };
+
+    let sample_rate = apu::DEFAULT_SAMPLE_RATE;
+    let mut nes = Nes::new(cart, &config, sample_rate);
+    let mut recording = Recording::start(&filename, sample_rate, &nes.stem_channels())?;
+
+    let total_samples = (seconds * sample_rate as f64) as u32;
+    while recording.num_samples() < total_samples {
+        nes.step_frame();
+
+        let remaining = (total_samples - recording.num_samples()) as usize;
+        let mut samples = nes.audio_samples();
+        let mut stem_samples = nes.stem_samples();
+
+        // don't go over the requested length
+        samples.truncate(remaining);
+        for stem in stem_samples.iter_mut() {
+            stem.truncate(remaining);
+        }
+        recording.write(&samples, &stem_samples)?;
+    }
+
+    recording.finish()
 }
 
-impl EmulatorContext {
-    pub fn new() -> EmulatorContext {
-        let sdl_context = ::sdl2::init().unwrap();
+fn nsf_player(nsf : Nsf, config : &Config, track : Option<u8>,
+              sample_rate : u32) -> Result<NsfPlayer, String> {
+    if let Some(track) = track {
+        if track >= nsf.num_tracks {
+            return Err(format!("invalid track {}, there are {}", track + 1, nsf.num_tracks));
+        }
+    }
 
-        EmulatorContext {
-            graphics : EmulatorGraphics::new(&sdl_context),
-            input : EmulatorInput::new(&sdl_context),
-            audio : EmulatorAudio::new(&sdl_context),
-            sdl_context : sdl_context,
+    let region = config.region.or(nsf.region()).unwrap_or(Region::Ntsc);
+
+    let mut player = NsfPlayer::new(nsf, region, sample_rate);
+    {
+        let mut apu = player.apu().borrow_mut();
+        for &channel in Channel::ALL.iter() {
+            apu.set_channel_volume(channel, config.channel_volumes[channel as usize]);
+            apu.set_channel_muted(channel, config.muted[channel as usize]);
         }
+        apu.set_solo(config.solo);
+    }
+
+    if let Some(track) = track {
+        player.start_track(track);
     }
+    Ok(player)
 }
 
-pub fn run_emulator(cart : Cartridge) {
-    let mut emulator = EmulatorContext::new();
+fn nsf_track_name(player : &NsfPlayer) -> String {
+    let nsf = player.nsf();
+    let track = player.track();
 
-    let cart  = ComponentRc::new(cart);
-    let ppu   = ComponentRc::new(PPU::new(cart.new_ref()));
-    let apu   = ComponentRc::new(APU::new());
-    let controller = ComponentRc::new(Controller::new());
+    let mut name = format!("{} - track {}/{}", nsf.title, track + 1, nsf.num_tracks);
+    if let Some(title) = nsf.track_title(track) {
+        name = format!("{}: {}", name, title);
+    }
+    name
+}
 
-    let mut cpu = CPU::new(
-        cart.new_ref(), ppu.new_ref(), apu.new_ref(), controller.new_ref());
+// renders the given number of seconds of an nsf track to config.record.
+// tracks are counted from 0, None is the file's starting track
+pub fn render_nsf_audio(nsf : Nsf, config : Config, track : Option<u8>,
+                        seconds : f64) -> Result<(), String> {
+    let filename = match config.record {
+        Some(ref filename) => filename.clone(),
+        None => return Err(String::from("rendering audio needs a file to record to")),
+    };
+
+    let sample_rate = apu::DEFAULT_SAMPLE_RATE;
+    let mut player = nsf_player(nsf, &config, track, sample_rate)?;
+    println!("rendering {}", nsf_track_name(&player));
+
+    player.apu().borrow_mut().set_stems_enabled(config.record_stems);
+    let stems = player.apu().borrow().stem_channels();
+    let mut recording = Recording::start(&filename, sample_rate, &stems)?;
+
+    let total_samples = (seconds * sample_rate as f64) as u32;
+    while recording.num_samples() < total_samples && !player.track_finished() {
+        player.run_frame();
+
+        let remaining = (total_samples - recording.num_samples()) as usize;
+        let mut samples = player.take_samples();
+        let mut stem_samples = player.apu().borrow_mut().take_stem_samples();
+
+        samples.truncate(remaining);
+        for stem in stem_samples.iter_mut() {
+            stem.truncate(remaining);
+        }
+        recording.write(&samples, &stem_samples)?;
+    }
 
-    use std::time::{ SystemTime, Duration };
-    let start = SystemTime::now();
-    let mut num_frames : usize = 0;
+    recording.finish()
+}
+
+// plays an nsf file with a blank screen. left and right change tracks, and
+// tracks with a known length go on to the next one when they end
+pub fn run_nsf_with_frontend<F : Frontend>(nsf : Nsf, config : Config, track : Option<u8>,
+                                           frontend : &mut F) -> Result<(), String> {
+    let sample_rate = frontend.sample_rate();
 
-    cpu.send_reset();
+    println!("{}", nsf.title);
+    println!("{}", nsf.artist);
+    println!("{}", nsf.copyright);
 
-    let frame_len = Duration::new(0, 1_000_000_000u32 / 60);
+    let mut player = nsf_player(nsf, &config, track, sample_rate)?;
+    let blank = vec![0; SCREEN_SIZE];
+
+    let change_track = |player : &mut NsfPlayer, track : u8, frontend : &mut F| {
+        player.start_track(track);
+        let name = nsf_track_name(player);
+        println!("{}", name);
+        frontend.set_title(&name);
+    };
+    let first = player.track();
+    change_track(&mut player, first, frontend);
 
     'running: loop {
-        let frame_start_time = SystemTime::now();
+        player.run_frame();
 
-        // step until CYCLES_PER_SCANLINE cycles passed
-        // render scanline
-        // when 240 scanlines rendered,
-        // step for 20 scanlines
+        frontend.present_frame(&blank);
+        frontend.queue_audio(&player.take_samples());
 
-        for scanline in 0..240 {
-            cpu.step_for_scanlines(1);
-            ppu.borrow_mut().render_scanline(scanline);
-        }
+        let num_tracks = player.nsf().num_tracks;
+        let track = player.track();
 
-        // post render scanline
-        cpu.step_for_scanlines(1);
+        if player.track_finished() {
+            change_track(&mut player, (track + 1) % num_tracks, frontend);
+        }
 
-        // start vblank
-        ppu.borrow_mut().set_vblank();
-        if ppu.borrow().nmi_enabled() {
-            cpu.send_nmi();
+        for event in frontend.events() {
+            match event {
+                EmulatorEvent::Exit => break 'running,
+                EmulatorEvent::ControllerEvent { action : ButtonAction::Pressed,
+                                                 button : Button::Right, .. } =>
+                    change_track(&mut player, (track + 1) % num_tracks, frontend),
+                EmulatorEvent::ControllerEvent { action : ButtonAction::Pressed,
+                                                 button : Button::Left, .. } =>
+                    change_track(&mut player, (track + num_tracks - 1) % num_tracks,
+                                 frontend),
+                EmulatorEvent::ToggleMute(channel) => {
+                    let mut apu = player.apu().borrow_mut();
+                    let muted = !apu.channel_muted(channel);
+                    apu.set_channel_muted(channel, muted);
+                },
+                EmulatorEvent::ToggleSolo(channel) => {
+                    let mut apu = player.apu().borrow_mut();
+                    let solo = if apu.solo() == Some(channel) { None } else { Some(channel) };
+                    apu.set_solo(solo);
+                },
+                _ => (),
+            }
         }
 
-        // cpu during vblank and pre render scanline
-        cpu.step_for_scanlines(21);
+        frontend.wait_for_frame(player.region().frame_rate());
+        player.apu().borrow_mut().set_rate_adjustment(frontend.rate_adjustment());
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sdl")]
+pub fn run_nsf_player(nsf : Nsf, config : Config, track : Option<u8>) -> Result<(), String> {
+    let mut frontend = SdlFrontend::new(&config);
+    run_nsf_with_frontend(nsf, config, track, &mut frontend)
+}
+
+// shows the frame, with the frame counter over it while there's a movie
+// going
+fn present<F : Frontend>(frontend : &mut F, nes : &Nes, movie : &Option<MovieState>) {
+    match *movie {
+        Some(ref movie) => {
+            let mut pixels = nes.framebuffer().to_vec();
+            let mode = match *movie {
+                MovieState::Recording(_) => 'R',
+                MovieState::Playing { .. } => 'P',
+            };
+            overlay::draw_text(&mut pixels, 8, 16, &format!("{} {}", mode, movie.frame()));
+            frontend.present_frame(&pixels);
+        },
+        None => frontend.present_frame(&nes.framebuffer()),
+    }
+}
+
+// a movie being recorded is saved when it stops
+fn stop_movie(movie : &mut Option<MovieState>, filename : &str) {
+    match movie.take() {
+        Some(MovieState::Recording(movie)) => match movie.save(filename) {
+            Ok(()) => println!("saved movie to {}", filename),
+            Err(e) => println!("{}", e),
+        },
+        Some(MovieState::Playing { .. }) => println!("stopped movie"),
+        None => (),
+    }
+}
 
-        ppu.borrow_mut().clear_vblank();
-        emulator.graphics.update(ppu.borrow().get_pixeldata());
+// recording errors shouldn't stop the game, so they're only printed
+fn start_recording(filename : &str, sample_rate : u32, nes : &Nes) -> Option<Recording> {
+    match Recording::start(filename, sample_rate, &nes.stem_channels()) {
+        Ok(recording) => {
+            println!("recording to {}", filename);
+            Some(recording)
+        },
+        Err(e) => {
+            println!("{}", e);
+            None
+        },
+    }
+}
 
-        for event in emulator.input.events() {
+fn stop_recording(recording : Recording) {
+    let filename = String::from(recording.filename());
+    match recording.finish() {
+        Ok(()) => println!("stopped recording to {}", filename),
+        Err(e) => println!("{}", e),
+    }
+}
+
+// runs until the frontend sends an Exit event
+pub fn run_with_frontend<F : Frontend>(cart : Cartridge, config : Config, frontend : &mut F) {
+    let sample_rate = frontend.sample_rate();
+    let mut nes = Nes::new(cart, &config, sample_rate);
+    let region = nes.region();
+    println!("running as {:?}", region);
+
+    let mut recording = config.record.as_ref().and_then(|filename|
+        start_recording(filename, sample_rate, &nes));
+
+    use std::time::SystemTime;
+    let start = SystemTime::now();
+    let mut num_frames : usize = 0;
+
+    let states = config.states.clone().unwrap_or_else(|| String::from("nes"));
+    let mut slot = 1;
+
+    let mut rewind = if config.rewind_memory > 0 {
+        Some(Rewind::new(config.rewind_interval, config.rewind_memory, config.rewind_speed))
+    } else {
+        None
+    };
+    let mut rewinding = false;
+
+    // movies name the rom they're for, which is what states are named after
+    let rom_name = ::std::path::Path::new(&states).file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut movie_file = config.record_movie.clone().unwrap_or_default();
+    let mut movie = if let Some(ref filename) = config.movie {
+        let played = Movie::from_file(filename)
+            .and_then(|movie| MovieState::play(&mut nes, movie))
+            .unwrap_or_else(|e| panic!("{}", e));
+        println!("playing movie {}", filename);
+        Some(played)
+    } else if config.record_movie.is_some() {
+        println!("recording movie to {}", movie_file);
+        Some(MovieState::record(&mut nes, &rom_name, true).unwrap_or_else(|e| panic!("{}", e)))
+    } else {
+        None
+    };
+
+    'running: loop {
+        match rewind {
+            Some(ref mut rewind) if rewinding => {
+                rewind.rewind_frame(&mut nes);
+                present(frontend, &nes, &movie);
+
+                // keep the audio queue going so pacing carries on
+                let silence = vec![0.0; (sample_rate as f64 / region.frame_rate()) as usize];
+                frontend.queue_audio(&silence);
+            },
+            _ => {
+                let finished = match movie {
+                    Some(ref mut movie) => !movie.before_frame(&mut nes),
+                    None => false,
+                };
+                if finished {
+                    println!("movie finished");
+                    movie = None;
+                }
+
+                nes.step_frame();
+
+                present(frontend, &nes, &movie);
+
+                let samples = nes.audio_samples();
+                frontend.queue_audio(&samples);
+                if let Some(Err(e)) = recording.as_mut()
+                    .map(|r| r.write(&samples, &nes.stem_samples())) {
+                    println!("{}, stopped recording", e);
+                    recording = None;
+                }
+
+                if let Some(ref mut rewind) = rewind {
+                    rewind.frame_done(&nes);
+                }
+            },
+        }
+
+        // with the Family BASIC keyboard plugged in, the keys on it are
+        // only for typing
+        let typing = nes.port_device(EXPANSION_PORT) == DeviceKind::FamilyKeyboard;
+
+        for event in frontend.events() {
             match event {
                 EmulatorEvent::Exit => break 'running,
                 EmulatorEvent::Continue => (),
-                EmulatorEvent::ControllerEvent { action, button } =>
-                    controller.borrow_mut().update(action, button),
+                EmulatorEvent::ControllerEvent { .. } | EmulatorEvent::SelectSlot(_) |
+                EmulatorEvent::ToggleMute(_) | EmulatorEvent::ToggleSolo(_) |
+                EmulatorEvent::SaveState | EmulatorEvent::LoadState if typing => (),
+                EmulatorEvent::ToggleMute(channel) => {
+                    let muted = !nes.channel_muted(channel);
+                    nes.set_channel_muted(channel, muted);
+                    println!("{} {}", channel.name(), if muted { "muted" } else { "unmuted" });
+                },
+                EmulatorEvent::ToggleSolo(channel) => {
+                    let solo = if nes.solo() == Some(channel) { None } else { Some(channel) };
+                    nes.set_solo(solo);
+                    match solo {
+                        Some(channel) => println!("{} solo", channel.name()),
+                        None => println!("solo off"),
+                    }
+                },
+                EmulatorEvent::ToggleRecording => match recording.take() {
+                    Some(r) => stop_recording(r),
+                    None => {
+                        let filename = config.record.clone()
+                            .unwrap_or_else(recording::next_free_filename);
+                        // throw out the stems made while not recording
+                        nes.stem_samples();
+                        recording = start_recording(&filename, sample_rate, &nes);
+                    },
+                },
+                EmulatorEvent::SaveState => {
+                    let filename = savestate::slot_filename(&states, slot);
+                    match nes.save_state_file(&filename) {
+                        Ok(()) => println!("saved state to {}", filename),
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                EmulatorEvent::LoadState => {
+                    // the movie can't follow the jump
+                    stop_movie(&mut movie, &movie_file);
+                    let filename = savestate::slot_filename(&states, slot);
+                    match nes.load_state_file(&filename) {
+                        Ok(()) => println!("loaded state from {}", filename),
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                EmulatorEvent::StartRewind => {
+                    stop_movie(&mut movie, &movie_file);
+                    rewinding = true;
+                },
+                EmulatorEvent::StopRewind => rewinding = false,
+                EmulatorEvent::SelectSlot(n) => {
+                    slot = n;
+                    println!("state slot {}", slot);
+                },
+                EmulatorEvent::ToggleMovieRecording => {
+                    if movie.is_some() {
+                        stop_movie(&mut movie, &movie_file);
+                    } else {
+                        match MovieState::record(&mut nes, &rom_name, false) {
+                            Ok(recording) => {
+                                movie_file = config.record_movie.clone()
+                                    .unwrap_or_else(movie::next_free_filename);
+                                println!("recording movie to {}", movie_file);
+                                movie = Some(recording);
+                            },
+                            Err(e) => println!("{}", e),
+                        }
+                    }
+                },
+                // the movie has the controller while it's playing
+                EmulatorEvent::ControllerEvent { .. }
+                    if matches!(movie, Some(MovieState::Playing { .. })) => (),
+                EmulatorEvent::ControllerEvent { player, action, button } =>
+                    nes.update_player_button(player, action, button),
+                EmulatorEvent::Pointer(pos) => nes.set_pointer(pos),
+                EmulatorEvent::Trigger(pulled) => nes.set_trigger(pulled),
+                EmulatorEvent::MouseMotion(dx, dy) => nes.move_pointer(dx, dy),
+                EmulatorEvent::KeyboardEvent { action, key } => nes.update_key(action, key),
             }
         }
 
-        let frame_duration = frame_start_time.elapsed().unwrap();
-
-        match frame_len.checked_sub(frame_duration) {
-            // if the frame took less time than 1/60 of a second,
-            // then delay so we get 60fps
-            Some(duration) => std::thread::sleep(duration),
-            None => (),
-        }
+        frontend.wait_for_frame(region.frame_rate());
+        nes.set_rate_adjustment(frontend.rate_adjustment());
 
         num_frames += 1;
     }
 
+    if let Some(recording) = recording {
+        stop_recording(recording);
+    }
+    stop_movie(&mut movie, &movie_file);
+
     let duration = start.elapsed().unwrap();
 
     let freq = num_frames as f64 /
@@ -127,6 +477,17 @@ pub fn run_emulator(cart : Cartridge) {
     println!("ran at an average of {:.2} frames/sec", freq);
 }
 
+#[cfg(feature = "sdl")]
+pub fn run_emulator(cart : Cartridge, config : Config) {
+    let mut frontend = SdlFrontend::new(&config);
+    run_with_frontend(cart, config, &mut frontend);
+
+    if frontend.underruns() > 0 || frontend.overruns() > 0 {
+        println!("audio: {} underruns, {} overruns",
+                 frontend.underruns(), frontend.overruns());
+    }
+}
+
 trait Memory {
     fn storeb(&mut self, addr : u16, val : u8);
     fn loadb(&self, addr : u16) -> u8;
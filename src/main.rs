@@ -1,23 +1,183 @@
 extern crate nes;
-// use nes::cartridge::Cartridge;
-// use nes::cpu::CPU;
+use nes::config::Config;
+
+fn usage() -> ! {
+    panic!("usage: nes <rom.nes> [--config <file>] [--palette <file.pal|ntsc>] \
+            [--hue <deg>] [--saturation <x>] [--contrast <x>] \
+            [--brightness <x>] [--gamma <x>] [--region <auto|ntsc|pal|dendy>] \
+            [--pacing <audio|vsync|timer>] [--record <file.wav>] \
+            [--record_stems <on|off>] [--states <prefix>] \
+            [--movie <file.fm2>] [--record_movie <file.fm2>] \
+            [--rewind_interval <frames>] [--rewind_memory <megabytes>] \
+            [--rewind_speed <x>] [--mute <channel,...>] [--solo <channel|none>] \
+            [--<channel>_volume <x>] [--port1 <device>] [--port2 <device>] \
+            [--four_score <on|off>] [--expansion <device>] \
+            [--gamepad <bindings>] [--gamepad_deadzone <x>] \
+            [--render <seconds>] \
+            [--track <n> (nsf files)]\n       \
+            nes test-rom <rom.nes or directory>... [--timeout <seconds>]\n\
+            the options are described in the README")
+}
+
+// the .nes files in a directory and everything under it
+fn find_roms(path : &std::path::Path, roms : &mut Vec<String>) {
+    if path.is_dir() {
+        let mut entries : Vec<_> = std::fs::read_dir(path)
+            .unwrap_or_else(|e| panic!("error reading {}: {}", path.display(), e))
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+        for entry in entries {
+            if entry.is_dir() || entry.extension().map_or(false, |ext| ext == "nes") {
+                find_roms(&entry, roms);
+            }
+        }
+    }
+    else {
+        roms.push(path.to_string_lossy().into_owned());
+    }
+}
+
+// runs test roms that report their results at $6000, exiting with an error
+// if any of them didn't pass
+fn test_roms(args : &[String]) -> ! {
+    use nes::test_rom::{ run_test_rom, TestRomOutcome };
+
+    let mut roms = Vec::new();
+    let mut timeout = 60.0;
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--timeout" => timeout = rest.next()
+                .and_then(|val| val.parse::<f64>().ok())
+                .unwrap_or_else(|| usage()),
+            _ => find_roms(std::path::Path::new(arg), &mut roms),
+        }
+    }
+    if roms.is_empty() {
+        usage();
+    }
+
+    let mut failed = 0;
+    for rom in &roms {
+        // a rom the emulator can't run (like one with an unsupported
+        // mapper) fails without stopping the rest
+        let result = std::panic::catch_unwind(|| {
+            let mut nes = nes::Nes::from_rom_file(rom);
+            run_test_rom(&mut nes, timeout)
+        });
+
+        match result {
+            Ok(result) => {
+                match result.outcome {
+                    TestRomOutcome::Passed => println!("{}: passed", rom),
+                    TestRomOutcome::Failed(code) => println!("{}: failed ({})", rom, code),
+                    TestRomOutcome::TimedOut => println!("{}: timed out", rom),
+                }
+                let message = result.message.trim();
+                if !message.is_empty() {
+                    println!("{}", message);
+                }
+                if !result.passed() {
+                    failed += 1;
+                }
+            },
+            Err(_) => {
+                println!("{}: crashed", rom);
+                failed += 1;
+            },
+        }
+    }
+
+    println!("{} of {} passed", roms.len() - failed, roms.len());
+    std::process::exit(if failed == 0 { 0 } else { 1 });
+}
+
+fn exit_on_error(result : Result<(), String>) {
+    if let Err(e) = result {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+// without the sdl feature there's no window to run in
+#[cfg(not(feature = "sdl"))]
+fn no_window() -> ! {
+    panic!("built without the sdl feature, only --render is available")
+}
 
 fn main() {
     let args : Vec<String> = std::env::args().collect();
 
-    match args.len() {
-        1 => panic!("need rom filename"),
-        2 => (),
-        // 3 =>
-        //     if args[2] == String::from("-d") {
-        //         debug_mode = true
-        //     } else {
-        //         panic!("invalid argument {}", args[2])
-        //     },
-        _ => panic!("too many arguments"),
+    if args.len() < 2 {
+        usage();
+    }
+
+    if args[1] == "test-rom" {
+        test_roms(&args[2..]);
     }
 
     let filename = args[1].clone();
 
-    nes::run_emulator(nes::cartridge::Cartridge::from_ines_file(&filename));
+    // the config file is applied first so that other options override it
+    let mut config = Config::default();
+    let mut options = Vec::new();
+    // record audio without opening a window
+    let mut render_seconds = None;
+    // for nsf files, counted from 1
+    let mut track = None;
+
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        if !arg.starts_with("--") {
+            panic!("invalid argument {}", arg);
+        }
+
+        let val = match rest.next() {
+            Some(val) => val.clone(),
+            None => usage(),
+        };
+
+        match &arg[2..] {
+            "config" => config = Config::from_file(&val),
+            "render" => render_seconds = Some(val.parse::<f64>()
+                .unwrap_or_else(|_| panic!("invalid number of seconds: {}", val))),
+            "track" => track = match val.parse::<u8>() {
+                Ok(n) if n > 0 => Some(n - 1),
+                _ => panic!("invalid track: {}", val),
+            },
+            key => options.push((String::from(key), val)),
+        }
+    }
+
+    for (key, val) in options {
+        config.set(&key, &val);
+    }
+
+    // save states go next to the rom unless told otherwise
+    if config.states.is_none() {
+        let prefix = std::path::Path::new(&filename).with_extension("");
+        config.states = Some(prefix.to_string_lossy().into_owned());
+    }
+
+    if filename.ends_with(".nsf") || filename.ends_with(".nsfe") {
+        let nsf = nes::nsf::Nsf::from_file(&filename);
+        match render_seconds {
+            Some(seconds) => exit_on_error(nes::render_nsf_audio(nsf, config, track, seconds)),
+            #[cfg(feature = "sdl")]
+            None => exit_on_error(nes::run_nsf_player(nsf, config, track)),
+            #[cfg(not(feature = "sdl"))]
+            None => no_window(),
+        }
+        return;
+    }
+
+    let cart = nes::cartridge::Cartridge::from_ines_file(&filename);
+    match render_seconds {
+        Some(seconds) => exit_on_error(nes::render_audio(cart, config, seconds)),
+        #[cfg(feature = "sdl")]
+        None => nes::run_emulator(cart, config),
+        #[cfg(not(feature = "sdl"))]
+        None => no_window(),
+    }
 }
@@ -0,0 +1,387 @@
+// http://fceux.com/web/help/fm2.html
+// Input movies, in FCEUX's text format: a header of "key value" lines and
+// then a line per frame like |0|R..U...A|||, with the commands for the
+// frame (1 is a reset, 2 a power cycle) and the buttons held on each port,
+// or by each of the four players with a Four Score.
+//
+// Movies that start from a save state carry one of ours, which FCEUX can't
+// load; ones from power on work in both. The rom checksum isn't written or
+// checked.
+
+use console::Nes;
+use ports::{ DeviceKind, NUM_PORTS, NUM_PLAYERS, EXPANSION_PORT };
+use region::Region;
+
+// the order buttons are written in, from bit 7 of the controller down
+const BUTTON_CHARS : &[u8; 8] = b"RLDUTSBA";
+
+const COMMAND_RESET : u8 = 1;
+const COMMAND_POWER : u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MovieFrame {
+    pub commands : u8,
+    // for each player, in the controller's order, see Button::mask
+    pub buttons : [u8; NUM_PLAYERS],
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Movie {
+    pub rom_filename : String,
+    pub pal : bool,
+    // four players, with a Four Score in both ports
+    pub four_score : bool,
+    pub rerecords : u32,
+    pub guid : String,
+    pub comments : Vec<String>,
+    // a save state to start from, otherwise the movie starts at power on
+    pub savestate : Option<Vec<u8>>,
+    pub frames : Vec<MovieFrame>,
+}
+
+fn parse_buttons(field : &str) -> u8 {
+    field.bytes().take(8).enumerate()
+        .filter(|&(_, c)| c != b'.' && c != b' ')
+        .fold(0, |buttons, (i, _)| buttons | (0x80 >> i))
+}
+
+fn format_buttons(buttons : u8) -> String {
+    BUTTON_CHARS.iter().enumerate()
+        .map(|(i, &c)| if buttons & (0x80 >> i) != 0 { c as char } else { '.' })
+        .collect()
+}
+
+impl Movie {
+    pub fn from_fm2(text : &str) -> Result<Movie, String> {
+        let mut movie = Movie::default();
+
+        for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.starts_with('|') {
+                // |commands|port0|port1|port2|, where port2 is the
+                // famicom expansion port, or |commands|1|2|3|4|port2| for
+                // four players
+                let fields : Vec<&str> = line.split('|').collect();
+                if fields.len() < 3 {
+                    return Err(format!("invalid movie frame: {}", line));
+                }
+                let players = if movie.four_score { NUM_PLAYERS } else { NUM_PORTS };
+                let mut buttons = [0; NUM_PLAYERS];
+                for (player, b) in buttons.iter_mut().enumerate().take(players) {
+                    *b = parse_buttons(fields.get(2 + player).unwrap_or(&""));
+                }
+                movie.frames.push(MovieFrame {
+                    commands : fields[1].trim().parse()
+                        .map_err(|_| format!("invalid movie frame: {}", line))?,
+                    buttons : buttons,
+                });
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let val = parts.next().unwrap_or("").trim();
+            match key {
+                "romFilename" => movie.rom_filename = String::from(val),
+                "palFlag" => movie.pal = val == "1",
+                "rerecordCount" => movie.rerecords = val.parse().unwrap_or(0),
+                "guid" => movie.guid = String::from(val),
+                "comment" => movie.comments.push(String::from(val)),
+                "binary" if val == "1" => return Err(String::from("binary movies aren't supported")),
+                "fourscore" => movie.four_score = val == "1",
+                "savestate" if !val.is_empty() => {
+                    let encoded = val.trim_start_matches("base64:");
+                    movie.savestate = Some(base64_decode(encoded)
+                        .ok_or_else(|| String::from("invalid save state in movie"))?);
+                },
+                _ => (),
+            }
+        }
+        Ok(movie)
+    }
+
+    pub fn to_fm2(&self) -> String {
+        let mut text = String::new();
+        text += "version 3\n";
+        text += "emuVersion 22020\n";
+        text += &format!("rerecordCount {}\n", self.rerecords);
+        text += &format!("palFlag {}\n", self.pal as u8);
+        text += &format!("romFilename {}\n", self.rom_filename);
+        text += &format!("guid {}\n", self.guid);
+        if self.four_score {
+            text += "fourscore 1\nport0 0\nport1 0\nport2 0\n";
+        } else {
+            text += "fourscore 0\nport0 1\nport1 1\nport2 0\n";
+        }
+        for comment in &self.comments {
+            text += &format!("comment {}\n", comment);
+        }
+        if let Some(ref state) = self.savestate {
+            text += &format!("savestate base64:{}\n", base64_encode(state));
+        }
+
+        let players = if self.four_score { NUM_PLAYERS } else { NUM_PORTS };
+        for frame in &self.frames {
+            text += &format!("|{}|", frame.commands);
+            for &buttons in &frame.buttons[..players] {
+                text += &format_buttons(buttons);
+                text += "|";
+            }
+            text += "|\n";
+        }
+        text
+    }
+
+    pub fn from_file(filename : &str) -> Result<Movie, String> {
+        let text = ::std::fs::read_to_string(filename)
+            .map_err(|e| format!("error reading {}: {}", filename, e))?;
+        Movie::from_fm2(&text)
+    }
+
+    pub fn save(&self, filename : &str) -> Result<(), String> {
+        ::std::fs::write(filename, self.to_fm2())
+            .map_err(|e| format!("error writing {}: {}", filename, e))
+    }
+}
+
+pub fn next_free_filename() -> String {
+    (1..).map(|n| format!("movie-{}.fm2", n))
+        .find(|name| !::std::path::Path::new(name).exists())
+        .unwrap()
+}
+
+// FCEUX writes these as a GUID, but anything unique does
+fn new_guid() -> String {
+    use std::time::{ SystemTime, UNIX_EPOCH };
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos()).unwrap_or(0);
+    let bytes = (nanos ^ 0x5DEE_CE66_D1CE_4E5B_9E37_79B9_7F4A_7C15).to_le_bytes();
+    let hex : String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+// fm2 only has a flag for PAL, and a Dendy times things differently from
+// both, so its movies wouldn't play back the same anywhere
+fn movie_pal(region : Region) -> Result<bool, String> {
+    match region {
+        Region::Ntsc  => Ok(false),
+        Region::Pal   => Ok(true),
+        Region::Dendy => Err(String::from("movies can't be recorded or played on a Dendy")),
+    }
+}
+
+// Runs a console along with a movie, either adding the input of each frame
+// to it or feeding the input from it
+pub enum MovieState {
+    Recording(Movie),
+    Playing { movie : Movie, frame : usize },
+}
+
+impl MovieState {
+    // starts recording from the console's current state, or from power on
+    pub fn record(nes : &mut Nes, rom_filename : &str,
+                  from_power_on : bool) -> Result<MovieState, String> {
+        let pal = movie_pal(nes.region())?;
+        let savestate = if from_power_on {
+            nes.power_cycle();
+            None
+        } else {
+            Some(nes.save_state())
+        };
+
+        Ok(MovieState::Recording(Movie {
+            rom_filename : String::from(rom_filename),
+            pal : pal,
+            four_score : nes.port_device(0) == DeviceKind::FourScore,
+            guid : new_guid(),
+            savestate : savestate,
+            ..Movie::default()
+        }))
+    }
+
+    // the console is set up the way the movie was recorded, so it plays
+    // back the same
+    pub fn play(nes : &mut Nes, movie : Movie) -> Result<MovieState, String> {
+        if movie.pal != movie_pal(nes.region())? {
+            return Err(format!("the movie is for {} consoles, this one is {:?}",
+                               if movie.pal { "PAL" } else { "NTSC" }, nes.region()));
+        }
+
+        let kind = if movie.four_score { DeviceKind::FourScore } else { DeviceKind::Controller };
+        for port in 0..NUM_PORTS {
+            nes.plug(port, kind);
+        }
+        nes.plug(EXPANSION_PORT, DeviceKind::None);
+
+        match movie.savestate {
+            Some(ref state) => nes.load_state(state)?,
+            None => nes.power_cycle(),
+        }
+        Ok(MovieState::Playing { movie : movie, frame : 0 })
+    }
+
+    // call before running each frame. Returns false once a movie being
+    // played has run out, after which the input is left alone
+    pub fn before_frame(&mut self, nes : &mut Nes) -> bool {
+        match *self {
+            MovieState::Recording(ref mut movie) => {
+                let mut buttons = [0; NUM_PLAYERS];
+                for (player, b) in buttons.iter_mut().enumerate() {
+                    *b = nes.player_buttons(player);
+                }
+                movie.frames.push(MovieFrame { commands : 0, buttons : buttons });
+                true
+            },
+            MovieState::Playing { ref movie, ref mut frame } => {
+                let input = match movie.frames.get(*frame) {
+                    Some(&input) => input,
+                    None => return false,
+                };
+                *frame += 1;
+
+                if input.commands & COMMAND_POWER != 0 {
+                    nes.power_cycle();
+                }
+                else if input.commands & COMMAND_RESET != 0 {
+                    nes.reset();
+                }
+                for (player, &buttons) in input.buttons.iter().enumerate() {
+                    nes.set_player_buttons(player, buttons);
+                }
+                true
+            },
+        }
+    }
+
+    // frames recorded or played so far
+    pub fn frame(&self) -> usize {
+        match *self {
+            MovieState::Recording(ref movie) => movie.frames.len(),
+            MovieState::Playing { frame, .. } => frame,
+        }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        match *self {
+            MovieState::Recording(ref movie) => movie,
+            MovieState::Playing { ref movie, .. } => movie,
+        }
+    }
+}
+
+// https://tools.ietf.org/html/rfc4648#section-4
+const BASE64_CHARS : &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data : &[u8]) -> String {
+    let mut out = String::new();
+    for group in data.chunks(3) {
+        let bits = group.iter().enumerate()
+            .fold(0u32, |bits, (i, &b)| bits | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(BASE64_CHARS[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text : &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut num_bits = 0;
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let val = BASE64_CHARS.iter().position(|&b| b == c)? as u32;
+        bits = (bits << 6) | val;
+        num_bits += 6;
+        if num_bits >= 8 {
+            num_bits -= 8;
+            out.push((bits >> num_bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_decode("Zm9vYg==").unwrap(), b"foob");
+        assert_eq!(base64_decode("Zm9vYmE").unwrap(), b"fooba");
+        assert!(base64_decode("Zm9v!").is_none());
+    }
+
+    #[test]
+    fn fceux_movie() {
+        let text = "version 3\n\
+                    emuVersion 22020\n\
+                    rerecordCount 12\n\
+                    palFlag 0\n\
+                    romFilename Some Game\n\
+                    romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n\
+                    guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B\n\
+                    fourscore 0\n\
+                    port0 1\n\
+                    port1 1\n\
+                    port2 0\n\
+                    comment author someone\n\
+                    |0|........|........||\n\
+                    |0|....T...|........||\n\
+                    |1|R......A|.......A||\n\
+                    |2|RLDUTSBA|........||\n";
+        let movie = Movie::from_fm2(text).unwrap();
+        assert_eq!(movie.rom_filename, "Some Game");
+        assert_eq!(movie.rerecords, 12);
+        assert!(!movie.pal);
+        assert_eq!(movie.comments, vec![String::from("author someone")]);
+        assert_eq!(movie.savestate, None);
+        assert_eq!(movie.frames, vec![
+            MovieFrame { commands : 0, buttons : [0x00, 0x00, 0x00, 0x00] },
+            MovieFrame { commands : 0, buttons : [0x08, 0x00, 0x00, 0x00] },
+            MovieFrame { commands : 1, buttons : [0x81, 0x01, 0x00, 0x00] },
+            MovieFrame { commands : 2, buttons : [0xFF, 0x00, 0x00, 0x00] },
+        ]);
+
+        let movie = Movie::from_fm2("fourscore 1\n|0|.......A|......B.|.....S..|....T...||\n").unwrap();
+        assert!(movie.four_score);
+        assert_eq!(movie.frames[0].buttons, [0x01, 0x02, 0x04, 0x08]);
+
+        assert!(Movie::from_fm2("binary 1\n").is_err());
+        assert!(Movie::from_fm2("|x|........|||\n").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let movie = Movie {
+            rom_filename : String::from("game.nes"),
+            pal : true,
+            four_score : false,
+            rerecords : 3,
+            guid : new_guid(),
+            comments : vec![String::from("a comment")],
+            savestate : Some(vec![0, 1, 2, 254, 255]),
+            frames : (0..=255).map(|b| MovieFrame { commands : 0, buttons : [b, !b, 0, 0] }).collect(),
+        };
+        let text = movie.to_fm2();
+        assert!(text.contains("\n|0|R.D..S.A|.L.UT.B.||\n"));
+        assert_eq!(Movie::from_fm2(&text).unwrap(), movie);
+
+        let movie = Movie {
+            four_score : true,
+            frames : vec![MovieFrame { commands : 0, buttons : [0x80, 0x40, 0x20, 0x10] }],
+            ..movie
+        };
+        let text = movie.to_fm2();
+        assert!(text.contains("\n|0|R.......|.L......|..D.....|...U....||\n"));
+        assert_eq!(Movie::from_fm2(&text).unwrap(), movie);
+    }
+}
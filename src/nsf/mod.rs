@@ -0,0 +1,343 @@
+// https://wiki.nesdev.com/w/index.php/NSF
+// https://wiki.nesdev.com/w/index.php/NSFe
+//
+// NSF files are music ripped from games: the game's sound driver and data,
+// plus the addresses of an INIT routine that starts a track and a PLAY
+// routine that gets called once per frame. NSFe is a chunk based version
+// of the same thing with extra metadata like track titles and lengths.
+
+#[cfg(test)]
+mod tests;
+mod player;
+
+pub use self::player::NsfPlayer;
+
+use region::Region;
+use apu::ExpansionChip;
+
+const NSF_MAGIC : &[u8] = b"NESM\x1A";
+const NSFE_MAGIC : &[u8] = b"NSFE";
+const NSF_HEADER_SIZE : usize = 0x80;
+
+// default play rates, in microseconds between PLAY calls
+const NTSC_SPEED : u16 = 16639;
+const PAL_SPEED : u16 = 19997;
+
+// expansion sound chip flags
+pub const CHIP_VRC6 : u8 = 0x01;
+pub const CHIP_VRC7 : u8 = 0x02;
+pub const CHIP_FDS  : u8 = 0x04;
+pub const CHIP_MMC5 : u8 = 0x08;
+pub const CHIP_N163 : u8 = 0x10;
+pub const CHIP_5B   : u8 = 0x20;
+
+static CHIPS : [(u8, ExpansionChip); 6] = [
+    (CHIP_VRC6, ExpansionChip::Vrc6), (CHIP_VRC7, ExpansionChip::Vrc7),
+    (CHIP_FDS, ExpansionChip::Fds), (CHIP_MMC5, ExpansionChip::Mmc5),
+    (CHIP_N163, ExpansionChip::N163), (CHIP_5B, ExpansionChip::Sunsoft5B),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Nsf {
+    pub num_tracks : u8,
+    // counted from 0
+    pub first_track : u8,
+
+    pub load_addr : u16,
+    pub init_addr : u16,
+    pub play_addr : u16,
+
+    pub title : String,
+    pub artist : String,
+    pub copyright : String,
+
+    // microseconds between PLAY calls
+    pub ntsc_speed : u16,
+    pub pal_speed : u16,
+
+    // None when the file doesn't use bank switching
+    pub bank_init : Option<[u8; 8]>,
+
+    pub pal : bool,
+    // plays on either region
+    pub dual_region : bool,
+    pub chips : u8,
+
+    // from NSFe files, indexed by track. times are in milliseconds
+    pub track_titles : Vec<String>,
+    pub track_times : Vec<Option<u32>>,
+    pub track_fades : Vec<Option<u32>>,
+
+    pub data : Vec<u8>,
+}
+
+fn read_u16(data : &[u8]) -> u16 {
+    data[0] as u16 | ((data[1] as u16) << 8)
+}
+
+fn read_u32(data : &[u8]) -> u32 {
+    read_u16(data) as u32 | ((read_u16(&data[2..]) as u32) << 16)
+}
+
+// header strings are padded with zeros
+fn read_string(data : &[u8]) -> String {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+// a list of zero terminated strings
+fn read_strings(data : &[u8]) -> Vec<String> {
+    let mut strings : Vec<String> = data.split(|&b| b == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect();
+
+    // the last string's terminator leaves an empty one at the end
+    if data.last() == Some(&0) {
+        strings.pop();
+    }
+    strings
+}
+
+// negative times mean unknown
+fn read_times(data : &[u8]) -> Vec<Option<u32>> {
+    data.chunks(4)
+        .filter(|chunk| chunk.len() == 4)
+        .map(|chunk| {
+            let time = read_u32(chunk) as i32;
+            if time < 0 { None } else { Some(time as u32) }
+        })
+        .collect()
+}
+
+fn read_banks(data : &[u8]) -> Option<[u8; 8]> {
+    if data.iter().all(|&b| b == 0) {
+        return None;
+    }
+
+    let mut banks = [0; 8];
+    for (bank, &val) in banks.iter_mut().zip(data) {
+        *bank = val;
+    }
+    Some(banks)
+}
+
+impl Nsf {
+    pub fn from_file(filename : &str) -> Nsf {
+        use std::fs::File;
+        use std::io::prelude::*;
+
+        let mut data = Vec::new();
+        File::open(filename).expect("error opening nsf file")
+            .read_to_end(&mut data).expect("error reading nsf file");
+
+        match Nsf::from_bytes(&data) {
+            Ok(nsf) => nsf,
+            Err(msg) => panic!("{}: {}", filename, msg),
+        }
+    }
+
+    pub fn from_bytes(data : &[u8]) -> Result<Nsf, String> {
+        if data.starts_with(NSF_MAGIC) {
+            Nsf::from_nsf_bytes(data)
+        }
+        else if data.starts_with(NSFE_MAGIC) {
+            Nsf::from_nsfe_bytes(data)
+        }
+        else {
+            Err(String::from("not an nsf or nsfe file"))
+        }
+    }
+
+    fn from_nsf_bytes(data : &[u8]) -> Result<Nsf, String> {
+        if data.len() < NSF_HEADER_SIZE {
+            return Err(String::from("nsf header is cut off"));
+        }
+
+        let header = &data[..NSF_HEADER_SIZE];
+        let num_tracks = header[0x06];
+        if num_tracks == 0 {
+            return Err(String::from("nsf has no tracks"));
+        }
+
+        let nsf = Nsf {
+            num_tracks : num_tracks,
+            first_track : header[0x07].saturating_sub(1),
+            load_addr : read_u16(&header[0x08..]),
+            init_addr : read_u16(&header[0x0A..]),
+            play_addr : read_u16(&header[0x0C..]),
+            title : read_string(&header[0x0E..0x2E]),
+            artist : read_string(&header[0x2E..0x4E]),
+            copyright : read_string(&header[0x4E..0x6E]),
+            ntsc_speed : read_u16(&header[0x6E..]),
+            bank_init : read_banks(&header[0x70..0x78]),
+            pal_speed : read_u16(&header[0x78..]),
+            pal : header[0x7A] & 0x01 != 0,
+            dual_region : header[0x7A] & 0x02 != 0,
+            chips : header[0x7B],
+            track_titles : Vec::new(),
+            track_times : Vec::new(),
+            track_fades : Vec::new(),
+            data : data[NSF_HEADER_SIZE..].to_vec(),
+        };
+
+        nsf.validate()
+    }
+
+    fn from_nsfe_bytes(data : &[u8]) -> Result<Nsf, String> {
+        let mut nsf = Nsf {
+            num_tracks : 1,
+            first_track : 0,
+            load_addr : 0,
+            init_addr : 0,
+            play_addr : 0,
+            title : String::new(),
+            artist : String::new(),
+            copyright : String::new(),
+            ntsc_speed : NTSC_SPEED,
+            pal_speed : PAL_SPEED,
+            bank_init : None,
+            pal : false,
+            dual_region : false,
+            chips : 0,
+            track_titles : Vec::new(),
+            track_times : Vec::new(),
+            track_fades : Vec::new(),
+            data : Vec::new(),
+        };
+
+        let mut found_info = false;
+        let mut found_data = false;
+        let mut rest = &data[NSFE_MAGIC.len()..];
+
+        loop {
+            if rest.len() < 8 {
+                return Err(String::from("nsfe is missing its NEND chunk"));
+            }
+
+            let len = read_u32(rest) as usize;
+            let id = &rest[4..8];
+            rest = &rest[8..];
+
+            if rest.len() < len {
+                return Err(format!("nsfe chunk {} is cut off",
+                                   String::from_utf8_lossy(id)));
+            }
+            let chunk = &rest[..len];
+            rest = &rest[len..];
+
+            match id {
+                b"INFO" => {
+                    if chunk.len() < 8 {
+                        return Err(String::from("nsfe INFO chunk is too short"));
+                    }
+                    nsf.load_addr = read_u16(&chunk[0..]);
+                    nsf.init_addr = read_u16(&chunk[2..]);
+                    nsf.play_addr = read_u16(&chunk[4..]);
+                    nsf.pal = chunk[6] & 0x01 != 0;
+                    nsf.dual_region = chunk[6] & 0x02 != 0;
+                    nsf.chips = chunk[7];
+                    if chunk.len() > 8 {
+                        nsf.num_tracks = chunk[8];
+                    }
+                    if chunk.len() > 9 {
+                        nsf.first_track = chunk[9];
+                    }
+                    found_info = true;
+                },
+                b"DATA" => {
+                    nsf.data = chunk.to_vec();
+                    found_data = true;
+                },
+                b"BANK" => nsf.bank_init = read_banks(chunk),
+                b"RATE" => {
+                    if chunk.len() >= 2 {
+                        nsf.ntsc_speed = read_u16(chunk);
+                    }
+                    if chunk.len() >= 4 {
+                        nsf.pal_speed = read_u16(&chunk[2..]);
+                    }
+                },
+                b"NEND" => break,
+                b"auth" => {
+                    let mut strings = read_strings(chunk).into_iter();
+                    nsf.title = strings.next().unwrap_or_default();
+                    nsf.artist = strings.next().unwrap_or_default();
+                    nsf.copyright = strings.next().unwrap_or_default();
+                },
+                b"tlbl" => nsf.track_titles = read_strings(chunk),
+                b"time" => nsf.track_times = read_times(chunk),
+                b"fade" => nsf.track_fades = read_times(chunk),
+
+                // chunks starting with a capital letter are required to
+                // play the file, anything else can be skipped
+                _ if id[0].is_ascii_uppercase() =>
+                    return Err(format!("unsupported nsfe chunk {}",
+                                       String::from_utf8_lossy(id))),
+                _ => (),
+            }
+        }
+
+        if !found_info || !found_data {
+            return Err(String::from("nsfe is missing its INFO or DATA chunk"));
+        }
+
+        nsf.validate()
+    }
+
+    fn validate(self) -> Result<Nsf, String> {
+        if self.load_addr < 0x8000 && self.bank_init.is_none() {
+            return Err(format!("unsupported load address ${:04X}", self.load_addr));
+        }
+        if self.first_track >= self.num_tracks {
+            return Err(format!("invalid starting track {}", self.first_track + 1));
+        }
+        Ok(self)
+    }
+
+    // the region the music was made for, None if it plays on either
+    pub fn region(&self) -> Option<Region> {
+        if self.dual_region {
+            None
+        }
+        else if self.pal {
+            Some(Region::Pal)
+        }
+        else {
+            Some(Region::Ntsc)
+        }
+    }
+
+    // microseconds between PLAY calls
+    pub fn play_speed(&self, region : Region) -> u16 {
+        let (speed, default) = match region {
+            Region::Ntsc => (self.ntsc_speed, NTSC_SPEED),
+            Region::Pal | Region::Dendy => (self.pal_speed, PAL_SPEED),
+        };
+        if speed == 0 { default } else { speed }
+    }
+
+    // the sound chips the music uses
+    pub fn expansion_chips(&self) -> Vec<ExpansionChip> {
+        CHIPS.iter()
+            .filter(|&&(flag, _)| self.chips & flag != 0)
+            .map(|&(_, chip)| chip)
+            .collect()
+    }
+
+    pub fn chip_names(&self) -> Vec<&'static str> {
+        self.expansion_chips().iter().map(|chip| chip.name()).collect()
+    }
+
+    pub fn track_title(&self, track : u8) -> Option<&str> {
+        self.track_titles.get(track as usize).map(|title| title.as_str())
+    }
+
+    // in milliseconds, when the file says
+    pub fn track_time(&self, track : u8) -> Option<u32> {
+        self.track_times.get(track as usize).cloned().unwrap_or(None)
+    }
+
+    pub fn track_fade(&self, track : u8) -> Option<u32> {
+        self.track_fades.get(track as usize).cloned().unwrap_or(None)
+    }
+}
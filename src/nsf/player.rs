@@ -0,0 +1,212 @@
+// Plays nsf files without a driver program: the file's data is mapped into
+// an otherwise empty system, and the INIT and PLAY routines are called
+// directly at the rate the file asks for.
+// https://wiki.nesdev.com/w/index.php/NSF#Initializing_a_tune
+
+use super::Nsf;
+use cartridge::Cartridge;
+use cpu::CPU;
+use ppu::PPU;
+use apu::APU;
+use ports::{ Ports, DeviceKind, NUM_PORTS };
+use region::Region;
+use ComponentRc;
+
+// calls return here. nothing is mapped at this address, and the cpu is
+// never stepped while its pc is here
+const RETURN_ADDR : u16 = 0x5FF6;
+
+// used when a track has a length but no fade time
+const DEFAULT_FADE_MS : u32 = 8000;
+
+pub struct NsfPlayer {
+    nsf : Nsf,
+    cpu : CPU,
+    apu : ComponentRc<APU>,
+    region : Region,
+
+    track : u8,
+    // in cpu cycles
+    play_period : f64,
+    until_play : f64,
+    // whether INIT or PLAY hasn't returned yet
+    in_call : bool,
+    // since the track started
+    samples_played : usize,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf : Nsf, region : Region, sample_rate : u32) -> NsfPlayer {
+        let cart = ComponentRc::new(Cartridge::from_nsf(&nsf));
+        let ppu  = ComponentRc::new(PPU::new(cart.new_ref()));
+        let apu  = ComponentRc::new(APU::new());
+        apu.borrow_mut().set_sample_rate(sample_rate);
+        let ports = ComponentRc::new(Ports::new([DeviceKind::None; NUM_PORTS]));
+
+        let mut cpu = CPU::new(cart, ppu, apu.new_ref(), ports);
+        cpu.set_region(region);
+
+        if !nsf.chip_names().is_empty() {
+            println!("expansion audio: {}", nsf.chip_names().join(", "));
+        }
+
+        let play_period = nsf.play_speed(region) as f64 / 1_000_000.0 *
+                          region.cpu_clock_rate();
+        let first_track = nsf.first_track;
+
+        let mut player = NsfPlayer {
+            nsf : nsf,
+            cpu : cpu,
+            apu : apu,
+            region : region,
+            track : 0,
+            play_period : play_period,
+            until_play : 0.0,
+            in_call : false,
+            samples_played : 0,
+        };
+
+        player.start_track(first_track);
+        player
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn apu(&self) -> &ComponentRc<APU> {
+        &self.apu
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    // tracks are counted from 0, and callers check the track is in the file
+    pub fn start_track(&mut self, track : u8) {
+        assert!(track < self.nsf.num_tracks, "invalid track {}", track + 1);
+
+        for addr in 0x0000..0x0800 {
+            self.cpu.write_mem(addr, 0);
+        }
+        for addr in 0x6000..0x8000 {
+            self.cpu.write_mem(addr, 0);
+        }
+
+        for addr in 0x4000..0x4014 {
+            self.cpu.write_mem(addr, 0);
+        }
+        self.cpu.write_mem(0x4015, 0x00);
+        self.cpu.write_mem(0x4015, 0x0F);
+        self.cpu.write_mem(0x4017, 0x40);
+
+        if let Some(banks) = self.nsf.bank_init {
+            for (i, &bank) in banks.iter().enumerate() {
+                self.cpu.write_mem(0x5FF8 + i as u16, bank);
+            }
+        }
+
+        // X says which region to play for
+        let x = match self.region {
+            Region::Ntsc => 0,
+            Region::Pal | Region::Dendy => 1,
+        };
+        let init_addr = self.nsf.init_addr;
+        self.cpu.call(init_addr, track, x, RETURN_ADDR);
+
+        self.track = track;
+        self.in_call = true;
+        self.until_play = self.play_period;
+        self.samples_played = 0;
+        self.apu.borrow_mut().take_samples();
+    }
+
+    // runs for about the given number of cpu cycles
+    pub fn run(&mut self, cycles : usize) {
+        let mut remaining = cycles as f64;
+
+        while remaining > 0.0 {
+            if self.until_play <= 0.0 {
+                self.until_play += self.play_period;
+
+                // if the last call is still going, this one gets skipped
+                if !self.in_call {
+                    let play_addr = self.nsf.play_addr;
+                    self.cpu.call(play_addr, 0, 0, RETURN_ADDR);
+                    self.in_call = true;
+                }
+            }
+
+            let ran = if self.in_call {
+                let ran = self.cpu.step();
+                if self.cpu.get_pc() == RETURN_ADDR {
+                    self.in_call = false;
+                }
+                ran
+            }
+            else {
+                let ran = self.until_play.min(remaining).ceil().max(1.0) as usize;
+                self.cpu.idle(ran);
+                ran
+            };
+
+            remaining -= ran as f64;
+            self.until_play -= ran as f64;
+        }
+    }
+
+    // runs for one video frame's worth of time
+    pub fn run_frame(&mut self) {
+        let cycles = self.region.cpu_clock_rate() / self.region.frame_rate();
+        self.run(cycles as usize);
+    }
+
+    #[cfg(test)]
+    pub fn read_mem(&self, addr : u16) -> u8 {
+        self.cpu.read_mem(addr)
+    }
+
+    // (start, length) of the fade out at the end of the track, in samples
+    fn fade(&self) -> Option<(usize, usize)> {
+        let ms_to_samples = |ms : u32| {
+            (ms as u64 * self.apu.borrow().sample_rate() as u64 / 1000) as usize
+        };
+
+        self.nsf.track_time(self.track).map(|time| {
+            let fade = self.nsf.track_fade(self.track).unwrap_or(DEFAULT_FADE_MS);
+            (ms_to_samples(time), ms_to_samples(fade))
+        })
+    }
+
+    // like APU::take_samples, with the end of the track faded out
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        let mut samples = self.apu.borrow_mut().take_samples();
+
+        if let Some((start, len)) = self.fade() {
+            for (i, sample) in samples.iter_mut().enumerate() {
+                let pos = self.samples_played + i;
+                if pos >= start + len {
+                    *sample = 0.0;
+                }
+                else if pos >= start {
+                    *sample *= 1.0 - (pos - start) as f32 / len as f32;
+                }
+            }
+        }
+
+        self.samples_played += samples.len();
+        samples
+    }
+
+    // when the file says how long the track is, whether it's over
+    pub fn track_finished(&self) -> bool {
+        match self.fade() {
+            Some((start, len)) => self.samples_played >= start + len,
+            None => false,
+        }
+    }
+}
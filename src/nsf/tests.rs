@@ -0,0 +1,161 @@
+use super::{ Nsf, NsfPlayer, CHIP_VRC6, CHIP_N163 };
+use cartridge::Cartridge;
+use region::Region;
+use Memory;
+
+// INIT stores the track number at $0200, PLAY counts its calls at $0201
+fn test_code() -> Vec<u8> {
+    let mut code = vec![0; 0x20];
+    code[0x00..0x04].copy_from_slice(&[0x8D, 0x00, 0x02, 0x60]);
+    code[0x10..0x14].copy_from_slice(&[0xEE, 0x01, 0x02, 0x60]);
+    code
+}
+
+fn test_nsf_bytes() -> Vec<u8> {
+    let mut data = vec![0; 0x80];
+    data[0x00..0x05].copy_from_slice(b"NESM\x1A");
+    data[0x05] = 1;
+    // 3 tracks, starting on the 2nd
+    data[0x06] = 3;
+    data[0x07] = 2;
+    data[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x10, 0x80]);
+    data[0x0E..0x13].copy_from_slice(b"Title");
+    data[0x2E..0x34].copy_from_slice(b"Artist");
+    data[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+    data[0x7B] = CHIP_VRC6 | CHIP_N163;
+
+    data.extend_from_slice(&test_code());
+    data
+}
+
+fn chunk(id : &[u8], data : &[u8]) -> Vec<u8> {
+    let mut ret = (data.len() as u32).to_le_bytes().to_vec();
+    ret.extend_from_slice(id);
+    ret.extend_from_slice(data);
+    ret
+}
+
+#[test]
+fn nsf_header() {
+    let nsf = Nsf::from_bytes(&test_nsf_bytes()).unwrap();
+
+    assert_eq!(nsf.num_tracks, 3);
+    assert_eq!(nsf.first_track, 1);
+    assert_eq!(nsf.load_addr, 0x8000);
+    assert_eq!(nsf.play_addr, 0x8010);
+    assert_eq!(nsf.title, "Title");
+    assert_eq!(nsf.artist, "Artist");
+    assert_eq!(nsf.bank_init, None);
+    assert_eq!(nsf.region(), Some(Region::Ntsc));
+    assert_eq!(nsf.chip_names(), vec!["VRC6", "Namco 163"]);
+    assert_eq!(nsf.data.len(), 0x20);
+
+    assert!(Nsf::from_bytes(&test_nsf_bytes()[..0x40]).is_err());
+    assert!(Nsf::from_bytes(b"not an nsf").is_err());
+}
+
+#[test]
+fn nsfe_chunks() {
+    let mut data = b"NSFE".to_vec();
+    data.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x10, 0x80, 0x01, 0x00, 2, 0]));
+    data.extend(chunk(b"DATA", &test_code()));
+    data.extend(chunk(b"auth", b"Game\0Composer\0Company\0Ripper\0"));
+    data.extend(chunk(b"tlbl", b"Intro\0Boss\0"));
+    data.extend(chunk(b"time", &[0x10, 0x27, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]));
+    data.extend(chunk(b"fade", &[0xE8, 0x03, 0, 0]));
+    // unknown optional chunks are skipped
+    data.extend(chunk(b"xtra", &[1, 2, 3]));
+    data.extend(chunk(b"NEND", &[]));
+
+    let nsf = Nsf::from_bytes(&data).unwrap();
+    assert_eq!(nsf.num_tracks, 2);
+    assert_eq!(nsf.region(), Some(Region::Pal));
+    assert_eq!(nsf.title, "Game");
+    assert_eq!(nsf.copyright, "Company");
+    assert_eq!(nsf.track_title(1), Some("Boss"));
+    assert_eq!(nsf.track_time(0), Some(10000));
+    assert_eq!(nsf.track_time(1), None);
+    assert_eq!(nsf.track_fade(0), Some(1000));
+    assert_eq!(nsf.track_fade(1), None);
+    assert_eq!(nsf.data, test_code());
+
+    // unknown required chunks aren't
+    let mut bad = data.clone();
+    let end = bad.len() - 8;
+    bad.truncate(end);
+    bad.extend(chunk(b"WHAT", &[]));
+    bad.extend(chunk(b"NEND", &[]));
+    assert!(Nsf::from_bytes(&bad).is_err());
+}
+
+#[test]
+fn bank_switching() {
+    let mut data = test_nsf_bytes();
+    // load at $8100, the data starts 256 bytes into bank 0
+    data[0x08..0x0A].copy_from_slice(&[0x00, 0x81]);
+    data[0x70..0x78].copy_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+    data.resize(0x80 + 0x1000, 0);
+    data.push(0xAB);
+
+    let nsf = Nsf::from_bytes(&data).unwrap();
+    let mut cart = Cartridge::from_nsf(&nsf);
+
+    // bank 1 is at $8000 to start
+    assert_eq!(cart.loadb(0x8100), 0xAB);
+    assert_eq!(cart.loadb(0x9100), 0x8D);
+
+    cart.storeb(0x5FF8, 0);
+    assert_eq!(cart.loadb(0x8100), 0x8D);
+
+    // and there's ram at $6000
+    cart.storeb(0x6000, 0x12);
+    assert_eq!(cart.loadb(0x6000), 0x12);
+}
+
+#[test]
+fn player() {
+    let nsf = Nsf::from_bytes(&test_nsf_bytes()).unwrap();
+    let mut p = NsfPlayer::new(nsf, Region::Ntsc, 44100);
+
+    // starts on the file's first track
+    assert_eq!(p.track(), 1);
+
+    // PLAY gets called 60 times a second
+    p.run(1_789_773);
+    assert_eq!(p.read_mem(0x0200), 1);
+    assert!(p.read_mem(0x0201) >= 59 && p.read_mem(0x0201) <= 60);
+
+    let samples = p.take_samples();
+    assert!(samples.len() >= 44090 && samples.len() <= 44100);
+    assert!(!p.track_finished());
+
+    // starting another track clears ram
+    p.start_track(2);
+    p.run(29830);
+    assert_eq!(p.read_mem(0x0200), 2);
+    assert_eq!(p.read_mem(0x0201), 1);
+}
+
+#[test]
+fn track_fade() {
+    let mut data = b"NSFE".to_vec();
+    data.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x10, 0x80, 0, 0]));
+    data.extend(chunk(b"DATA", &test_code()));
+    // 100ms long with a 100ms fade
+    data.extend(chunk(b"time", &100u32.to_le_bytes()));
+    data.extend(chunk(b"fade", &100u32.to_le_bytes()));
+    data.extend(chunk(b"NEND", &[]));
+
+    let nsf = Nsf::from_bytes(&data).unwrap();
+    let mut p = NsfPlayer::new(nsf, Region::Ntsc, 10000);
+
+    p.run(1_789_773 / 10);
+    assert!(!p.track_finished());
+    p.take_samples();
+
+    p.run(1_789_773 / 5);
+    let samples = p.take_samples();
+    assert!(p.track_finished());
+    // silence after the fade
+    assert_eq!(samples[samples.len() - 1], 0.0);
+}
@@ -0,0 +1,69 @@
+// Text drawn over the picture, like the movie frame counter. Only digits
+// and a few letters, in a 3x5 font doubled in size.
+
+use ppu::{ SCREEN_WIDTH, SCREEN_HEIGHT, BYTES_PER_PIXEL };
+
+const SCALE : usize = 2;
+const CHAR_WIDTH : usize = 3;
+const CHAR_HEIGHT : usize = 5;
+
+// each row is 3 bits, leftmost pixel in bit 2
+fn glyph(c : char) -> Option<[u8; CHAR_HEIGHT]> {
+    Some(match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'P' => [0b111, 0b101, 0b111, 0b100, 0b100],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        ' ' => [0; CHAR_HEIGHT],
+        _   => return None,
+    })
+}
+
+fn set_pixel(pixels : &mut [u8], x : usize, y : usize, color : u8) {
+    if x < SCREEN_WIDTH && y < SCREEN_HEIGHT {
+        let i = (y * SCREEN_WIDTH + x) * BYTES_PER_PIXEL;
+        for b in &mut pixels[i..i + BYTES_PER_PIXEL] {
+            *b = color;
+        }
+    }
+}
+
+// white text on a black box, with its top left corner at x, y. Characters
+// without a glyph are skipped
+pub fn draw_text(pixels : &mut [u8], x : usize, y : usize, text : &str) {
+    let glyphs : Vec<_> = text.chars().filter_map(glyph).collect();
+
+    // a pixel of border all around, and between characters
+    let width = (glyphs.len() * (CHAR_WIDTH + 1) + 1) * SCALE;
+    let height = (CHAR_HEIGHT + 2) * SCALE;
+    for py in y..y + height {
+        for px in x..x + width {
+            set_pixel(pixels, px, py, 0x00);
+        }
+    }
+
+    for (n, rows) in glyphs.iter().enumerate() {
+        let left = x + (n * (CHAR_WIDTH + 1) + 1) * SCALE;
+        let top = y + SCALE;
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..CHAR_WIDTH {
+                if bits & (0b100 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..SCALE {
+                    for dx in 0..SCALE {
+                        set_pixel(pixels, left + col * SCALE + dx, top + row * SCALE + dy, 0xFF);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// https://wiki.nesdev.com/w/index.php/PPU_palettes
+//
+// The PPU outputs a 6 bit color index plus 3 emphasis bits from PPUMASK,
+// so a full master palette has 64 * 8 entries. Entries are stored as BGR
+// triples to match the pixel format used by the graphics module.
+
+pub const NUM_COLORS : usize = 64;
+pub const NUM_EMPHASIS : usize = 8;
+pub const NUM_ENTRIES : usize = NUM_COLORS * NUM_EMPHASIS;
+
+// sizes of the two common .pal file layouts
+const PAL_FILE_SIZE : usize = NUM_COLORS * 3;
+const PAL_FILE_SIZE_EMPHASIS : usize = NUM_ENTRIES * 3;
+
+// how much the non-emphasized channels are darkened when a .pal file
+// doesn't come with its own emphasis entries
+const EMPHASIS_ATTENUATION : f64 = 0.816;
+
+pub struct Palette {
+    entries : [(u8, u8, u8); NUM_ENTRIES],
+}
+
+// parameters for deriving a palette from the NTSC signal the PPU generates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscParams {
+    // in degrees
+    pub hue        : f64,
+    pub saturation : f64,
+    pub contrast   : f64,
+    pub brightness : f64,
+    pub gamma      : f64,
+}
+
+impl Default for NtscParams {
+    fn default() -> NtscParams {
+        NtscParams {
+            hue        : 0.0,
+            saturation : 1.0,
+            contrast   : 1.0,
+            brightness : 1.0,
+            gamma      : 1.8,
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::from_base_colors(&PALETTE_BGR)
+    }
+}
+
+impl Palette {
+    // color is the 6 bit palette index, emphasis is PPUMASK bits 5-7
+    pub fn get_bgr(&self, color : usize, emphasis : u8) -> (u8, u8, u8) {
+        debug_assert!(color < NUM_COLORS, "invalid color {:x}", color);
+        self.entries[(emphasis as usize & 0b111) * NUM_COLORS + color]
+    }
+
+    // .pal files are a list of RGB triples, either 64 of them or
+    // 512 of them (64 for each combination of emphasis bits)
+    pub fn from_pal_bytes(data : &[u8]) -> Result<Palette, String> {
+        let to_bgr = |rgb : &[u8]| (rgb[2], rgb[1], rgb[0]);
+
+        match data.len() {
+            PAL_FILE_SIZE => {
+                let mut base = [(0, 0, 0); NUM_COLORS];
+                for (entry, rgb) in base.iter_mut().zip(data.chunks(3)) {
+                    *entry = to_bgr(rgb);
+                }
+                Ok(Palette::from_base_colors(&base))
+            },
+            PAL_FILE_SIZE_EMPHASIS => {
+                let mut entries = [(0, 0, 0); NUM_ENTRIES];
+                for (entry, rgb) in entries.iter_mut().zip(data.chunks(3)) {
+                    *entry = to_bgr(rgb);
+                }
+                Ok(Palette { entries : entries })
+            },
+            len => Err(format!("invalid .pal file size: {} bytes \
+                               (expected {} or {})",
+                               len, PAL_FILE_SIZE, PAL_FILE_SIZE_EMPHASIS)),
+        }
+    }
+
+    pub fn from_pal_file(filename : &str) -> Palette {
+        use std::fs::File;
+        use std::io::prelude::*;
+
+        let mut data = Vec::new();
+        File::open(filename).expect("error opening palette file")
+            .read_to_end(&mut data).expect("error reading palette file");
+
+        match Palette::from_pal_bytes(&data) {
+            Ok(palette) => palette,
+            Err(msg) => panic!("{}: {}", filename, msg),
+        }
+    }
+
+    // the inverse of from_pal_bytes, always writes all 512 entries
+    pub fn to_pal_bytes(&self) -> Vec<u8> {
+        self.entries.iter()
+            .flat_map(|&(b, g, r)| vec![r, g, b])
+            .collect()
+    }
+
+    // Based on Bisqwit's NTSC palette generator:
+    // https://wiki.nesdev.com/w/index.php/NTSC_video
+    // Each color is sampled at the 12 phases of the color subcarrier, then
+    // decoded from YIQ to RGB like a TV would.
+    pub fn generate(params : &NtscParams) -> Palette {
+        // signal levels relative to sync, low and high for each luma row
+        const LEVELS_LOW  : [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+        const LEVELS_HIGH : [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+        const BLACK : f64 = 0.518;
+        const WHITE : f64 = 1.962;
+        const ATTENUATION : f64 = 0.746;
+
+        let gamma_fix = |f : f64| {
+            if f <= 0.0 { 0.0 } else { f.powf(2.2 / params.gamma) }
+        };
+        let clamp = |f : f64| (f * 255.0).clamp(0.0, 255.0).round() as u8;
+
+        let hue = params.hue / 30.0;
+        let mut entries = [(0, 0, 0); NUM_ENTRIES];
+
+        for (n, entry) in entries.iter_mut().enumerate() {
+            let color = n % NUM_COLORS;
+            let emphasis = n / NUM_COLORS;
+
+            let hue_num = color & 0x0F;
+            // colors $xE and $xF are always black
+            let luma = if hue_num > 0x0D { 1 } else { (color >> 4) & 0b11 };
+
+            let low = if hue_num == 0x00 { LEVELS_HIGH[luma] } else { LEVELS_LOW[luma] };
+            let high = if hue_num < 0x0D { LEVELS_HIGH[luma] } else { LEVELS_LOW[luma] };
+
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+
+            for phase in 0..12 {
+                let in_color_phase = |c : usize| (c + phase) % 12 < 6;
+
+                let mut signal = if in_color_phase(hue_num) { high } else { low };
+
+                // emphasis doesn't touch the blacks, which would go below
+                // the black level
+                if hue_num < 0x0E &&
+                   ((emphasis & 0b001 != 0 && in_color_phase(0)) ||
+                    (emphasis & 0b010 != 0 && in_color_phase(4)) ||
+                    (emphasis & 0b100 != 0 && in_color_phase(8))) {
+                    signal *= ATTENUATION;
+                }
+
+                let v = (signal - BLACK) / (WHITE - BLACK);
+                let v = ((v - 0.5) * params.contrast + 0.5) *
+                        params.brightness / 12.0;
+
+                let angle = ::std::f64::consts::PI / 6.0 * (phase as f64 + hue);
+                y += v;
+                i += v * angle.cos();
+                q += v * angle.sin();
+            }
+
+            i *= params.saturation;
+            q *= params.saturation;
+
+            // FCC YIQ to RGB matrix
+            let r = gamma_fix(y + 0.946882 * i + 0.623557 * q);
+            let g = gamma_fix(y - 0.274788 * i - 0.635691 * q);
+            let b = gamma_fix(y - 1.108545 * i + 1.709007 * q);
+
+            *entry = (clamp(b), clamp(g), clamp(r));
+        }
+
+        Palette { entries : entries }
+    }
+
+    // fills in the emphasis entries by darkening the channels that
+    // aren't emphasized
+    fn from_base_colors(base : &[(u8, u8, u8); NUM_COLORS]) -> Palette {
+        let mut entries = [(0, 0, 0); NUM_ENTRIES];
+
+        for (i, entry) in entries.iter_mut().enumerate() {
+            let (b, g, r) = base[i % NUM_COLORS];
+            let emphasis = i / NUM_COLORS;
+
+            let attenuate = |val : u8, emphasized : bool| {
+                if emphasis == 0 || emphasized {
+                    val
+                }
+                else {
+                    (val as f64 * EMPHASIS_ATTENUATION).round() as u8
+                }
+            };
+
+            *entry = (attenuate(b, emphasis & 0b100 != 0),
+                      attenuate(g, emphasis & 0b010 != 0),
+                      attenuate(r, emphasis & 0b001 != 0));
+        }
+
+        Palette { entries : entries }
+    }
+}
+
+pub static PALETTE_BGR: [(u8, u8, u8); NUM_COLORS] = [
+    (124, 124, 124), (252, 000, 000), (188, 000, 000), (188, 040, 068),
+    (132, 000, 148), (032, 000, 168), (000, 016, 168), (000, 020, 136),
+    (000, 048, 080), (000, 120, 000), (000, 104, 000), (000, 088, 000),
+    (088, 064, 000), (000, 000, 000), (000, 000, 000), (000, 000, 000),
+    (188, 188, 188), (248, 120, 000), (248, 088, 000), (252, 068, 104),
+    (204, 000, 216), (088, 000, 228), (000, 056, 248), (016, 092, 228),
+    (000, 124, 172), (000, 184, 000), (000, 168, 000), (068, 168, 000),
+    (136, 136, 000), (000, 000, 000), (000, 000, 000), (000, 000, 000),
+    (248, 248, 248), (252, 188, 060), (252, 136, 104), (248, 120, 152),
+    (248, 120, 248), (152, 088, 248), (088, 120, 248), (068, 160, 252),
+    (000, 184, 248), (024, 248, 184), (084, 216, 088), (152, 248, 088),
+    (216, 232, 000), (120, 120, 120), (000, 000, 000), (000, 000, 000),
+    (252, 252, 252), (252, 228, 164), (248, 184, 184), (248, 184, 216),
+    (248, 184, 248), (192, 164, 248), (176, 208, 240), (168, 224, 252),
+    (120, 216, 248), (120, 248, 216), (184, 248, 184), (216, 248, 184),
+    (252, 252, 000), (248, 216, 248), (000, 000, 000), (000, 000, 000),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_matches_base_colors() {
+        let p = Palette::default();
+        for (color, &bgr) in PALETTE_BGR.iter().enumerate() {
+            assert_eq!(p.get_bgr(color, 0), bgr);
+        }
+
+        // emphasizing red darkens green and blue
+        let (b, g, r) = p.get_bgr(0x30, 0b001);
+        assert_eq!(r, 252);
+        assert!(g < 252 && b < 252);
+    }
+
+    #[test]
+    fn pal_file_sizes() {
+        let mut data = Vec::new();
+        for i in 0..NUM_COLORS {
+            data.push(i as u8);
+            data.push(0x10);
+            data.push(0x20);
+        }
+
+        let p = Palette::from_pal_bytes(&data).unwrap();
+        assert_eq!(p.get_bgr(5, 0), (0x20, 0x10, 5));
+
+        let full = p.to_pal_bytes();
+        assert_eq!(full.len(), NUM_ENTRIES * 3);
+
+        let p2 = Palette::from_pal_bytes(&full).unwrap();
+        for i in 0..NUM_ENTRIES {
+            assert_eq!(p.entries[i], p2.entries[i]);
+        }
+
+        assert!(Palette::from_pal_bytes(&data[..100]).is_err());
+    }
+
+    #[test]
+    fn generated_palette() {
+        let p = Palette::generate(&NtscParams::default());
+
+        // $0F is black and $20/$30 are white
+        assert_eq!(p.get_bgr(0x0F, 0), (0, 0, 0));
+        assert_eq!(p.get_bgr(0x30, 0), (255, 255, 255));
+
+        // grays have no chroma
+        let (b, g, r) = p.get_bgr(0x10, 0);
+        assert_eq!(b, g);
+        assert_eq!(g, r);
+
+        // emphasis darkens
+        let (b, _, _) = p.get_bgr(0x30, 0b011);
+        assert!(b < 255);
+
+        // but $xE and $xF stay black
+        for emphasis in 0..8 {
+            for &color in &[0x0E, 0x0F, 0x1E, 0x3F] {
+                assert_eq!(p.get_bgr(color, emphasis), (0, 0, 0));
+            }
+        }
+    }
+}
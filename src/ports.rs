@@ -0,0 +1,405 @@
+// https://wiki.nesdev.com/w/index.php/Input_devices
+// https://wiki.nesdev.com/w/index.php/Controller_port_pinout
+// The two controller ports. Writes to $4016 go to both (bit 0 is the strobe
+// that makes standard controllers latch their buttons), while reads of
+// $4016 and $4017 clock and read the device in port 1 and 2.
+//
+// With a Four Score in both ports there are four players, players 1 and 3
+// on port 1 and players 2 and 4 on port 2.
+//
+// https://wiki.nesdev.com/w/index.php/Expansion_port
+// The Famicom also has an expansion port, whose device sees the same writes
+// and drives the other bits of both registers, mostly D1.
+
+use controller::{ Controller, ButtonAction, Button };
+use zapper::Zapper;
+use four_score::FourScore;
+use vaus::Vaus;
+use family_keyboard::{ FamilyKeyboard, KeyboardKey };
+use hori_track::HoriTrack;
+use ppu::PPU;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+pub const NUM_PORTS : usize = 2;
+pub const NUM_PLAYERS : usize = 4;
+// plug's index for the Famicom expansion port
+pub const EXPANSION_PORT : usize = NUM_PORTS;
+
+// something plugged into a controller port
+pub trait InputDevice : SaveState {
+    // a write to $4016
+    fn write(&mut self, val : u8);
+
+    // a read of port's register, $4016 for 0 and $4017 for 1. Devices in a
+    // port only see their own port's, expansion port devices see both. Only
+    // the low 5 bits are driven, the rest is open bus. Light guns look at
+    // the picture being drawn
+    fn read(&mut self, port : usize, ppu : &PPU) -> u8;
+
+    // what read would return, without clocking the device
+    fn peek(&self, port : usize, ppu : &PPU) -> u8;
+
+    // the buttons held on one of the device's controllers, for devices
+    // that have standard controller buttons
+    fn set_buttons(&mut self, _controller : usize, _buttons : u8) {}
+
+    fn buttons(&self, _controller : usize) -> u8 {
+        0
+    }
+
+    // the pixel a pointing device is aimed at, None when it's off the
+    // screen
+    fn set_pointer(&mut self, _pos : Option<(i32, i32)>) {}
+
+    fn set_trigger(&mut self, _pulled : bool) {}
+
+    // how far the mouse moved, for paddles and trackballs
+    fn move_pointer(&mut self, _dx : i32, _dy : i32) {}
+
+    fn update_key(&mut self, _action : ButtonAction, _key : KeyboardKey) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    None,
+    Controller,
+    Zapper,
+    FourScore,
+    // Arkanoid's paddle
+    Vaus,
+    // expansion port devices
+    VausFamicom,
+    FamilyKeyboard,
+    HoriTrack,
+}
+
+impl DeviceKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DeviceKind::None           => "none",
+            DeviceKind::Controller     => "controller",
+            DeviceKind::Zapper         => "zapper",
+            DeviceKind::FourScore      => "four_score",
+            DeviceKind::Vaus           => "vaus",
+            DeviceKind::VausFamicom    => "vaus_famicom",
+            DeviceKind::FamilyKeyboard => "keyboard",
+            DeviceKind::HoriTrack      => "hori_track",
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<DeviceKind> {
+        match name {
+            "none"         => Some(DeviceKind::None),
+            "controller"   => Some(DeviceKind::Controller),
+            "zapper"       => Some(DeviceKind::Zapper),
+            "four_score"   => Some(DeviceKind::FourScore),
+            "vaus"         => Some(DeviceKind::Vaus),
+            "vaus_famicom" => Some(DeviceKind::VausFamicom),
+            "keyboard"     => Some(DeviceKind::FamilyKeyboard),
+            "hori_track"   => Some(DeviceKind::HoriTrack),
+            _              => None,
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match *self {
+            DeviceKind::None           => 0,
+            DeviceKind::Controller     => 1,
+            DeviceKind::Zapper         => 2,
+            DeviceKind::FourScore      => 3,
+            DeviceKind::Vaus           => 4,
+            DeviceKind::VausFamicom    => 5,
+            DeviceKind::FamilyKeyboard => 6,
+            DeviceKind::HoriTrack      => 7,
+        }
+    }
+
+    fn from_id(id : u8) -> DeviceKind {
+        match id {
+            1 => DeviceKind::Controller,
+            2 => DeviceKind::Zapper,
+            3 => DeviceKind::FourScore,
+            4 => DeviceKind::Vaus,
+            5 => DeviceKind::VausFamicom,
+            6 => DeviceKind::FamilyKeyboard,
+            7 => DeviceKind::HoriTrack,
+            _ => DeviceKind::None,
+        }
+    }
+
+    // a new device for the port
+    pub fn create(&self, port : usize) -> Box<dyn InputDevice> {
+        match *self {
+            DeviceKind::None           => Box::new(Unplugged),
+            DeviceKind::Controller     => Box::new(Controller::new()),
+            DeviceKind::Zapper         => Box::new(Zapper::new()),
+            DeviceKind::FourScore      => Box::new(FourScore::new(port)),
+            DeviceKind::Vaus           => Box::new(Vaus::new(false)),
+            DeviceKind::VausFamicom    => Box::new(Vaus::new(true)),
+            DeviceKind::FamilyKeyboard => Box::new(FamilyKeyboard::new()),
+            DeviceKind::HoriTrack      => Box::new(HoriTrack::new()),
+        }
+    }
+
+    // whether it goes in the expansion port rather than a controller port
+    pub fn is_expansion(&self) -> bool {
+        matches!(*self, DeviceKind::VausFamicom | DeviceKind::FamilyKeyboard | DeviceKind::HoriTrack)
+    }
+}
+
+// an empty port reads as all zeros
+struct Unplugged;
+
+impl InputDevice for Unplugged {
+    fn write(&mut self, _val : u8) {}
+
+    fn read(&mut self, _port : usize, _ppu : &PPU) -> u8 {
+        0
+    }
+
+    fn peek(&self, _port : usize, _ppu : &PPU) -> u8 {
+        0
+    }
+}
+
+impl SaveState for Unplugged {
+    fn save_state(&self, _w : &mut StateWriter) {}
+    fn load_state(&mut self, _r : &mut StateReader) {}
+}
+
+pub struct Ports {
+    // the controller ports, then the expansion port
+    kinds : [DeviceKind; NUM_PORTS + 1],
+    devices : Vec<Box<dyn InputDevice>>,
+}
+
+impl Ports {
+    // with nothing in the expansion port
+    pub fn new(kinds : [DeviceKind; NUM_PORTS]) -> Ports {
+        let mut ports = Ports {
+            kinds : [DeviceKind::None; NUM_PORTS + 1],
+            devices : (0..=NUM_PORTS).map(|_| DeviceKind::None.create(0)).collect(),
+        };
+        for (port, &kind) in kinds.iter().enumerate() {
+            ports.plug(port, kind);
+        }
+        ports
+    }
+
+    // the devices in the controller ports, in port order
+    pub fn kinds(&self) -> [DeviceKind; NUM_PORTS] {
+        let mut kinds = [DeviceKind::None; NUM_PORTS];
+        kinds.copy_from_slice(&self.kinds[..NUM_PORTS]);
+        kinds
+    }
+
+    // what's in a port, or the expansion port
+    pub fn kind(&self, port : usize) -> DeviceKind {
+        self.kinds[port]
+    }
+
+    // swaps what's in a port, or the expansion port, for a new device
+    pub fn plug(&mut self, port : usize, kind : DeviceKind) {
+        self.kinds[port] = kind;
+        self.devices[port] = kind.create(port);
+    }
+
+    pub fn write(&mut self, val : u8) {
+        for device in &mut self.devices {
+            device.write(val);
+        }
+    }
+
+    pub fn read(&mut self, port : usize, ppu : &PPU) -> u8 {
+        let val = self.devices[port].read(port, ppu);
+        (val | self.devices[EXPANSION_PORT].read(port, ppu)) & 0x1F
+    }
+
+    pub fn peek(&self, port : usize, ppu : &PPU) -> u8 {
+        let val = self.devices[port].peek(port, ppu);
+        (val | self.devices[EXPANSION_PORT].peek(port, ppu)) & 0x1F
+    }
+
+    pub fn move_pointer(&mut self, dx : i32, dy : i32) {
+        for device in &mut self.devices {
+            device.move_pointer(dx, dy);
+        }
+    }
+
+    // keys of a keyboard in any port
+    pub fn update_key(&mut self, action : ButtonAction, key : KeyboardKey) {
+        for device in &mut self.devices {
+            device.update_key(action, key);
+        }
+    }
+
+    // pointing devices in any port follow the same pointer
+    pub fn set_pointer(&mut self, pos : Option<(i32, i32)>) {
+        for device in &mut self.devices {
+            device.set_pointer(pos);
+        }
+    }
+
+    pub fn set_trigger(&mut self, pulled : bool) {
+        for device in &mut self.devices {
+            device.set_trigger(pulled);
+        }
+    }
+
+    // every button of a player's controller at once, as Button masks ored
+    // together. Expansion port devices with buttons follow player 1's
+    pub fn set_buttons(&mut self, player : usize, buttons : u8) {
+        self.devices[player % NUM_PORTS].set_buttons(player / NUM_PORTS, buttons);
+        if player == 0 {
+            self.devices[EXPANSION_PORT].set_buttons(0, buttons);
+        }
+    }
+
+    pub fn buttons(&self, player : usize) -> u8 {
+        self.devices[player % NUM_PORTS].buttons(player / NUM_PORTS)
+    }
+
+    pub fn update(&mut self, player : usize, action : ButtonAction, button : Button) {
+        let buttons = match action {
+            ButtonAction::Pressed  => self.buttons(player) | button.mask(),
+            ButtonAction::Released => self.buttons(player) & !button.mask(),
+        };
+        self.set_buttons(player, buttons);
+    }
+}
+
+impl SaveState for Ports {
+    fn save_state(&self, w : &mut StateWriter) {
+        for (kind, device) in self.kinds.iter().zip(&self.devices) {
+            // each device's state is sized, so devices can add to theirs
+            let mut device_state = StateWriter::new();
+            device.save_state(&mut device_state);
+            let device_state = device_state.into_bytes();
+
+            w.u8(kind.id());
+            w.u32(device_state.len() as u32);
+            w.bytes(&device_state);
+        }
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        // before there were ports, this was just the first controller
+        if r.version() < 2 {
+            self.plug(0, DeviceKind::Controller);
+            self.devices[0].load_state(r);
+            return;
+        }
+
+        // states from before the expansion port end after the controller
+        // ports, and the rest reads as nothing plugged in
+        for port in 0..=NUM_PORTS {
+            let kind = DeviceKind::from_id(r.u8());
+            let mut device_state = vec![0; r.u32() as usize];
+            r.bytes(&mut device_state);
+
+            self.plug(port, kind);
+            let mut device_reader = StateReader::new(&device_state, r.version());
+            self.devices[port].load_state(&mut device_reader);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::ButtonAction::*;
+    use controller::Button::*;
+    use savestate::STATE_VERSION;
+
+    #[test]
+    fn shared_strobe() {
+        let ppu = PPU::test();
+        let mut ports = Ports::new([DeviceKind::Controller; NUM_PORTS]);
+        ports.update(0, Pressed, A);
+        ports.update(1, Pressed, B);
+
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(ports.read(0, &ppu), 1);
+        assert_eq!(ports.read(1, &ppu), 0);
+        assert_eq!(ports.read(0, &ppu), 0);
+        assert_eq!(ports.read(1, &ppu), 1);
+
+        ports.plug(1, DeviceKind::None);
+        ports.write(1);
+        assert_eq!(ports.read(1, &ppu), 0);
+
+        // the zapper sees no light and its trigger isn't pulled
+        ports.plug(1, DeviceKind::Zapper);
+        assert_eq!(ports.read(1, &ppu), 0x08);
+        assert_eq!(ports.buttons(1), 0);
+    }
+
+    #[test]
+    fn expansion() {
+        let ppu = PPU::test();
+        let mut ports = Ports::new([DeviceKind::Controller; NUM_PORTS]);
+        ports.plug(EXPANSION_PORT, DeviceKind::VausFamicom);
+        ports.update(0, Pressed, A);
+        ports.set_trigger(true);
+
+        // the two devices drive different bits of $4016
+        ports.write(1);
+        ports.write(0);
+        assert_eq!(ports.read(0, &ppu), 0x03);
+        assert_eq!(ports.kinds(), [DeviceKind::Controller; NUM_PORTS]);
+        assert_eq!(ports.kind(EXPANSION_PORT), DeviceKind::VausFamicom);
+
+        let mut w = StateWriter::new();
+        ports.save_state(&mut w);
+        let state = w.into_bytes();
+        let mut loaded = Ports::new([DeviceKind::Controller; NUM_PORTS]);
+        loaded.load_state(&mut StateReader::new(&state, STATE_VERSION));
+        assert_eq!(loaded.kind(EXPANSION_PORT), DeviceKind::VausFamicom);
+    }
+
+    #[test]
+    fn four_players() {
+        let ppu = PPU::test();
+        let mut ports = Ports::new([DeviceKind::FourScore; NUM_PORTS]);
+        for player in 0..NUM_PLAYERS {
+            ports.update(player, Pressed, Start);
+        }
+        ports.update(3, Pressed, A);
+        assert_eq!(ports.buttons(3), 0x09);
+
+        ports.write(1);
+        ports.write(0);
+        let read = |ports : &mut Ports, port| (0..24).map(|_| ports.read(port, &ppu)).collect::<Vec<u8>>();
+        let (port1, port2) = (read(&mut ports, 0), read(&mut ports, 1));
+        assert_eq!(&port1[..16], &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&port2[..16], &[0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(port1[19], 1);
+        assert_eq!(port2[18], 1);
+
+        // players 3 and 4 aren't there with controllers plugged in directly
+        let mut ports = Ports::new([DeviceKind::Controller; NUM_PORTS]);
+        ports.update(2, Pressed, A);
+        assert_eq!(ports.buttons(2), 0);
+    }
+
+    #[test]
+    fn states() {
+        let mut ports = Ports::new([DeviceKind::Controller, DeviceKind::None]);
+        ports.set_buttons(0, 0x81);
+        let mut w = StateWriter::new();
+        ports.save_state(&mut w);
+        let state = w.into_bytes();
+
+        let mut loaded = Ports::new([DeviceKind::None, DeviceKind::Controller]);
+        loaded.load_state(&mut StateReader::new(&state, STATE_VERSION));
+        assert_eq!(loaded.kinds(), [DeviceKind::Controller, DeviceKind::None]);
+        assert_eq!(loaded.buttons(0), 0x81);
+
+        // a version 1 state, which had the one controller
+        let mut loaded = Ports::new([DeviceKind::None, DeviceKind::Controller]);
+        loaded.load_state(&mut StateReader::new(&[0x42, 0x42, 0], 1));
+        assert_eq!(loaded.kinds(), [DeviceKind::Controller, DeviceKind::Controller]);
+        assert_eq!(loaded.buttons(0), 0x42);
+    }
+}
@@ -1,17 +1,27 @@
 
-use super::graphics::SCREEN_SIZE;
 use super::{ ComponentRc, Memory, Cartridge };
+use palette::Palette;
+use region::Region;
+use savestate::{ SaveState, StateWriter, StateReader };
 
 #[cfg(test)]
 mod tests;
 
 const OAM_SIZE : usize = 256;
 
+pub const SCREEN_WIDTH : usize = 256;
+pub const SCREEN_HEIGHT : usize = 240;
+// BGR24
+pub const BYTES_PER_PIXEL : usize = 3;
+pub const SCREEN_SIZE : usize = SCREEN_WIDTH * SCREEN_HEIGHT * BYTES_PER_PIXEL;
+
 // TODO byte 2 of sprites anded with E3
 pub struct PPU {
     pub oam             : [u8; OAM_SIZE],
     mem                 : PPUMem,
     pixeldata           : [u8; SCREEN_SIZE],
+    palette             : Palette,
+    region              : Region,
 
     control             : u8,
     mask                : u8,
@@ -31,8 +41,29 @@ pub struct PPU {
     data_readbuf : u8,
     scanline_cycle : u16,
     scanline : u16,
+
+    odd_frame : bool,
+    frame_complete : bool,
+
+    // set when vblank starts with NMI enabled, or when NMI is enabled
+    // during vblank, and cleared once the CPU has been told about it
+    nmi_pending : bool,
+    // a $2002 read one dot before vblank keeps the flag from being set
+    suppress_vblank : bool,
+
+    // https://wiki.nesdev.com/w/index.php/PPU_registers#Ports
+    // the data bus between the CPU and PPU holds the last value that was
+    // written or read, and each bit decays to 0 if it isn't refreshed
+    io_latch : u8,
+    io_latch_refreshed : [u64; 8],
+    dots : u64,
 }
 
+// roughly 600ms worth of dots
+const IO_LATCH_DECAY_DOTS : u64 = 3_220_000;
+
+const CYCLES_PER_SCANLINE : u16 = 341;
+
 const PALETTE_RAM_SIZE  : u16 = 0x0020;
 const PALETTE_RAM_FIRST : u16 = 0x3F00;
 const PALETTE_RAM_LAST  : u16 = 0x3FFF;
@@ -143,6 +174,8 @@ impl PPU {
                 palette_ram : [0; PALETTE_RAM_SIZE as usize],
             },
             pixeldata : [0; SCREEN_SIZE],
+            palette   : Palette::default(),
+            region    : Region::Ntsc,
             oam       : [0xFF; OAM_SIZE], // init to FF so sprites are hidden
             control  : 0,
             mask     : 0,
@@ -156,6 +189,13 @@ impl PPU {
             data_readbuf : 0,
             scanline_cycle : 0,
             scanline : 0,
+            odd_frame : false,
+            frame_complete : false,
+            nmi_pending : false,
+            suppress_vblank : false,
+            io_latch : 0,
+            io_latch_refreshed : [0; 8],
+            dots : 0,
         }
     }
 
@@ -163,39 +203,132 @@ impl PPU {
         PPU::new(Cartridge::test_ref())
     }
 
+    // value of the io latch after decay
+    fn io_latch(&self) -> u8 {
+        let mut ret = self.io_latch;
+        for (bit, refreshed) in self.io_latch_refreshed.iter().enumerate() {
+            if self.dots - refreshed >= IO_LATCH_DECAY_DOTS {
+                ret &= !(1 << bit);
+            }
+        }
+        ret
+    }
+
+    // only the bits in mask are driven onto the bus
+    fn refresh_io_latch(&mut self, val : u8, mask : u8) {
+        self.io_latch = (self.io_latch() & !mask) | (val & mask);
+        for (bit, refreshed) in self.io_latch_refreshed.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {
+                *refreshed = self.dots;
+            }
+        }
+    }
+
     pub fn reg_read(&mut self, reg_num : u8) -> u8 {
+        let (val, driven_bits) = self.reg_read_driven(reg_num);
+
+        // bits the register doesn't drive come from the io latch
+        let ret = (val & driven_bits) | (self.io_latch() & !driven_bits);
+        self.refresh_io_latch(ret, driven_bits);
+        ret
+    }
+
+    // what reg_read would return, without any of its side effects
+    pub fn peek_reg(&self, reg_num : u8) -> u8 {
+        use self::reg_id::*;
+        let (val, driven_bits) = match reg_num {
+            STATUS  => (self.status, 0xE0),
+            OAMDATA => (self.oam[self.oam_addr as usize], 0xFF),
+            DATA    => {
+                let addr = self.v & 0x3FFF;
+                if addr < PALETTE_RAM_FIRST {
+                    (self.data_readbuf, 0xFF)
+                }
+                else {
+                    (self.mem.loadb(addr), 0x3F)
+                }
+            },
+            _ => (0, 0x00),
+        };
+        (val & driven_bits) | (self.io_latch() & !driven_bits)
+    }
+
+    // the ppu's address space, without going through the registers
+    pub fn peek_mem(&self, addr : u16) -> u8 {
+        self.mem.loadb(addr & 0x3FFF)
+    }
+
+    pub fn poke_mem(&mut self, addr : u16, val : u8) {
+        self.mem.storeb(addr & 0x3FFF, val);
+    }
+
+    // https://wiki.nesdev.com/w/index.php/PPU_power_up_state
+    pub fn reset(&mut self) {
+        self.control = 0;
+        self.mask = 0;
+        self.w = false;
+        self.t = 0;
+        self.x = 0;
+        self.data_readbuf = 0;
+        self.nmi_pending = false;
+    }
+
+    // the scanline the ppu is on, counting from 0 at the top of the picture
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    // the dot within the scanline
+    pub fn dot(&self) -> u16 {
+        self.scanline_cycle
+    }
+
+    // returns the register value and which of its bits are actually driven
+    fn reg_read_driven(&mut self, reg_num : u8) -> (u8, u8) {
         use self::reg_id::*;
         match reg_num {
 
-            CONTROL => 0,
-            MASK    => 0,
-            // TODO reset vblank flag after read of $2002
+            CONTROL => (0, 0x00),
+            MASK    => (0, 0x00),
+            // https://wiki.nesdev.com/w/index.php/PPU_frame_timing#VBL_Flag_Timing
             STATUS  => {
                 self.w = false;
-                self.status
+
+                if self.scanline == self.region.vblank_scanline() {
+                    match self.scanline_cycle {
+                        // reading one dot before vblank starts means the
+                        // flag never gets set this frame
+                        0 => self.suppress_vblank = true,
+                        // reading right as it's set returns it but
+                        // still suppresses the NMI
+                        1 | 2 => self.nmi_pending = false,
+                        _ => (),
+                    }
+                }
+
+                let ret = self.status;
+                self.clear_vblank();
+                (ret, 0xE0)
             },
-            OAMADDR => 0,
-            OAMDATA => self.oam[self.oam_addr as usize],
-            SCROLL  => 0,
-            ADDRESS => 0,
+            OAMADDR => (0, 0x00),
+            OAMDATA => (self.oam[self.oam_addr as usize], 0xFF),
+            SCROLL  => (0, 0x00),
+            ADDRESS => (0, 0x00),
 
-            // TODO https://wiki.nesdev.com/w/index.php/PPU_scrolling#.242007_reads_and_writes
-            // fix read/write behavior during rendering (not an important feature)
             DATA    => {
-                let addr = self.v;
-                let inc_amount =
-                    if (self.control & 4) >> 2 == 1 { 0x20 } else { 0x01 };
-
-                self.v = self.v.wrapping_add(inc_amount);
+                // v is 15 bits but the ppu address bus is 14
+                let addr = self.v & 0x3FFF;
+                self.increment_data_addr();
 
                 if addr < PALETTE_RAM_FIRST {
                     let ret = self.data_readbuf;
                     self.data_readbuf = self.mem.loadb(addr);
-                    ret
+                    (ret, 0xFF)
                 }
-                // if it's in palette ram, don't use the read buffer
+                // if it's in palette ram, don't use the read buffer.
+                // palette entries are only 6 bits wide
                 else {
-                    self.mem.loadb(addr)
+                    (self.mem.loadb(addr), 0x3F)
                 }
             },
             _ => panic!("invalid ppu reg num"),
@@ -204,10 +337,24 @@ impl PPU {
 
     pub fn reg_write(&mut self, reg_num : u8, val : u8) {
         use self::reg_id::*;
+
+        self.refresh_io_latch(val, 0xFF);
+
         match reg_num {
             CONTROL => {
+                let nmi_was_enabled = self.nmi_enabled();
                 self.control = val;
 
+                // enabling NMI while the vblank flag is still set causes
+                // an NMI right away, disabling it cancels one that hasn't
+                // been taken yet
+                if !self.nmi_enabled() {
+                    self.nmi_pending = false;
+                }
+                else if !nmi_was_enabled && self.in_vblank() {
+                    self.nmi_pending = true;
+                }
+
                 // https://wiki.nesdev.com/w/index.php/PPU_scrolling#.242000_write
                 self.t = (self.t & 0b11110011_11111111) | (((val & 0b11) as u16) << 10); 
             },
@@ -248,18 +395,80 @@ impl PPU {
                   self.w = false;
                 }
             },
-            // https://wiki.nesdev.com/w/index.php/PPU_scrolling#.242007_reads_and_writes
             DATA    => {
-                self.mem.storeb(self.v, val);
-                let inc_amount =
-                    if (self.control & 4) >> 2 == 1 { 0x20 } else { 0x01 };
-
-                self.v = self.v.wrapping_add(inc_amount);
+                self.mem.storeb(self.v & 0x3FFF, val);
+                self.increment_data_addr();
             },
             _ => panic!("invalid ppu reg num"),
         }
     }
 
+    // https://wiki.nesdev.com/w/index.php/PPU_scrolling#.242007_reads_and_writes
+    // while rendering, accessing $2007 bumps v the same way the rendering
+    // hardware does instead of the normal +1/+32
+    fn increment_data_addr(&mut self) {
+        let rendering_line = self.scanline < 240 ||
+                             self.scanline == self.region.pre_render_scanline();
+
+        if self.rendering_enabled() && rendering_line {
+            self.increment_coarse_x();
+            self.increment_y();
+        }
+        else {
+            let inc_amount =
+                if (self.control & 4) >> 2 == 1 { 0x20 } else { 0x01 };
+
+            self.v = self.v.wrapping_add(inc_amount);
+        }
+    }
+
+    // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Coarse_X_increment
+    fn increment_coarse_x(&mut self) {
+        if (self.v & 0x001F) == 31 {
+            // wrap to the next horizontal nametable
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        }
+        else {
+            self.v += 1;
+        }
+    }
+
+    // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Y_increment
+    fn increment_y(&mut self) {
+        if (self.v & 0x7000) != 0x7000 {
+            // fine y
+            self.v += 0x1000;
+        }
+        else {
+            self.v &= !0x7000;
+            let mut coarse_y = (self.v & 0x03E0) >> 5;
+
+            if coarse_y == 29 {
+                // wrap to the next vertical nametable
+                coarse_y = 0;
+                self.v ^= 0x0800;
+            }
+            else if coarse_y == 31 {
+                // out of bounds coarse y wraps without switching nametables
+                coarse_y = 0;
+            }
+            else {
+                coarse_y += 1;
+            }
+
+            self.v = (self.v & !0x03E0) | (coarse_y << 5);
+        }
+    }
+
+    pub fn set_region(&mut self, region : Region) {
+        self.region = region;
+    }
+
+    pub fn set_palette(&mut self, palette : Palette) {
+        self.palette = palette;
+    }
+
     pub fn oamdma_write(&mut self, val : u8) {
         self.reg_write(reg_id::OAMDATA, val);
     }
@@ -270,9 +479,8 @@ impl PPU {
 
         let i = (y*256 + x) * 3;
 
-        debug_assert!(color < 64, "invalid color {:x}", color);
-
-        let color_bgr = PALETTE_BGR[color];
+        // emphasis bits are the top 3 bits of PPUMASK
+        let color_bgr = self.palette.get_bgr(color, self.mask >> 5);
 
         self.pixeldata[i + 0] = color_bgr.0;
         self.pixeldata[i + 1] = color_bgr.1;
@@ -307,36 +515,76 @@ impl PPU {
         }
     }
 
+    // https://wiki.nesdev.com/w/index.php/PPU_frame_timing
     fn tick(&mut self) {
-        const CYCLES_PER_SCANLINE : u16 = 341;
-        const SCANLINES_PER_FRAME : u16 = 262;
-
+        self.dots += 1;
         self.scanline_cycle += 1;
+
+        let vblank_scanline = self.region.vblank_scanline();
+        let pre_render_scanline = self.region.pre_render_scanline();
+
+        // on odd frames with rendering enabled, the last dot of the
+        // pre-render scanline is skipped
+        if self.scanline == pre_render_scanline &&
+           self.scanline_cycle == CYCLES_PER_SCANLINE - 1 &&
+           self.odd_frame && self.rendering_enabled() &&
+           self.region.skips_odd_frame_dot() {
+            self.scanline_cycle = CYCLES_PER_SCANLINE;
+        }
+
         if self.scanline_cycle == CYCLES_PER_SCANLINE {
             self.scanline_cycle = 0;
             self.scanline += 1;
 
-          if self.scanline == SCANLINES_PER_FRAME {
-            self.scanline = 0;
-          }
+            if self.scanline == self.region.scanlines_per_frame() {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
         }
 
-        match self.scanline {
-            0..=239 => {
-                match self.scanline_cycle {
-                    0 => (), // idle cycle
-                    1..=256 => (), // tile data fetch
-                    _ => (), // TODO
-                };
-            }, // visible scanline
-            240 => (), // post-render scanline
-            241 => (), // first vblank scanline
-            242..=260 => (), // remainder of vblank
-            261 => (), // pre-render scanline
-            _ => panic!("invalid scanline: {}", self.scanline), 
+        match (self.scanline, self.scanline_cycle) {
+            // visible scanline, rendered all at once after the tile data
+            // for it would have been fetched
+            (0..=239, 256) => self.render_scanline(self.scanline as u8),
+
+            (line, 1) if line == vblank_scanline => {
+                if !self.suppress_vblank {
+                    self.set_vblank();
+                }
+                self.suppress_vblank = false;
+                self.frame_complete = true;
+            },
+
+            (line, 1) if line == pre_render_scanline => self.clear_vblank(),
+            _ => (),
         };
     }
 
+    // advance the ppu by the given number of dots
+    pub fn step(&mut self, dots : usize) {
+        for _ in 0..dots {
+            self.tick();
+        }
+    }
+
+    // returns true once per frame, when vblank starts
+    pub fn take_frame_complete(&mut self) -> bool {
+        let ret = self.frame_complete;
+        self.frame_complete = false;
+        ret
+    }
+
+    // returns true if the CPU should take an NMI
+    pub fn poll_nmi(&mut self) -> bool {
+        let ret = self.nmi_pending;
+        self.nmi_pending = false;
+        ret
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        (self.mask & 0x18) != 0
+    }
+
     // TODO check that t and v registers are working properly
 
     fn render_scanline_bg(&mut self, scanline : u8) {
@@ -525,7 +773,14 @@ impl PPU {
     }
 
     pub fn set_vblank(&mut self) {
-        self.status |= 0x80
+        self.status |= 0x80;
+        if self.nmi_enabled() {
+            self.nmi_pending = true;
+        }
+    }
+
+    fn in_vblank(&self) -> bool {
+        (self.status & 0x80) != 0
     }
 
     pub fn clear_vblank(&mut self) {
@@ -553,21 +808,65 @@ impl PPU {
     }
 }
 
-static PALETTE_BGR: [(u8, u8, u8); 64] = [
-    (124, 124, 124), (252, 000, 000), (188, 000, 000), (188, 040, 068),
-    (132, 000, 148), (032, 000, 168), (000, 016, 168), (000, 020, 136),
-    (000, 048, 080), (000, 120, 000), (000, 104, 000), (000, 088, 000),
-    (088, 064, 000), (000, 000, 000), (000, 000, 000), (000, 000, 000),
-    (188, 188, 188), (248, 120, 000), (248, 088, 000), (252, 068, 104),
-    (204, 000, 216), (088, 000, 228), (000, 056, 248), (016, 092, 228),
-    (000, 124, 172), (000, 184, 000), (000, 168, 000), (068, 168, 000),
-    (136, 136, 000), (000, 000, 000), (000, 000, 000), (000, 000, 000),
-    (248, 248, 248), (252, 188, 060), (252, 136, 104), (248, 120, 152),
-    (248, 120, 248), (152, 088, 248), (088, 120, 248), (068, 160, 252),
-    (000, 184, 248), (024, 248, 184), (084, 216, 088), (152, 248, 088),
-    (216, 232, 000), (120, 120, 120), (000, 000, 000), (000, 000, 000),
-    (252, 252, 252), (252, 228, 164), (248, 184, 184), (248, 184, 216),
-    (248, 184, 248), (192, 164, 248), (176, 208, 240), (168, 224, 252),
-    (120, 216, 248), (120, 248, 216), (184, 248, 184), (216, 248, 184),
-    (252, 252, 000), (248, 216, 248), (000, 000, 000), (000, 000, 000),
-];
+// everything but the palette and region, which come from the settings
+impl SaveState for PPU {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bytes(&self.oam);
+        w.bytes(&self.mem.palette_ram);
+        w.bytes(&self.pixeldata);
+
+        w.u8(self.control);
+        w.u8(self.mask);
+        w.u8(self.status);
+        w.u8(self.oam_addr);
+        w.u8(self.scroll);
+        w.u16(self.v);
+        w.u16(self.t);
+        w.u8(self.x);
+        w.bool(self.w);
+        w.u8(self.data_readbuf);
+
+        w.u16(self.scanline_cycle);
+        w.u16(self.scanline);
+        w.bool(self.odd_frame);
+        w.bool(self.frame_complete);
+        w.bool(self.nmi_pending);
+        w.bool(self.suppress_vblank);
+
+        w.u8(self.io_latch);
+        for &refreshed in self.io_latch_refreshed.iter() {
+            w.u64(refreshed);
+        }
+        w.u64(self.dots);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        r.bytes(&mut self.oam);
+        r.bytes(&mut self.mem.palette_ram);
+        r.bytes(&mut self.pixeldata);
+
+        self.control = r.u8();
+        self.mask = r.u8();
+        self.status = r.u8();
+        self.oam_addr = r.u8();
+        self.scroll = r.u8();
+        self.v = r.u16();
+        self.t = r.u16();
+        self.x = r.u8();
+        self.w = r.bool();
+        self.data_readbuf = r.u8();
+
+        self.scanline_cycle = r.u16();
+        self.scanline = r.u16();
+        self.odd_frame = r.bool();
+        self.frame_complete = r.bool();
+        self.nmi_pending = r.bool();
+        self.suppress_vblank = r.bool();
+
+        self.io_latch = r.u8();
+        for refreshed in self.io_latch_refreshed.iter_mut() {
+            *refreshed = r.u64();
+        }
+        self.dots = r.u64();
+    }
+}
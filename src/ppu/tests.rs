@@ -1,4 +1,5 @@
-use super::{ PPU, Memory, PALETTE_BGR };
+use super::{ PPU, Memory, IO_LATCH_DECAY_DOTS, SCREEN_WIDTH };
+use palette::PALETTE_BGR;
 use super::reg_id::*;
 
 macro_rules! assert_eq_hex_int {
@@ -7,8 +8,6 @@ macro_rules! assert_eq_hex_int {
     );
 }
 
-const SCREEN_WIDTH : usize = 256;
-
 // TODO test 8x16 sprite horizontal flip
 // TODO test sprite overlap
 // TODO test sprite and bg priority
@@ -1232,9 +1231,11 @@ fn data_reg() {
     assert_eq_hex_int!(p.mem.loadb(0x3F00), 0xFF);
     assert_eq_hex_int!(p.v, 0x3F01);
 
+    // palette ram is 6 bits, the top 2 come from the io latch,
+    // which the last address write set to 0
     p.reg_write(ADDRESS, 0x3F);
     p.reg_write(ADDRESS, 0x00);
-    assert_eq_hex_int!(p.reg_read(DATA), 0xFF);
+    assert_eq_hex_int!(p.reg_read(DATA), 0x3F);
     assert_eq_hex_int!(p.v, 0x3F01);
 
     p.reg_write(ADDRESS, 0x20);
@@ -1255,3 +1256,227 @@ fn data_reg() {
     assert_eq_hex_int!(p.reg_read(DATA), 0x02);
     assert_eq_hex_int!(p.v, 0x2004);
 }
+
+// moves the ppu to the given position, the next tick happens after it
+fn seek(p : &mut PPU, scanline : u16, cycle : u16) {
+    p.scanline = scanline;
+    p.scanline_cycle = cycle;
+}
+
+#[test]
+fn vblank_timing() {
+    let mut p = PPU::test();
+
+    seek(&mut p, 241, 0);
+    p.step(1);
+    assert_eq_hex_int!(p.status & 0x80, 0x80);
+    assert_eq!(p.take_frame_complete(), true);
+    assert_eq!(p.take_frame_complete(), false);
+
+    // flag is cleared on read
+    assert_eq_hex_int!(p.reg_read(STATUS) & 0x80, 0x80);
+    assert_eq_hex_int!(p.reg_read(STATUS) & 0x80, 0x00);
+
+    // and at the start of the pre-render scanline
+    p.set_vblank();
+    seek(&mut p, 261, 0);
+    p.step(1);
+    assert_eq_hex_int!(p.status & 0x80, 0x00);
+}
+
+#[test]
+fn vblank_read_race() {
+    let mut p = PPU::test();
+    p.reg_write(CONTROL, 0x80);
+
+    // reading one dot before vblank suppresses the flag and the NMI
+    seek(&mut p, 241, 0);
+    assert_eq_hex_int!(p.reg_read(STATUS) & 0x80, 0x00);
+    p.step(1);
+    assert_eq_hex_int!(p.status & 0x80, 0x00);
+    assert_eq!(p.poll_nmi(), false);
+    assert_eq!(p.take_frame_complete(), true);
+
+    // only for one frame
+    seek(&mut p, 241, 0);
+    p.step(1);
+    assert_eq_hex_int!(p.status & 0x80, 0x80);
+    assert_eq!(p.poll_nmi(), true);
+
+    // reading on the dot it's set returns the flag but suppresses the NMI
+    p.clear_vblank();
+    seek(&mut p, 241, 0);
+    p.step(1);
+    assert_eq_hex_int!(p.reg_read(STATUS) & 0x80, 0x80);
+    assert_eq!(p.poll_nmi(), false);
+}
+
+#[test]
+fn nmi_on_ctrl_write() {
+    let mut p = PPU::test();
+
+    // vblank starts with NMI disabled
+    seek(&mut p, 241, 0);
+    p.step(1);
+    assert_eq!(p.poll_nmi(), false);
+
+    // enabling it during vblank sends one
+    p.reg_write(CONTROL, 0x80);
+    assert_eq!(p.poll_nmi(), true);
+
+    // but not if it was already enabled
+    p.reg_write(CONTROL, 0x80);
+    assert_eq!(p.poll_nmi(), false);
+
+    // or if the flag has been read
+    p.reg_write(CONTROL, 0x00);
+    p.reg_read(STATUS);
+    p.reg_write(CONTROL, 0x80);
+    assert_eq!(p.poll_nmi(), false);
+}
+
+#[test]
+fn odd_frame_skip() {
+    let mut p = PPU::test();
+
+    // rendering disabled, no skipped dot
+    p.odd_frame = true;
+    seek(&mut p, 261, 339);
+    p.step(1);
+    assert_eq!((p.scanline, p.scanline_cycle), (261, 340));
+
+    // rendering enabled on an even frame
+    p.mask = 0x08;
+    p.odd_frame = false;
+    seek(&mut p, 261, 339);
+    p.step(1);
+    assert_eq!((p.scanline, p.scanline_cycle), (261, 340));
+
+    // odd frame skips straight to the first visible scanline
+    p.odd_frame = true;
+    seek(&mut p, 261, 339);
+    p.step(1);
+    assert_eq!((p.scanline, p.scanline_cycle), (0, 0));
+    assert_eq!(p.odd_frame, false);
+}
+
+#[test]
+fn io_latch() {
+    let mut p = PPU::test();
+
+    // write only registers read back the last value written
+    p.reg_write(OAMADDR, 0xA5);
+    assert_eq_hex_int!(p.reg_read(CONTROL), 0xA5);
+    assert_eq_hex_int!(p.reg_read(SCROLL), 0xA5);
+
+    // status only drives the top 3 bits
+    p.set_vblank();
+    p.reg_write(MASK, 0x1F);
+    assert_eq_hex_int!(p.reg_read(STATUS), 0x9F);
+    assert_eq_hex_int!(p.reg_read(ADDRESS), 0x9F);
+    assert_eq_hex_int!(p.reg_read(STATUS), 0x1F);
+    assert_eq_hex_int!(p.reg_read(ADDRESS), 0x1F);
+
+    // palette reads fill in the top 2 bits
+    p.mem.storeb(0x3F01, 0x2A);
+    p.reg_write(ADDRESS, 0x3F);
+    p.reg_write(ADDRESS, 0x01);
+    p.reg_write(OAMADDR, 0xC0);
+    assert_eq_hex_int!(p.reg_read(DATA), 0xEA);
+}
+
+#[test]
+fn io_latch_decay() {
+    let mut p = PPU::test();
+
+    p.reg_write(OAMADDR, 0xFF);
+    p.step(IO_LATCH_DECAY_DOTS as usize - 1);
+    assert_eq_hex_int!(p.reg_read(MASK), 0xFF);
+
+    // status reads only refresh the top 3 bits
+    p.reg_read(STATUS);
+    p.step(1);
+    assert_eq_hex_int!(p.reg_read(MASK) & 0x1F, 0x00);
+
+    p.step(IO_LATCH_DECAY_DOTS as usize);
+    assert_eq_hex_int!(p.reg_read(MASK), 0x00);
+}
+
+#[test]
+fn data_reg_during_rendering() {
+    let mut p = PPU::test();
+
+    // rendering enabled on a visible scanline
+    p.mask = 0x08;
+    seek(&mut p, 100, 10);
+
+    // coarse x and fine y both increment
+    p.v = 0x0000;
+    p.reg_write(DATA, 0x00);
+    assert_eq_hex_int!(p.v, 0x1001);
+
+    p.reg_read(DATA);
+    assert_eq_hex_int!(p.v, 0x2002);
+
+    // coarse x wraps to the next nametable, fine y overflows to coarse y
+    p.v = 0x701F;
+    p.reg_read(DATA);
+    assert_eq_hex_int!(p.v, 0x0420);
+
+    // coarse y wraps at 29 and switches nametables
+    p.v = 0x73A0;
+    p.reg_read(DATA);
+    assert_eq_hex_int!(p.v, 0x0801);
+
+    // but not at 31
+    p.v = 0x73E0;
+    p.reg_read(DATA);
+    assert_eq_hex_int!(p.v, 0x0001);
+
+    // the pre-render scanline too
+    seek(&mut p, 261, 10);
+    p.v = 0x0000;
+    p.reg_read(DATA);
+    assert_eq_hex_int!(p.v, 0x1001);
+
+    // but not during vblank
+    seek(&mut p, 241, 10);
+    p.v = 0x0000;
+    p.reg_read(DATA);
+    assert_eq_hex_int!(p.v, 0x0001);
+
+    // or with rendering disabled
+    p.mask = 0x00;
+    seek(&mut p, 100, 10);
+    p.v = 0x0000;
+    p.reg_read(DATA);
+    assert_eq_hex_int!(p.v, 0x0001);
+}
+
+#[test]
+fn region_timing() {
+    use region::Region;
+    let mut p = PPU::test();
+
+    // dendy starts vblank 50 scanlines later
+    p.set_region(Region::Dendy);
+    seek(&mut p, 241, 0);
+    p.step(1);
+    assert_eq_hex_int!(p.status & 0x80, 0x00);
+    seek(&mut p, 291, 0);
+    p.step(1);
+    assert_eq_hex_int!(p.status & 0x80, 0x80);
+
+    // pal frames are 312 scanlines with no skipped dot
+    p.set_region(Region::Pal);
+    p.mask = 0x08;
+    p.odd_frame = true;
+    seek(&mut p, 261, 340);
+    p.step(1);
+    assert_eq!((p.scanline, p.scanline_cycle), (262, 0));
+    seek(&mut p, 311, 339);
+    p.step(1);
+    assert_eq!((p.scanline, p.scanline_cycle), (311, 340));
+    p.step(1);
+    assert_eq!((p.scanline, p.scanline_cycle), (0, 0));
+}
@@ -0,0 +1,79 @@
+// Records the emulator's audio to .wav files, optionally with a file for
+// each APU channel next to the main one.
+
+use apu::Channel;
+use wav::WavWriter;
+
+pub struct Recording {
+    filename : String,
+    output : WavWriter,
+    stems : Vec<WavWriter>,
+}
+
+// song.wav -> song-pulse1.wav
+fn stem_filename(filename : &str, channel : Channel) -> String {
+    let base = filename.strip_suffix(".wav").unwrap_or(filename);
+    format!("{}-{}.wav", base, channel.name())
+}
+
+// the first of recording-1.wav, recording-2.wav, ... that doesn't exist yet
+pub fn next_free_filename() -> String {
+    (1..).map(|n| format!("recording-{}.wav", n))
+        .find(|name| !::std::path::Path::new(name).exists())
+        .unwrap()
+}
+
+impl Recording {
+    // stems are the channels to record separately, see APU::stem_channels
+    pub fn start(filename : &str, sample_rate : u32, stems : &[Channel])
+                 -> Result<Recording, String> {
+        let output = WavWriter::create(filename, sample_rate)?;
+        let stems = stems.iter()
+            .map(|&channel| WavWriter::create(&stem_filename(filename, channel),
+                                              sample_rate))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Recording {
+            filename : String::from(filename),
+            output : output,
+            stems : stems,
+        })
+    }
+
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    // stem_samples is in the order of the stems given to start, like
+    // APU::take_stem_samples
+    pub fn write(&mut self, samples : &[f32], stem_samples : &[Vec<f32>]) -> Result<(), String> {
+        self.output.write(samples)?;
+        for (stem, samples) in self.stems.iter_mut().zip(stem_samples) {
+            stem.write(samples)?;
+        }
+        Ok(())
+    }
+
+    pub fn num_samples(&self) -> u32 {
+        self.output.num_samples()
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.output.finish()?;
+        for stem in self.stems {
+            stem.finish()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stem_filenames() {
+        assert_eq!(stem_filename("song.wav", Channel::Pulse1), "song-pulse1.wav");
+        assert_eq!(stem_filename("out/song", Channel::DMC), "out/song-dmc.wav");
+    }
+}
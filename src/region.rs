@@ -0,0 +1,94 @@
+// https://wiki.nesdev.com/w/index.php/Cycle_reference_chart
+//
+// The console came in a few variants that run at different clock rates
+// and have different frame lengths.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    // famiclone used in Russia, PAL frame length with NTSC-like timing
+    Dendy,
+}
+
+impl Region {
+    pub fn from_name(name : &str) -> Option<Region> {
+        match name {
+            "ntsc"  => Some(Region::Ntsc),
+            "pal"   => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _       => None,
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> u16 {
+        match *self {
+            Region::Ntsc  => 262,
+            Region::Pal   => 312,
+            Region::Dendy => 312,
+        }
+    }
+
+    // the scanline that vblank (and the NMI) starts on. Dendy has 51 idle
+    // scanlines after rendering before vblank instead of 1
+    pub fn vblank_scanline(&self) -> u16 {
+        match *self {
+            Region::Ntsc  => 241,
+            Region::Pal   => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.scanlines_per_frame() - 1
+    }
+
+    // only the NTSC ppu skips a dot on odd frames
+    pub fn skips_odd_frame_dot(&self) -> bool {
+        *self == Region::Ntsc
+    }
+
+    // ppu dots per cpu cycle as (numerator, denominator),
+    // PAL runs 3.2 dots per cycle
+    pub fn dots_per_cpu_cycle(&self) -> (usize, usize) {
+        match *self {
+            Region::Ntsc  => (3, 1),
+            Region::Pal   => (16, 5),
+            Region::Dendy => (3, 1),
+        }
+    }
+
+    // in Hz
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match *self {
+            Region::Ntsc  => 1_789_773.0,
+            Region::Pal   => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        let (num, den) = self.dots_per_cpu_cycle();
+        let dots_per_second = self.cpu_clock_rate() * num as f64 / den as f64;
+
+        let mut dots_per_frame = 341.0 * self.scanlines_per_frame() as f64;
+        if self.skips_odd_frame_dot() {
+            // every other frame is one dot shorter
+            dots_per_frame -= 0.5;
+        }
+
+        dots_per_second / dots_per_frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_rates() {
+        assert!((Region::Ntsc.frame_rate() - 60.0988).abs() < 0.001);
+        assert!((Region::Pal.frame_rate() - 50.0070).abs() < 0.001);
+        assert!((Region::Dendy.frame_rate() - 50.0).abs() < 0.01);
+    }
+}
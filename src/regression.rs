@@ -0,0 +1,400 @@
+// Regression tests driven by input movies. A rom runs headless with a movie
+// feeding its input, and hashes of the picture, and optionally of RAM, at
+// chosen frames are compared against an expectations file like
+//
+//     rom ../../color_test.nes
+//     movie color_test.fm2
+//     # frame, picture hash, and optionally a RAM hash
+//     frame 60 1a2b3c4d
+//     frame 120 5e6f7a8b ram 01234567
+//
+// Paths are relative to the expectations file, and frame N is the picture
+// after N frames have run. A hash of - hasn't been filled in yet; blessing
+// fills in (or replaces) every hash with what came out.
+//
+// Pictures are written out as .ppm files: as the reference for the next run
+// when a frame matches, and along with an image of the pixels that changed
+// from that reference when it doesn't.
+
+use std::path::{ Path, PathBuf };
+
+use console::Nes;
+use movie::{ Movie, MovieState };
+use ppu::{ SCREEN_WIDTH, SCREEN_HEIGHT, BYTES_PER_PIXEL };
+use savestate;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Check {
+    pub frame : usize,
+    pub picture : Option<u32>,
+    // Some if RAM is checked at this frame
+    pub ram : Option<Option<u32>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expectations {
+    pub rom : String,
+    pub movie : Option<String>,
+    pub checks : Vec<Check>,
+}
+
+// what came out at a checked frame
+pub struct FrameResult {
+    pub frame : usize,
+    pub picture : u32,
+    pub ram : u32,
+    pub pixels : Vec<u8>,
+}
+
+fn parse_hash(val : &str) -> Result<Option<u32>, String> {
+    match val {
+        "-" => Ok(None),
+        _ => u32::from_str_radix(val, 16).map(Some)
+            .map_err(|_| format!("invalid hash: {}", val)),
+    }
+}
+
+fn format_hash(hash : Option<u32>) -> String {
+    match hash {
+        Some(hash) => format!("{:08x}", hash),
+        None => String::from("-"),
+    }
+}
+
+fn format_check(check : &Check) -> String {
+    match check.ram {
+        Some(ram) => format!("frame {} {} ram {}", check.frame,
+                             format_hash(check.picture), format_hash(ram)),
+        None => format!("frame {} {}", check.frame, format_hash(check.picture)),
+    }
+}
+
+fn parse_check(fields : &[&str]) -> Result<Check, String> {
+    let invalid = || format!("invalid check: {}", fields.join(" "));
+    let frame = fields.get(1).and_then(|n| n.parse().ok())
+        .filter(|&frame| frame > 0).ok_or_else(invalid)?;
+    let picture = parse_hash(fields.get(2).ok_or_else(invalid)?)?;
+    let ram = match (fields.get(3), fields.get(4)) {
+        (None, _) => None,
+        (Some(&"ram"), Some(hash)) => Some(parse_hash(hash)?),
+        _ => return Err(invalid()),
+    };
+    Ok(Check { frame : frame, picture : picture, ram : ram })
+}
+
+impl Expectations {
+    pub fn parse(text : &str) -> Result<Expectations, String> {
+        let mut rom = None;
+        let mut movie = None;
+        let mut checks = Vec::new();
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields : Vec<&str> = line.split_whitespace().collect();
+            match fields[0] {
+                "rom" if fields.len() == 2 => rom = Some(String::from(fields[1])),
+                "movie" if fields.len() == 2 => movie = Some(String::from(fields[1])),
+                "frame" => checks.push(parse_check(&fields)?),
+                _ => return Err(format!("invalid line: {}", line)),
+            }
+        }
+
+        Ok(Expectations {
+            rom : rom.ok_or_else(|| String::from("no rom given"))?,
+            movie : movie,
+            checks : checks,
+        })
+    }
+
+    pub fn from_file(filename : &str) -> Result<Expectations, String> {
+        let text = ::std::fs::read_to_string(filename)
+            .map_err(|e| format!("error reading {}: {}", filename, e))?;
+        Expectations::parse(&text)
+    }
+
+    // the expectations with the hashes replaced by what came out
+    pub fn blessed(&self, results : &[FrameResult]) -> Expectations {
+        let checks = self.checks.iter().zip(results).map(|(check, result)| Check {
+            frame : check.frame,
+            picture : Some(result.picture),
+            ram : check.ram.map(|_| Some(result.ram)),
+        }).collect();
+        Expectations { checks : checks, ..self.clone() }
+    }
+}
+
+// rewrites the frame lines of an expectations file, leaving the rest
+fn update_text(text : &str, expectations : &Expectations) -> String {
+    let mut checks = expectations.checks.iter();
+    text.lines().map(|line| {
+        if line.trim_start().starts_with("frame") {
+            checks.next().map(format_check).unwrap_or_default()
+        } else {
+            String::from(line)
+        }
+    }).map(|line| line + "\n").collect()
+}
+
+// runs the rom with the movie's input, if any, and takes the picture and
+// RAM at each of the frames
+pub fn run(rom : &[u8], movie : Option<Movie>, frames : &[usize])
+    -> Result<Vec<FrameResult>, String> {
+
+    let mut nes = Nes::from_rom(rom);
+    let mut movie = match movie {
+        Some(movie) => Some(MovieState::play(&mut nes, movie)?),
+        None => None,
+    };
+
+    // in the order they were asked for
+    let mut results : Vec<Option<FrameResult>> = frames.iter().map(|_| None).collect();
+    let last = frames.iter().cloned().max().unwrap_or(0);
+    for frame in 1..=last {
+        if let Some(ref mut movie) = movie {
+            movie.before_frame(&mut nes);
+        }
+        nes.step_frame();
+
+        for (i, _) in frames.iter().enumerate().filter(|&(_, &f)| f == frame) {
+            let pixels = nes.framebuffer().to_vec();
+            let ram : Vec<u8> = (0..0x800).map(|addr| nes.peek_cpu(addr)).collect();
+            results[i] = Some(FrameResult {
+                frame : frame,
+                picture : savestate::hash(&pixels),
+                ram : savestate::hash(&ram),
+                pixels : pixels,
+            });
+        }
+    }
+    // frames start from 1, so they've all been reached
+    Ok(results.into_iter().map(Option::unwrap).collect())
+}
+
+// http://netpbm.sourceforge.net/doc/ppm.html
+pub fn write_ppm(filename : &Path, pixels : &[u8]) -> Result<(), String> {
+    let mut data = format!("P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    // pixels are BGR
+    for pixel in pixels.chunks(BYTES_PER_PIXEL) {
+        data.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+    }
+    ::std::fs::write(filename, data)
+        .map_err(|e| format!("error writing {}: {}", filename.display(), e))
+}
+
+pub fn read_ppm(filename : &Path) -> Option<Vec<u8>> {
+    let data = ::std::fs::read(filename).ok()?;
+    let header = format!("P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    if !data.starts_with(&header) {
+        return None;
+    }
+    Some(data[header.len()..].chunks(3)
+        .flat_map(|pixel| vec![pixel[2], pixel[1], pixel[0]])
+        .collect())
+}
+
+// the actual picture dimmed, with the pixels that differ from the
+// reference in red
+pub fn diff_image(reference : &[u8], actual : &[u8]) -> Vec<u8> {
+    reference.chunks(BYTES_PER_PIXEL).zip(actual.chunks(BYTES_PER_PIXEL))
+        .flat_map(|(a, b)| if a == b {
+            vec![b[0] / 4, b[1] / 4, b[2] / 4]
+        } else {
+            vec![0x00, 0x00, 0xFF]
+        })
+        .collect()
+}
+
+// Runs the tests in an expectations file, with pictures going into
+// out_dir. Returns what didn't match, so nothing means it passed
+pub fn run_file(filename : &str, out_dir : &Path, bless : bool) -> Result<Vec<String>, String> {
+    let expectations = Expectations::from_file(filename)?;
+    let base = Path::new(filename).parent().unwrap_or_else(|| Path::new(""));
+    let name = Path::new(filename).file_stem().unwrap().to_string_lossy().into_owned();
+
+    let rom_file = base.join(&expectations.rom);
+    let rom = ::std::fs::read(&rom_file)
+        .map_err(|e| format!("error reading {}: {}", rom_file.display(), e))?;
+    let movie = match expectations.movie {
+        Some(ref movie) => Some(Movie::from_file(&base.join(movie).to_string_lossy())?),
+        None => None,
+    };
+
+    let frames : Vec<usize> = expectations.checks.iter().map(|c| c.frame).collect();
+    let results = run(&rom, movie, &frames)?;
+
+    ::std::fs::create_dir_all(out_dir)
+        .map_err(|e| format!("error creating {}: {}", out_dir.display(), e))?;
+    let image = |suffix : &str| -> PathBuf { out_dir.join(format!("{}{}.ppm", name, suffix)) };
+
+    if bless {
+        let text = ::std::fs::read_to_string(filename).map_err(|e| e.to_string())?;
+        let text = update_text(&text, &expectations.blessed(&results));
+        ::std::fs::write(filename, text).map_err(|e| e.to_string())?;
+        for result in &results {
+            write_ppm(&image(&format!("-{}", result.frame)), &result.pixels)?;
+        }
+        return Ok(Vec::new());
+    }
+
+    let mut failures = Vec::new();
+    for (check, result) in expectations.checks.iter().zip(&results) {
+        let reference = image(&format!("-{}", check.frame));
+
+        if check.picture == Some(result.picture) {
+            write_ppm(&reference, &result.pixels)?;
+        } else {
+            let actual = image(&format!("-{}-actual", check.frame));
+            write_ppm(&actual, &result.pixels)?;
+
+            let shown = match read_ppm(&reference) {
+                Some(ref pixels) if pixels.len() == result.pixels.len() => {
+                    let diff = image(&format!("-{}-diff", check.frame));
+                    write_ppm(&diff, &diff_image(pixels, &result.pixels))?;
+                    diff
+                },
+                _ => actual,
+            };
+            failures.push(format!("{}: frame {} picture is {:08x}, expected {}, see {}",
+                                  name, check.frame, result.picture,
+                                  format_hash(check.picture), shown.display()));
+        }
+
+        if let Some(ram) = check.ram {
+            if ram != Some(result.ram) {
+                failures.push(format!("{}: frame {} ram is {:08x}, expected {}",
+                                      name, check.frame, result.ram, format_hash(ram)));
+            }
+        }
+    }
+    Ok(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge;
+
+    #[test]
+    fn expectations_file() {
+        let text = "# a comment\n\
+                    rom ../game.nes\n\
+                    movie game.fm2\n\
+                    frame 60 0000abcd\n\
+                    frame 120 - ram 12345678\n";
+        let expectations = Expectations::parse(text).unwrap();
+        assert_eq!(expectations, Expectations {
+            rom : String::from("../game.nes"),
+            movie : Some(String::from("game.fm2")),
+            checks : vec![
+                Check { frame : 60, picture : Some(0xABCD), ram : None },
+                Check { frame : 120, picture : None, ram : Some(Some(0x1234_5678)) },
+            ],
+        });
+
+        let results : Vec<FrameResult> = [(60, 1, 2), (120, 3, 4)].iter()
+            .map(|&(frame, picture, ram)|
+                 FrameResult { frame : frame, picture : picture, ram : ram, pixels : vec![] })
+            .collect();
+        let blessed = update_text(text, &expectations.blessed(&results));
+        assert_eq!(blessed, "# a comment\n\
+                             rom ../game.nes\n\
+                             movie game.fm2\n\
+                             frame 60 00000001\n\
+                             frame 120 00000003 ram 00000004\n");
+
+        assert!(Expectations::parse("movie game.fm2\n").is_err());
+        assert!(Expectations::parse("rom a.nes\nframe 0 -\n").is_err());
+        assert!(Expectations::parse("rom a.nes\nframe x 00\n").is_err());
+        assert!(Expectations::parse("rom a.nes\nframe 1 00 rom 00\n").is_err());
+    }
+
+    // an NROM image that keeps reading whether A is held into $10
+    fn controller_rom() -> Vec<u8> {
+        // LDA #1; STA $4016; LDA #0; STA $4016; LDA $4016; AND #1; STA $10; JMP $8000
+        cartridge::test_nrom(&[0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40,
+                               0xAD, 0x16, 0x40, 0x29, 0x01, 0x85, 0x10, 0x4C, 0x00, 0x80],
+                             0x8000, 0x8000)
+    }
+
+    #[test]
+    fn movie_input() {
+        use movie::MovieFrame;
+
+        let rom = controller_rom();
+        let held = |frames : usize| Movie {
+            frames : (0..10).map(|n| MovieFrame {
+                commands : 0,
+                buttons : [if n < frames { 0x01 } else { 0x00 }, 0x00, 0x00, 0x00],
+            }).collect(),
+            ..Movie::default()
+        };
+
+        let first = run(&rom, Some(held(5)), &[10, 3]).unwrap();
+        let again = run(&rom, Some(held(5)), &[10, 3]).unwrap();
+        assert_eq!(first.iter().map(|r| r.frame).collect::<Vec<_>>(), vec![10, 3]);
+        assert_eq!(first[0].picture, again[0].picture);
+        assert_eq!(first[0].ram, again[0].ram);
+
+        // A is held at frame 3 and not at 10
+        assert!(first[0].ram != first[1].ram);
+        let never = run(&rom, Some(held(0)), &[3]).unwrap();
+        assert_eq!(never[0].ram, first[0].ram);
+    }
+
+    #[test]
+    fn diff() {
+        let reference = vec![0x40; SCREEN_WIDTH * SCREEN_HEIGHT * BYTES_PER_PIXEL];
+        let mut actual = reference.clone();
+        actual[3..6].copy_from_slice(&[1, 2, 3]);
+        let diff = diff_image(&reference, &actual);
+        assert_eq!(&diff[..9], &[0x10, 0x10, 0x10, 0x00, 0x00, 0xFF, 0x10, 0x10, 0x10]);
+    }
+
+    // runs every expectations file in tests/regression whose rom has been
+    // built, see the build scripts in roms-src. Set NES_BLESS to fill in
+    // the hashes instead, and NES_REGRESSION_STRICT where the roms are
+    // always built (like CI) to fail on ones that aren't
+    #[test]
+    fn regression_roms() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let out_dir = root.join("target").join("regression");
+        let bless = ::std::env::var_os("NES_BLESS").is_some();
+        let strict = ::std::env::var_os("NES_REGRESSION_STRICT").is_some();
+
+        let mut files : Vec<PathBuf> = ::std::fs::read_dir(root.join("tests").join("regression"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "txt"))
+            .collect();
+        files.sort();
+
+        let mut failures = Vec::new();
+        for file in files {
+            let filename = file.to_string_lossy().into_owned();
+            let expectations = Expectations::from_file(&filename).unwrap();
+            if !file.parent().unwrap().join(&expectations.rom).exists() {
+                if strict {
+                    failures.push(format!("{}: {} isn't built", filename, expectations.rom));
+                } else {
+                    println!("skipping {}, {} isn't built", filename, expectations.rom);
+                }
+                continue;
+            }
+            let unblessed = expectations.checks.iter()
+                .any(|check| check.picture.is_none() || check.ram == Some(None));
+            if unblessed && !bless {
+                failures.push(format!("{}: some hashes are -, fill them in with NES_BLESS=1",
+                                      filename));
+                continue;
+            }
+            failures.extend(run_file(&filename, &out_dir, bless).unwrap());
+        }
+
+        for failure in &failures {
+            println!("{}", failure);
+        }
+        assert!(failures.is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+// Going back in time, using save states.
+//
+// A snapshot is taken every few frames. Only the newest one is kept whole;
+// each older one is stored as the XOR of it and the snapshot after it, which
+// is mostly zeros since little changes in a few frames, with the runs of
+// zeros squeezed out. Going back a snapshot undoes the newest delta. When the
+// history goes over its memory budget the oldest snapshots are dropped.
+
+use std::collections::VecDeque;
+
+use console::Nes;
+
+pub struct Rewind {
+    // frames between snapshots
+    interval : usize,
+    // bytes the history can use
+    budget : usize,
+    // frames of play to go back per frame while rewinding
+    speed : f64,
+
+    newest : Option<Vec<u8>>,
+    // oldest first, each one turns the snapshot after it into itself
+    deltas : VecDeque<Vec<u8>>,
+    used : usize,
+
+    frames_since_snapshot : usize,
+    // the newest snapshot is of the frame on screen
+    at_newest : bool,
+    // frames to go back that don't add up to a whole snapshot yet
+    rewind_remainder : f64,
+}
+
+impl Rewind {
+    pub fn new(interval : usize, budget : usize, speed : f64) -> Rewind {
+        Rewind {
+            interval : interval.max(1),
+            budget : budget,
+            speed : speed,
+            newest : None,
+            deltas : VecDeque::new(),
+            used : 0,
+            frames_since_snapshot : 0,
+            at_newest : false,
+            rewind_remainder : 0.0,
+        }
+    }
+
+    // call after each frame played forwards
+    pub fn frame_done(&mut self, nes : &Nes) {
+        self.rewind_remainder = 0.0;
+        self.frames_since_snapshot += 1;
+        self.at_newest = self.frames_since_snapshot >= self.interval;
+        if self.at_newest {
+            self.frames_since_snapshot = 0;
+            self.push(nes.save_state());
+        }
+    }
+
+    // call once a frame instead of running the console, to go back by
+    // `speed` frames. Returns false when there's no history left
+    pub fn rewind_frame(&mut self, nes : &mut Nes) -> bool {
+        // going back to the frame on screen wouldn't go anywhere
+        if self.at_newest {
+            self.at_newest = false;
+            if self.len() > 1 {
+                self.pop();
+            }
+        }
+
+        self.rewind_remainder += self.speed;
+        let mut state = None;
+        while self.rewind_remainder >= self.interval as f64 && !self.is_empty() {
+            self.rewind_remainder -= self.interval as f64;
+            state = self.pop();
+        }
+
+        // the snapshot includes the frame that was on screen at the time
+        if let Some(state) = state {
+            nes.load_state(&state).expect("invalid rewind snapshot");
+        }
+        // play resumes from here, so count the next snapshot from here too
+        self.frames_since_snapshot = 0;
+        !self.is_empty()
+    }
+
+    pub fn push(&mut self, state : Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            let delta = compress(&previous, &state);
+            self.used += delta.len();
+            self.deltas.push_back(delta);
+            self.used -= previous.len();
+        }
+        self.used += state.len();
+        self.newest = Some(state);
+
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // takes the newest snapshot off
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.used -= newest.len();
+
+        if let Some(delta) = self.deltas.pop_back() {
+            self.used -= delta.len();
+            let previous = decompress(&newest, &delta);
+            self.used += previous.len();
+            self.newest = Some(previous);
+        }
+        Some(newest)
+    }
+
+    // snapshots in the history
+    pub fn len(&self) -> usize {
+        match self.newest {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    // bytes used by the history
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+}
+
+// https://en.wikipedia.org/wiki/LEB128
+fn write_varint(out : &mut Vec<u8>, mut val : usize) {
+    while val >= 0x80 {
+        out.push((val as u8 & 0x7F) | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data : &[u8], pos : &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let b = data[*pos];
+        *pos += 1;
+        val |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+fn byte_at(data : &[u8], i : usize) -> u8 {
+    data.get(i).cloned().unwrap_or(0)
+}
+
+// `old` as the XOR of it and `new`, made up of the length of `old` followed
+// by pairs of a run of zeros and a run of literal bytes
+fn compress(old : &[u8], new : &[u8]) -> Vec<u8> {
+    let len = old.len().max(new.len());
+    let xor = |i| byte_at(old, i) ^ byte_at(new, i);
+
+    let mut out = Vec::new();
+    write_varint(&mut out, old.len());
+
+    let mut i = 0;
+    while i < len {
+        let zeros_start = i;
+        while i < len && xor(i) == 0 {
+            i += 1;
+        }
+        if i == len {
+            break;
+        }
+        let literal_start = i;
+        // a couple of zeros in a row aren't worth ending the literals for
+        while i < len && (xor(i) != 0 || (i + 2 < len && (xor(i + 1) != 0 || xor(i + 2) != 0))) {
+            i += 1;
+        }
+
+        write_varint(&mut out, literal_start - zeros_start);
+        write_varint(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(&xor));
+    }
+    out
+}
+
+// gets back the `old` that compress was given
+fn decompress(new : &[u8], delta : &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let old_len = read_varint(delta, &mut pos);
+    let mut old : Vec<u8> = (0..old_len).map(|i| byte_at(new, i)).collect();
+
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+        for &b in &delta[pos..pos + literals] {
+            if i < old_len {
+                old[i] ^= b;
+            }
+            i += 1;
+        }
+        pos += literals;
+    }
+    old
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge;
+
+    fn snapshot(n : u8) -> Vec<u8> {
+        let mut state = vec![0x55; 4000];
+        state[100] = n;
+        state[2000] = n.wrapping_mul(3);
+        state[2001] = 0;
+        state[2002] = n;
+        state
+    }
+
+    #[test]
+    fn deltas() {
+        let old = snapshot(1);
+        let new = snapshot(2);
+        let delta = compress(&old, &new);
+        assert!(delta.len() < 20);
+        assert_eq!(decompress(&new, &delta), old);
+
+        // snapshots can change size
+        assert_eq!(decompress(&old[..10], &compress(&old, &old[..10])), old);
+        assert_eq!(decompress(&old, &compress(&old[..10], &old)), &old[..10]);
+        assert_eq!(decompress(&new, &compress(&[], &new)), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn history() {
+        let mut rewind = Rewind::new(1, 1 << 20, 1.0);
+        assert!(rewind.pop().is_none());
+
+        for n in 0..10 {
+            rewind.push(snapshot(n));
+        }
+        assert_eq!(rewind.len(), 10);
+        // one whole snapshot, the rest are small
+        assert!(rewind.memory_used() < 4000 + 9 * 20);
+
+        for n in (0..10).rev() {
+            assert_eq!(rewind.pop(), Some(snapshot(n)));
+        }
+        assert!(rewind.is_empty());
+        assert_eq!(rewind.memory_used(), 0);
+    }
+
+    #[test]
+    fn budget() {
+        let mut rewind = Rewind::new(1, 4100, 1.0);
+        for n in 0..100 {
+            rewind.push(snapshot(n));
+        }
+        assert!(rewind.memory_used() <= 4100);
+        assert!(rewind.len() > 1 && rewind.len() < 100);
+
+        // the newest ones are kept
+        let len = rewind.len() as u8;
+        for n in (100 - len..100).rev() {
+            assert_eq!(rewind.pop(), Some(snapshot(n)));
+        }
+    }
+
+    // an NROM image that loops forever
+    fn test_rom() -> Vec<u8> {
+        // JMP $8000
+        cartridge::test_nrom(&[0x4C, 0x00, 0x80], 0x8000, 0x8000)
+    }
+
+    #[test]
+    fn rewinding() {
+        let mut nes = Nes::from_rom(&test_rom());
+        let mut rewind = Rewind::new(1, 1 << 20, 1.0);
+        let mut cycles = vec![nes.cycles()];
+        for _ in 0..5 {
+            nes.step_frame();
+            rewind.frame_done(&nes);
+            cycles.push(nes.cycles());
+        }
+
+        // every rewound frame goes back one, starting from the one on screen
+        for i in (1..5).rev() {
+            assert_eq!(rewind.rewind_frame(&mut nes), i > 1);
+            assert_eq!(nes.cycles(), cycles[i]);
+        }
+        assert!(!rewind.rewind_frame(&mut nes));
+        assert_eq!(nes.cycles(), cycles[1]);
+    }
+}
@@ -0,0 +1,297 @@
+// Snapshots of the whole machine.
+//
+// A state is a header followed by a chunk for each part of the machine, in
+// little endian. Fields are only ever added to the end of a chunk, and
+// reading past the end of one gives zeros, so states saved by older
+// versions still load: anything they don't have comes out zeroed, unless
+// the component checks StateReader::version and does something better.
+// Chunks from this version have to be exactly as long as what's read
+// from them, or the state is broken.
+
+use region::Region;
+
+// bump when fields are added, so loaders can tell what's missing
+// 2: both controller ports, rather than just the first controller
+// 3: the cartridge's sound chips
+pub const STATE_VERSION : u32 = 3;
+
+const MAGIC : &[u8] = b"NESSTATE";
+
+pub trait SaveState {
+    fn save_state(&self, w : &mut StateWriter);
+    fn load_state(&mut self, r : &mut StateReader);
+}
+
+pub struct StateWriter {
+    data : Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data : Vec::new() }
+    }
+
+    pub fn u8(&mut self, val : u8) {
+        self.data.push(val);
+    }
+
+    pub fn bool(&mut self, val : bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u16(&mut self, val : u16) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, val : u32) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val : u64) {
+        self.data.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, val : usize) {
+        self.u64(val as u64);
+    }
+
+    pub fn f64(&mut self, val : f64) {
+        self.u64(val.to_bits());
+    }
+
+    pub fn bytes(&mut self, bytes : &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    data : &'a [u8],
+    pos : usize,
+    version : u32,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data : &'a [u8], version : u32) -> StateReader<'a> {
+        StateReader {
+            data : data,
+            pos : 0,
+            version : version,
+        }
+    }
+
+    // the version the state was saved with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // fills buf from the state, with zeros for anything past the end, which
+    // is only allowed in older states
+    pub fn bytes(&mut self, buf : &mut [u8]) {
+        let start = self.pos.min(self.data.len());
+        let end = (self.pos + buf.len()).min(self.data.len());
+        let available = end - start;
+
+        buf[..available].copy_from_slice(&self.data[start..end]);
+        for b in buf[available..].iter_mut() {
+            *b = 0;
+        }
+        self.pos += buf.len();
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        let mut buf = [0; 1];
+        self.bytes(&mut buf);
+        buf[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        let mut buf = [0; 2];
+        self.bytes(&mut buf);
+        u16::from_le_bytes(buf)
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        let mut buf = [0; 8];
+        self.bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    pub fn usize(&mut self) -> usize {
+        self.u64() as usize
+    }
+
+    pub fn f64(&mut self) -> f64 {
+        f64::from_bits(self.u64())
+    }
+
+    // checks everything was read, once the component is done with it
+    pub fn finish(&self) -> Result<(), String> {
+        if self.version < STATE_VERSION || self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(format!("save state chunk is {} bytes, expected {}", self.data.len(), self.pos))
+        }
+    }
+}
+
+// what a state has to match to be loaded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateHeader {
+    pub version : u32,
+    pub rom_hash : u32,
+    pub region : Region,
+}
+
+fn region_id(region : Region) -> u8 {
+    match region {
+        Region::Ntsc  => 0,
+        Region::Pal   => 1,
+        Region::Dendy => 2,
+    }
+}
+
+// tags are 4 bytes, like "CPU "
+pub fn write_state(header : &StateHeader, chunks : &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.bytes(MAGIC);
+    w.u32(header.version);
+    w.u32(header.rom_hash);
+    w.u8(region_id(header.region));
+
+    for &(tag, ref data) in chunks {
+        w.bytes(tag);
+        w.u32(data.len() as u32);
+        w.bytes(data);
+    }
+    w.into_bytes()
+}
+
+// a tag and the data under it
+pub type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+// splits a state into its header and chunks, checking that it's complete
+pub fn read_state(data : &[u8]) -> Result<(StateHeader, Vec<Chunk<'_>>), String> {
+    const HEADER_SIZE : usize = 17;
+    if data.len() < HEADER_SIZE || &data[..MAGIC.len()] != MAGIC {
+        return Err(String::from("not a save state"));
+    }
+
+    let mut r = StateReader::new(&data[MAGIC.len()..HEADER_SIZE], 0);
+    let version = r.u32();
+    let rom_hash = r.u32();
+    let region = match r.u8() {
+        0 => Region::Ntsc,
+        1 => Region::Pal,
+        2 => Region::Dendy,
+        id => return Err(format!("invalid region {} in save state", id)),
+    };
+    if version > STATE_VERSION {
+        return Err(format!("save state is from a newer version ({})", version));
+    }
+
+    let mut chunks = Vec::new();
+    let mut pos = HEADER_SIZE;
+    while pos < data.len() {
+        if pos + 8 > data.len() {
+            return Err(String::from("save state is cut off"));
+        }
+        let mut tag = [0; 4];
+        tag.copy_from_slice(&data[pos..pos + 4]);
+        let len = StateReader::new(&data[pos + 4..pos + 8], 0).u32() as usize;
+        pos += 8;
+
+        if pos + len > data.len() {
+            return Err(String::from("save state is cut off"));
+        }
+        chunks.push((tag, &data[pos..pos + len]));
+        pos += len;
+    }
+
+    let header = StateHeader {
+        version : version,
+        rom_hash : rom_hash,
+        region : region,
+    };
+    Ok((header, chunks))
+}
+
+// FNV-1a, for telling roms apart
+pub fn hash(data : &[u8]) -> u32 {
+    data.iter().fold(0x811C_9DC5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+// the file for a numbered slot, next to whatever prefix is
+pub fn slot_filename(prefix : &str, slot : u8) -> String {
+    format!("{}.state{}", prefix, slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader_past_end() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.u16(0x3456);
+        w.bool(true);
+        let data = w.into_bytes();
+        assert_eq!(data, vec![0x12, 0x56, 0x34, 1]);
+
+        let mut r = StateReader::new(&data, 1);
+        assert_eq!(r.u8(), 0x12);
+        assert_eq!(r.u16(), 0x3456);
+        assert!(r.bool());
+        // fields added after this state was saved
+        assert_eq!(r.u32(), 0);
+        assert!(!r.bool());
+        assert!(r.finish().is_ok());
+
+        // but a state from this version has to have everything
+        let mut r = StateReader::new(&data, STATE_VERSION);
+        r.u8();
+        r.u16();
+        r.bool();
+        assert!(r.finish().is_ok());
+        r.u8();
+        assert!(r.finish().is_err());
+
+        let mut r = StateReader::new(&data, STATE_VERSION);
+        r.u8();
+        assert!(r.finish().is_err());
+    }
+
+    #[test]
+    fn chunks() {
+        let header = StateHeader {
+            version : STATE_VERSION,
+            rom_hash : hash(b"rom"),
+            region : Region::Pal,
+        };
+        let data = write_state(&header, &[(b"ONE ", vec![1, 2]), (b"TWO ", vec![])]);
+
+        let (read_header, chunks) = read_state(&data).unwrap();
+        assert_eq!(read_header, header);
+        assert_eq!(chunks, vec![(*b"ONE ", &[1u8, 2][..]), (*b"TWO ", &[][..])]);
+
+        assert!(read_state(&data[..data.len() - 9]).is_err());
+        assert!(read_state(b"not a state at all").is_err());
+
+        let newer = StateHeader { version : STATE_VERSION + 1, ..header };
+        assert!(read_state(&write_state(&newer, &[])).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+// A window with keyboard, mouse and gamepad input and an audio device,
+// using SDL.
+
+use sdl2::Sdl;
+
+use frontend::{ Frontend, EmulatorEvent };
+use config::{ Config, Pacing };
+use graphics::EmulatorGraphics;
+use audio::EmulatorAudio;
+use input::EmulatorInput;
+
+use std::time::{ Duration, Instant };
+
+pub struct SdlFrontend {
+    graphics : EmulatorGraphics,
+    input : EmulatorInput,
+    audio : EmulatorAudio,
+    sdl_context : Sdl,
+
+    pacing : Pacing,
+    // when the next frame is due with timer pacing
+    next_frame : Instant,
+}
+
+impl SdlFrontend {
+    pub fn new(config : &Config) -> SdlFrontend {
+        let sdl_context = ::sdl2::init().unwrap();
+
+        SdlFrontend {
+            graphics : EmulatorGraphics::new(&sdl_context, config.pacing == Pacing::Vsync),
+            input : EmulatorInput::new(&sdl_context, config),
+            audio : EmulatorAudio::new(&sdl_context),
+            sdl_context : sdl_context,
+            pacing : config.pacing,
+            next_frame : Instant::now(),
+        }
+    }
+
+    pub fn underruns(&self) -> usize {
+        self.audio.underruns()
+    }
+
+    pub fn overruns(&self) -> usize {
+        self.audio.overruns()
+    }
+}
+
+impl Frontend for SdlFrontend {
+    fn sample_rate(&self) -> u32 {
+        self.audio.sample_rate()
+    }
+
+    fn present_frame(&mut self, pixels : &[u8]) {
+        self.graphics.update(pixels);
+    }
+
+    fn queue_audio(&mut self, samples : &[f32]) {
+        self.audio.queue_samples(samples);
+    }
+
+    fn events(&mut self) -> Vec<EmulatorEvent> {
+        self.input.events()
+    }
+
+    fn set_title(&mut self, title : &str) {
+        self.graphics.set_title(title);
+    }
+
+    fn wait_for_frame(&mut self, frame_rate : f64) {
+        match self.pacing {
+            Pacing::Timer => {
+                // frames are due at fixed times rather than a frame after
+                // the last one ended, so the time spent emulating doesn't
+                // add up into drift
+                let frame_len = Duration::from_secs_f64(1.0 / frame_rate);
+                self.next_frame += frame_len;
+                let now = Instant::now();
+                if self.next_frame > now {
+                    ::std::thread::sleep(self.next_frame - now);
+                }
+                else if now - self.next_frame > frame_len {
+                    // fell too far behind to catch up, start over from here
+                    self.next_frame = now;
+                }
+            },
+            Pacing::Audio => self.audio.wait_for_space(),
+            // presenting the frame already waited for the display
+            Pacing::Vsync => (),
+        }
+    }
+
+    // neither the audio device or the display runs at exactly the
+    // console's rate, so nudge the sample rate to keep the queue level
+    fn rate_adjustment(&self) -> f64 {
+        match self.pacing {
+            Pacing::Timer => 1.0,
+            Pacing::Audio | Pacing::Vsync => self.audio.rate_adjustment(),
+        }
+    }
+}
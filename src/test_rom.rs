@@ -0,0 +1,170 @@
+// http://wiki.nesdev.com/w/index.php/Emulator_tests
+// Runs test roms that report their results in PRG RAM, like blargg's
+// instr_test, ppu_vbl_nmi and apu_test:
+//
+//   $6000      status: $80 while running, $81 when the console needs to be
+//              reset, and anything else is the final result, 0 for passed
+//   $6001-6003 $DE $B0 $61 once the status is valid
+//   $6004-     a NUL terminated message
+
+use console::Nes;
+
+const STATUS : u16 = 0x6000;
+const SIGNATURE : u16 = 0x6001;
+const MESSAGE : u16 = 0x6004;
+const MESSAGE_END : u16 = 0x7FFF;
+
+const SIGNATURE_BYTES : [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING : u8 = 0x80;
+const STATUS_NEEDS_RESET : u8 = 0x81;
+
+// how long to wait before pressing reset, the roms ask for at least 100ms
+const RESET_DELAY : f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestRomOutcome {
+    Passed,
+    // the rom's result code
+    Failed(u8),
+    // there was no final status in time
+    TimedOut,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestRomResult {
+    pub outcome : TestRomOutcome,
+    pub message : String,
+}
+
+impl TestRomResult {
+    pub fn passed(&self) -> bool {
+        self.outcome == TestRomOutcome::Passed
+    }
+}
+
+fn has_signature(nes : &Nes) -> bool {
+    (0..3).all(|i| nes.peek_cpu(SIGNATURE + i) == SIGNATURE_BYTES[i as usize])
+}
+
+fn read_message(nes : &Nes) -> String {
+    let bytes : Vec<u8> = (MESSAGE..=MESSAGE_END)
+        .map(|addr| nes.peek_cpu(addr))
+        .take_while(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// runs the rom until it gives a final status, or for at most `timeout`
+// seconds of emulated time
+pub fn run_test_rom(nes : &mut Nes, timeout : f64) -> TestRomResult {
+    let frame_rate = nes.region().frame_rate();
+    let timeout_frames = (timeout * frame_rate) as usize;
+    let reset_delay = (RESET_DELAY * frame_rate).ceil() as usize;
+
+    // frames left before pressing reset
+    let mut reset_in = None;
+    // the status stays at $81 for a bit after a reset, until the rom
+    // clears it
+    let mut just_reset = false;
+
+    for _ in 0..timeout_frames {
+        nes.step_frame();
+        if !has_signature(nes) {
+            continue;
+        }
+
+        match nes.peek_cpu(STATUS) {
+            STATUS_RUNNING => just_reset = false,
+            STATUS_NEEDS_RESET if !just_reset => {
+                match reset_in {
+                    None => reset_in = Some(reset_delay),
+                    Some(0) => {
+                        nes.reset();
+                        reset_in = None;
+                        just_reset = true;
+                    },
+                    Some(n) => reset_in = Some(n - 1),
+                }
+            },
+            STATUS_NEEDS_RESET => (),
+            status => return TestRomResult {
+                outcome : if status == 0 {
+                    TestRomOutcome::Passed
+                } else {
+                    TestRomOutcome::Failed(status)
+                },
+                message : read_message(nes),
+            },
+        }
+    }
+
+    TestRomResult {
+        outcome : TestRomOutcome::TimedOut,
+        message : if has_signature(nes) { read_message(nes) } else { String::new() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cartridge;
+
+    fn store(code : &mut Vec<u8>, addr : u16, val : u8) {
+        // LDA #val; STA addr
+        code.extend_from_slice(&[0xA9, val, 0x8D, addr as u8, (addr >> 8) as u8]);
+    }
+
+    fn jump_to_self(code : &mut Vec<u8>) {
+        let addr = 0x8000 + code.len() as u16;
+        code.extend_from_slice(&[0x4C, addr as u8, (addr >> 8) as u8]);
+    }
+
+    // an NROM image that reports status and message, asking to be reset
+    // first if `resets` is set
+    fn test_rom(status : u8, message : &str, resets : bool) -> Vec<u8> {
+        let mut code = Vec::new();
+        for (i, &b) in SIGNATURE_BYTES.iter().enumerate() {
+            store(&mut code, SIGNATURE + i as u16, b);
+        }
+        if resets {
+            // LDA $6010; BNE past the reset request; INC $6010
+            code.extend_from_slice(&[0xAD, 0x10, 0x60, 0xD0, 11, 0xEE, 0x10, 0x60]);
+            store(&mut code, STATUS, STATUS_NEEDS_RESET);
+            jump_to_self(&mut code);
+        }
+        store(&mut code, STATUS, STATUS_RUNNING);
+        for (i, b) in message.bytes().chain(Some(0)).enumerate() {
+            store(&mut code, MESSAGE + i as u16, b);
+        }
+        store(&mut code, STATUS, status);
+        jump_to_self(&mut code);
+
+        cartridge::test_nrom(&code, 0x8000, 0x8000)
+    }
+
+    #[test]
+    fn results() {
+        let mut nes = Nes::from_rom(&test_rom(0, "Passed\n", false));
+        assert_eq!(run_test_rom(&mut nes, 1.0), TestRomResult {
+            outcome : TestRomOutcome::Passed,
+            message : String::from("Passed\n"),
+        });
+
+        let mut nes = Nes::from_rom(&test_rom(3, "Failed #3", false));
+        let result = run_test_rom(&mut nes, 1.0);
+        assert_eq!(result.outcome, TestRomOutcome::Failed(3));
+        assert!(!result.passed());
+
+        let mut nes = Nes::from_rom(&test_rom(0, "after reset", true));
+        let result = run_test_rom(&mut nes, 1.0);
+        assert!(result.passed());
+        assert_eq!(nes.peek_cpu(0x6010), 1);
+
+        // JMP $8000 forever
+        let mut rom = test_rom(0, "", false);
+        rom[0x10..0x13].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        let mut nes = Nes::from_rom(&rom);
+        assert_eq!(run_test_rom(&mut nes, 0.5).outcome, TestRomOutcome::TimedOut);
+    }
+}
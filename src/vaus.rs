@@ -0,0 +1,137 @@
+// https://wiki.nesdev.com/w/index.php/Arkanoid_controller
+// The Vaus paddle that came with Arkanoid: a knob and a fire button. A
+// strobe latches the knob's position, which is then read as 8 bits, high
+// bit first and inverted. The NES version is in a controller port:
+//
+//   D3 : 1 while the button is pressed
+//   D4 : knob bit
+//
+// and the Famicom one is in the expansion port, with the button on D1 of
+// $4016 and the knob on D1 of $4017.
+
+use ports::InputDevice;
+use ppu::PPU;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+// about how far the real knob turns
+const KNOB_MIN : i32 = 0x54;
+const KNOB_MAX : i32 = 0xF4;
+
+#[derive(Debug)]
+pub struct Vaus {
+    famicom : bool,
+    // the knob follows the mouse across
+    knob : i32,
+    button : bool,
+    // the latched knob bits, the next to be read in bit 7
+    shift : u8,
+    strobe : bool,
+}
+
+impl Vaus {
+    pub fn new(famicom : bool) -> Vaus {
+        Vaus {
+            famicom : famicom,
+            knob : (KNOB_MIN + KNOB_MAX) / 2,
+            button : false,
+            shift : 0,
+            strobe : false,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift = !(self.knob as u8);
+    }
+
+    // the bits for a read of port's register, and whether the knob is read
+    fn bits(&self, port : usize) -> (u8, bool) {
+        let knob = self.shift >> 7;
+        let button = self.button as u8;
+        match (self.famicom, port) {
+            (false, _) => ((knob << 4) | (button << 3), true),
+            (true, 0)  => (button << 1, false),
+            (true, _)  => (knob << 1, true),
+        }
+    }
+}
+
+impl InputDevice for Vaus {
+    fn write(&mut self, val : u8) {
+        self.strobe = (val & 1) == 1;
+        self.latch();
+    }
+
+    fn read(&mut self, port : usize, _ppu : &PPU) -> u8 {
+        let (bits, knob_read) = self.bits(port);
+        if knob_read && !self.strobe {
+            self.shift <<= 1;
+        }
+        bits
+    }
+
+    fn peek(&self, port : usize, _ppu : &PPU) -> u8 {
+        self.bits(port).0
+    }
+
+    fn set_trigger(&mut self, pulled : bool) {
+        self.button = pulled;
+    }
+
+    fn move_pointer(&mut self, dx : i32, _dy : i32) {
+        self.knob = (self.knob + dx).clamp(KNOB_MIN, KNOB_MAX);
+        if self.strobe {
+            self.latch();
+        }
+    }
+}
+
+impl SaveState for Vaus {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.u8(self.knob as u8);
+        w.bool(self.button);
+        w.u8(self.shift);
+        w.bool(self.strobe);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        self.knob = (r.u8() as i32).clamp(KNOB_MIN, KNOB_MAX);
+        self.button = r.bool();
+        self.shift = r.u8();
+        self.strobe = r.bool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the knob's position, read the way games do
+    fn read_knob(vaus : &mut Vaus, port : usize, bit : u8) -> u8 {
+        let ppu = PPU::test();
+        vaus.write(1);
+        vaus.write(0);
+        (0..8).fold(0, |val, _| (val << 1) | ((vaus.read(port, &ppu) >> bit) & 1)) ^ 0xFF
+    }
+
+    #[test]
+    fn knob() {
+        let ppu = PPU::test();
+        let mut vaus = Vaus::new(false);
+        vaus.move_pointer(-1000, 0);
+        assert_eq!(read_knob(&mut vaus, 1, 4), KNOB_MIN as u8);
+        vaus.move_pointer(0x10, 0);
+        assert_eq!(read_knob(&mut vaus, 1, 4), KNOB_MIN as u8 + 0x10);
+        vaus.move_pointer(1000, 0);
+        assert_eq!(read_knob(&mut vaus, 1, 4), KNOB_MAX as u8);
+
+        vaus.set_trigger(true);
+        assert_eq!(vaus.peek(1, &ppu) & 0x08, 0x08);
+
+        // the famicom one has the button on $4016 and the knob on $4017
+        let mut vaus = Vaus::new(true);
+        vaus.move_pointer(-1000, 0);
+        vaus.set_trigger(true);
+        assert_eq!(vaus.read(0, &ppu), 0x02);
+        assert_eq!(read_knob(&mut vaus, 1, 1), KNOB_MIN as u8);
+    }
+}
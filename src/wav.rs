@@ -0,0 +1,119 @@
+// http://soundfile.sapp.org/doc/WaveFormat/
+// Writes mono 16 bit PCM .wav files.
+
+use std::fs::File;
+use std::io::{ Seek, SeekFrom, Write, BufWriter };
+
+const HEADER_SIZE : u32 = 44;
+
+pub struct WavWriter {
+    filename : String,
+    file : BufWriter<File>,
+    sample_rate : u32,
+    num_samples : u32,
+}
+
+impl WavWriter {
+    pub fn create(filename : &str, sample_rate : u32) -> Result<WavWriter, String> {
+        let file = File::create(filename)
+            .map_err(|e| format!("error creating {}: {}", filename, e))?;
+
+        let mut writer = WavWriter {
+            filename : String::from(filename),
+            file : BufWriter::new(file),
+            sample_rate : sample_rate,
+            num_samples : 0,
+        };
+
+        // the sizes get filled in when the file is finished
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn error(&self, e : ::std::io::Error) -> String {
+        format!("error writing {}: {}", self.filename, e)
+    }
+
+    fn write_header(&mut self) -> Result<(), String> {
+        let data_size = self.num_samples * 2;
+        let byte_rate = self.sample_rate * 2;
+
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, 1 channel
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&self.sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        // bytes per sample frame, bits per sample
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_size.to_le_bytes());
+
+        self.file.write_all(&header).map_err(|e| self.error(e))
+    }
+
+    // samples are between -1 and 1
+    pub fn write(&mut self, samples : &[f32]) -> Result<(), String> {
+        for &sample in samples {
+            let val = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&val.to_le_bytes()).map_err(|e| self.error(e))?;
+        }
+        self.num_samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn num_samples(&self) -> u32 {
+        self.num_samples
+    }
+
+    // writes the final sizes into the header
+    pub fn finish(mut self) -> Result<(), String> {
+        self.file.seek(SeekFrom::Start(0)).map_err(|e| self.error(e))?;
+        self.write_header()?;
+        self.file.flush().map_err(|e| self.error(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn header_and_data() {
+        let filename = ::std::env::temp_dir().join("nes-wav-test.wav");
+        let filename = filename.to_str().unwrap();
+
+        let mut w = WavWriter::create(filename, 44100).unwrap();
+        w.write(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        w.finish().unwrap();
+
+        let mut data = Vec::new();
+        File::open(filename).unwrap().read_to_end(&mut data).unwrap();
+        ::std::fs::remove_file(filename).unwrap();
+
+        assert_eq!(data.len(), 44 + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(&data[4..8], &44u32.to_le_bytes());
+        assert_eq!(&data[24..28], &44100u32.to_le_bytes());
+        assert_eq!(&data[40..44], &8u32.to_le_bytes());
+
+        // clamped to the 16 bit range
+        assert_eq!(&data[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn create_error() {
+        let filename = ::std::env::temp_dir().join("nes-wav-test-missing").join("out.wav");
+        let err = WavWriter::create(filename.to_str().unwrap(), 44100).err().unwrap();
+        assert!(err.starts_with("error creating"));
+    }
+}
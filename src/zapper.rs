@@ -0,0 +1,156 @@
+// https://wiki.nesdev.com/w/index.php/Zapper
+// The light gun. Its photodiode sees a bright spot for a while after the
+// beam draws it, so games blank the screen and draw bright targets for a
+// frame, and read whether the gun saw light while that frame is drawn.
+//
+//   D3 : 0 when light is seen
+//   D4 : 1 while the trigger is pulled
+
+use ppu::{ PPU, SCREEN_WIDTH, SCREEN_HEIGHT, BYTES_PER_PIXEL };
+use ports::InputDevice;
+use savestate::{ SaveState, StateWriter, StateReader };
+
+// how many scanlines the photodiode stays lit after the beam passes
+const LIGHT_LINES : i32 = 24;
+// how far around where it's pointed the gun sees, in pixels
+const SIGHT_RADIUS : i32 = 2;
+// how bright a pixel has to be to set it off, out of 255
+const BRIGHTNESS_THRESHOLD : u32 = 0x80;
+
+// the dot a scanline is drawn on, see PPU::tick
+const RENDER_DOT : u16 = 256;
+
+#[derive(Debug)]
+pub struct Zapper {
+    // the pixel it's pointed at, None when it's off the screen
+    pointer : Option<(i32, i32)>,
+    trigger : bool,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper {
+            pointer : None,
+            trigger : false,
+        }
+    }
+
+    fn bits(&self, ppu : &PPU) -> u8 {
+        let light = match self.pointer {
+            Some(pos) => light_sensed(ppu.get_pixeldata(), ppu.scanline(), ppu.dot(), pos),
+            None => false,
+        };
+        ((!light as u8) << 3) | ((self.trigger as u8) << 4)
+    }
+}
+
+fn brightness(pixels : &[u8], x : i32, y : i32) -> u32 {
+    let i = (y as usize * SCREEN_WIDTH + x as usize) * BYTES_PER_PIXEL;
+    // pixels are BGR
+    let (b, g, r) = (pixels[i] as u32, pixels[i + 1] as u32, pixels[i + 2] as u32);
+    (r * 299 + g * 587 + b * 114) / 1000
+}
+
+// whether something bright near pos was drawn recently enough to still be
+// lighting the photodiode, with the beam at scanline and dot
+fn light_sensed(pixels : &[u8], scanline : u16, dot : u16, pos : (i32, i32)) -> bool {
+    let (x, y) = pos;
+    let beam = scanline as i32;
+
+    for row in y - SIGHT_RADIUS..=y + SIGHT_RADIUS {
+        if row < 0 || row >= SCREEN_HEIGHT as i32 {
+            continue;
+        }
+        // rows further down are still from the last frame
+        let drawn = row < beam || (row == beam && dot >= RENDER_DOT);
+        if !drawn || beam - row >= LIGHT_LINES {
+            continue;
+        }
+
+        for col in x - SIGHT_RADIUS..=x + SIGHT_RADIUS {
+            if col >= 0 && col < SCREEN_WIDTH as i32 &&
+               brightness(pixels, col, row) >= BRIGHTNESS_THRESHOLD {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+impl InputDevice for Zapper {
+    // the zapper doesn't use the strobe
+    fn write(&mut self, _val : u8) {}
+
+    fn read(&mut self, _port : usize, ppu : &PPU) -> u8 {
+        self.bits(ppu)
+    }
+
+    fn peek(&self, _port : usize, ppu : &PPU) -> u8 {
+        self.bits(ppu)
+    }
+
+    fn set_pointer(&mut self, pos : Option<(i32, i32)>) {
+        self.pointer = pos;
+    }
+
+    fn set_trigger(&mut self, pulled : bool) {
+        self.trigger = pulled;
+    }
+}
+
+impl SaveState for Zapper {
+    fn save_state(&self, w : &mut StateWriter) {
+        w.bool(self.pointer.is_some());
+        let (x, y) = self.pointer.unwrap_or((0, 0));
+        w.u16(x as u16);
+        w.u16(y as u16);
+        w.bool(self.trigger);
+    }
+
+    fn load_state(&mut self, r : &mut StateReader) {
+        let on_screen = r.bool();
+        let pos = (r.u16() as i16 as i32, r.u16() as i16 as i32);
+        self.pointer = if on_screen { Some(pos) } else { None };
+        self.trigger = r.bool();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ppu::SCREEN_SIZE;
+
+    #[test]
+    fn light() {
+        let mut pixels = vec![0; SCREEN_SIZE];
+        // a white pixel at 100, 50
+        let i = (50 * SCREEN_WIDTH + 100) * BYTES_PER_PIXEL;
+        pixels[i..i + 3].copy_from_slice(&[0xFF, 0xFF, 0xFF]);
+
+        // not drawn yet, then drawn this scanline
+        assert!(!light_sensed(&pixels, 40, 0, (100, 50)));
+        assert!(!light_sensed(&pixels, 50, 100, (100, 50)));
+        assert!(light_sensed(&pixels, 50, 300, (100, 50)));
+        // still lit a while later, and not a while after that
+        assert!(light_sensed(&pixels, 60, 0, (101, 51)));
+        assert!(!light_sensed(&pixels, 50 + LIGHT_LINES as u16, 0, (100, 50)));
+
+        // pointing away from it
+        assert!(!light_sensed(&pixels, 60, 0, (110, 50)));
+        // and off the edge of the screen
+        assert!(!light_sensed(&pixels, 60, 0, (-5, 300)));
+
+        // dark colors don't count
+        pixels[i..i + 3].copy_from_slice(&[0x40, 0x20, 0x20]);
+        assert!(!light_sensed(&pixels, 60, 0, (100, 50)));
+    }
+
+    #[test]
+    fn bits() {
+        let ppu = PPU::test();
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.peek(1, &ppu), 0x08);
+        zapper.set_trigger(true);
+        assert_eq!(zapper.read(1, &ppu), 0x18);
+    }
+}